@@ -0,0 +1,52 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rmr::cmd::StringCommand;
+use rmr::db::Db;
+
+// 同时执行命令的线程数
+const THREADS: usize = 8;
+// 每个线程每轮执行的命令数
+const OPS: usize = 1000;
+// 命令分散在这么多个 key 上
+const KEYS: usize = 1024;
+
+// 所有线程同时对随机的 key 执行 INCR，返回总共用了多少时间
+fn incr(db: &Db, keys: &[Bytes]) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..THREADS {
+            scope.spawn(move || {
+                for i in 0..OPS {
+                    let key = keys[(i * 31 + t * 97) % keys.len()].clone();
+                    let cmd = StringCommand::parse("incr", vec![key]).unwrap();
+                    let mut keyspace = db.blocking_lock_keys(&cmd.keys());
+                    cmd.execute(&mut keyspace);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+// 同样的负载，只有一个 shard（整个 keyspace 一把锁）和分成多个 shard 的对比
+fn contention(c: &mut Criterion) {
+    let keys: Vec<Bytes> = (0..KEYS)
+        .map(|i| Bytes::from(format!("counter:{}", i)))
+        .collect();
+
+    let mut group = c.benchmark_group("incr under contention");
+    group.throughput(Throughput::Elements((THREADS * OPS) as u64));
+    for shards in [1, 4, 16, 64] {
+        let db = Db::with_shards(shards);
+        group.bench_with_input(BenchmarkId::new("shards", shards), &db, |b, db| {
+            b.iter_custom(|iters| (0..iters).map(|_| incr(db, &keys)).sum())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+criterion_main!(benches);
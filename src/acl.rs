@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::RwLock;
+
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::db::is_url;
+use crate::glob;
+
+/// 每个命令（有子命令的写成 `命令|子命令`）属于哪些类别，`+@类别` 按这张表展开。
+/// 不在表里的命令（比如未知命令）不做权限检查
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("acl|deluser", &["admin", "slow", "dangerous"]),
+    ("acl|list", &["admin", "slow", "dangerous"]),
+    ("acl|setuser", &["admin", "slow", "dangerous"]),
+    ("acl|whoami", &["slow"]),
+    ("append", &["write", "string", "fast"]),
+    ("auth", &["fast", "connection"]),
+    ("blmove", &["write", "list", "slow", "blocking"]),
+    ("blpop", &["write", "list", "slow", "blocking"]),
+    ("brpop", &["write", "list", "slow", "blocking"]),
+    ("client|getname", &["slow", "connection"]),
+    ("client|id", &["slow", "connection"]),
+    ("client|info", &["slow", "connection"]),
+    ("client|kill", &["admin", "slow", "dangerous", "connection"]),
+    ("client|list", &["admin", "slow", "dangerous", "connection"]),
+    ("client|setname", &["slow", "connection"]),
+    ("dbsize", &["read", "keyspace", "fast"]),
+    ("decr", &["write", "string", "fast"]),
+    ("decrby", &["write", "string", "fast"]),
+    ("del", &["write", "keyspace", "slow"]),
+    ("discard", &["fast", "transaction"]),
+    ("eval", &["slow", "scripting"]),
+    ("evalsha", &["slow", "scripting"]),
+    ("exec", &["slow", "transaction"]),
+    ("exists", &["read", "keyspace", "fast"]),
+    ("expire", &["write", "keyspace", "fast"]),
+    ("expireat", &["write", "keyspace", "fast"]),
+    ("flushdb", &["write", "keyspace", "slow", "dangerous"]),
+    ("get", &["read", "string", "upstream", "slow"]),
+    ("getdel", &["write", "string", "fast"]),
+    ("getex", &["write", "string", "fast"]),
+    ("getrange", &["read", "string", "slow"]),
+    ("hdel", &["write", "hash", "fast"]),
+    ("hexists", &["read", "hash", "fast"]),
+    ("hfetch", &["write", "hash", "upstream", "slow"]),
+    ("hget", &["read", "hash", "fast"]),
+    ("hgetall", &["read", "hash", "slow"]),
+    ("hincrby", &["write", "hash", "fast"]),
+    ("hkeys", &["read", "hash", "slow"]),
+    ("hlen", &["read", "hash", "fast"]),
+    ("hmget", &["read", "hash", "fast"]),
+    ("hscan", &["read", "hash", "slow"]),
+    ("hset", &["write", "hash", "fast"]),
+    ("hvals", &["read", "hash", "slow"]),
+    ("incr", &["write", "string", "fast"]),
+    ("incrby", &["write", "string", "fast"]),
+    ("incrbyfloat", &["write", "string", "fast"]),
+    ("info", &["slow", "dangerous"]),
+    ("keys", &["read", "keyspace", "slow", "dangerous"]),
+    ("lindex", &["read", "list", "slow"]),
+    ("llen", &["read", "list", "fast"]),
+    ("lmove", &["write", "list", "slow"]),
+    ("lpop", &["write", "list", "fast"]),
+    ("lpush", &["write", "list", "fast"]),
+    ("lrange", &["read", "list", "slow"]),
+    ("lrem", &["write", "list", "slow"]),
+    ("ltrim", &["write", "list", "slow"]),
+    ("mget", &["read", "string", "upstream", "fast"]),
+    ("monitor", &["admin", "slow", "dangerous"]),
+    ("mset", &["write", "string", "slow"]),
+    ("msetnx", &["write", "string", "slow"]),
+    ("multi", &["fast", "transaction"]),
+    ("persist", &["write", "keyspace", "fast"]),
+    ("pexpire", &["write", "keyspace", "fast"]),
+    ("pexpireat", &["write", "keyspace", "fast"]),
+    ("pttl", &["read", "keyspace", "fast"]),
+    ("rename", &["write", "keyspace", "slow"]),
+    ("renamenx", &["write", "keyspace", "fast"]),
+    ("rpop", &["write", "list", "fast"]),
+    ("rpush", &["write", "list", "fast"]),
+    ("sadd", &["write", "set", "fast"]),
+    ("scan", &["read", "keyspace", "slow"]),
+    ("scard", &["read", "set", "fast"]),
+    ("script|exists", &["slow", "scripting"]),
+    ("script|flush", &["slow", "scripting"]),
+    ("script|load", &["slow", "scripting"]),
+    ("sdiff", &["read", "set", "slow"]),
+    ("set", &["write", "string", "slow"]),
+    ("setrange", &["write", "string", "slow"]),
+    ("sinter", &["read", "set", "slow"]),
+    ("sismember", &["read", "set", "fast"]),
+    ("slowlog|get", &["admin", "slow", "dangerous"]),
+    ("slowlog|len", &["admin", "slow", "dangerous"]),
+    ("slowlog|reset", &["admin", "slow", "dangerous"]),
+    ("smembers", &["read", "set", "slow"]),
+    ("spop", &["write", "set", "fast"]),
+    ("srandmember", &["read", "set", "slow"]),
+    ("srem", &["write", "set", "fast"]),
+    ("strlen", &["read", "string", "fast"]),
+    ("sunion", &["read", "set", "slow"]),
+    ("ttl", &["read", "keyspace", "fast"]),
+    ("type", &["read", "keyspace", "fast"]),
+    ("unlink", &["write", "keyspace", "fast"]),
+    ("unwatch", &["fast", "transaction"]),
+    ("watch", &["fast", "transaction"]),
+    ("xack", &["write", "stream", "fast"]),
+    ("xadd", &["write", "stream", "fast"]),
+    ("xclaim", &["write", "stream", "fast"]),
+    ("xgroup|create", &["write", "stream", "slow"]),
+    ("xlen", &["read", "stream", "fast"]),
+    ("xpending", &["read", "stream", "slow"]),
+    ("xrange", &["read", "stream", "slow"]),
+    ("xread", &["read", "stream", "slow", "blocking"]),
+    ("xreadgroup", &["write", "stream", "slow", "blocking"]),
+    ("xrevrange", &["read", "stream", "slow"]),
+    ("xtrim", &["write", "stream", "slow"]),
+    ("zadd", &["write", "sortedset", "fast"]),
+    ("zcard", &["read", "sortedset", "fast"]),
+    ("zincrby", &["write", "sortedset", "fast"]),
+    ("zpopmin", &["write", "sortedset", "fast"]),
+    ("zrange", &["read", "sortedset", "slow"]),
+    ("zrank", &["read", "sortedset", "fast"]),
+    ("zrem", &["write", "sortedset", "fast"]),
+    ("zscore", &["read", "sortedset", "fast"]),
+];
+
+pub const DEFAULT_USER: &str = "default";
+
+/// 一个 ACL 用户：能不能登录、密码（只保存 sha256）、能执行的命令、能访问的 key 和上游 url
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    enabled: bool,
+    nopass: bool,
+    passwords: BTreeSet<String>,
+    commands: BTreeSet<&'static str>,
+    keys: Vec<String>,
+    urls: Vec<String>,
+}
+
+impl User {
+    /// 新用户和 Redis 一样什么权限都没有，而且是禁用的
+    pub fn new(name: impl ToString) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: BTreeSet::new(),
+            keys: Vec::new(),
+            urls: Vec::new(),
+        }
+    }
+
+    /// 按照 ACL SETUSER 的规则修改用户。除了 Redis 的规则，
+    /// 还支持 `url~<pattern>` / `allurls` / `reseturls` 限制能访问的上游 url
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let lower = rule.to_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".to_string()],
+            "resetkeys" => self.keys.clear(),
+            "allurls" => self.urls = vec!["*".to_string()],
+            "reseturls" => self.urls.clear(),
+            "allcommands" => self.apply("+@all")?,
+            "nocommands" => self.apply("-@all")?,
+            "reset" => {
+                for rule in ["resetpass", "resetkeys", "reseturls", "nocommands", "off"] {
+                    self.apply(rule)?;
+                }
+            }
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.passwords.insert(hash(password));
+                    self.nopass = false;
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    if !self.passwords.remove(&hash(password)) {
+                        return Err("no such password".to_string());
+                    }
+                } else if let Some(hashed) = rule.strip_prefix('#') {
+                    if hashed.len() != 64 || !hashed.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err("the password hash must be 64 hex characters".to_string());
+                    }
+                    self.passwords.insert(hashed.to_lowercase());
+                    self.nopass = false;
+                } else if let Some(hashed) = rule.strip_prefix('!') {
+                    if !self.passwords.remove(&hashed.to_lowercase()) {
+                        return Err("no such password".to_string());
+                    }
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    self.keys.push(pattern.to_string());
+                } else if rule.len() > 4 && lower.starts_with("url~") {
+                    self.urls.push(rule[4..].to_string());
+                } else if let Some(name) = lower.strip_prefix('+') {
+                    self.commands.extend(expand(name)?);
+                } else if let Some(name) = lower.strip_prefix('-') {
+                    for id in expand(name)? {
+                        self.commands.remove(id);
+                    }
+                } else {
+                    return Err("Syntax error".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        self.nopass || self.passwords.contains(&hash(password))
+    }
+}
+
+// `@类别` 或者命令名展开成命令 id 的列表。命令名不带子命令时包括它所有的子命令
+fn expand(name: &str) -> Result<Vec<&'static str>, String> {
+    let ids: Vec<&'static str> = match name.strip_prefix('@') {
+        Some("all") => COMMANDS.iter().map(|(id, _)| *id).collect(),
+        Some(category) => COMMANDS
+            .iter()
+            .filter(|(_, categories)| categories.contains(&category))
+            .map(|(id, _)| *id)
+            .collect(),
+        None => COMMANDS
+            .iter()
+            .filter(|(id, _)| {
+                *id == name || (id.starts_with(name) && id[name.len()..].starts_with('|'))
+            })
+            .map(|(id, _)| *id)
+            .collect(),
+    };
+
+    if ids.is_empty() {
+        return Err("Unknown command or category name in ACL".to_string());
+    }
+    Ok(ids)
+}
+
+fn hash(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// ACL LIST 的一行，和 Redis 的格式一样
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "user {} {}",
+            self.name,
+            if self.enabled { "on" } else { "off" }
+        )?;
+        if self.nopass {
+            write!(f, " nopass")?;
+        }
+        for password in &self.passwords {
+            write!(f, " #{}", password)?;
+        }
+        for key in &self.keys {
+            write!(f, " ~{}", key)?;
+        }
+        for url in &self.urls {
+            write!(f, " url~{}", url)?;
+        }
+        if self.commands.len() == COMMANDS.len() {
+            write!(f, " +@all")
+        } else {
+            write!(f, " -@all")?;
+            for id in &self.commands {
+                write!(f, " +{}", id)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// 所有的 ACL 用户。启动时根据 requirepass 和 user 指令创建，之后可以用 ACL SETUSER 修改
+#[derive(Debug)]
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+}
+
+impl Acl {
+    pub fn new(config: &Config) -> Acl {
+        // default 用户可以执行所有命令、访问所有 key 和 url；
+        // 没有设置 requirepass 的话不需要密码，连接上来就自动以它登录
+        let mut default = User::new(DEFAULT_USER);
+        let mut rules = vec!["on", "allcommands", "allkeys", "allurls"];
+        let password = config.requirepass.as_ref().map(|p| format!(">{}", p));
+        match &password {
+            Some(password) => rules.push(password),
+            None => rules.push("nopass"),
+        }
+        for rule in rules {
+            // 这些规则都是合法的
+            let _ = default.apply(rule);
+        }
+
+        let mut users = BTreeMap::new();
+        users.insert(default.name.clone(), default);
+        for user in &config.users {
+            users.insert(user.name.clone(), user.clone());
+        }
+
+        Acl {
+            users: RwLock::new(users),
+        }
+    }
+
+    /// 新连接自动登录的用户：default 用户启用了而且不需要密码时就是它
+    pub fn auto_login(&self) -> Option<String> {
+        let users = self.users.read().unwrap();
+        users
+            .get(DEFAULT_USER)
+            .filter(|user| user.enabled && user.nopass)
+            .map(|user| user.name.clone())
+    }
+
+    /// default 用户是不是不需要密码。这时 `AUTH <password>` 和 Redis 一样报错
+    pub fn default_nopass(&self) -> bool {
+        let users = self.users.read().unwrap();
+        users.get(DEFAULT_USER).is_some_and(|user| user.nopass)
+    }
+
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        let users = self.users.read().unwrap();
+        users
+            .get(name)
+            .is_some_and(|user| user.enabled && user.check_password(password))
+    }
+
+    /// 检查用户能不能执行这个命令、访问这些 key。不能的话返回要回复给客户端的错误
+    pub fn check(&self, user: Option<&str>, command: &str, keys: &[&[u8]]) -> Result<(), String> {
+        let users = self.users.read().unwrap();
+        let user = match user.and_then(|name| users.get(name)) {
+            Some(user) if user.enabled => user,
+            _ => return Err("NOAUTH Authentication required.".to_string()),
+        };
+
+        if !COMMANDS.iter().any(|(id, _)| *id == command) {
+            return Ok(());
+        }
+        if !user.commands.contains(command) {
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user.name, command
+            ));
+        }
+
+        // 上游的 url 用 url 的模式检查，其他的 key 用 key 的模式检查
+        for key in keys {
+            let (patterns, what) = if is_url(key) {
+                (&user.urls, "url")
+            } else {
+                (&user.keys, "key")
+            };
+            if !patterns.iter().any(|p| glob::matches(p.as_bytes(), key)) {
+                return Err(format!("NOPERM No permissions to access a {}", what));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// ACL SETUSER：用户不存在的话先创建。有一条规则不对的话整个修改都不生效
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| format!("Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    pub fn del_user(&self, name: &str) -> bool {
+        self.users.write().unwrap().remove(name).is_some()
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let users = self.users.read().unwrap();
+        users.values().map(|user| user.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(users: &[&str]) -> Acl {
+        let mut config = Config {
+            requirepass: Some("foobared".to_string()),
+            ..Config::default()
+        };
+        for line in users {
+            let mut args = line.split_whitespace();
+            let mut user = User::new(args.next().unwrap());
+            for rule in args {
+                user.apply(rule).unwrap();
+            }
+            config.users.push(user);
+        }
+        Acl::new(&config)
+    }
+
+    #[test]
+    fn ts_authenticate() {
+        let acl = acl(&["alice on >secret +@all ~*"]);
+        assert_eq!(acl.auto_login(), None);
+        assert!(acl.authenticate("default", "foobared"));
+        assert!(!acl.authenticate("default", "secret"));
+        assert!(acl.authenticate("alice", "secret"));
+
+        acl.set_user("alice", &["off".to_string()]).unwrap();
+        assert!(!acl.authenticate("alice", "secret"));
+    }
+
+    #[test]
+    fn ts_check() {
+        let acl = acl(&[
+            "reader on nopass +@read url~https://api.example.com/*",
+            "admin on nopass +@all -client|kill",
+        ]);
+
+        assert!(acl.check(None, "get", &[]).is_err());
+        assert!(acl
+            .check(Some("reader"), "get", &[b"https://api.example.com/ip"])
+            .is_ok());
+        assert_eq!(
+            acl.check(Some("reader"), "get", &[b"https://evil.com/ip"]),
+            Err("NOPERM No permissions to access a url".to_string())
+        );
+        assert_eq!(
+            acl.check(Some("reader"), "get", &[b"plain-key"]),
+            Err("NOPERM No permissions to access a key".to_string())
+        );
+        assert!(acl.check(Some("reader"), "info", &[]).is_err());
+
+        assert!(acl.check(Some("admin"), "client|list", &[]).is_ok());
+        assert!(acl.check(Some("admin"), "client|kill", &[]).is_err());
+    }
+
+    #[test]
+    fn ts_set_user() {
+        let acl = acl(&[]);
+        assert!(acl
+            .set_user("bob", &["on".to_string(), "+nothing".to_string()])
+            .is_err());
+        // 失败的修改不会留下半个用户
+        assert_eq!(acl.list().len(), 1);
+
+        acl.set_user(
+            "bob",
+            &["on".to_string(), ">pw".to_string(), "+get".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            acl.list()[0],
+            format!("user bob on #{} -@all +get", hash("pw"))
+        );
+        assert!(acl.del_user("bob"));
+        assert!(!acl.del_user("bob"));
+    }
+}
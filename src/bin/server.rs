@@ -1,15 +1,37 @@
-use log::warn;
-use tokio::net::TcpListener;
+use rmr::config::Config;
 use tokio::signal;
+use tokio::signal::unix::SignalKind;
+use tracing::warn;
 
 #[tokio::main]
 async fn main() {
-    // install global collector configured based on RUST_LOG env var.
-    tracing_subscriber::fmt::init();
+    // 第一个参数是配置文件的路径；不指定的话，使用默认配置（监听 127.0.0.1:6379）
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::from_file(path).unwrap(),
+        None => Config::default(),
+    };
 
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
+    // 日志按照 RUST_LOG 过滤；配置了 otlp-endpoint 的话同时导出 trace。
+    // 退出 main 时 drop 它，把还没有发出去的 span 发完
+    let _telemetry = rmr::telemetry::init(&config).unwrap();
 
-    warn!("the server starts to listen on PORT: 6379");
+    let endpoints = rmr::server::bind(&config).await.unwrap();
 
-    rmr::server::run(listener, signal::ctrl_c()).await.unwrap();
+    for endpoint in &endpoints {
+        warn!("the server starts to listen on: {}", endpoint);
+    }
+
+    rmr::server::run_endpoints(endpoints, config, shutdown_signal())
+        .await
+        .unwrap();
+}
+
+// ctrl-c（SIGINT）或者 SIGTERM（比如 kubernetes 停止 pod 时）都会触发优雅关闭
+async fn shutdown_signal() {
+    let mut terminate = signal::unix::signal(SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 }
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::broadcast;
+
+use crate::shutdown::Shutdown;
+
+/// 所有在线客户端的登记表，CLIENT LIST / CLIENT KILL 等命令通过它查看和管理客户端
+#[derive(Debug)]
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<ClientInfo>>>,
+}
+
+/// 一个在线客户端的信息
+#[derive(Debug)]
+pub struct ClientInfo {
+    pub id: u64,
+    /// 对端地址。unix socket 没有对端地址，和 Redis 一样写成 `<path>:0`
+    pub addr: String,
+    /// 本端地址，也就是客户端连上的那个端点
+    pub laddr: String,
+    pub fd: i32,
+    created: Instant,
+    // 通过它通知这个连接的任务结束，和 Shutdown 的用法一样
+    notify_kill: broadcast::Sender<()>,
+    activity: Mutex<Activity>,
+}
+
+// 随着客户端执行命令而变化的那部分信息
+#[derive(Debug)]
+struct Activity {
+    name: Option<String>,
+    // 登录的 ACL 用户，还没有登录时是 None
+    user: Option<String>,
+    last_active: Instant,
+    cmd: &'static str,
+    qbuf: usize,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients {
+            next_id: AtomicU64::new(1),
+            clients: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// 登记一个新连接。返回的 Shutdown 在这个客户端被 kill 时收到通知
+    pub fn register(&self, addr: String, laddr: String, fd: i32) -> (Arc<ClientInfo>, Shutdown) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (notify_kill, killed) = broadcast::channel(1);
+        let now = Instant::now();
+
+        let client = Arc::new(ClientInfo {
+            id,
+            addr,
+            laddr,
+            fd,
+            created: now,
+            notify_kill,
+            activity: Mutex::new(Activity {
+                name: None,
+                user: None,
+                last_active: now,
+                cmd: "NULL",
+                qbuf: 0,
+            }),
+        });
+        self.clients.lock().unwrap().insert(id, client.clone());
+
+        (client, Shutdown::new(killed))
+    }
+
+    /// 连接结束时从登记表中移除
+    pub fn unregister(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// 按 id 排好序的所有在线客户端
+    pub fn list(&self) -> Vec<Arc<ClientInfo>> {
+        self.clients.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 结束所有满足条件的客户端，返回结束的个数
+    pub fn kill(&self, filter: impl Fn(&ClientInfo) -> bool) -> usize {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .values()
+            .filter(|client| filter(client))
+            .map(|client| client.kill())
+            .count()
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Clients {
+        Clients::new()
+    }
+}
+
+impl ClientInfo {
+    pub fn name(&self) -> Option<String> {
+        self.activity.lock().unwrap().name.clone()
+    }
+
+    pub fn set_name(&self, name: Option<String>) {
+        self.activity.lock().unwrap().name = name;
+    }
+
+    /// 对端的 IP，按客户端限流时用。unix socket 的客户端都是同一个路径
+    pub fn ip(&self) -> &str {
+        let host = self
+            .addr
+            .rsplit_once(':')
+            .map_or(self.addr.as_str(), |(host, _)| host);
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.activity.lock().unwrap().user.clone()
+    }
+
+    pub fn set_user(&self, user: Option<String>) {
+        self.activity.lock().unwrap().user = user;
+    }
+
+    /// 开始执行一条命令时调用，记录命令名和读缓冲里还没处理的字节数
+    pub fn touch(&self, cmd: &'static str, qbuf: usize) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.last_active = Instant::now();
+        activity.cmd = cmd;
+        activity.qbuf = qbuf;
+    }
+
+    // 客户端的任务收到通知后马上结束，正在执行的命令也会被中断
+    fn kill(&self) {
+        let _ = self.notify_kill.send(());
+    }
+}
+
+/// CLIENT LIST 的一行，字段的含义和 Redis 一样。
+/// 目前没有 pub/sub，sub 始终是 0
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let activity = self.activity.lock().unwrap();
+        write!(
+            f,
+            "id={} addr={} laddr={} fd={} name={} age={} idle={} sub=0 qbuf={} cmd={} user={}",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.fd,
+            activity.name.as_deref().unwrap_or(""),
+            self.created.elapsed().as_secs(),
+            activity.last_active.elapsed().as_secs(),
+            activity.qbuf,
+            activity.cmd,
+            activity.user.as_deref().unwrap_or(""),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ts_register_and_kill() {
+        let clients = Clients::new();
+        let (a, _) = clients.register(
+            "127.0.0.1:5000".to_string(),
+            "127.0.0.1:6379".to_string(),
+            8,
+        );
+        let (b, mut killed) = clients.register(
+            "127.0.0.1:5001".to_string(),
+            "127.0.0.1:6379".to_string(),
+            9,
+        );
+        assert_eq!((a.id, b.id), (1, 2));
+        assert_eq!(a.ip(), "127.0.0.1");
+        assert_eq!(clients.len(), 2);
+
+        b.set_name(Some("worker".to_string()));
+        assert!(b
+            .to_string()
+            .starts_with("id=2 addr=127.0.0.1:5001 laddr=127.0.0.1:6379 fd=9 name=worker "));
+
+        assert_eq!(clients.kill(|c| c.addr == "127.0.0.1:5001"), 1);
+        killed.recv().await;
+        assert!(killed.is_shutdown());
+
+        clients.unregister(a.id);
+        assert_eq!(clients.list().len(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use bytes::Bytes;
+
+use crate::acl::DEFAULT_USER;
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::parser;
+
+use super::{CommandSnafu, ConnectSnafu, Context, Result};
+use snafu::ResultExt;
+
+/// ACL WHOAMI / LIST / SETUSER / DELUSER
+#[derive(Debug)]
+pub enum AclCommand {
+    Whoami,
+    List,
+    SetUser(String, Vec<String>),
+    DelUser(Vec<String>),
+    // 子命令或者参数不对，直接回复这个错误
+    Invalid(String),
+}
+
+impl AclCommand {
+    pub fn parse_frame(parser: &mut parser::Parser) -> Result<AclCommand> {
+        let sub = parser.next_string().context(CommandSnafu)?.to_lowercase();
+
+        let mut args = Vec::new();
+        while parser.has_next() {
+            args.push(parser.next_string().context(CommandSnafu)?);
+        }
+
+        let cmd = match (sub.as_str(), args.len()) {
+            ("whoami", 0) => AclCommand::Whoami,
+            ("list", 0) => AclCommand::List,
+            ("setuser", n) if n > 0 => {
+                let name = args.remove(0);
+                AclCommand::SetUser(name, args)
+            }
+            ("deluser", n) if n > 0 => AclCommand::DelUser(args),
+            ("whoami" | "list" | "setuser" | "deluser", _) => AclCommand::Invalid(format!(
+                "ERR wrong number of arguments for 'acl|{}' command",
+                sub
+            )),
+            _ => AclCommand::Invalid(format!("ERR unknown subcommand '{}'. Try ACL HELP.", sub)),
+        };
+
+        Ok(cmd)
+    }
+
+    /// 检查权限时用的命令 id
+    pub fn acl_id(&self) -> &'static str {
+        match self {
+            AclCommand::Whoami => "acl|whoami",
+            AclCommand::List => "acl|list",
+            AclCommand::SetUser(..) => "acl|setuser",
+            AclCommand::DelUser(_) => "acl|deluser",
+            AclCommand::Invalid(_) => "acl",
+        }
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        let acl = &ctx.state.acl;
+        let mut deleted: Vec<String> = Vec::new();
+        let response = match self {
+            AclCommand::Whoami => match ctx.client.user() {
+                Some(user) => Frame::Bulk(Bytes::from(user)),
+                None => Frame::Null,
+            },
+            AclCommand::List => Frame::Array(
+                acl.list()
+                    .into_iter()
+                    .map(|line| Frame::Bulk(Bytes::from(line)))
+                    .collect(),
+            ),
+            AclCommand::SetUser(name, rules) => match acl.set_user(&name, &rules) {
+                Ok(()) => Frame::Simple("OK".to_string()),
+                Err(msg) => Frame::Error(format!("ERR {}", msg)),
+            },
+            AclCommand::DelUser(names) => {
+                if names.iter().any(|name| name == DEFAULT_USER) {
+                    Frame::Error("ERR The 'default' user cannot be removed".to_string())
+                } else {
+                    deleted = names
+                        .into_iter()
+                        .filter(|name| acl.del_user(name))
+                        .collect();
+                    Frame::Integer(deleted.len() as i64)
+                }
+            }
+            AclCommand::Invalid(msg) => Frame::Error(msg),
+        };
+
+        connection
+            .write_frame(&response)
+            .await
+            .context(ConnectSnafu)?;
+
+        // 用被删除的用户登录的客户端都断开。先回复再断开，删除自己时也能收到回复
+        if !deleted.is_empty() {
+            ctx.state
+                .clients
+                .kill(|client| client.user().is_some_and(|user| deleted.contains(&user)));
+        }
+
+        Ok(())
+    }
+}
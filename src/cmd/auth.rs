@@ -0,0 +1,78 @@
+use crate::acl::DEFAULT_USER;
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::parser;
+
+use super::{CommandSnafu, ConnectSnafu, Context, Result};
+use snafu::ResultExt;
+use tracing::info;
+
+/// AUTH [username] password：以某个 ACL 用户登录，不写用户名时是 default 用户
+#[derive(Debug)]
+pub struct Auth {
+    user: Option<String>,
+    password: String,
+    // 参数个数不对
+    invalid: bool,
+}
+
+impl Auth {
+    pub fn parse_frame(parser: &mut parser::Parser) -> Result<Auth> {
+        let mut args = Vec::new();
+        while parser.has_next() {
+            args.push(parser.next_string().context(CommandSnafu)?);
+        }
+
+        let auth = match args.len() {
+            1 => Auth {
+                user: None,
+                password: args.remove(0),
+                invalid: false,
+            },
+            2 => Auth {
+                user: Some(args.remove(0)),
+                password: args.remove(0),
+                invalid: false,
+            },
+            _ => Auth {
+                user: None,
+                password: String::new(),
+                invalid: true,
+            },
+        };
+
+        Ok(auth)
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        let acl = &ctx.state.acl;
+        let response = if self.invalid {
+            Frame::Error("ERR wrong number of arguments for 'auth' command".to_string())
+        } else if self.user.is_none() && acl.default_nopass() {
+            Frame::Error(
+                "ERR AUTH <password> called without any password configured for the default user. \
+                 Are you sure your configuration is correct?"
+                    .to_string(),
+            )
+        } else {
+            let user = self.user.unwrap_or_else(|| DEFAULT_USER.to_string());
+            if acl.authenticate(&user, &self.password) {
+                ctx.client.set_user(Some(user));
+                Frame::Simple("OK".to_string())
+            } else {
+                // 和 Redis 一样不区分是用户不存在还是密码不对
+                info!("authentication failed for user '{}'", user);
+                Frame::Error(
+                    "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                )
+            }
+        };
+
+        connection
+            .write_frame(&response)
+            .await
+            .context(ConnectSnafu)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use bytes::Bytes;
+
+use crate::clients::ClientInfo;
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::parser;
+
+use super::{CommandSnafu, ConnectSnafu, Context, Result};
+use snafu::ResultExt;
+
+/// CLIENT 的各个子命令，用来查看和管理在线的客户端
+#[derive(Debug)]
+pub enum ClientCommand {
+    List,
+    Info,
+    Id,
+    GetName,
+    SetName(String),
+    Kill(Kill),
+    // 子命令或者参数不对，直接回复这个错误
+    Invalid(String),
+}
+
+/// CLIENT KILL 有两种写法：
+/// 旧的 `CLIENT KILL addr`，回复 OK 或者错误；
+/// 新的 `CLIENT KILL <filter> <value> ...`，回复结束的客户端个数
+#[derive(Debug, Default)]
+pub struct Kill {
+    legacy: bool,
+    id: Option<u64>,
+    addr: Option<String>,
+    laddr: Option<String>,
+    user: Option<String>,
+    skipme: bool,
+}
+
+impl ClientCommand {
+    pub fn parse_frame(parser: &mut parser::Parser) -> Result<ClientCommand> {
+        let sub = parser.next_string().context(CommandSnafu)?.to_lowercase();
+
+        let mut args = Vec::new();
+        while parser.has_next() {
+            args.push(parser.next_string().context(CommandSnafu)?);
+        }
+
+        let cmd = match (sub.as_str(), args.len()) {
+            ("list", 0) => ClientCommand::List,
+            ("info", 0) => ClientCommand::Info,
+            ("id", 0) => ClientCommand::Id,
+            ("getname", 0) => ClientCommand::GetName,
+            ("setname", 1) => ClientCommand::SetName(args.remove(0)),
+            ("kill", 1) => ClientCommand::Kill(Kill {
+                legacy: true,
+                addr: Some(args.remove(0)),
+                ..Kill::default()
+            }),
+            ("kill", n) if n > 0 && n % 2 == 0 => parse_kill_filters(args),
+            ("list" | "info" | "id" | "getname" | "setname" | "kill", _) => ClientCommand::Invalid(
+                format!("ERR wrong number of arguments for 'client|{}' command", sub),
+            ),
+            _ => ClientCommand::Invalid(format!(
+                "ERR unknown subcommand '{}'. Try CLIENT HELP.",
+                sub
+            )),
+        };
+
+        Ok(cmd)
+    }
+
+    /// 检查权限时用的命令 id
+    pub fn acl_id(&self) -> &'static str {
+        match self {
+            ClientCommand::List => "client|list",
+            ClientCommand::Info => "client|info",
+            ClientCommand::Id => "client|id",
+            ClientCommand::GetName => "client|getname",
+            ClientCommand::SetName(_) => "client|setname",
+            ClientCommand::Kill(_) => "client|kill",
+            ClientCommand::Invalid(_) => "client",
+        }
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        let response = match self {
+            ClientCommand::List => {
+                let list: String = ctx
+                    .state
+                    .clients
+                    .list()
+                    .iter()
+                    .map(|client| format!("{}\n", client))
+                    .collect();
+                Frame::Bulk(Bytes::from(list))
+            }
+            ClientCommand::Info => Frame::Bulk(Bytes::from(format!("{}\n", ctx.client))),
+            ClientCommand::Id => Frame::Integer(ctx.client.id as i64),
+            ClientCommand::GetName => match ctx.client.name() {
+                Some(name) => Frame::Bulk(Bytes::from(name)),
+                None => Frame::Null,
+            },
+            ClientCommand::SetName(name) => {
+                // 和 Redis 一样，名字里不能有空格、换行等字符，否则 CLIENT LIST 没法解析
+                if name.chars().any(|c| !('!'..='~').contains(&c)) {
+                    Frame::Error(
+                        "ERR Client names cannot contain spaces, newlines or special characters."
+                            .to_string(),
+                    )
+                } else {
+                    // 设置成空字符串表示去掉名字
+                    ctx.client.set_name((!name.is_empty()).then_some(name));
+                    Frame::Simple("OK".to_string())
+                }
+            }
+            ClientCommand::Kill(kill) => {
+                let me = ctx.client.id;
+                let killed = ctx.state.clients.kill(|client| kill.matches(client, me));
+
+                match (kill.legacy, killed) {
+                    (true, 0) => Frame::Error("ERR No such client".to_string()),
+                    (true, _) => Frame::Simple("OK".to_string()),
+                    (false, n) => Frame::Integer(n as i64),
+                }
+            }
+            ClientCommand::Invalid(msg) => Frame::Error(msg),
+        };
+
+        connection
+            .write_frame(&response)
+            .await
+            .context(ConnectSnafu)?;
+
+        Ok(())
+    }
+}
+
+fn parse_kill_filters(args: Vec<String>) -> ClientCommand {
+    let mut kill = Kill {
+        skipme: true,
+        ..Kill::default()
+    };
+
+    for pair in args.chunks(2) {
+        let value = pair[1].clone();
+        match pair[0].to_lowercase().as_str() {
+            "id" => match value.parse() {
+                Ok(id) => kill.id = Some(id),
+                Err(_) => {
+                    return ClientCommand::Invalid(
+                        "ERR client-id should be greater than 0".to_string(),
+                    )
+                }
+            },
+            "addr" => kill.addr = Some(value),
+            "laddr" => kill.laddr = Some(value),
+            "user" => kill.user = Some(value),
+            "skipme" => match value.to_lowercase().as_str() {
+                "yes" => kill.skipme = true,
+                "no" => kill.skipme = false,
+                _ => return ClientCommand::Invalid("ERR syntax error".to_string()),
+            },
+            _ => return ClientCommand::Invalid("ERR syntax error".to_string()),
+        }
+    }
+
+    ClientCommand::Kill(kill)
+}
+
+impl Kill {
+    // 所有给出的条件都满足才结束这个客户端。旧的写法可以结束自己
+    fn matches(&self, client: &ClientInfo, me: u64) -> bool {
+        if !self.legacy && self.skipme && client.id == me {
+            return false;
+        }
+        self.id.is_none_or(|id| client.id == id)
+            && self.addr.as_ref().is_none_or(|addr| &client.addr == addr)
+            && self
+                .laddr
+                .as_ref()
+                .is_none_or(|laddr| &client.laddr == laddr)
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| client.user().as_ref() == Some(user))
+    }
+}
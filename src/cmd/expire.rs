@@ -0,0 +1,300 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use crate::db::Keyspace;
+use crate::frame::Frame;
+
+use super::{int_arg, wrong_args, SYNTAX_ERROR};
+
+// 过期时间太远时（Instant 表示不了）按 100 年算，效果上就是不过期
+const FAR_FUTURE: Duration = Duration::from_secs(100 * 365 * 86400);
+
+/// 命令参数里的过期时间：相对现在的毫秒数，或者 unix 时间的毫秒数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deadline {
+    In(i64),
+    At(i64),
+}
+
+impl Deadline {
+    /// 解析 SET / GETEX 的 `EX` / `PX` / `EXAT` / `PXAT` 参数。和 Redis 一样，时间必须是正数
+    pub fn parse_option(option: &str, value: &[u8], command: &str) -> Result<Deadline, String> {
+        let invalid = || format!("ERR invalid expire time in '{}' command", command);
+        let n = int_arg(value)?;
+        if n <= 0 {
+            return Err(invalid());
+        }
+        let deadline = match option {
+            "ex" => Deadline::In(n.checked_mul(1000).ok_or_else(invalid)?),
+            "px" => Deadline::In(n),
+            "exat" => Deadline::At(n.checked_mul(1000).ok_or_else(invalid)?),
+            "pxat" => Deadline::At(n),
+            _ => return Err(SYNTAX_ERROR.to_string()),
+        };
+        Ok(deadline)
+    }
+
+    /// 换算成 Instant。时间已经过去的话返回 None，这时 key 应该直接删除
+    pub fn instant(&self) -> Option<Instant> {
+        let millis = match *self {
+            Deadline::In(ms) => ms,
+            Deadline::At(ms) => ms.saturating_sub(unix_millis()),
+        };
+        if millis <= 0 {
+            return None;
+        }
+        let now = Instant::now();
+        now.checked_add(Duration::from_millis(millis as u64))
+            .or_else(|| now.checked_add(FAR_FUTURE))
+    }
+}
+
+/// 当前的 unix 时间（毫秒）
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+// EXPIRE 的 NX / XX / GT / LT：分别表示只在没有过期时间、已经有过期时间、
+// 新的时间更晚、新的时间更早时才修改。没有过期时间看作无限长
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+/// EXPIRE / PEXPIRE / EXPIREAT / PEXPIREAT key time [NX | XX | GT | LT]
+#[derive(Debug)]
+pub struct Expire {
+    name: &'static str,
+    pub(crate) key: Bytes,
+    deadline: Deadline,
+    condition: Option<Condition>,
+}
+
+impl Expire {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<Expire, String> {
+        let mut args = args.into_iter();
+        let (key, time) = match (args.next(), args.next()) {
+            (Some(key), Some(time)) => (key, time),
+            _ => return Err(wrong_args(name)),
+        };
+
+        let n = int_arg(&time)?;
+        let invalid = || format!("ERR invalid expire time in '{}' command", name);
+        let deadline = match name {
+            "expire" => Deadline::In(n.checked_mul(1000).ok_or_else(invalid)?),
+            "pexpire" => Deadline::In(n),
+            "expireat" => Deadline::At(n.checked_mul(1000).ok_or_else(invalid)?),
+            _ => Deadline::At(n),
+        };
+
+        let mut condition = None;
+        for arg in args {
+            let c = match arg.to_ascii_lowercase().as_slice() {
+                b"nx" => Condition::Nx,
+                b"xx" => Condition::Xx,
+                b"gt" => Condition::Gt,
+                b"lt" => Condition::Lt,
+                _ => {
+                    return Err(format!(
+                        "ERR Unsupported option {}",
+                        String::from_utf8_lossy(&arg)
+                    ))
+                }
+            };
+            // 和 Redis 一样，NX 不能和其他条件一起用，GT 和 LT 不能一起用
+            if condition.is_some_and(|old| old != c) {
+                return Err(
+                    "ERR NX and XX, GT or LT options at the same time are not compatible"
+                        .to_string(),
+                );
+            }
+            condition = Some(c);
+        }
+
+        Ok(Expire {
+            name,
+            key,
+            deadline,
+            condition,
+        })
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        let current = match keyspace.expires_at(&self.key) {
+            Some(current) => current,
+            None => return Frame::Integer(0),
+        };
+        let when = self.deadline.instant();
+
+        // 已经过去的时间比任何过期时间都早
+        let earlier = |a: Option<Instant>, b: Option<Instant>| match (a, b) {
+            (_, None) => true,
+            (None, _) => false,
+            (Some(a), Some(b)) => a < b,
+        };
+        let new = when.or_else(|| Some(Instant::now()));
+        let allowed = match self.condition {
+            None => true,
+            Some(Condition::Nx) => current.is_none(),
+            Some(Condition::Xx) => current.is_some(),
+            Some(Condition::Gt) => current.is_some() && earlier(current, new),
+            Some(Condition::Lt) => earlier(new, current),
+        };
+        if !allowed {
+            return Frame::Integer(0);
+        }
+
+        match when {
+            Some(when) => {
+                keyspace.set_expires_at(&self.key, Some(when));
+            }
+            None => {
+                keyspace.remove(&self.key);
+            }
+        }
+        Frame::Integer(1)
+    }
+}
+
+/// TTL / PTTL key
+#[derive(Debug)]
+pub struct Ttl {
+    pub(crate) key: Bytes,
+    millis: bool,
+}
+
+impl Ttl {
+    pub fn name(&self) -> &'static str {
+        if self.millis {
+            "pttl"
+        } else {
+            "ttl"
+        }
+    }
+
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<Ttl, String> {
+        match <[Bytes; 1]>::try_from(args) {
+            Ok([key]) => Ok(Ttl {
+                key,
+                millis: name == "pttl",
+            }),
+            Err(_) => Err(wrong_args(name)),
+        }
+    }
+
+    /// key 不存在时是 -2，没有过期时间时是 -1
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        let when = match keyspace.expires_at(&self.key) {
+            None => return Frame::Integer(-2),
+            Some(None) => return Frame::Integer(-1),
+            Some(Some(when)) => when,
+        };
+
+        let ms = when.saturating_duration_since(Instant::now()).as_millis() as i64;
+        if self.millis {
+            Frame::Integer(ms)
+        } else {
+            Frame::Integer((ms + 500) / 1000)
+        }
+    }
+}
+
+/// PERSIST key：去掉过期时间
+#[derive(Debug)]
+pub struct Persist {
+    pub(crate) key: Bytes,
+}
+
+impl Persist {
+    pub fn parse(args: Vec<Bytes>) -> Result<Persist, String> {
+        match <[Bytes; 1]>::try_from(args) {
+            Ok([key]) => Ok(Persist { key }),
+            Err(_) => Err(wrong_args("persist")),
+        }
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match keyspace.expires_at(&self.key) {
+            Some(Some(_)) => {
+                keyspace.set_expires_at(&self.key, None);
+                Frame::Integer(1)
+            }
+            _ => Frame::Integer(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Db, Value};
+
+    fn args(args: &[&str]) -> Vec<Bytes> {
+        args.iter().map(|a| Bytes::from(a.to_string())).collect()
+    }
+
+    fn integer(frame: Frame) -> i64 {
+        match frame {
+            Frame::Integer(n) => n,
+            other => panic!("not an integer: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ts_expire_and_ttl() {
+        let db = Db::new();
+        let mut keyspace = db.blocking_lock();
+        let ttl = |keyspace: &mut Keyspace, name| {
+            integer(Ttl::parse(name, args(&["k"])).unwrap().execute(keyspace))
+        };
+        let expire = |keyspace: &mut Keyspace, a: &[&str]| {
+            integer(Expire::parse("expire", args(a)).unwrap().execute(keyspace))
+        };
+
+        assert_eq!(ttl(&mut keyspace, "ttl"), -2);
+        keyspace.insert(Bytes::from("k"), Value::String(Bytes::from("v")), None);
+        assert_eq!(ttl(&mut keyspace, "ttl"), -1);
+
+        assert_eq!(expire(&mut keyspace, &["k", "100", "xx"]), 0);
+        assert_eq!(expire(&mut keyspace, &["k", "100", "nx"]), 1);
+        assert_eq!(ttl(&mut keyspace, "ttl"), 100);
+        assert!(ttl(&mut keyspace, "pttl") > 99_000);
+        assert_eq!(expire(&mut keyspace, &["k", "50", "gt"]), 0);
+        assert_eq!(expire(&mut keyspace, &["k", "50", "lt"]), 1);
+        assert_eq!(ttl(&mut keyspace, "ttl"), 50);
+
+        let persist = Persist::parse(args(&["k"])).unwrap();
+        assert_eq!(integer(persist.execute(&mut keyspace)), 1);
+        assert_eq!(ttl(&mut keyspace, "ttl"), -1);
+
+        // 过去的时间直接删除 key
+        assert_eq!(expire(&mut keyspace, &["k", "-1"]), 1);
+        assert_eq!(ttl(&mut keyspace, "ttl"), -2);
+
+        assert!(Expire::parse("expire", args(&["k"])).is_err());
+        assert!(Expire::parse("expire", args(&["k", "1", "nx", "gt"])).is_err());
+        assert!(Expire::parse("expire", args(&["k", "9223372036854775807"])).is_err());
+    }
+
+    #[test]
+    fn ts_deadline() {
+        assert_eq!(
+            Deadline::parse_option("ex", b"10", "set"),
+            Ok(Deadline::In(10_000))
+        );
+        assert!(Deadline::parse_option("px", b"0", "set").is_err());
+        assert!(Deadline::At(unix_millis() - 1000).instant().is_none());
+        assert!(Deadline::At(unix_millis() + 10_000).instant().is_some());
+        assert!(Deadline::In(i64::MAX).instant().is_some());
+    }
+}
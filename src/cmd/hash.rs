@@ -0,0 +1,502 @@
+use bytes::Bytes;
+use snafu::ResultExt;
+use tracing::info;
+
+use crate::connection::Connection;
+use crate::db::{Hash, Keyspace, Value, WRONGTYPE};
+use crate::frame::Frame;
+use crate::redact;
+
+use super::scan::{self, ScanArgs};
+use super::{
+    exact, int_arg, parse_int, read_body, request, upstream_reply, wrong_args, ConnectSnafu,
+    Context, Error, HttpSnafu, JsonSnafu, ObjectJsonSnafu, Result,
+};
+
+/// hash 的命令（HFETCH 要访问上游，单独是 `HFetch`）
+#[derive(Debug)]
+pub enum HashCommand {
+    HSet {
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    HGet {
+        key: Bytes,
+        field: Bytes,
+    },
+    HMGet {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    HGetAll {
+        key: Bytes,
+    },
+    HDel {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    HExists {
+        key: Bytes,
+        field: Bytes,
+    },
+    HIncrBy {
+        key: Bytes,
+        field: Bytes,
+        delta: i64,
+    },
+    HKeys {
+        key: Bytes,
+    },
+    HVals {
+        key: Bytes,
+    },
+    HLen {
+        key: Bytes,
+    },
+    HScan {
+        key: Bytes,
+        scan: ScanArgs,
+    },
+}
+
+impl HashCommand {
+    /// 这一组命令的命令名
+    pub const NAMES: &'static [&'static str] = &[
+        "hdel", "hexists", "hget", "hgetall", "hincrby", "hkeys", "hlen", "hmget", "hscan", "hset",
+        "hvals",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashCommand::HSet { .. } => "hset",
+            HashCommand::HGet { .. } => "hget",
+            HashCommand::HMGet { .. } => "hmget",
+            HashCommand::HGetAll { .. } => "hgetall",
+            HashCommand::HDel { .. } => "hdel",
+            HashCommand::HExists { .. } => "hexists",
+            HashCommand::HIncrBy { .. } => "hincrby",
+            HashCommand::HKeys { .. } => "hkeys",
+            HashCommand::HVals { .. } => "hvals",
+            HashCommand::HLen { .. } => "hlen",
+            HashCommand::HScan { .. } => "hscan",
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            HashCommand::HSet { key, .. }
+            | HashCommand::HGet { key, .. }
+            | HashCommand::HMGet { key, .. }
+            | HashCommand::HGetAll { key }
+            | HashCommand::HDel { key, .. }
+            | HashCommand::HExists { key, .. }
+            | HashCommand::HIncrBy { key, .. }
+            | HashCommand::HKeys { key }
+            | HashCommand::HVals { key }
+            | HashCommand::HLen { key }
+            | HashCommand::HScan { key, .. } => key,
+        }
+    }
+
+    /// `name` 是 `NAMES` 里的一个
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<HashCommand, String> {
+        if args.is_empty() {
+            return Err(wrong_args(name));
+        }
+        let cmd = match name {
+            "hset" => {
+                if args.len() < 3 || args.len() % 2 != 1 {
+                    return Err(wrong_args(name));
+                }
+                let mut args = args.into_iter();
+                let key = args.next().unwrap_or_default();
+                let mut pairs = Vec::new();
+                while let (Some(field), Some(value)) = (args.next(), args.next()) {
+                    pairs.push((field, value));
+                }
+                HashCommand::HSet { key, pairs }
+            }
+            "hget" | "hexists" => {
+                let [key, field] = exact(name, args)?;
+                if name == "hget" {
+                    HashCommand::HGet { key, field }
+                } else {
+                    HashCommand::HExists { key, field }
+                }
+            }
+            "hmget" | "hdel" => {
+                let mut args = args.into_iter();
+                let key = args.next().unwrap_or_default();
+                let fields: Vec<Bytes> = args.collect();
+                if fields.is_empty() {
+                    return Err(wrong_args(name));
+                }
+                if name == "hmget" {
+                    HashCommand::HMGet { key, fields }
+                } else {
+                    HashCommand::HDel { key, fields }
+                }
+            }
+            "hincrby" => {
+                let [key, field, delta] = exact(name, args)?;
+                let delta = int_arg(&delta)?;
+                HashCommand::HIncrBy { key, field, delta }
+            }
+            "hscan" => {
+                let mut args = args.into_iter();
+                let key = args.next().unwrap_or_default();
+                let cursor = args.next().ok_or_else(|| wrong_args(name))?;
+                let scan = ScanArgs::parse(&cursor, args)?;
+                HashCommand::HScan { key, scan }
+            }
+            _ => {
+                let [key] = exact(name, args)?;
+                match name {
+                    "hgetall" => HashCommand::HGetAll { key },
+                    "hkeys" => HashCommand::HKeys { key },
+                    "hvals" => HashCommand::HVals { key },
+                    _ => HashCommand::HLen { key },
+                }
+            }
+        };
+        Ok(cmd)
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self.run(keyspace) {
+            Ok(frame) => frame,
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn run(self, keyspace: &mut Keyspace) -> Result<Frame, String> {
+        let frame = match self {
+            HashCommand::HSet { key, pairs } => {
+                let added = update(keyspace, &key, |hash| {
+                    let mut added = 0;
+                    for (field, value) in pairs {
+                        if hash.insert(field, value).is_none() {
+                            added += 1;
+                        }
+                    }
+                    added
+                })?;
+                Frame::Integer(added as i64)
+            }
+            HashCommand::HGet { key, field } => {
+                match hash(keyspace.get(&key))?.and_then(|hash| hash.get(&field)) {
+                    Some(value) => Frame::Bulk(value.clone()),
+                    None => Frame::Null,
+                }
+            }
+            HashCommand::HMGet { key, fields } => {
+                let hash = hash(keyspace.get(&key))?;
+                Frame::Array(
+                    fields
+                        .iter()
+                        .map(|field| match hash.and_then(|hash| hash.get(field)) {
+                            Some(value) => Frame::Bulk(value.clone()),
+                            None => Frame::Null,
+                        })
+                        .collect(),
+                )
+            }
+            HashCommand::HGetAll { key } => {
+                let items = hash(keyspace.get(&key))?
+                    .into_iter()
+                    .flat_map(|hash| hash.iter())
+                    .flat_map(|(field, value)| {
+                        [Frame::Bulk(field.clone()), Frame::Bulk(value.clone())]
+                    })
+                    .collect();
+                Frame::Array(items)
+            }
+            HashCommand::HDel { key, fields } => {
+                if hash(keyspace.peek(&key))?.is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let removed = update(keyspace, &key, |hash| {
+                    fields
+                        .iter()
+                        .filter(|field| hash.remove(field).is_some())
+                        .count()
+                })?;
+                Frame::Integer(removed as i64)
+            }
+            HashCommand::HExists { key, field } => {
+                let exists = hash(keyspace.get(&key))?.is_some_and(|hash| hash.contains(&field));
+                Frame::Integer(exists as i64)
+            }
+            HashCommand::HIncrBy { key, field, delta } => {
+                let n = update(keyspace, &key, |hash| {
+                    let current = match hash.get(&field) {
+                        Some(value) => parse_int(value)
+                            .ok_or_else(|| "ERR hash value is not an integer".to_string())?,
+                        None => 0,
+                    };
+                    let n = current
+                        .checked_add(delta)
+                        .ok_or_else(|| "ERR increment or decrement would overflow".to_string())?;
+                    hash.insert(field, Bytes::from(n.to_string()));
+                    Ok::<_, String>(n)
+                })??;
+                Frame::Integer(n)
+            }
+            HashCommand::HKeys { key } => Frame::Array(
+                hash(keyspace.get(&key))?
+                    .into_iter()
+                    .flat_map(|hash| hash.iter())
+                    .map(|(field, _)| Frame::Bulk(field.clone()))
+                    .collect(),
+            ),
+            HashCommand::HVals { key } => Frame::Array(
+                hash(keyspace.get(&key))?
+                    .into_iter()
+                    .flat_map(|hash| hash.iter())
+                    .map(|(_, value)| Frame::Bulk(value.clone()))
+                    .collect(),
+            ),
+            HashCommand::HLen { key } => {
+                Frame::Integer(hash(keyspace.get(&key))?.map_or(0, |hash| hash.len()) as i64)
+            }
+            HashCommand::HScan { key, scan } => {
+                let fields = hash(keyspace.get(&key))?
+                    .into_iter()
+                    .flat_map(|hash| hash.iter())
+                    .map(|(field, value)| (field.as_ref(), (field, value)));
+                let (cursor, page) = scan.page(fields);
+                let items = page
+                    .into_iter()
+                    .flat_map(|(field, value)| {
+                        [Frame::Bulk(field.clone()), Frame::Bulk(value.clone())]
+                    })
+                    .collect();
+                scan::reply(cursor, items)
+            }
+        };
+
+        Ok(frame)
+    }
+}
+
+/// HFETCH key url：访问上游，把返回的 JSON 对象的第一层字段整个存成 hash，返回字段数。
+/// 原来的 hash 被替换掉，过期时间保留。字符串字段存原始的值，其他的存 JSON 文本
+#[derive(Debug)]
+pub struct HFetch {
+    pub(crate) key: Bytes,
+    pub(crate) url: Bytes,
+}
+
+impl HFetch {
+    pub fn parse(args: Vec<Bytes>) -> Result<HFetch, String> {
+        let [key, url] = exact("hfetch", args)?;
+        if std::str::from_utf8(&url).is_err() {
+            return Err("ERR invalid url".to_string());
+        }
+        Ok(HFetch { key, url })
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        // key 已经是别的类型的话，不用访问上游
+        let wrongtype = hash(ctx.state.db.lock_keys(&[&self.key]).await.peek(&self.key)).is_err();
+        let response = if wrongtype {
+            Frame::Error(WRONGTYPE.to_string())
+        } else {
+            let url = String::from_utf8_lossy(&self.url);
+            let (upstream, res) = request(&url, ctx).await;
+            ctx.upstream = upstream;
+            let res = match res {
+                Ok(resp) => parse_object(&url, resp, ctx).await,
+                Err(err) => Err(err),
+            };
+            match res {
+                Ok(hash) => store(
+                    &mut *ctx.state.db.lock_keys(&[&self.key]).await,
+                    self.key.clone(),
+                    hash,
+                ),
+                Err(err @ Error::RateLimitedError { .. }) => upstream_reply(Err(err)),
+                Err(err) => Frame::Error(format!("ERR {}", err)),
+            }
+        };
+
+        connection
+            .write_frame(&response)
+            .await
+            .context(ConnectSnafu)?;
+        info!(
+            "for hfetch key: {}, url: {}. the sent response successfully: {:?}",
+            String::from_utf8_lossy(&self.key),
+            redact::url(&String::from_utf8_lossy(&self.url)),
+            response
+        );
+        Ok(())
+    }
+}
+
+// 把上游返回的 JSON 对象转换成 hash。非 2xx 的状态码和不是对象的 JSON 都是错误
+async fn parse_object(url: &str, resp: reqwest::Response, ctx: &Context) -> Result<Hash> {
+    // 非 2xx 的回复也要先读出来，记到 upstream-stream 里
+    let status = resp.error_for_status_ref().map(|_| ()).context(HttpSnafu);
+    let body = read_body(url, resp, ctx).await?;
+    status?;
+    let v: serde_json::Value = serde_json::from_str(&body).context(JsonSnafu)?;
+    let object = match v {
+        serde_json::Value::Object(object) => object,
+        _ => ObjectJsonSnafu.fail()?,
+    };
+
+    Ok(object
+        .into_iter()
+        .map(|(field, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                other => other.to_string(),
+            };
+            (Bytes::from(field), Bytes::from(value))
+        })
+        .collect())
+}
+
+// 访问上游期间 key 可能被改成了别的类型，这时不覆盖它
+fn store(keyspace: &mut Keyspace, key: Bytes, fetched: Hash) -> Frame {
+    if let Err(msg) = hash(keyspace.peek(&key)) {
+        return Frame::Error(msg);
+    }
+    let len = fetched.len();
+    if fetched.is_empty() {
+        keyspace.remove(&key);
+    } else {
+        keyspace.set_value(key, Value::Hash(fetched));
+    }
+    Frame::Integer(len as i64)
+}
+
+// 取出 hash；key 存在但不是 hash 时是 WRONGTYPE 错误
+fn hash(value: Option<&Value>) -> Result<Option<&Hash>, String> {
+    match value {
+        Some(value) => value
+            .as_hash()
+            .map(Some)
+            .ok_or_else(|| WRONGTYPE.to_string()),
+        None => Ok(None),
+    }
+}
+
+// 修改 hash，key 不存在时先建一个空的。改完之后 hash 空了的话 key 会被删除
+fn update<R>(
+    keyspace: &mut Keyspace,
+    key: &Bytes,
+    f: impl FnOnce(&mut Hash) -> R,
+) -> Result<R, String> {
+    if !keyspace.contains(key) {
+        keyspace.insert(key.clone(), Value::Hash(Hash::default()), None);
+    }
+    keyspace
+        .modify(key, |value| value.as_hash_mut().map(f))
+        .flatten()
+        .ok_or_else(|| WRONGTYPE.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    fn run(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let name = HashCommand::NAMES.iter().find(|n| **n == args[0]).unwrap();
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        match HashCommand::parse(name, args) {
+            Ok(cmd) => cmd.execute(keyspace),
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    #[test]
+    fn ts_hash_commands() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        assert_eq!(
+            run(&mut ks, &["hset", "h", "a", "1", "b", "2"]),
+            Frame::Integer(2)
+        );
+        assert_eq!(
+            run(&mut ks, &["hset", "h", "a", "10", "c", "3"]),
+            Frame::Integer(1)
+        );
+        assert_eq!(
+            run(&mut ks, &["hget", "h", "a"]),
+            Frame::Bulk(Bytes::from("10"))
+        );
+        assert_eq!(
+            run(&mut ks, &["hmget", "h", "b", "x"]),
+            Frame::Array(vec![Frame::Bulk(Bytes::from("2")), Frame::Null])
+        );
+        assert_eq!(run(&mut ks, &["hlen", "h"]), Frame::Integer(3));
+        assert_eq!(
+            run(&mut ks, &["hincrby", "h", "a", "-11"]),
+            Frame::Integer(-1)
+        );
+        assert_eq!(run(&mut ks, &["hincrby", "h", "n", "5"]), Frame::Integer(5));
+        assert_eq!(run(&mut ks, &["hexists", "h", "n"]), Frame::Integer(1));
+
+        // 删掉最后一个 field 之后 key 也没有了，内存也跟着释放
+        let used = ks.used_memory();
+        assert!(used > 0);
+        assert_eq!(
+            run(&mut ks, &["hdel", "h", "a", "b", "c", "x"]),
+            Frame::Integer(3)
+        );
+        assert!(ks.used_memory() < used);
+        assert_eq!(run(&mut ks, &["hdel", "h", "n"]), Frame::Integer(1));
+        assert!(!ks.contains(b"h"));
+        assert_eq!(ks.used_memory(), 0);
+        assert_eq!(run(&mut ks, &["hgetall", "h"]), Frame::Array(vec![]));
+
+        ks.insert(Bytes::from("s"), Value::String(Bytes::from("x")), None);
+        assert_eq!(
+            run(&mut ks, &["hset", "s", "a", "1"]),
+            Frame::Error(WRONGTYPE.to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["hset", "h", "a"]),
+            Frame::Error(wrong_args("hset"))
+        );
+        run(&mut ks, &["hset", "h", "a", "x"]);
+        assert_eq!(
+            run(&mut ks, &["hincrby", "h", "a", "1"]),
+            Frame::Error("ERR hash value is not an integer".to_string())
+        );
+    }
+
+    #[test]
+    fn ts_hscan() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+        for i in 0..25 {
+            run(&mut ks, &["hset", "h", &format!("f{}", i), "v"]);
+        }
+
+        let mut fields = 0;
+        let mut cursor = "0".to_string();
+        loop {
+            let reply = run(&mut ks, &["hscan", "h", &cursor, "count", "10"]);
+            let Frame::Array(parts) = reply else {
+                panic!("unexpected reply")
+            };
+            let (Frame::Bulk(next), Frame::Array(items)) = (&parts[0], &parts[1]) else {
+                panic!("unexpected reply")
+            };
+            fields += items.len() / 2;
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(fields, 25);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use bytes::Bytes;
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::metrics::METRICS;
+use crate::parser;
+use crate::state::State;
+
+use super::{CommandSnafu, ConnectSnafu, Result};
+use snafu::ResultExt;
+
+// 不带参数（或者 default / all / everything）时返回的所有 section
+const SECTIONS: [&str; 7] = [
+    "server",
+    "clients",
+    "memory",
+    "stats",
+    "upstream",
+    "commandstats",
+    "keyspace",
+];
+
+/// INFO [section ...]：以 Redis 的格式返回 server 的各项统计，
+/// 这样抓取 Redis INFO 的监控程序不用改就能用。
+///
+/// 计数都来自 `METRICS`，和 `/metrics` 上看到的是同一份数据
+#[derive(Debug)]
+pub struct Info {
+    sections: Vec<String>,
+}
+
+impl Info {
+    pub fn parse_frame(parser: &mut parser::Parser) -> Result<Info> {
+        let mut sections = Vec::new();
+        while parser.has_next() {
+            let section = parser.next_string().context(CommandSnafu)?;
+            sections.push(section.to_lowercase());
+        }
+        Ok(Info { sections })
+    }
+
+    pub async fn apply(self, state: &State, connection: &mut Connection) -> Result<()> {
+        let all = self.sections.is_empty()
+            || self
+                .sections
+                .iter()
+                .any(|s| matches!(s.as_str(), "default" | "all" | "everything"));
+
+        // 未知的 section 和 Redis 一样忽略掉
+        let mut out = String::new();
+        for section in SECTIONS {
+            if !all && !self.sections.iter().any(|s| s == section) {
+                continue;
+            }
+            if !out.is_empty() {
+                out.push_str("\r\n");
+            }
+            render(section, state, &mut out).await;
+        }
+
+        let response = Frame::Bulk(Bytes::from(out));
+        connection
+            .write_frame(&response)
+            .await
+            .context(ConnectSnafu)?;
+
+        Ok(())
+    }
+}
+
+async fn render(section: &str, state: &State, out: &mut String) {
+    // 写到 String 里不会出错
+    let _ = match section {
+        "server" => render_server(state, out),
+        "clients" => render_clients(state, out),
+        "memory" => render_memory(state, out),
+        "stats" => render_stats(out),
+        "upstream" => render_upstream(out),
+        "commandstats" => render_commandstats(out),
+        "keyspace" => render_keyspace(state, out).await,
+        _ => Ok(()),
+    };
+}
+
+fn render_server(state: &State, out: &mut String) -> std::fmt::Result {
+    let uptime = state.uptime().as_secs();
+    write!(out, "# Server\r\n")?;
+    write!(out, "rmr_version:{}\r\n", env!("CARGO_PKG_VERSION"))?;
+    write!(out, "process_id:{}\r\n", std::process::id())?;
+    write!(out, "uptime_in_seconds:{}\r\n", uptime)?;
+    write!(out, "uptime_in_days:{}\r\n", uptime / 86400)?;
+    write!(
+        out,
+        "shutdown_in_progress:{}\r\n",
+        state.is_shutting_down() as u8
+    )
+}
+
+fn render_clients(state: &State, out: &mut String) -> std::fmt::Result {
+    write!(out, "# Clients\r\n")?;
+    write!(
+        out,
+        "connected_clients:{}\r\n",
+        METRICS.connections_active.get()
+    )?;
+    write!(out, "blocked_clients:{}\r\n", METRICS.blocked_clients.get())?;
+    write!(out, "maxclients:{}\r\n", state.config.maxclients)
+}
+
+fn render_memory(state: &State, out: &mut String) -> std::fmt::Result {
+    // keyspace 里所有 key 和 value 大致占用的内存
+    write!(out, "# Memory\r\n")?;
+    write!(out, "used_memory_dataset:{}\r\n", state.db.used_memory())?;
+    write!(out, "maxmemory:{}\r\n", state.config.maxmemory)?;
+    write!(
+        out,
+        "maxmemory_policy:{}\r\n",
+        state.config.maxmemory_policy
+    )?;
+    write!(out, "number_of_cached_scripts:{}\r\n", state.scripts.len())
+}
+
+fn render_stats(out: &mut String) -> std::fmt::Result {
+    let processed: u64 = families(&METRICS.commands)
+        .iter()
+        .flat_map(|mf| mf.get_metric())
+        .map(|m| m.get_counter().get_value() as u64)
+        .sum();
+
+    write!(out, "# Stats\r\n")?;
+    write!(
+        out,
+        "total_connections_received:{}\r\n",
+        METRICS.connections_accepted.get()
+    )?;
+    write!(out, "total_commands_processed:{}\r\n", processed)?;
+    write!(
+        out,
+        "total_net_input_bytes:{}\r\n",
+        METRICS.net_input_bytes.get()
+    )?;
+    write!(
+        out,
+        "total_net_output_bytes:{}\r\n",
+        METRICS.net_output_bytes.get()
+    )?;
+    write!(
+        out,
+        "total_error_replies_protocol:{}\r\n",
+        METRICS.frame_errors.get()
+    )?;
+    write!(out, "expired_keys:{}\r\n", METRICS.expired_keys.get())?;
+    write!(out, "evicted_keys:{}\r\n", METRICS.evicted_keys.get())?;
+    write!(out, "keyspace_hits:{}\r\n", METRICS.keyspace_hits.get())?;
+    write!(out, "keyspace_misses:{}\r\n", METRICS.keyspace_misses.get())
+}
+
+fn render_upstream(out: &mut String) -> std::fmt::Result {
+    // 2xx 算成功，其他状态码和请求本身失败都算错误
+    let (mut ok, mut failed) = (0, 0);
+    for mf in families(&METRICS.upstream_requests) {
+        for m in mf.get_metric() {
+            let count = m.get_counter().get_value() as u64;
+            match label(m, "status") {
+                Some(status) if status.starts_with('2') => ok += count,
+                _ => failed += count,
+            }
+        }
+    }
+
+    write!(out, "# Upstream\r\n")?;
+    write!(out, "upstream_requests_ok:{}\r\n", ok)?;
+    write!(out, "upstream_requests_error:{}\r\n", failed)
+}
+
+fn render_commandstats(out: &mut String) -> std::fmt::Result {
+    write!(out, "# Commandstats\r\n")?;
+
+    let failed: HashMap<String, u64> = families(&METRICS.commands)
+        .iter()
+        .flat_map(|mf| mf.get_metric())
+        .filter(|m| label(m, "outcome") == Some("error"))
+        .map(|m| {
+            let name = label(m, "command").unwrap_or_default().to_string();
+            (name, m.get_counter().get_value() as u64)
+        })
+        .collect();
+
+    let mut stats: Vec<(String, u64, f64)> = families(&METRICS.command_duration)
+        .iter()
+        .flat_map(|mf| mf.get_metric())
+        .map(|m| {
+            let h = m.get_histogram();
+            let name = label(m, "command").unwrap_or_default().to_string();
+            (name, h.get_sample_count(), h.get_sample_sum())
+        })
+        .collect();
+    stats.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (name, calls, secs) in stats {
+        let usec = (secs * 1_000_000.0) as u64;
+        write!(
+            out,
+            "cmdstat_{}:calls={},usec={},usec_per_call={:.2},failed_calls={}\r\n",
+            name,
+            calls,
+            usec,
+            usec as f64 / calls.max(1) as f64,
+            failed.get(&name).copied().unwrap_or(0)
+        )?;
+    }
+
+    Ok(())
+}
+
+async fn render_keyspace(state: &State, out: &mut String) -> std::fmt::Result {
+    let (keys, expires) = {
+        let keyspace = state.db.lock().await;
+        (keyspace.len(), keyspace.expires())
+    };
+
+    // 和 Redis 一样，没有 key 时不输出 db0 这一行
+    write!(out, "# Keyspace\r\n")?;
+    if keys > 0 {
+        write!(out, "db0:keys={},expires={},avg_ttl=0\r\n", keys, expires)?;
+    }
+    Ok(())
+}
+
+fn families(collector: &impl Collector) -> Vec<MetricFamily> {
+    collector.collect()
+}
+
+fn label<'a>(metric: &'a prometheus::proto::Metric, name: &str) -> Option<&'a str> {
+    metric
+        .get_label()
+        .iter()
+        .find(|l| l.get_name() == name)
+        .map(|l| l.get_value())
+}
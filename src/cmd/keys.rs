@@ -0,0 +1,327 @@
+use bytes::Bytes;
+
+use crate::db::Keyspace;
+use crate::frame::Frame;
+use crate::glob;
+
+use super::scan::{self, ScanArgs};
+use super::{exact, wrong_args, SYNTAX_ERROR};
+
+/// 管理 keyspace 的命令：查看、删除、改名
+#[derive(Debug)]
+pub enum KeysCommand {
+    Del {
+        keys: Vec<Bytes>,
+        /// UNLINK：值在后台释放
+        lazy: bool,
+    },
+    Exists {
+        keys: Vec<Bytes>,
+    },
+    Type {
+        key: Bytes,
+    },
+    Rename {
+        from: Bytes,
+        to: Bytes,
+        /// RENAMENX：to 已经存在时不改
+        nx: bool,
+    },
+    DbSize,
+    FlushDb {
+        lazy: bool,
+    },
+    Keys {
+        pattern: Bytes,
+    },
+    Scan {
+        args: ScanArgs,
+        /// 只返回这个类型的 key
+        kind: Option<String>,
+    },
+}
+
+impl KeysCommand {
+    /// 这一组命令的命令名
+    pub const NAMES: &'static [&'static str] = &[
+        "dbsize", "del", "exists", "flushdb", "keys", "rename", "renamenx", "scan", "type",
+        "unlink",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeysCommand::Del { lazy: false, .. } => "del",
+            KeysCommand::Del { lazy: true, .. } => "unlink",
+            KeysCommand::Exists { .. } => "exists",
+            KeysCommand::Type { .. } => "type",
+            KeysCommand::Rename { nx: false, .. } => "rename",
+            KeysCommand::Rename { nx: true, .. } => "renamenx",
+            KeysCommand::DbSize => "dbsize",
+            KeysCommand::FlushDb { .. } => "flushdb",
+            KeysCommand::Keys { .. } => "keys",
+            KeysCommand::Scan { .. } => "scan",
+        }
+    }
+
+    /// 访问的 key。KEYS / SCAN / FLUSHDB 这样访问所有 key 的命令靠命令本身的权限控制
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            KeysCommand::Del { keys, .. } | KeysCommand::Exists { keys } => {
+                keys.iter().map(|key| key.as_ref()).collect()
+            }
+            KeysCommand::Type { key } => vec![key],
+            KeysCommand::Rename { from, to, .. } => vec![from, to],
+            _ => Vec::new(),
+        }
+    }
+
+    /// `name` 是 `NAMES` 里的一个
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<KeysCommand, String> {
+        let cmd = match name {
+            "del" | "unlink" | "exists" => {
+                if args.is_empty() {
+                    return Err(wrong_args(name));
+                }
+                match name {
+                    "exists" => KeysCommand::Exists { keys: args },
+                    _ => KeysCommand::Del {
+                        keys: args,
+                        lazy: name == "unlink",
+                    },
+                }
+            }
+            "type" => {
+                let [key] = exact(name, args)?;
+                KeysCommand::Type { key }
+            }
+            "rename" | "renamenx" => {
+                let [from, to] = exact(name, args)?;
+                KeysCommand::Rename {
+                    from,
+                    to,
+                    nx: name == "renamenx",
+                }
+            }
+            "dbsize" => {
+                let [] = exact(name, args)?;
+                KeysCommand::DbSize
+            }
+            "flushdb" => {
+                let lazy = match args.as_slice() {
+                    [] => false,
+                    [mode] if mode.eq_ignore_ascii_case(b"async") => true,
+                    [mode] if mode.eq_ignore_ascii_case(b"sync") => false,
+                    _ => return Err(SYNTAX_ERROR.to_string()),
+                };
+                KeysCommand::FlushDb { lazy }
+            }
+            "keys" => {
+                let [pattern] = exact(name, args)?;
+                KeysCommand::Keys { pattern }
+            }
+            _ => {
+                let mut args = args.into_iter();
+                let cursor = args.next().ok_or_else(|| wrong_args(name))?;
+                // TYPE 是 SCAN 自己的选项，其他的交给 ScanArgs
+                let mut rest = Vec::new();
+                let mut kind = None;
+                while let Some(arg) = args.next() {
+                    let value = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+                    if arg.eq_ignore_ascii_case(b"type") {
+                        kind = Some(String::from_utf8_lossy(&value).to_lowercase());
+                    } else {
+                        rest.extend([arg, value]);
+                    }
+                }
+                KeysCommand::Scan {
+                    args: ScanArgs::parse(&cursor, rest)?,
+                    kind,
+                }
+            }
+        };
+        Ok(cmd)
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self {
+            KeysCommand::Del { keys, lazy } => {
+                let removed = keys
+                    .iter()
+                    .filter(|key| {
+                        if lazy {
+                            keyspace.unlink(key)
+                        } else {
+                            keyspace.remove(key).is_some()
+                        }
+                    })
+                    .count();
+                Frame::Integer(removed as i64)
+            }
+            // 和 Redis 一样，重复的 key 重复计数
+            KeysCommand::Exists { keys } => {
+                Frame::Integer(keys.iter().filter(|key| keyspace.contains(key)).count() as i64)
+            }
+            KeysCommand::Type { key } => {
+                let name = keyspace.get(&key).map_or("none", |value| value.type_name());
+                Frame::Simple(name.to_string())
+            }
+            KeysCommand::Rename { from, to, nx } => {
+                if !keyspace.contains(&from) {
+                    return Frame::Error("ERR no such key".to_string());
+                }
+                if nx && keyspace.contains(&to) {
+                    return Frame::Integer(0);
+                }
+                keyspace.rename(&from, to);
+                if nx {
+                    Frame::Integer(1)
+                } else {
+                    Frame::Simple("OK".to_string())
+                }
+            }
+            KeysCommand::DbSize => Frame::Integer(keyspace.len() as i64),
+            KeysCommand::FlushDb { lazy } => {
+                keyspace.flush(lazy);
+                Frame::Simple("OK".to_string())
+            }
+            KeysCommand::Keys { pattern } => Frame::Array(
+                keyspace
+                    .iter()
+                    .filter(|(key, _)| glob::matches(&pattern, key))
+                    .map(|(key, _)| Frame::Bulk(key.clone()))
+                    .collect(),
+            ),
+            KeysCommand::Scan { args, kind } => {
+                let items = keyspace
+                    .iter()
+                    .map(|(key, value)| (key.as_ref(), (key, value.type_name())));
+                let (cursor, page) = args.page(items);
+                // 和 MATCH 一样，TYPE 是取出一页之后再过滤
+                let keys = page
+                    .into_iter()
+                    .filter(|(_, type_name)| kind.as_deref().is_none_or(|kind| kind == *type_name))
+                    .map(|(key, _)| Frame::Bulk(key.clone()))
+                    .collect();
+                scan::reply(cursor, keys)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Db, Value};
+
+    fn run(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let name = KeysCommand::NAMES.iter().find(|n| **n == args[0]).unwrap();
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        match KeysCommand::parse(name, args) {
+            Ok(cmd) => cmd.execute(keyspace),
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(Bytes::from(s.to_string()))
+    }
+
+    #[test]
+    fn ts_keys_commands() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+        for key in ["user:1", "user:2", "session:1"] {
+            ks.insert(Bytes::from(key), string("v"), None);
+        }
+
+        assert_eq!(
+            run(&mut ks, &["exists", "user:1", "user:1", "nope"]),
+            Frame::Integer(2)
+        );
+        assert_eq!(
+            run(&mut ks, &["type", "user:1"]),
+            Frame::Simple("string".to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["type", "nope"]),
+            Frame::Simple("none".to_string())
+        );
+        match run(&mut ks, &["keys", "user:*"]) {
+            Frame::Array(keys) => assert_eq!(keys.len(), 2),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!(
+            run(&mut ks, &["renamenx", "user:1", "user:2"]),
+            Frame::Integer(0)
+        );
+        assert_eq!(
+            run(&mut ks, &["rename", "user:1", "user:3"]),
+            Frame::Simple("OK".to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["rename", "user:1", "user:3"]),
+            Frame::Error("ERR no such key".to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["del", "user:2", "user:3", "nope"]),
+            Frame::Integer(2)
+        );
+        assert_eq!(run(&mut ks, &["unlink", "session:1"]), Frame::Integer(1));
+        assert_eq!(run(&mut ks, &["dbsize"]), Frame::Integer(0));
+
+        ks.insert(Bytes::from("a"), string("v"), None);
+        assert_eq!(
+            run(&mut ks, &["flushdb", "async"]),
+            Frame::Simple("OK".to_string())
+        );
+        assert_eq!((ks.len(), ks.used_memory()), (0, 0));
+        assert_eq!(
+            run(&mut ks, &["flushdb", "later"]),
+            Frame::Error(SYNTAX_ERROR.to_string())
+        );
+    }
+
+    #[test]
+    fn ts_scan_with_type() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+        for i in 0..20 {
+            ks.insert(Bytes::from(format!("s{}", i)), string("v"), None);
+        }
+        ks.push(
+            &Bytes::from("list"),
+            crate::db::Side::Left,
+            [Bytes::from("x")],
+        )
+        .unwrap();
+
+        let mut found = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let reply = run(&mut ks, &["scan", &cursor, "count", "3", "type", "list"]);
+            let mut parts = match reply {
+                Frame::Array(parts) => parts,
+                other => panic!("unexpected {:?}", other),
+            };
+            if let Frame::Array(keys) = parts.pop().unwrap() {
+                found.extend(keys);
+            }
+            cursor = match parts.pop().unwrap() {
+                Frame::Bulk(cursor) => String::from_utf8(cursor.to_vec()).unwrap(),
+                other => panic!("unexpected {:?}", other),
+            };
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(found, vec![Frame::Bulk(Bytes::from("list"))]);
+        assert_eq!(
+            run(&mut ks, &["scan", "0", "type"]),
+            Frame::Error(SYNTAX_ERROR.to_string())
+        );
+    }
+}
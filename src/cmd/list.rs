@@ -0,0 +1,606 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::time;
+
+use crate::connection::Connection;
+use crate::db::{Keyspace, List, Served, Side, Value, WRONGTYPE};
+use crate::frame::Frame;
+
+use super::{
+    exact, int_arg, positive, reply, wrong_args, ConnectSnafu, Context, Result, SYNTAX_ERROR,
+};
+use snafu::ResultExt;
+
+/// 不会阻塞的 list 命令
+#[derive(Debug)]
+pub enum ListCommand {
+    Push {
+        name: &'static str,
+        key: Bytes,
+        side: Side,
+        items: Vec<Bytes>,
+    },
+    Pop {
+        name: &'static str,
+        key: Bytes,
+        side: Side,
+        count: Option<usize>,
+    },
+    Range {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    Len {
+        key: Bytes,
+    },
+    Index {
+        key: Bytes,
+        index: i64,
+    },
+    Rem {
+        key: Bytes,
+        count: i64,
+        item: Bytes,
+    },
+    Trim {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    Move {
+        source: Bytes,
+        destination: Bytes,
+        from: Side,
+        to: Side,
+    },
+}
+
+impl ListCommand {
+    /// 这一组命令的命令名
+    pub const NAMES: &'static [&'static str] = &[
+        "lindex", "llen", "lmove", "lpop", "lpush", "lrange", "lrem", "ltrim", "rpop", "rpush",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ListCommand::Push { name, .. } | ListCommand::Pop { name, .. } => name,
+            ListCommand::Range { .. } => "lrange",
+            ListCommand::Len { .. } => "llen",
+            ListCommand::Index { .. } => "lindex",
+            ListCommand::Rem { .. } => "lrem",
+            ListCommand::Trim { .. } => "ltrim",
+            ListCommand::Move { .. } => "lmove",
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            ListCommand::Push { key, .. }
+            | ListCommand::Pop { key, .. }
+            | ListCommand::Range { key, .. }
+            | ListCommand::Len { key }
+            | ListCommand::Index { key, .. }
+            | ListCommand::Rem { key, .. }
+            | ListCommand::Trim { key, .. } => vec![key],
+            ListCommand::Move {
+                source,
+                destination,
+                ..
+            } => vec![source, destination],
+        }
+    }
+
+    /// `name` 是 `NAMES` 里的一个
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<ListCommand, String> {
+        let cmd = match name {
+            "lpush" | "rpush" => {
+                if args.len() < 2 {
+                    return Err(wrong_args(name));
+                }
+                let mut args = args.into_iter();
+                let key = args.next().unwrap_or_default();
+                ListCommand::Push {
+                    name,
+                    key,
+                    side: if name == "lpush" {
+                        Side::Left
+                    } else {
+                        Side::Right
+                    },
+                    items: args.collect(),
+                }
+            }
+            "lpop" | "rpop" => {
+                let mut args = args.into_iter();
+                let key = args.next().ok_or_else(|| wrong_args(name))?;
+                let count = match args.next() {
+                    Some(count) => Some(positive(&count)?),
+                    None => None,
+                };
+                if args.next().is_some() {
+                    return Err(wrong_args(name));
+                }
+                ListCommand::Pop {
+                    name,
+                    key,
+                    side: if name == "lpop" {
+                        Side::Left
+                    } else {
+                        Side::Right
+                    },
+                    count,
+                }
+            }
+            "lrange" | "ltrim" => {
+                let [key, start, stop] = exact(name, args)?;
+                let (start, stop) = (int_arg(&start)?, int_arg(&stop)?);
+                if name == "lrange" {
+                    ListCommand::Range { key, start, stop }
+                } else {
+                    ListCommand::Trim { key, start, stop }
+                }
+            }
+            "llen" => {
+                let [key] = exact(name, args)?;
+                ListCommand::Len { key }
+            }
+            "lindex" => {
+                let [key, index] = exact(name, args)?;
+                let index = int_arg(&index)?;
+                ListCommand::Index { key, index }
+            }
+            "lrem" => {
+                let [key, count, item] = exact(name, args)?;
+                let count = int_arg(&count)?;
+                ListCommand::Rem { key, count, item }
+            }
+            _ => {
+                let [source, destination, from, to] = exact(name, args)?;
+                ListCommand::Move {
+                    source,
+                    destination,
+                    from: side(&from)?,
+                    to: side(&to)?,
+                }
+            }
+        };
+        Ok(cmd)
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self.run(keyspace) {
+            Ok(frame) => frame,
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn run(self, keyspace: &mut Keyspace) -> Result<Frame, String> {
+        let frame = match self {
+            ListCommand::Push {
+                key, side, items, ..
+            } => Frame::Integer(keyspace.push(&key, side, items)? as i64),
+            ListCommand::Pop {
+                key, side, count, ..
+            } => {
+                if list(keyspace.peek(&key))?.is_none() {
+                    return Ok(Frame::Null);
+                }
+                match count {
+                    None => keyspace.pop(&key, side).map_or(Frame::Null, Frame::Bulk),
+                    Some(count) => Frame::Array(
+                        (0..count)
+                            .map_while(|_| keyspace.pop(&key, side))
+                            .map(Frame::Bulk)
+                            .collect(),
+                    ),
+                }
+            }
+            ListCommand::Range { key, start, stop } => {
+                let items = match list(keyspace.get(&key))? {
+                    Some(list) => match range(list.len(), start, stop) {
+                        Some((start, stop)) => list
+                            .iter()
+                            .skip(start)
+                            .take(stop - start + 1)
+                            .map(|item| Frame::Bulk(item.clone()))
+                            .collect(),
+                        None => Vec::new(),
+                    },
+                    None => Vec::new(),
+                };
+                Frame::Array(items)
+            }
+            ListCommand::Len { key } => {
+                Frame::Integer(list(keyspace.get(&key))?.map_or(0, |list| list.len()) as i64)
+            }
+            ListCommand::Index { key, index } => {
+                let item = list(keyspace.get(&key))?.and_then(|list| {
+                    let index = if index < 0 {
+                        list.len() as i64 + index
+                    } else {
+                        index
+                    };
+                    list.get(usize::try_from(index).ok()?)
+                });
+                item.map_or(Frame::Null, |item| Frame::Bulk(item.clone()))
+            }
+            ListCommand::Rem { key, count, item } => {
+                let matched = match list(keyspace.peek(&key))? {
+                    Some(list) => list.iter().filter(|i| **i == item).count(),
+                    None => return Ok(Frame::Integer(0)),
+                };
+                // count 小于 0 时从尾部开始删：跳过前面多出来的那些
+                let limit = if count == 0 {
+                    matched
+                } else {
+                    matched.min(count.unsigned_abs() as usize)
+                };
+                let mut skip = if count < 0 { matched - limit } else { 0 };
+                let mut removed = 0;
+                keyspace.modify(&key, |value| {
+                    if let Some(list) = value.as_list_mut() {
+                        list.retain(|i| {
+                            if *i != item || removed == limit {
+                                return true;
+                            }
+                            if skip > 0 {
+                                skip -= 1;
+                                return true;
+                            }
+                            removed += 1;
+                            false
+                        });
+                    }
+                });
+                Frame::Integer(removed as i64)
+            }
+            ListCommand::Trim { key, start, stop } => {
+                let len = match list(keyspace.peek(&key))? {
+                    Some(list) => list.len(),
+                    None => return Ok(Frame::Simple("OK".to_string())),
+                };
+                match range(len, start, stop) {
+                    Some((start, stop)) => {
+                        keyspace.modify(&key, |value| {
+                            if let Some(list) = value.as_list_mut() {
+                                list.truncate(start, stop);
+                            }
+                        });
+                    }
+                    None => {
+                        keyspace.remove(&key);
+                    }
+                }
+                Frame::Simple("OK".to_string())
+            }
+            ListCommand::Move {
+                source,
+                destination,
+                from,
+                to,
+            } => match move_item(keyspace, &source, from, &destination, to)? {
+                Some(item) => Frame::Bulk(item),
+                None => Frame::Null,
+            },
+        };
+
+        Ok(frame)
+    }
+}
+
+/// BLPOP / BRPOP key [key ...] timeout 和 BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout：
+/// 所有的 list 都是空的时候阻塞，直到有元素、超时、客户端断开或者 server 开始 shutdown
+#[derive(Debug)]
+pub struct BlockingPop {
+    name: &'static str,
+    pub(crate) keys: Vec<Bytes>,
+    from: Side,
+    // BLMOVE 的目标 list 和放入的一端
+    to: Option<(Bytes, Side)>,
+    // None 表示一直等
+    timeout: Option<Duration>,
+}
+
+impl BlockingPop {
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<BlockingPop, String> {
+        let mut args = args;
+        if name == "blmove" {
+            let [source, destination, from, to, timeout] = exact(name, args)?;
+            return Ok(BlockingPop {
+                name,
+                keys: vec![source],
+                from: side(&from)?,
+                to: Some((destination, side(&to)?)),
+                timeout: parse_timeout(&timeout)?,
+            });
+        }
+
+        let timeout = match args.pop() {
+            Some(timeout) if !args.is_empty() => parse_timeout(&timeout)?,
+            _ => return Err(wrong_args(name)),
+        };
+        Ok(BlockingPop {
+            name,
+            keys: args,
+            from: if name == "blpop" {
+                Side::Left
+            } else {
+                Side::Right
+            },
+            to: None,
+            timeout,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// 访问的 key，BLMOVE 包括目标 list
+    pub fn keys(&self) -> Vec<&[u8]> {
+        let mut keys: Vec<&[u8]> = self.keys.iter().map(|key| key.as_ref()).collect();
+        if let Some((destination, _)) = &self.to {
+            keys.push(destination);
+        }
+        keys
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        // 检查 list 和排队在同一次加锁里完成，中间放入的元素不会错过
+        let blocked = {
+            let mut keyspace = ctx.state.db.lock_keys(&self.keys()).await;
+            match self.pop_now(&mut keyspace) {
+                Some(served) => Ok(served),
+                None => Err(keyspace.block(self.keys.clone(), self.from, self.to.clone())),
+            }
+        };
+
+        let served = match blocked {
+            Ok(served) => Some(served),
+            Err(mut blocked) => {
+                tokio::select! {
+                    served = blocked.recv() => served,
+                    _ = timeout(self.timeout) => None,
+                    _ = ctx.shutdown.recv() => None,
+                    // 客户端走了，不用再等，也不用回复
+                    res = connection.closed() => {
+                        res.context(ConnectSnafu)?;
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        reply(connection, self.response(served)).await
+    }
+
+    /// 在事务里不阻塞，list 都是空的话马上回复 nil
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        let served = self.pop_now(keyspace);
+        self.response(served)
+    }
+
+    fn response(&self, served: Option<Served>) -> Frame {
+        match served {
+            Some(Ok((key, item))) if self.to.is_none() => {
+                Frame::Array(vec![Frame::Bulk(key), Frame::Bulk(item)])
+            }
+            Some(Ok((_, item))) => Frame::Bulk(item),
+            Some(Err(msg)) => Frame::Error(msg),
+            None => Frame::Null,
+        }
+    }
+
+    // 不用等的情况：某个 list 里已经有元素了，或者 key 的类型不对
+    fn pop_now(&self, keyspace: &mut Keyspace) -> Option<Served> {
+        for key in &self.keys {
+            let res = match &self.to {
+                Some((destination, to)) => {
+                    move_item(keyspace, key, self.from, destination, *to).transpose()?
+                }
+                None => match list(keyspace.peek(key)) {
+                    Ok(Some(_)) => Ok(keyspace.pop(key, self.from).unwrap_or_default()),
+                    Ok(None) => continue,
+                    Err(msg) => Err(msg),
+                },
+            };
+            return Some(res.map(|item| (key.clone(), item)));
+        }
+        None
+    }
+}
+
+// LMOVE：从 source 的一端取出一个元素放到 destination 的一端。source 不存在时返回 None
+fn move_item(
+    keyspace: &mut Keyspace,
+    source: &Bytes,
+    from: Side,
+    destination: &Bytes,
+    to: Side,
+) -> Result<Option<Bytes>, String> {
+    if list(keyspace.peek(source))?.is_none() {
+        return Ok(None);
+    }
+    list(keyspace.peek(destination))?;
+
+    let item = keyspace.pop(source, from).unwrap_or_default();
+    keyspace.push(destination, to, [item.clone()])?;
+    Ok(Some(item))
+}
+
+// 取出 list；key 存在但不是 list 时是 WRONGTYPE 错误
+fn list(value: Option<&Value>) -> Result<Option<&List>, String> {
+    match value {
+        Some(value) => value
+            .as_list()
+            .map(Some)
+            .ok_or_else(|| WRONGTYPE.to_string()),
+        None => Ok(None),
+    }
+}
+
+fn side(arg: &[u8]) -> Result<Side, String> {
+    match String::from_utf8_lossy(arg).to_lowercase().as_str() {
+        "left" => Ok(Side::Left),
+        "right" => Ok(Side::Right),
+        _ => Err(SYNTAX_ERROR.to_string()),
+    }
+}
+
+// 阻塞命令的超时，单位是秒，可以是小数。0 表示一直等
+fn parse_timeout(arg: &[u8]) -> Result<Option<Duration>, String> {
+    let secs: f64 = std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .filter(|secs: &f64| secs.is_finite())
+        .ok_or_else(|| "ERR timeout is not a float or out of range".to_string())?;
+    if secs < 0.0 {
+        return Err("ERR timeout is negative".to_string());
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    // 太大的超时和一直等没有区别
+    Ok(Duration::try_from_secs_f64(secs).ok())
+}
+
+async fn timeout(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+// LRANGE / LTRIM 的下标：负数从末尾算起。和 GETRANGE 不一样，
+// 换算之后 stop 还是负数的话结果是空的。结果为空时返回 None
+fn range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop.min(len - 1) as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    fn run(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let name = ListCommand::NAMES.iter().find(|n| **n == args[0]).unwrap();
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        match ListCommand::parse(name, args) {
+            Ok(cmd) => cmd.execute(keyspace),
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn items(frame: Frame) -> Vec<String> {
+        match frame {
+            Frame::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Frame::Bulk(s) => String::from_utf8(s.to_vec()).unwrap(),
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ts_list_commands() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        assert_eq!(
+            run(&mut ks, &["rpush", "l", "a", "b", "a", "c", "a"]),
+            Frame::Integer(5)
+        );
+        assert_eq!(run(&mut ks, &["lpush", "l", "x", "y"]), Frame::Integer(7));
+        assert_eq!(
+            items(run(&mut ks, &["lrange", "l", "0", "-1"])),
+            ["y", "x", "a", "b", "a", "c", "a"]
+        );
+        assert_eq!(
+            items(run(&mut ks, &["lrange", "l", "-3", "100"])),
+            ["a", "c", "a"]
+        );
+        assert!(items(run(&mut ks, &["lrange", "l", "0", "-100"])).is_empty());
+        assert_eq!(
+            run(&mut ks, &["lindex", "l", "-1"]),
+            Frame::Bulk(Bytes::from("a"))
+        );
+        assert_eq!(run(&mut ks, &["lindex", "l", "7"]), Frame::Null);
+
+        // 从尾部删掉一个 a
+        assert_eq!(run(&mut ks, &["lrem", "l", "-1", "a"]), Frame::Integer(1));
+        assert_eq!(
+            items(run(&mut ks, &["lrange", "l", "0", "-1"])),
+            ["y", "x", "a", "b", "a", "c"]
+        );
+        assert_eq!(run(&mut ks, &["lrem", "l", "0", "a"]), Frame::Integer(2));
+        assert_eq!(
+            run(&mut ks, &["ltrim", "l", "1", "-1"]),
+            Frame::Simple("OK".to_string())
+        );
+        assert_eq!(
+            items(run(&mut ks, &["lrange", "l", "0", "-1"])),
+            ["x", "b", "c"]
+        );
+
+        assert_eq!(
+            run(&mut ks, &["lmove", "l", "m", "left", "right"]),
+            Frame::Bulk(Bytes::from("x"))
+        );
+        assert_eq!(items(run(&mut ks, &["rpop", "l", "5"])), ["c", "b"]);
+        assert!(!ks.contains(b"l"));
+        assert_eq!(run(&mut ks, &["lpop", "l"]), Frame::Null);
+        assert_eq!(run(&mut ks, &["llen", "m"]), Frame::Integer(1));
+        run(&mut ks, &["ltrim", "m", "5", "10"]);
+        assert!(!ks.contains(b"m"));
+
+        ks.insert(Bytes::from("s"), Value::String(Bytes::from("x")), None);
+        assert_eq!(
+            run(&mut ks, &["lpush", "s", "a"]),
+            Frame::Error(WRONGTYPE.to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["lmove", "s", "m", "up", "left"]),
+            Frame::Error(SYNTAX_ERROR.to_string())
+        );
+        assert_eq!(ks.used_memory(), 1 + 1 + 64);
+    }
+
+    #[test]
+    fn ts_parse_blocking() {
+        let args = |args: &[&str]| args.iter().map(|a| Bytes::from(a.to_string())).collect();
+
+        let blpop = BlockingPop::parse("blpop", args(&["a", "b", "0.5"])).unwrap();
+        assert_eq!(blpop.keys(), vec![b"a".as_ref(), b"b"]);
+        assert_eq!(blpop.timeout, Some(Duration::from_millis(500)));
+        let blmove = BlockingPop::parse("blmove", args(&["a", "b", "right", "left", "0"])).unwrap();
+        assert_eq!(blmove.keys(), vec![b"a".as_ref(), b"b"]);
+        assert_eq!(blmove.timeout, None);
+
+        assert_eq!(
+            BlockingPop::parse("brpop", args(&["a", "-1"])).unwrap_err(),
+            "ERR timeout is negative"
+        );
+        assert_eq!(
+            BlockingPop::parse("brpop", args(&["a", "soon"])).unwrap_err(),
+            "ERR timeout is not a float or out of range"
+        );
+        assert_eq!(
+            BlockingPop::parse("brpop", args(&["1"])).unwrap_err(),
+            wrong_args("brpop")
+        );
+    }
+}
@@ -1,13 +1,80 @@
+mod acl;
+pub use acl::AclCommand;
+
+mod auth;
+pub use auth::Auth;
+
+mod client;
+pub use client::ClientCommand;
+
+mod expire;
+pub use expire::{Expire, Persist, Ttl};
+
+mod hash;
+pub use hash::{HFetch, HashCommand};
+
+mod info;
+pub use info::Info;
+
+mod keys;
+pub use keys::KeysCommand;
+
+mod list;
+pub use list::{BlockingPop, ListCommand};
+
+mod monitor;
+pub use monitor::Monitor;
+
+mod multi;
+pub use multi::{Transaction, Watch};
+
+mod scan;
+pub use scan::ScanArgs;
+
+mod script;
+pub use script::{Eval, ScriptCommand};
+
+mod set;
+pub use set::Set;
+
+mod sets;
+pub use sets::{SetCommand, SetOp};
+
+mod slowlog;
+pub use slowlog::SlowlogCommand;
+
+mod stream;
+pub use stream::{StreamCommand, XRead};
+
+mod string;
+pub use string::{GetExTtl, MGet, StringCommand};
+
+mod zset;
+pub use zset::ZSetCommand;
+
+use std::sync::Arc;
+use std::time::Duration;
+
 use bytes::Bytes;
 
+use crate::clients::ClientInfo;
+use crate::config::Config;
 use crate::connection;
+use crate::db::{self, Keyspace, NewId, Trim, Watched, WRONGTYPE};
 use crate::frame::Frame;
+use crate::metrics::METRICS;
 use crate::parser;
+use crate::ratelimit::Scope;
+use crate::redact;
+use crate::shutdown::Shutdown;
+use crate::state::State;
+use crate::telemetry;
 use connection::Connection;
 
 use snafu::{prelude::*, ResultExt};
-use tracing::info;
+use tracing::{debug, field, info, info_span, warn, Instrument};
 
+use reqwest::header::HeaderMap;
 use reqwest::Client;
 
 use serde_json::Value;
@@ -24,28 +91,302 @@ pub enum Error {
     JsonError { source: serde_json::Error },
     #[snafu(display("failed for bad json string"))]
     StrJsonError,
+    #[snafu(display("failed for json that is not an object"))]
+    ObjectJsonError,
+    #[snafu(display("rate limited by {}, retry after {:?}", scope, retry_after))]
+    RateLimitedError { scope: Scope, retry_after: Duration },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// 执行命令时用到的上下文：访问上游的 http client，所有连接共享的 server 状态，
+/// 以及当前连接在客户端列表中的登记信息
+#[derive(Debug)]
+pub struct Context {
+    pub cli: Client,
+    pub state: Arc<State>,
+    pub client: Arc<ClientInfo>,
+    /// server 开始 shutdown 时收到通知。会一直占用连接的命令（MONITOR 等）靠它结束
+    pub shutdown: Shutdown,
+    /// 当前命令最后一次访问的上游，slowlog 里会记录它
+    pub upstream: Option<Upstream>,
+    /// MULTI 之后排队的命令，None 表示不在事务里
+    pub transaction: Option<Transaction>,
+    /// WATCH 的 key，EXEC / DISCARD / UNWATCH 之后清空
+    pub watched: Option<Watched>,
+}
+
+/// 一次上游请求的结果
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upstream {
+    pub url: String,
+    /// http 状态码。请求本身失败（连不上、超时等）时是 error
+    pub status: String,
+}
+
 #[derive(Debug)]
 pub struct Get {
-    key: String,
+    key: Bytes,
 }
 
-async fn call_api(url: &str, cli: &mut Client) -> Result<String> {
-    let doge = cli
+const SYNTAX_ERROR: &str = "ERR syntax error";
+
+const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+// 可能增加内存的命令（按 acl_id）。超过 maxmemory 而且腾不出空间时拒绝执行，
+// DEL 这样读取或者释放内存的命令照常执行
+const DENYOOM: &[&str] = &[
+    "append",
+    "blmove",
+    "decr",
+    "decrby",
+    // 脚本里的命令执行之前不知道会不会增加内存，和 Redis 一样整个脚本都拒绝
+    "eval",
+    "evalsha",
+    "hfetch",
+    "hincrby",
+    "hset",
+    "incr",
+    "incrby",
+    "incrbyfloat",
+    "lmove",
+    "lpush",
+    "mset",
+    "msetnx",
+    "rpush",
+    "sadd",
+    "set",
+    "setrange",
+    "xadd",
+    // XCLAIM / XREADGROUP 会在 consumer group 的 PEL 里增加或者修改条目
+    "xclaim",
+    "xgroup|create",
+    "xreadgroup",
+    "zadd",
+    "zincrby",
+];
+
+/// 取出请求里的命令名和参数，给 slowlog 和 MONITOR 用。
+/// Bytes 的 clone 只是增加引用计数，开销很小
+pub fn args(frame: &Frame) -> Vec<Bytes> {
+    match frame {
+        Frame::Array(parts) => parts
+            .iter()
+            .map(|part| match part {
+                Frame::Bulk(data) => data.clone(),
+                Frame::Simple(s) => Bytes::from(s.clone()),
+                Frame::Integer(n) => Bytes::from(n.to_string()),
+                _ => Bytes::new(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// 读出剩下的所有参数，keyspace 的命令自己检查参数并回复 Redis 的错误
+fn rest(parser: &mut parser::Parser) -> Result<Vec<Bytes>> {
+    let mut args = Vec::new();
+    while parser.has_next() {
+        args.push(parser.next_bytes().context(CommandSnafu)?);
+    }
+    Ok(args)
+}
+
+fn wrong_args(name: &str) -> String {
+    format!("ERR wrong number of arguments for '{}' command", name)
+}
+
+// 参数的个数必须正好是 N
+fn exact<const N: usize>(name: &str, args: Vec<Bytes>) -> Result<[Bytes; N], String> {
+    <[Bytes; N]>::try_from(args).map_err(|_| wrong_args(name))
+}
+
+const NOT_INTEGER: &str = "ERR value is not an integer or out of range";
+
+const NOT_FLOAT: &str = "ERR value is not a valid float";
+
+fn int_arg(arg: &[u8]) -> Result<i64, String> {
+    parse_int(arg).ok_or_else(|| NOT_INTEGER.to_string())
+}
+
+// LPOP / SPOP / ZPOPMIN 等的 count 参数，不能是负数
+fn positive(arg: &[u8]) -> Result<usize, String> {
+    usize::try_from(int_arg(arg)?)
+        .map_err(|_| "ERR value is out of range, must be positive".to_string())
+}
+
+// 和 Redis 一样，只接受规范的十进制写法：不能有 `+`、空格
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    if arg.first() == Some(&b'+') {
+        return None;
+    }
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// 上游的 host，作为指标的 label
+fn upstream_host(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// 访问上游，解析出返回的 origin。只需要 &Context，MGET 可以同时发出多个请求
+async fn call_api(url: &str, ctx: &Context) -> (Option<Upstream>, Result<String>) {
+    match request(url, ctx).await {
+        (upstream, Ok(resp)) => {
+            let res = match read_body(url, resp, ctx).await {
+                Ok(body) => parse_origin(&body),
+                Err(err) => Err(err),
+            };
+            (upstream, res)
+        }
+        (upstream, Err(err)) => (upstream, Err(err)),
+    }
+}
+
+// 访问上游，读出回复的状态码和内容，不记录到 upstream-stream。
+// 脚本里的 http.get 已经锁住了 keyspace，由它自己记录
+async fn fetch(url: &str, ctx: &Context) -> (Option<Upstream>, Result<(u16, String)>) {
+    match request(url, ctx).await {
+        (upstream, Ok(resp)) => {
+            let status = resp.status().as_u16();
+            let res = resp.text().await.context(HttpSnafu);
+            (upstream, res.map(|body| (status, body)))
+        }
+        (upstream, Err(err)) => (upstream, Err(err)),
+    }
+}
+
+// 向上游发出请求，同时返回这次请求的结果（被限流时没有发请求，是 None），给 slowlog 用
+async fn request(url: &str, ctx: &Context) -> (Option<Upstream>, Result<reqwest::Response>) {
+    let host = upstream_host(url);
+
+    // 先检查限流，超过限制的直接拒绝，不发请求
+    let user = ctx.client.user();
+    if let Err((scope, retry_after)) =
+        ctx.state
+            .ratelimit
+            .acquire(user.as_deref(), ctx.client.ip(), &host)
+    {
+        METRICS
+            .rate_limited
+            .with_label_values(&[scope.as_str()])
+            .inc();
+        return (None, RateLimitedSnafu { scope, retry_after }.fail());
+    }
+
+    // 每次访问上游是当前命令的一个子 span，并通过 traceparent 头传给上游
+    let span = info_span!(
+        "upstream",
+        otel.name = "GET",
+        otel.kind = "client",
+        http.method = "GET",
+        http.url = %redact::url(url),
+        net.peer.name = %host,
+        http.status_code = field::Empty,
+        otel.status_code = field::Empty,
+    );
+    let mut headers = HeaderMap::new();
+    telemetry::inject(&span, &mut headers);
+
+    let timer = METRICS
+        .upstream_duration
+        .with_label_values(&[&host])
+        .start_timer();
+    let res = ctx
+        .cli
         .get(url)
+        .headers(headers)
         .send()
-        .await
-        .context(HttpSnafu)?
-        .text()
-        .await
-        .context(HttpSnafu)?;
+        .instrument(span.clone())
+        .await;
+    timer.observe_duration();
 
-    info!("Got {:#?}", doge);
+    let status = match &res {
+        Ok(resp) => {
+            span.record("http.status_code", resp.status().as_u16());
+            if !resp.status().is_success() {
+                span.record("otel.status_code", "ERROR");
+            }
+            resp.status().as_u16().to_string()
+        }
+        Err(_) => {
+            span.record("otel.status_code", "ERROR");
+            "error".to_string()
+        }
+    };
+    METRICS
+        .upstream_requests
+        .with_label_values(&[&host, &status])
+        .inc();
+    let upstream = Upstream {
+        url: url.to_string(),
+        status,
+    };
 
-    let v: Value = serde_json::from_str(doge.as_str()).context(JsonSnafu)?;
+    (Some(upstream), res.context(HttpSnafu))
+}
+
+// 读出上游回复的内容。配置了 upstream-stream 的话，同时把这次回复（不管状态码是什么）
+// 追加到这个 stream 里。stream 和 keyspace 里别的 key 共用 maxmemory，腾不出空间时不记录
+async fn read_body(url: &str, resp: reqwest::Response, ctx: &Context) -> Result<String> {
+    let status = resp.status().as_u16();
+    let body = resp.text().await.context(HttpSnafu)?;
+
+    let config = &ctx.state.config;
+    if let Some(key) = &config.upstream_stream {
+        if !ctx
+            .state
+            .db
+            .evict(
+                config.maxmemory,
+                config.maxmemory_policy,
+                config.maxmemory_samples,
+            )
+            .await
+        {
+            warn!("not recording upstream response to {}: {}", key, OOM);
+            return Ok(body);
+        }
+        let stream = Bytes::from(key.clone());
+        append_upstream(
+            &mut *ctx.state.db.lock_keys(&[&stream]).await,
+            config,
+            url,
+            status,
+            &body,
+        );
+    }
+
+    Ok(body)
+}
+
+// 把上游的回复追加到 upstream-stream，keyspace 里要已经锁住了它的 shard。
+// 脚本里的 http.get 用脚本锁住的 keyspace 记录，不能再加一次锁
+fn append_upstream(keyspace: &mut Keyspace, config: &Config, url: &str, status: u16, body: &str) {
+    if let Some(key) = &config.upstream_stream {
+        let fields = vec![
+            (Bytes::from_static(b"url"), Bytes::from(url.to_string())),
+            (
+                Bytes::from_static(b"status"),
+                Bytes::from(status.to_string()),
+            ),
+            (Bytes::from_static(b"body"), Bytes::from(body.to_string())),
+        ];
+        let trim = (config.upstream_stream_maxlen > 0)
+            .then_some(Trim::MaxLen(config.upstream_stream_maxlen));
+        let stream = Bytes::from(key.clone());
+        if let Err(msg) = keyspace.add_entry(&stream, NewId::Auto, fields, true, trim) {
+            warn!("failed to record upstream response to {}: {}", key, msg);
+        }
+    }
+}
+
+fn parse_origin(doge: &str) -> Result<String> {
+    debug!("Got {:#?}", doge);
+
+    let v: Value = serde_json::from_str(doge).context(JsonSnafu)?;
 
     let origin = match v["origin"].as_str() {
         Some(s) => s,
@@ -57,33 +398,56 @@ async fn call_api(url: &str, cli: &mut Client) -> Result<String> {
     Ok(origin.to_string())
 }
 
+// 把上游请求的结果转换成回复
+fn upstream_reply(res: Result<String>) -> Frame {
+    match res {
+        Ok(origin) => Frame::Bulk(Bytes::from(origin)),
+        // 被限流时回复错误，告诉客户端多久之后可以重试
+        Err(Error::RateLimitedError { retry_after, .. }) => Frame::Error(format!(
+            "RATELIMITED retry after {}ms",
+            ceil_millis(retry_after)
+        )),
+        Err(Error::HttpError { source: _ }) => Frame::Bulk(Bytes::from("failed on http")),
+        Err(_) => Frame::Bulk(Bytes::from("bad json")),
+    }
+}
+
+// 向上取整，避免客户端按照 0ms 马上重试
+fn ceil_millis(d: Duration) -> u128 {
+    d.as_micros().div_ceil(1000).max(1)
+}
+
 impl Get {
-    pub fn new(key: impl ToString) -> Get {
-        Get {
-            key: key.to_string(),
-        }
+    pub fn new(key: impl Into<Bytes>) -> Get {
+        Get { key: key.into() }
     }
 
     pub fn parse_frame(parser: &mut parser::Parser) -> Result<Get> {
         // Redis 的 Get 命令也是一个数组。数组中的第一个元素是字符串 'Get'，
-        // 第二个元素也是一个 string：key
-        let key = parser.next_string().context(CommandSnafu)?;
+        // 第二个元素是 key
+        let key = parser.next_bytes().context(CommandSnafu)?;
         let get = Get::new(key);
         Ok(get)
     }
 
-    // 实现 Get 命令：调用 Http 请求，查询 httpbin.org/ip 服务
-    pub async fn apply(self, cli: &mut Client, connection: &mut Connection) -> Result<()> {
-        let origin = call_api(&self.key, cli)
-            .await
-            .unwrap_or_else(|error| match error {
-                Error::HttpError { source: _ } => "failed on http".to_string(),
-                _ => "bad json".to_string(),
-            });
+    // 实现 Get 命令：先查 keyspace；http(s) 的 key 在 keyspace 里没有的话
+    // （worker 没有缓存，或者缓存已经过期），再调用 Http 请求查询上游
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        let cached = self.cached(&mut *ctx.state.db.lock_keys(&[&self.key]).await);
+        let url = std::str::from_utf8(&self.key)
+            .ok()
+            .filter(|key| db::is_url(key.as_bytes()));
 
-        let data = Bytes::from(origin);
+        let response = match (cached, url) {
+            (Some(frame), _) => frame,
+            (None, Some(url)) => {
+                let (upstream, res) = call_api(url, ctx).await;
+                ctx.upstream = upstream;
+                upstream_reply(res)
+            }
+            (None, None) => Frame::Null,
+        };
 
-        let response = Frame::Bulk(data);
         // 如果 write_frame 出错，也会结束循环，抛出一个 IoFailed
         connection
             .write_frame(&response)
@@ -91,21 +455,65 @@ impl Get {
             .context(ConnectSnafu)?;
         info!(
             "for get key: {}. the sent response successfully: {:?}",
-            self.key, response
+            redact::url(&String::from_utf8_lossy(&self.key)),
+            response
         );
 
         Ok(())
     }
+
+    /// 在事务里只读 keyspace，不访问上游
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        self.cached(keyspace).unwrap_or(Frame::Null)
+    }
+
+    fn cached(&self, keyspace: &mut Keyspace) -> Option<Frame> {
+        keyspace
+            .get(&self.key)
+            .map(|value| match value.as_string() {
+                Some(s) => Frame::Bulk(s.clone()),
+                None => Frame::Error(WRONGTYPE.to_string()),
+            })
+    }
 }
 
 #[derive(Debug)]
 pub enum Command {
     Get(Get),
+    Auth(Auth),
+    Acl(AclCommand),
+    Client(ClientCommand),
+    Info(Info),
+    Monitor(Monitor),
+    Slowlog(SlowlogCommand),
+    Set(Set),
+    MGet(MGet),
+    String(StringCommand),
+    Hash(HashCommand),
+    HFetch(HFetch),
+    List(ListCommand),
+    BlockingPop(BlockingPop),
+    Sets(SetCommand),
+    SortedSet(ZSetCommand),
+    Stream(StreamCommand),
+    XRead(XRead),
+    Keys(KeysCommand),
+    Expire(Expire),
+    Ttl(Ttl),
+    Persist(Persist),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Watch),
+    Unwatch,
+    Eval(Eval),
+    Script(ScriptCommand),
     Publish(String),
-    Set(String),
     Subscribe(String),
     Unsubscribe(String),
     Ping(String),
+    /// 参数不对的命令：不执行，直接回复这个错误
+    Invalid(&'static str, String),
     Unknown(String),
 }
 
@@ -117,22 +525,358 @@ impl Command {
         // Get / Set 等命令。
         let s = parser.next_string().context(CommandSnafu)?;
 
-        let cmd = match s.as_str() {
+        // 和 Redis 一样，命令名不区分大小写
+        let cmd = match s.to_lowercase().as_str() {
             // 当前我们先只实现 Get 命令
             "get" => {
                 let g = Get::parse_frame(&mut parser)?;
                 Command::Get(g)
             }
-            _ => Command::Unknown(s),
+            "auth" => Command::Auth(Auth::parse_frame(&mut parser)?),
+            "acl" => Command::Acl(AclCommand::parse_frame(&mut parser)?),
+            "client" => Command::Client(ClientCommand::parse_frame(&mut parser)?),
+            "info" => Command::Info(Info::parse_frame(&mut parser)?),
+            "monitor" => Command::Monitor(Monitor),
+            "slowlog" => Command::Slowlog(SlowlogCommand::parse_frame(&mut parser)?),
+            "set" => parsed("set", Set::parse(rest(&mut parser)?), Command::Set),
+            "expire" => parsed(
+                "expire",
+                Expire::parse("expire", rest(&mut parser)?),
+                Command::Expire,
+            ),
+            "pexpire" => parsed(
+                "pexpire",
+                Expire::parse("pexpire", rest(&mut parser)?),
+                Command::Expire,
+            ),
+            "expireat" => parsed(
+                "expireat",
+                Expire::parse("expireat", rest(&mut parser)?),
+                Command::Expire,
+            ),
+            "pexpireat" => parsed(
+                "pexpireat",
+                Expire::parse("pexpireat", rest(&mut parser)?),
+                Command::Expire,
+            ),
+            "ttl" => parsed("ttl", Ttl::parse("ttl", rest(&mut parser)?), Command::Ttl),
+            "pttl" => parsed("pttl", Ttl::parse("pttl", rest(&mut parser)?), Command::Ttl),
+            "persist" => parsed(
+                "persist",
+                Persist::parse(rest(&mut parser)?),
+                Command::Persist,
+            ),
+            "mget" => parsed("mget", MGet::parse(rest(&mut parser)?), Command::MGet),
+            "hfetch" => parsed("hfetch", HFetch::parse(rest(&mut parser)?), Command::HFetch),
+            "blpop" => parsed(
+                "blpop",
+                BlockingPop::parse("blpop", rest(&mut parser)?),
+                Command::BlockingPop,
+            ),
+            "brpop" => parsed(
+                "brpop",
+                BlockingPop::parse("brpop", rest(&mut parser)?),
+                Command::BlockingPop,
+            ),
+            "blmove" => parsed(
+                "blmove",
+                BlockingPop::parse("blmove", rest(&mut parser)?),
+                Command::BlockingPop,
+            ),
+            "xread" => parsed(
+                "xread",
+                XRead::parse("xread", rest(&mut parser)?),
+                Command::XRead,
+            ),
+            "xreadgroup" => parsed(
+                "xreadgroup",
+                XRead::parse("xreadgroup", rest(&mut parser)?),
+                Command::XRead,
+            ),
+            "multi" => parsed("multi", exact::<0>("multi", rest(&mut parser)?), |_| {
+                Command::Multi
+            }),
+            "exec" => parsed("exec", exact::<0>("exec", rest(&mut parser)?), |_| {
+                Command::Exec
+            }),
+            "discard" => parsed("discard", exact::<0>("discard", rest(&mut parser)?), |_| {
+                Command::Discard
+            }),
+            "watch" => parsed("watch", Watch::parse(rest(&mut parser)?), Command::Watch),
+            "unwatch" => parsed("unwatch", exact::<0>("unwatch", rest(&mut parser)?), |_| {
+                Command::Unwatch
+            }),
+            "eval" => parsed(
+                "eval",
+                Eval::parse("eval", rest(&mut parser)?),
+                Command::Eval,
+            ),
+            "evalsha" => parsed(
+                "evalsha",
+                Eval::parse("evalsha", rest(&mut parser)?),
+                Command::Eval,
+            ),
+            "script" => Command::Script(ScriptCommand::parse_frame(&mut parser)?),
+            other => {
+                if let Some(name) = StringCommand::NAMES.iter().find(|name| **name == other) {
+                    parsed(
+                        name,
+                        StringCommand::parse(name, rest(&mut parser)?),
+                        Command::String,
+                    )
+                } else if let Some(name) = HashCommand::NAMES.iter().find(|name| **name == other) {
+                    parsed(
+                        name,
+                        HashCommand::parse(name, rest(&mut parser)?),
+                        Command::Hash,
+                    )
+                } else if let Some(name) = ListCommand::NAMES.iter().find(|name| **name == other) {
+                    parsed(
+                        name,
+                        ListCommand::parse(name, rest(&mut parser)?),
+                        Command::List,
+                    )
+                } else if let Some(name) = SetCommand::NAMES.iter().find(|name| **name == other) {
+                    parsed(
+                        name,
+                        SetCommand::parse(name, rest(&mut parser)?),
+                        Command::Sets,
+                    )
+                } else if let Some(name) = ZSetCommand::NAMES.iter().find(|name| **name == other) {
+                    parsed(
+                        name,
+                        ZSetCommand::parse(name, rest(&mut parser)?),
+                        Command::SortedSet,
+                    )
+                } else if let Some(name) = StreamCommand::NAMES.iter().find(|name| **name == other)
+                {
+                    parsed(
+                        name,
+                        StreamCommand::parse(name, rest(&mut parser)?),
+                        Command::Stream,
+                    )
+                } else if let Some(name) = KeysCommand::NAMES.iter().find(|name| **name == other) {
+                    parsed(
+                        name,
+                        KeysCommand::parse(name, rest(&mut parser)?),
+                        Command::Keys,
+                    )
+                } else {
+                    Command::Unknown(s)
+                }
+            }
         };
 
         Ok(cmd)
     }
 
-    pub async fn apply(self, cli: &mut Client, connection: &mut Connection) -> Result<()> {
+    /// 命令名，用于日志和指标。未知命令统一叫 unknown，避免指标的 label 无限增长
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) => "get",
+            Command::Auth(_) => "auth",
+            Command::Acl(_) => "acl",
+            Command::Client(_) => "client",
+            Command::Info(_) => "info",
+            Command::Monitor(_) => "monitor",
+            Command::Slowlog(_) => "slowlog",
+            Command::Set(_) => "set",
+            Command::MGet(_) => "mget",
+            Command::String(string) => string.name(),
+            Command::Hash(hash) => hash.name(),
+            Command::HFetch(_) => "hfetch",
+            Command::List(list) => list.name(),
+            Command::BlockingPop(pop) => pop.name(),
+            Command::Sets(set) => set.name(),
+            Command::SortedSet(zset) => zset.name(),
+            Command::Stream(stream) => stream.name(),
+            Command::XRead(read) => read.name(),
+            Command::Keys(keys) => keys.name(),
+            Command::Expire(expire) => expire.name(),
+            Command::Ttl(ttl) => ttl.name(),
+            Command::Persist(_) => "persist",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch(_) => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Eval(eval) => eval.name(),
+            Command::Script(_) => "script",
+            Command::Publish(_) => "publish",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::Ping(_) => "ping",
+            Command::Invalid(name, _) => name,
+            Command::Unknown(_) => "unknown",
+        }
+    }
+
+    /// 检查权限时用的命令 id：有子命令的写成 `命令|子命令`
+    pub fn acl_id(&self) -> &'static str {
+        match self {
+            Command::Acl(acl) => acl.acl_id(),
+            Command::Client(client) => client.acl_id(),
+            Command::Slowlog(slowlog) => slowlog.acl_id(),
+            Command::Stream(stream) => stream.acl_id(),
+            Command::Script(script) => script.acl_id(),
+            _ => self.name(),
+        }
+    }
+
+    /// 命令要访问的 key（包括作为 key 的上游 url），检查 key 的权限时用
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Get(Get { key })
+            | Command::Set(Set { key, .. })
+            | Command::Expire(Expire { key, .. })
+            | Command::Ttl(Ttl { key, .. })
+            | Command::Persist(Persist { key }) => vec![key],
+            Command::MGet(MGet { keys }) => keys.iter().map(|key| key.as_ref()).collect(),
+            Command::String(string) => string.keys(),
+            Command::Hash(hash) => vec![hash.key()],
+            Command::HFetch(HFetch { key, url }) => vec![key, url],
+            Command::List(list) => list.keys(),
+            Command::BlockingPop(pop) => pop.keys(),
+            Command::Sets(set) => set.keys(),
+            Command::SortedSet(zset) => vec![zset.key()],
+            Command::Stream(stream) => vec![stream.key()],
+            Command::XRead(read) => read.keys(),
+            Command::Keys(keys) => keys.keys(),
+            Command::Watch(watch) => watch.keys(),
+            Command::Eval(eval) => eval.keys(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// 能不能在 MULTI 里排队。要访问上游的、要占用连接的命令没法在 EXEC 的加锁里执行
+    pub fn transactional(&self) -> bool {
+        !matches!(
+            self,
+            Command::Auth(_)
+                | Command::Acl(_)
+                | Command::Client(_)
+                | Command::Info(_)
+                | Command::Monitor(_)
+                | Command::Slowlog(_)
+                | Command::HFetch(_)
+                | Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch(_)
+                | Command::Eval(_)
+                | Command::Script(_)
+        )
+    }
+
+    /// 在已经锁住的 keyspace 上执行命令。不访问上游也不阻塞：GET / MGET 只读 keyspace 里缓存的值，
+    /// BLPOP / XREAD BLOCK 这样的命令没有结果时马上回复 nil。EXEC 用它执行排队的命令
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self {
+            Command::Get(get) => get.execute(keyspace),
+            Command::Set(set) => set.execute(keyspace),
+            Command::MGet(mget) => mget.execute(keyspace),
+            Command::String(string) => string.execute(keyspace),
+            Command::Hash(hash) => hash.execute(keyspace),
+            Command::List(list) => list.execute(keyspace),
+            Command::BlockingPop(pop) => pop.execute(keyspace),
+            Command::Sets(set) => set.execute(keyspace),
+            Command::SortedSet(zset) => zset.execute(keyspace),
+            Command::Stream(stream) => stream.execute(keyspace),
+            Command::XRead(read) => read.execute(keyspace),
+            Command::Keys(keys) => keys.execute(keyspace),
+            Command::Expire(expire) => expire.execute(keyspace),
+            Command::Ttl(ttl) => ttl.execute(keyspace),
+            Command::Persist(persist) => persist.execute(keyspace),
+            Command::Invalid(_, msg) => Frame::Error(msg),
+            cmd if !cmd.transactional() => Frame::Error(multi::NOT_ALLOWED.to_string()),
+            _ => Frame::Simple("OK".to_string()),
+        }
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        // AUTH 不需要先登录。其他命令执行前先检查当前用户能不能执行它、访问这些 key
+        if !matches!(self, Command::Auth(_)) {
+            let user = ctx.client.user();
+            if let Err(msg) = ctx
+                .state
+                .acl
+                .check(user.as_deref(), self.acl_id(), &self.keys())
+            {
+                // 事务里的命令没有权限的话，EXEC 时放弃整个事务
+                if let Some(transaction) = &mut ctx.transaction {
+                    transaction.abort();
+                }
+                connection
+                    .write_frame(&Frame::Error(msg))
+                    .await
+                    .context(ConnectSnafu)?;
+                return Ok(());
+            }
+        }
+
+        // MULTI 之后除了 EXEC / DISCARD 这些控制事务的命令，都先排队
+        let control = matches!(
+            self,
+            Command::Multi | Command::Exec | Command::Discard | Command::Watch(_)
+        );
+        if let Some(transaction) = ctx.transaction.as_mut().filter(|_| !control) {
+            let response = multi::queue(transaction, self);
+            return reply(connection, response).await;
+        }
+
+        // 占用的内存超过 maxmemory 时先淘汰一些 key，腾不出空间的话拒绝可能增加内存的命令
+        let config = &ctx.state.config;
+        let room = ctx
+            .state
+            .db
+            .evict(
+                config.maxmemory,
+                config.maxmemory_policy,
+                config.maxmemory_samples,
+            )
+            .await;
+        if !room && DENYOOM.contains(&self.acl_id()) {
+            return reply(connection, Frame::Error(OOM.to_string())).await;
+        }
+
+        // 只锁住命令访问的 key 所在的 shard，访问其他 shard 的命令可以同时执行
+        let shards = ctx.state.db.shards(&self.keys());
+
         // Command 自己是一个 enum，对这个 enum 进行 match
         match self {
-            Command::Get(get) => get.apply(cli, connection).await?,
+            Command::Get(get) => get.apply(ctx, connection).await?,
+            Command::Auth(auth) => auth.apply(ctx, connection).await?,
+            Command::Acl(acl) => acl.apply(ctx, connection).await?,
+            Command::Client(client) => client.apply(ctx, connection).await?,
+            Command::Info(info) => info.apply(&ctx.state, connection).await?,
+            Command::Monitor(monitor) => monitor.apply(ctx, connection).await?,
+            Command::Slowlog(slowlog) => slowlog.apply(&ctx.state, connection).await?,
+            Command::MGet(mget) => mget.apply(ctx, connection).await?,
+            Command::HFetch(hfetch) => hfetch.apply(ctx, connection).await?,
+            Command::BlockingPop(pop) => pop.apply(ctx, connection).await?,
+            Command::XRead(read) => read.apply(ctx, connection).await?,
+            Command::Multi => multi::multi(ctx, connection).await?,
+            Command::Exec => multi::exec(ctx, connection).await?,
+            Command::Discard => multi::discard(ctx, connection).await?,
+            Command::Watch(watch) => watch.apply(ctx, connection).await?,
+            Command::Unwatch => multi::unwatch(ctx, connection).await?,
+            Command::Eval(eval) => eval.apply(ctx, connection).await?,
+            Command::Script(script) => script.apply(&ctx.state, connection).await?,
+            cmd @ (Command::Set(_)
+            | Command::String(_)
+            | Command::Hash(_)
+            | Command::List(_)
+            | Command::Sets(_)
+            | Command::SortedSet(_)
+            | Command::Stream(_)
+            | Command::Keys(_)
+            | Command::Expire(_)
+            | Command::Ttl(_)
+            | Command::Persist(_)) => {
+                let response = cmd.execute(&mut *ctx.state.db.lock_shards(&shards).await);
+                reply(connection, response).await?
+            }
+            Command::Invalid(_, msg) => reply(connection, Frame::Error(msg)).await?,
             _ => {
                 // 目前先只实现 Get，其他的命令简单回复简单 string：OK
                 let response = Frame::Simple("OK".to_string());
@@ -146,3 +890,18 @@ impl Command {
         Ok(())
     }
 }
+
+// 把参数检查的结果转换成命令：参数不对的话变成 Invalid，执行时回复错误
+fn parsed<T>(name: &'static str, res: Result<T, String>, f: fn(T) -> Command) -> Command {
+    match res {
+        Ok(cmd) => f(cmd),
+        Err(msg) => Command::Invalid(name, msg),
+    }
+}
+
+async fn reply(connection: &mut Connection, response: Frame) -> Result<()> {
+    connection
+        .write_frame(&response)
+        .await
+        .context(ConnectSnafu)
+}
@@ -0,0 +1,47 @@
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::connection::Connection;
+use crate::frame::Frame;
+
+use super::{ConnectSnafu, Context, Result};
+use snafu::ResultExt;
+
+/// MONITOR：把这个连接变成监视器，实时收到所有客户端执行的命令，
+/// 直到客户端断开、被 kill 或者 server 开始 shutdown
+#[derive(Debug)]
+pub struct Monitor;
+
+impl Monitor {
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        // 先订阅再回复 OK，客户端收到 OK 之后的命令都不会漏掉
+        let mut rx = ctx.state.monitor.subscribe();
+        connection
+            .write_frame(&Frame::Simple("OK".to_string()))
+            .await
+            .context(ConnectSnafu)?;
+
+        loop {
+            tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(line) => connection
+                        .write_frame(&Frame::Simple(line))
+                        .await
+                        .context(ConnectSnafu)?,
+                    // 客户端读得太慢，丢掉了一部分记录
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("the monitor is too slow, {} commands are skipped", n);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                // 和 Redis 一样，监视器发来的命令都忽略掉；读到 None 说明客户端断开了
+                res = connection.read_frame() => {
+                    if res.context(ConnectSnafu)?.is_none() {
+                        return Ok(());
+                    }
+                }
+                _ = ctx.shutdown.recv() => return Ok(()),
+            }
+        }
+    }
+}
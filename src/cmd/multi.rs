@@ -0,0 +1,168 @@
+use bytes::Bytes;
+
+use crate::connection::Connection;
+use crate::frame::Frame;
+
+use super::{reply, wrong_args, Command, Context, Result, DENYOOM, OOM};
+
+pub(super) const NOT_ALLOWED: &str = "ERR Command not allowed inside a transaction";
+
+/// MULTI 之后排队等 EXEC 的命令
+#[derive(Debug, Default)]
+pub struct Transaction {
+    queued: Vec<Command>,
+    // 排队时有命令出错（参数不对、没有权限等），EXEC 时放弃整个事务
+    aborted: bool,
+}
+
+impl Transaction {
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+}
+
+/// WATCH key [key ...]：EXEC 之前这些 key 被别的客户端修改过的话，EXEC 不执行，回复 nil
+#[derive(Debug)]
+pub struct Watch {
+    keys: Vec<Bytes>,
+}
+
+impl Watch {
+    pub fn parse(args: Vec<Bytes>) -> Result<Watch, String> {
+        if args.is_empty() {
+            return Err(wrong_args("watch"));
+        }
+        Ok(Watch { keys: args })
+    }
+
+    pub fn keys(&self) -> Vec<&[u8]> {
+        self.keys.iter().map(|key| key.as_ref()).collect()
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        if ctx.transaction.is_some() {
+            let msg = "ERR WATCH inside MULTI is not allowed".to_string();
+            return reply(connection, Frame::Error(msg)).await;
+        }
+        let watched = ctx.state.db.lock_keys(&self.keys()).await.watch(self.keys);
+        match &mut ctx.watched {
+            Some(all) => all.extend(watched),
+            None => ctx.watched = Some(watched),
+        }
+        reply(connection, Frame::Simple("OK".to_string())).await
+    }
+}
+
+pub async fn multi(ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+    let response = if ctx.transaction.is_some() {
+        Frame::Error("ERR MULTI calls can not be nested".to_string())
+    } else {
+        ctx.transaction = Some(Transaction::default());
+        Frame::Simple("OK".to_string())
+    };
+    reply(connection, response).await
+}
+
+/// MULTI 之后的命令不执行，先排队。在事务里没法执行的命令（要访问上游、要占用连接的）
+/// 和参数不对的命令、不认识的命令一样回复错误，EXEC 时放弃整个事务
+pub fn queue(transaction: &mut Transaction, cmd: Command) -> Frame {
+    match cmd {
+        Command::Invalid(_, msg) => {
+            transaction.abort();
+            Frame::Error(msg)
+        }
+        Command::Unknown(name) => {
+            transaction.abort();
+            Frame::Error(format!("ERR unknown command '{}'", name))
+        }
+        cmd if !cmd.transactional() => {
+            transaction.abort();
+            Frame::Error(NOT_ALLOWED.to_string())
+        }
+        cmd => {
+            transaction.queued.push(cmd);
+            Frame::Simple("QUEUED".to_string())
+        }
+    }
+}
+
+/// EXEC：在同一次加锁里检查 WATCH 的 key、执行所有排队的命令，回复每条命令的结果。
+/// 不管执行了没有，之后都不再 WATCH 这些 key
+pub async fn exec(ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+    let watched = ctx.watched.take();
+    let transaction = match ctx.transaction.take() {
+        Some(transaction) => transaction,
+        None => {
+            let msg = "ERR EXEC without MULTI".to_string();
+            return reply(connection, Frame::Error(msg)).await;
+        }
+    };
+    if transaction.aborted {
+        let msg = "EXECABORT Transaction discarded because of previous errors.".to_string();
+        return reply(connection, Frame::Error(msg)).await;
+    }
+
+    let config = &ctx.state.config;
+    let room = ctx
+        .state
+        .db
+        .evict(
+            config.maxmemory,
+            config.maxmemory_policy,
+            config.maxmemory_samples,
+        )
+        .await;
+
+    let response = {
+        // 锁住所有排队的命令和 WATCH 访问的 key。有 DBSIZE 这样访问所有 key 的命令时锁住所有的 shard
+        let mut keyspace = {
+            let mut keys = watched.as_ref().map(|w| w.keys()).unwrap_or_default();
+            let mut all = false;
+            for cmd in &transaction.queued {
+                let cmd_keys = cmd.keys();
+                all |= cmd_keys.is_empty();
+                keys.extend(cmd_keys);
+            }
+            if all {
+                keys.clear();
+            }
+            ctx.state.db.lock_keys(&keys).await
+        };
+
+        if watched.as_ref().is_some_and(|w| w.changed(&mut keyspace)) {
+            Frame::Null
+        } else {
+            let replies = transaction
+                .queued
+                .into_iter()
+                .map(|cmd| {
+                    if !room && DENYOOM.contains(&cmd.acl_id()) {
+                        Frame::Error(OOM.to_string())
+                    } else {
+                        cmd.execute(&mut keyspace)
+                    }
+                })
+                .collect();
+            Frame::Array(replies)
+        }
+    };
+    drop(watched);
+
+    reply(connection, response).await
+}
+
+pub async fn discard(ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+    let response = match ctx.transaction.take() {
+        Some(_) => {
+            ctx.watched = None;
+            Frame::Simple("OK".to_string())
+        }
+        None => Frame::Error("ERR DISCARD without MULTI".to_string()),
+    };
+    reply(connection, response).await
+}
+
+pub async fn unwatch(ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+    ctx.watched = None;
+    reply(connection, Frame::Simple("OK".to_string())).await
+}
@@ -0,0 +1,156 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use bytes::Bytes;
+
+use crate::frame::Frame;
+use crate::glob;
+
+use super::{int_arg, SYNTAX_ERROR};
+
+// 不写 COUNT 时每次最多返回多少个
+const DEFAULT_COUNT: usize = 10;
+
+/// SCAN 系列命令共同的参数：`cursor [MATCH pattern] [COUNT count]`。
+///
+/// 每个元素按名字的 hash 值排序，游标是下一次从哪个 hash 值开始。
+/// 所以两次调用之间增删了元素也没关系：从头到尾一直存在的元素一定会返回，而且只返回一次
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanArgs {
+    pub cursor: u64,
+    pub pattern: Option<Bytes>,
+    pub count: usize,
+}
+
+impl ScanArgs {
+    pub fn parse(cursor: &[u8], args: impl IntoIterator<Item = Bytes>) -> Result<ScanArgs, String> {
+        let cursor = std::str::from_utf8(cursor)
+            .ok()
+            .and_then(|cursor| cursor.parse().ok())
+            .ok_or_else(|| "ERR invalid cursor".to_string())?;
+        let mut scan = ScanArgs {
+            cursor,
+            pattern: None,
+            count: DEFAULT_COUNT,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let option = String::from_utf8_lossy(&arg).to_lowercase();
+            let value = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+            match option.as_str() {
+                "match" => scan.pattern = Some(value),
+                "count" => {
+                    scan.count = usize::try_from(int_arg(&value)?)
+                        .ok()
+                        .filter(|count| *count > 0)
+                        .ok_or_else(|| SYNTAX_ERROR.to_string())?;
+                }
+                _ => return Err(SYNTAX_ERROR.to_string()),
+            }
+        }
+        Ok(scan)
+    }
+
+    /// 从 `items`（名字和要返回的内容）里取出游标之后的一页，返回下一次的游标（0 表示结束）。
+    /// 和 Redis 一样，先取 COUNT 个再按 MATCH 过滤，所以一页可能不满，甚至是空的
+    pub fn page<'a, T>(&self, items: impl Iterator<Item = (&'a [u8], T)>) -> (u64, Vec<T>) {
+        let mut candidates: Vec<(u64, &[u8], T)> = items
+            .map(|(name, item)| (position(name), name, item))
+            .filter(|(pos, _, _)| *pos >= self.cursor)
+            .collect();
+
+        let mut next = 0;
+        if candidates.len() > self.count {
+            candidates.select_nth_unstable_by_key(self.count - 1, |(pos, _, _)| *pos);
+            // hash 值相同的元素放在同一页，游标不会把它们分开
+            let last = candidates[self.count - 1].0;
+            if candidates[self.count..]
+                .iter()
+                .any(|(pos, _, _)| *pos > last)
+            {
+                next = last + 1;
+            }
+            candidates.retain(|(pos, _, _)| *pos <= last);
+        }
+
+        let page = candidates
+            .into_iter()
+            .filter(|(_, name, _)| match &self.pattern {
+                Some(pattern) => glob::matches(pattern, name),
+                None => true,
+            })
+            .map(|(_, _, item)| item)
+            .collect();
+        (next, page)
+    }
+}
+
+/// SCAN 系列命令的回复：下一次的游标和这一页的元素
+pub fn reply(cursor: u64, items: Vec<Frame>) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(cursor.to_string())),
+        Frame::Array(items),
+    ])
+}
+
+// 元素在遍历顺序中的位置。DefaultHasher::new() 的 key 是固定的，同一个进程里结果不变
+fn position(name: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(args: &[&str]) -> ScanArgs {
+        let args: Vec<Bytes> = args.iter().map(|a| Bytes::from(a.to_string())).collect();
+        ScanArgs::parse(&args[0], args[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn ts_scan_is_stable() {
+        let names: Vec<String> = (0..100).map(|i| format!("k{}", i)).collect();
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            // 每一页之间删掉一个还没返回的元素，其他的元素仍然各返回一次
+            let live = names.iter().filter(|name| *name != "k50" || cursor == 0);
+            let items = live.map(|name| (name.as_bytes(), name.clone()));
+            let args = scan(&[&cursor.to_string(), "count", "7"]);
+            let (next, page) = args.page(items);
+            assert!(page.len() <= 7);
+            seen.extend(page);
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        // 没有重复的
+        let total = seen.len();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), total);
+        assert!(names
+            .iter()
+            .filter(|n| *n != "k50")
+            .all(|n| seen.contains(n)));
+
+        let args = scan(&["0", "match", "k1?", "count", "1000"]);
+        let (next, page) = args.page(names.iter().map(|n| (n.as_bytes(), n)));
+        assert_eq!((next, page.len()), (0, 10));
+
+        let args: Vec<Bytes> = vec![Bytes::from("x")];
+        assert!(ScanArgs::parse(&args[0], vec![]).is_err());
+        let args: Vec<Bytes> = ["0", "count", "0"]
+            .iter()
+            .map(|a| Bytes::from(*a))
+            .collect();
+        assert_eq!(
+            ScanArgs::parse(&args[0], args[1..].to_vec()),
+            Err(SYNTAX_ERROR.to_string())
+        );
+    }
+}
@@ -0,0 +1,702 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self as sync_mpsc, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use mlua::{ChunkMode, HookTriggers, Lua, LuaOptions, StdLib, Table, Value as LuaValue, Variadic};
+use snafu::ResultExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::connection::Connection;
+use crate::db::{self, KeyspaceGuard};
+use crate::frame::Frame;
+use crate::parser;
+use crate::state::State;
+
+use super::{
+    append_upstream, fetch, int_arg, parse_origin, reply, rest, upstream_reply, wrong_args,
+    Command, CommandSnafu, Context, Result, DENYOOM, OOM,
+};
+
+// 每个脚本的 Lua 虚拟机最多使用这么多内存
+const SCRIPT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+// 每执行这么多条 Lua 指令检查一次有没有超时
+const HOOK_INSTRUCTIONS: u32 = 10_000;
+
+// 脚本的函数都定义好之后执行：提供 redis.error_reply / status_reply，
+// 禁止脚本创建全局变量或者访问不存在的全局变量，和 Redis 一样
+const PRELUDE: &str = r#"
+redis.error_reply = function(msg) return {err = msg} end
+redis.status_reply = function(msg) return {ok = msg} end
+setmetatable(_G, {
+    __newindex = function(_, name)
+        error("Script attempted to create global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __index = function(_, name)
+        error("Script attempted to access nonexistent global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __metatable = false,
+})
+"#;
+
+// 沙箱里去掉的函数：可以读文件、加载字节码或者绕过全局变量保护的
+const REMOVED_GLOBALS: &[&str] = &[
+    "dofile",
+    "loadfile",
+    "load",
+    "loadstring",
+    "getfenv",
+    "setfenv",
+    "newproxy",
+    "print",
+];
+
+/// EVAL script numkeys [key ...] [arg ...] / EVALSHA sha1 numkeys [key ...] [arg ...]
+///
+/// 脚本在一个只有 base / table / string / math 库的 Lua 虚拟机里执行，可以用 `redis.call` /
+/// `redis.pcall` 执行命令，用 `http.get(url)` 访问上游。执行期间一直锁住 KEYS 所在的 shard
+/// （没有声明 key 的话锁住所有 shard），脚本里的命令和上游请求对别的客户端是原子的
+#[derive(Debug)]
+pub struct Eval {
+    name: &'static str,
+    script: Source,
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+}
+
+#[derive(Debug)]
+enum Source {
+    Body(Bytes),
+    Sha(String),
+}
+
+impl Eval {
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<Eval, String> {
+        if args.len() < 2 {
+            return Err(wrong_args(name));
+        }
+        let mut args = args.into_iter();
+        let script = args.next().unwrap_or_default();
+        let numkeys = int_arg(&args.next().unwrap_or_default())?;
+        let mut args: Vec<Bytes> = args.collect();
+        if numkeys < 0 {
+            return Err("ERR Number of keys can't be negative".to_string());
+        }
+        if numkeys as usize > args.len() {
+            return Err("ERR Number of keys can't be greater than number of args".to_string());
+        }
+        let rest = args.split_off(numkeys as usize);
+
+        let script = if name == "evalsha" {
+            Source::Sha(String::from_utf8_lossy(&script).into_owned())
+        } else {
+            Source::Body(script)
+        };
+        Ok(Eval {
+            name,
+            script,
+            keys: args,
+            args: rest,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// 脚本声明的 KEYS
+    pub fn keys(&self) -> Vec<&[u8]> {
+        self.keys.iter().map(|key| key.as_ref()).collect()
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        let (body, cache) = match self.script {
+            Source::Body(body) => (body, true),
+            Source::Sha(sha) => match ctx.state.scripts.get(&sha) {
+                Some(body) => (body, false),
+                None => {
+                    let msg = "NOSCRIPT No matching script. Please use EVAL.".to_string();
+                    return reply(connection, Frame::Error(msg)).await;
+                }
+            },
+        };
+
+        let config = &ctx.state.config;
+        let room = ctx
+            .state
+            .db
+            .evict(
+                config.maxmemory,
+                config.maxmemory_policy,
+                config.maxmemory_samples,
+            )
+            .await;
+
+        // 脚本在 spawn_blocking 的线程上执行，不占用 tokio 的线程。
+        // http.get 通过 channel 让这里访问上游，和 GET 一样经过限流、记到 slowlog 里
+        let (fetch_tx, mut fetch_rx) = mpsc::unbounded_channel();
+        // 连接被关闭（CLIENT KILL、shutdown 超时）时这个任务被 drop，通知脚本停下来
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel = Cancel(cancelled.clone());
+        let job = Job {
+            body: body.clone(),
+            keys: self.keys,
+            args: self.args,
+            state: ctx.state.clone(),
+            user: ctx.client.user(),
+            room,
+            fetch: fetch_tx,
+            cancelled,
+        };
+        let mut task = tokio::task::spawn_blocking(move || job.run());
+
+        let res = loop {
+            tokio::select! {
+                res = &mut task => break res,
+                Some(Fetch { url, reply }) = fetch_rx.recv() => {
+                    let (upstream, res) = fetch(&url, ctx).await;
+                    if upstream.is_some() {
+                        ctx.upstream = upstream;
+                    }
+                    // 脚本超时的话已经不等了，发送失败也没关系
+                    let _ = reply.send(res.map_err(|err| upstream_reply(Err(err))));
+                }
+            }
+        };
+
+        let response = match res {
+            Ok(Ok(response)) => {
+                if cache {
+                    ctx.state.scripts.load(body);
+                }
+                response
+            }
+            Ok(Err(msg)) => Frame::Error(msg),
+            Err(err) => {
+                warn!("script task failed: {}", err);
+                Frame::Error("ERR Error running script".to_string())
+            }
+        };
+        reply(connection, response).await
+    }
+}
+
+/// SCRIPT LOAD script / EXISTS sha1 [sha1 ...] / FLUSH [ASYNC|SYNC]
+#[derive(Debug)]
+pub enum ScriptCommand {
+    Load(Bytes),
+    Exists(Vec<String>),
+    Flush,
+    // 子命令或者参数不对，直接回复这个错误
+    Invalid(String),
+}
+
+impl ScriptCommand {
+    pub fn parse_frame(parser: &mut parser::Parser) -> Result<ScriptCommand> {
+        let sub = parser.next_string().context(CommandSnafu)?.to_lowercase();
+        let args = rest(parser)?;
+
+        let cmd = match (sub.as_str(), args.as_slice()) {
+            ("load", [body]) => ScriptCommand::Load(body.clone()),
+            ("exists", shas) if !shas.is_empty() => ScriptCommand::Exists(
+                shas.iter()
+                    .map(|sha| String::from_utf8_lossy(sha).into_owned())
+                    .collect(),
+            ),
+            ("flush", []) => ScriptCommand::Flush,
+            // 脚本缓存只是一个 HashMap，ASYNC 和 SYNC 没有区别
+            ("flush", [mode])
+                if mode.eq_ignore_ascii_case(b"async") || mode.eq_ignore_ascii_case(b"sync") =>
+            {
+                ScriptCommand::Flush
+            }
+            ("load" | "exists" | "flush", _) => ScriptCommand::Invalid(format!(
+                "ERR wrong number of arguments for 'script|{}' command",
+                sub
+            )),
+            _ => ScriptCommand::Invalid(format!(
+                "ERR unknown subcommand '{}'. Try SCRIPT HELP.",
+                sub
+            )),
+        };
+
+        Ok(cmd)
+    }
+
+    /// 检查权限时用的命令 id
+    pub fn acl_id(&self) -> &'static str {
+        match self {
+            ScriptCommand::Load(_) => "script|load",
+            ScriptCommand::Exists(_) => "script|exists",
+            ScriptCommand::Flush => "script|flush",
+            ScriptCommand::Invalid(_) => "script",
+        }
+    }
+
+    pub async fn apply(self, state: &State, connection: &mut Connection) -> Result<()> {
+        let response = match self {
+            // 和 Redis 一样，编译不过的脚本不保存
+            ScriptCommand::Load(body) => match sandbox().and_then(|lua| compile(&lua, &body)) {
+                Ok(()) => Frame::Bulk(Bytes::from(state.scripts.load(body))),
+                Err(err) => Frame::Error(error_reply(&err)),
+            },
+            ScriptCommand::Exists(shas) => Frame::Array(
+                shas.iter()
+                    .map(|sha| Frame::Integer(state.scripts.exists(sha) as i64))
+                    .collect(),
+            ),
+            ScriptCommand::Flush => {
+                state.scripts.flush();
+                Frame::Simple("OK".to_string())
+            }
+            ScriptCommand::Invalid(msg) => Frame::Error(msg),
+        };
+        reply(connection, response).await
+    }
+}
+
+// 脚本里的 http.get 请求连接的任务访问上游，等它回复状态码和内容
+struct Fetch {
+    url: String,
+    reply: sync_mpsc::SyncSender<Result<(u16, String), Frame>>,
+}
+
+type FetchReceiver = sync_mpsc::Receiver<Result<(u16, String), Frame>>;
+
+// drop 时告诉脚本执行它的连接已经没有了
+struct Cancel(Arc<AtomicBool>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// 在 spawn_blocking 的线程上执行一个脚本需要的所有东西
+struct Job {
+    body: Bytes,
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    state: Arc<State>,
+    user: Option<String>,
+    // maxmemory 还有没有空间，没有的话脚本里可能增加内存的命令回复 OOM
+    room: bool,
+    fetch: mpsc::UnboundedSender<Fetch>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Job {
+    // 执行脚本，返回脚本的结果。脚本编译不过或者执行出错时返回错误回复
+    fn run(self) -> Result<Frame, String> {
+        let config = &self.state.config;
+        let deadline = (config.lua_time_limit > 0)
+            .then(|| Instant::now().checked_add(Duration::from_millis(config.lua_time_limit)))
+            .flatten();
+
+        // 声明了 KEYS 的脚本只锁住这些 key 和 upstream-stream 所在的 shard
+        let mut locked = self
+            .keys
+            .iter()
+            .map(|key| key.as_ref())
+            .collect::<Vec<&[u8]>>();
+        if let (false, Some(stream)) = (locked.is_empty(), &config.upstream_stream) {
+            locked.push(stream.as_bytes());
+        }
+        // 在 spawn_blocking 的线程上，可以阻塞着等锁。别的客户端异步地等这些锁，
+        // 脚本等上游回复的时候 tokio 的 worker 照样能执行访问上游的任务
+        let bridge = Bridge {
+            keyspace: RefCell::new(self.state.db.blocking_lock_keys(&locked)),
+            state: &self.state,
+            user: self.user.as_deref(),
+            room: self.room,
+            deadline,
+            fetch: &self.fetch,
+        };
+
+        let lua = sandbox().map_err(|err| error_reply(&err))?;
+        let limit = config.lua_time_limit;
+        let cancelled = self.cancelled.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| {
+                if cancelled.load(Ordering::Relaxed) {
+                    Err(mlua::Error::external(Raised(
+                        "ERR Script killed because the client is closed".to_string(),
+                    )))
+                } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    Err(timed_out(limit))
+                } else {
+                    Ok(())
+                }
+            },
+        );
+
+        let res = lua.scope(|scope| {
+            let globals = lua.globals();
+            globals.raw_set("KEYS", strings(&lua, &self.keys)?)?;
+            globals.raw_set("ARGV", strings(&lua, &self.args)?)?;
+
+            let redis = lua.create_table()?;
+            redis.raw_set(
+                "call",
+                scope.create_function(|lua, args: Variadic<LuaValue>| {
+                    raise(lua, bridge.call(&args))
+                })?,
+            )?;
+            redis.raw_set(
+                "pcall",
+                scope.create_function(|lua, args: Variadic<LuaValue>| {
+                    to_lua(lua, bridge.call(&args))
+                })?,
+            )?;
+            globals.raw_set("redis", redis)?;
+
+            let http = lua.create_table()?;
+            http.raw_set(
+                "get",
+                scope.create_function(|lua, url: mlua::String| {
+                    raise(lua, bridge.http_get(&url.to_string_lossy())?)
+                })?,
+            )?;
+            globals.raw_set("http", http)?;
+
+            lua.load(PRELUDE).set_name("@prelude").exec()?;
+            lua.load(self.body.as_ref())
+                .set_name("@user_script")
+                .set_mode(ChunkMode::Text)
+                .eval::<LuaValue>()
+                .map(from_lua)
+        });
+
+        res.map_err(|err| error_reply(&err))
+    }
+}
+
+// 脚本访问 keyspace 和上游的入口，redis.call / redis.pcall / http.get 共用
+struct Bridge<'a> {
+    keyspace: RefCell<KeyspaceGuard<'a>>,
+    state: &'a State,
+    user: Option<&'a str>,
+    room: bool,
+    deadline: Option<Instant>,
+    fetch: &'a mpsc::UnboundedSender<Fetch>,
+}
+
+impl Bridge<'_> {
+    // 在脚本锁住的 keyspace 上执行一条命令。和 MULTI 里一样，GET 只读 keyspace 不访问上游，
+    // 阻塞命令不阻塞。命令出错时返回错误回复
+    fn call(&self, args: &[LuaValue]) -> Frame {
+        let mut parts = Vec::with_capacity(args.len());
+        for arg in args {
+            match arg {
+                LuaValue::String(s) => {
+                    parts.push(Frame::Bulk(Bytes::copy_from_slice(s.as_bytes())))
+                }
+                LuaValue::Integer(n) => parts.push(Frame::Bulk(Bytes::from(n.to_string()))),
+                LuaValue::Number(n) => parts.push(Frame::Bulk(Bytes::from(n.to_string()))),
+                _ => {
+                    return Frame::Error(
+                        "ERR Lua redis lib command arguments must be strings or integers"
+                            .to_string(),
+                    )
+                }
+            }
+        }
+        let name = match parts.first() {
+            Some(Frame::Bulk(name)) => String::from_utf8_lossy(name).to_lowercase(),
+            _ => {
+                return Frame::Error(
+                    "ERR Please specify at least one argument for this redis lib call".to_string(),
+                )
+            }
+        };
+
+        let cmd = match Command::from_frame(Frame::Array(parts)) {
+            Ok(Command::Unknown(_)) => {
+                return Frame::Error("ERR Unknown Redis command called from script".to_string())
+            }
+            Ok(cmd) => cmd,
+            Err(_) => return Frame::Error(wrong_args(&name)),
+        };
+        if !cmd.transactional() {
+            return Frame::Error("ERR This Redis command is not allowed from script".to_string());
+        }
+        if let Err(msg) = self.state.acl.check(self.user, cmd.acl_id(), &cmd.keys()) {
+            return Frame::Error(msg);
+        }
+        if !self.room && DENYOOM.contains(&cmd.acl_id()) {
+            return Frame::Error(OOM.to_string());
+        }
+
+        // 只能访问锁住了的 shard。DBSIZE、SCAN 这样不带 key 的命令需要锁住所有 shard
+        let mut keyspace = self.keyspace.borrow_mut();
+        let keys = cmd.keys();
+        let locked = if keys.is_empty() {
+            keyspace.all_locked()
+        } else {
+            keys.iter().all(|key| keyspace.is_locked(key))
+        };
+        if !locked {
+            return Frame::Error(
+                "ERR Script attempted to access a key that was not declared in KEYS".to_string(),
+            );
+        }
+        cmd.execute(&mut keyspace)
+    }
+
+    // http.get(url)：返回值和 GET url 一样。上游的回复记录到 upstream-stream 里
+    fn http_get(&self, url: &str) -> mlua::Result<Frame> {
+        if !db::is_url(url.as_bytes()) {
+            return Ok(Frame::Error(
+                "ERR http.get needs an http(s) url".to_string(),
+            ));
+        }
+        if let Err(msg) = self.state.acl.check(self.user, "get", &[url.as_bytes()]) {
+            return Ok(Frame::Error(msg));
+        }
+
+        let (tx, rx) = sync_mpsc::sync_channel(1);
+        let fetch = Fetch {
+            url: url.to_string(),
+            reply: tx,
+        };
+        if self.fetch.send(fetch).is_err() {
+            return Ok(Frame::Error("ERR Error running script".to_string()));
+        }
+
+        // 等上游的时候不放开锁，上游的回复和脚本里的写命令对别的客户端是一起发生的
+        Ok(match self.wait(rx)? {
+            Ok((status, body)) => {
+                if self.room {
+                    let mut keyspace = self.keyspace.borrow_mut();
+                    append_upstream(&mut keyspace, &self.state.config, url, status, &body);
+                }
+                upstream_reply(parse_origin(&body))
+            }
+            Err(response) => response,
+        })
+    }
+
+    // 等连接的任务回复上游的结果，最多等到脚本超时
+    fn wait(&self, rx: FetchReceiver) -> mlua::Result<Result<(u16, String), Frame>> {
+        match self.deadline {
+            Some(deadline) => rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|err| match err {
+                    RecvTimeoutError::Timeout => timed_out(self.state.config.lua_time_limit),
+                    RecvTimeoutError::Disconnected => {
+                        mlua::Error::external(Raised("ERR Error running script".to_string()))
+                    }
+                }),
+            None => rx
+                .recv()
+                .map_err(|_| mlua::Error::external(Raised("ERR Error running script".to_string()))),
+        }
+    }
+}
+
+// redis.call 收到的错误回复和脚本超时作为 Lua 的错误抛出，EVAL 原样回复给客户端
+#[derive(Debug)]
+struct Raised(String);
+
+impl fmt::Display for Raised {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Raised {}
+
+fn timed_out(limit: u64) -> mlua::Error {
+    mlua::Error::external(Raised(format!(
+        "ERR Script killed after running for more than lua-time-limit ({}ms)",
+        limit
+    )))
+}
+
+// 创建一个沙箱：只加载 base / table / string / math 库，去掉能读文件、加载字节码的函数
+fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::new(),
+    )?;
+    lua.set_memory_limit(SCRIPT_MEMORY_LIMIT)?;
+    {
+        let globals = lua.globals();
+        for name in REMOVED_GLOBALS {
+            globals.raw_set(*name, LuaValue::Nil)?;
+        }
+    }
+    Ok(lua)
+}
+
+// 只编译不执行。不接受预编译的字节码
+fn compile(lua: &Lua, body: &[u8]) -> mlua::Result<()> {
+    lua.load(body)
+        .set_name("@user_script")
+        .set_mode(ChunkMode::Text)
+        .into_function()
+        .map(|_| ())
+}
+
+fn strings<'lua>(lua: &'lua Lua, items: &[Bytes]) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    for (i, item) in items.iter().enumerate() {
+        table.raw_set(i + 1, lua.create_string(item)?)?;
+    }
+    Ok(table)
+}
+
+// 错误回复作为 Lua 的错误抛出，其他的回复转换成 Lua 的值
+fn raise(lua: &Lua, frame: Frame) -> mlua::Result<LuaValue<'_>> {
+    match frame {
+        Frame::Error(msg) => Err(mlua::Error::external(Raised(msg))),
+        frame => to_lua(lua, frame),
+    }
+}
+
+// 和 Redis 一样：status 回复是 {ok = ...}，错误回复是 {err = ...}，nil 是 false
+fn to_lua(lua: &Lua, frame: Frame) -> mlua::Result<LuaValue<'_>> {
+    Ok(match frame {
+        Frame::Simple(s) => {
+            let table = lua.create_table()?;
+            table.raw_set("ok", s)?;
+            LuaValue::Table(table)
+        }
+        Frame::Error(msg) => {
+            let table = lua.create_table()?;
+            table.raw_set("err", msg)?;
+            LuaValue::Table(table)
+        }
+        Frame::Integer(n) => LuaValue::Integer(n as mlua::Integer),
+        Frame::Bulk(b) => LuaValue::String(lua.create_string(&b)?),
+        Frame::Null => LuaValue::Boolean(false),
+        Frame::Array(frames) => {
+            let table = lua.create_table()?;
+            for (i, frame) in frames.into_iter().enumerate() {
+                table.raw_set(i + 1, to_lua(lua, frame)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    })
+}
+
+// 脚本的返回值转换成回复。数字截断成整数，true 是 1，false 和 nil 是 nil，
+// 数组到第一个 nil 为止
+fn from_lua(value: LuaValue) -> Frame {
+    match value {
+        LuaValue::Boolean(true) => Frame::Integer(1),
+        LuaValue::Integer(n) => Frame::Integer(n),
+        LuaValue::Number(n) => Frame::Integer(n as i64),
+        LuaValue::String(s) => Frame::Bulk(Bytes::copy_from_slice(s.as_bytes())),
+        LuaValue::Table(table) => {
+            if let Ok(LuaValue::String(msg)) = table.raw_get("err") {
+                return Frame::Error(one_line(&msg.to_string_lossy()));
+            }
+            if let Ok(LuaValue::String(msg)) = table.raw_get("ok") {
+                return Frame::Simple(one_line(&msg.to_string_lossy()));
+            }
+            let mut frames = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, LuaValue>(i) {
+                    Ok(LuaValue::Nil) | Err(_) => break,
+                    Ok(value) => frames.push(from_lua(value)),
+                }
+            }
+            Frame::Array(frames)
+        }
+        _ => Frame::Null,
+    }
+}
+
+// 脚本出错时的回复。redis.call 抛出的错误回复和超时原样回复，其他的错误只保留第一行，
+// Lua 的 traceback 不回复给客户端
+fn error_reply(err: &mlua::Error) -> String {
+    if let Some(Raised(msg)) = raised(err) {
+        return one_line(msg);
+    }
+    match err {
+        mlua::Error::SyntaxError { message, .. } => {
+            format!("ERR Error compiling script: {}", one_line(message))
+        }
+        mlua::Error::RuntimeError(msg) => format!("ERR Error running script: {}", one_line(msg)),
+        mlua::Error::MemoryError(_) => "ERR Error running script: out of memory".to_string(),
+        err => format!("ERR Error running script: {}", one_line(&err.to_string())),
+    }
+}
+
+fn raised(err: &mlua::Error) -> Option<&Raised> {
+    match err {
+        mlua::Error::CallbackError { cause, .. } | mlua::Error::WithContext { cause, .. } => {
+            raised(cause)
+        }
+        err => err.downcast_ref::<Raised>(),
+    }
+}
+
+// 回复里不能有换行
+fn one_line(msg: &str) -> String {
+    msg.lines().next().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ts_parse_eval() {
+        let args = |args: &[&str]| {
+            args.iter()
+                .map(|arg| Bytes::from(arg.to_string()))
+                .collect()
+        };
+
+        let eval = Eval::parse("eval", args(&["return 1", "2", "a", "b", "c"])).unwrap();
+        assert_eq!(eval.keys(), vec![b"a".as_ref(), b"b".as_ref()]);
+        assert_eq!(eval.args, vec![Bytes::from("c")]);
+
+        assert!(Eval::parse("eval", args(&["return 1"])).is_err());
+        assert!(Eval::parse("eval", args(&["return 1", "-1"])).is_err());
+        assert!(Eval::parse("eval", args(&["return 1", "2", "a"])).is_err());
+        assert!(Eval::parse("evalsha", args(&["abc", "x"])).is_err());
+    }
+
+    #[test]
+    fn ts_sandbox() {
+        let lua = sandbox().unwrap();
+        for name in [
+            "os",
+            "io",
+            "debug",
+            "package",
+            "require",
+            "loadstring",
+            "dofile",
+        ] {
+            let value: LuaValue = lua.globals().get(name).unwrap();
+            assert!(value.is_nil(), "{} is loaded", name);
+        }
+
+        let value = lua
+            .load("return {1, 'two', {ok = 'OK'}, nil, 4}")
+            .eval()
+            .unwrap();
+        assert_eq!(
+            from_lua(value),
+            Frame::Array(vec![
+                Frame::Integer(1),
+                Frame::Bulk(Bytes::from("two")),
+                Frame::Simple("OK".to_string()),
+            ])
+        );
+
+        let err = lua.load("error('boom')").exec().unwrap_err();
+        assert!(error_reply(&err).starts_with("ERR Error running script: "));
+        assert!(!error_reply(&err).contains('\n'));
+    }
+}
@@ -0,0 +1,163 @@
+use bytes::Bytes;
+
+use crate::db::{Keyspace, Value, WRONGTYPE};
+use crate::frame::Frame;
+
+use super::expire::Deadline;
+use super::{wrong_args, SYNTAX_ERROR};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    /// 只在 key 不存在时写入
+    Nx,
+    /// 只在 key 已经存在时写入
+    Xx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ttl {
+    /// 不过期（会去掉原来的过期时间）
+    Never,
+    /// KEEPTTL：保留原来的过期时间
+    Keep,
+    Deadline(Deadline),
+}
+
+/// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+/// EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+#[derive(Debug)]
+pub struct Set {
+    pub(crate) key: Bytes,
+    value: Bytes,
+    condition: Option<Condition>,
+    ttl: Ttl,
+    get: bool,
+}
+
+impl Set {
+    pub fn parse(args: Vec<Bytes>) -> Result<Set, String> {
+        let mut args = args.into_iter();
+        let (key, value) = match (args.next(), args.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(wrong_args("set")),
+        };
+
+        let mut set = Set {
+            key,
+            value,
+            condition: None,
+            ttl: Ttl::Never,
+            get: false,
+        };
+        while let Some(arg) = args.next() {
+            let option = String::from_utf8_lossy(&arg).to_lowercase();
+            match option.as_str() {
+                "nx" | "xx" if set.condition.is_none() => {
+                    set.condition = Some(if option == "nx" {
+                        Condition::Nx
+                    } else {
+                        Condition::Xx
+                    });
+                }
+                "get" => set.get = true,
+                "keepttl" if set.ttl == Ttl::Never => set.ttl = Ttl::Keep,
+                "ex" | "px" | "exat" | "pxat" if set.ttl == Ttl::Never => {
+                    let value = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+                    set.ttl = Ttl::Deadline(Deadline::parse_option(&option, &value, "set")?);
+                }
+                _ => return Err(SYNTAX_ERROR.to_string()),
+            }
+        }
+
+        Ok(set)
+    }
+
+    /// 没有写入时回复 nil；带了 GET 的话回复原来的值
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        let (exists, old) = match keyspace.peek(&self.key) {
+            Some(value) => match (self.get, value.as_string()) {
+                (true, Some(old)) => (true, Some(old.clone())),
+                (true, None) => return Frame::Error(WRONGTYPE.to_string()),
+                (false, _) => (true, None),
+            },
+            None => (false, None),
+        };
+
+        let allowed = match self.condition {
+            None => true,
+            Some(Condition::Nx) => !exists,
+            Some(Condition::Xx) => exists,
+        };
+        if allowed {
+            let expires_at = match self.ttl {
+                Ttl::Never => None,
+                Ttl::Keep => keyspace.expires_at(&self.key).flatten(),
+                Ttl::Deadline(deadline) => match deadline.instant() {
+                    Some(when) => Some(when),
+                    // 过期时间已经过去了（EXAT / PXAT），相当于写入后马上过期
+                    None => {
+                        keyspace.remove(&self.key);
+                        return reply(self.get, allowed, old);
+                    }
+                },
+            };
+            keyspace.insert(self.key, Value::String(self.value), expires_at);
+        }
+
+        reply(self.get, allowed, old)
+    }
+}
+
+fn reply(get: bool, written: bool, old: Option<Bytes>) -> Frame {
+    match (get, written, old) {
+        (true, _, Some(old)) => Frame::Bulk(old),
+        (true, _, None) | (false, false, _) => Frame::Null,
+        (false, true, _) => Frame::Simple("OK".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    fn set(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let args = args.iter().map(|a| Bytes::from(a.to_string())).collect();
+        Set::parse(args).unwrap().execute(keyspace)
+    }
+
+    #[test]
+    fn ts_set_options() {
+        let db = Db::new();
+        let mut keyspace = db.blocking_lock();
+
+        assert!(matches!(set(&mut keyspace, &["k", "1", "xx"]), Frame::Null));
+        assert!(matches!(
+            set(&mut keyspace, &["k", "1", "nx"]),
+            Frame::Simple(_)
+        ));
+        assert!(matches!(set(&mut keyspace, &["k", "2", "nx"]), Frame::Null));
+        assert!(matches!(
+            set(&mut keyspace, &["k", "2", "get", "ex", "100"]),
+            Frame::Bulk(old) if old == "1"
+        ));
+        assert!(keyspace.expires_at(b"k").unwrap().is_some());
+
+        // KEEPTTL 保留过期时间，不带的话去掉过期时间
+        set(&mut keyspace, &["k", "3", "keepttl"]);
+        assert!(keyspace.expires_at(b"k").unwrap().is_some());
+        set(&mut keyspace, &["k", "4"]);
+        assert_eq!(keyspace.expires_at(b"k"), Some(None));
+
+        set(&mut keyspace, &["k", "5", "pxat", "1"]);
+        assert!(!keyspace.contains(b"k"));
+
+        let parse =
+            |args: &[&str]| Set::parse(args.iter().map(|a| Bytes::from(a.to_string())).collect());
+        assert!(parse(&["k"]).is_err());
+        assert!(parse(&["k", "v", "nx", "xx"]).is_err());
+        assert!(parse(&["k", "v", "ex", "10", "keepttl"]).is_err());
+        assert!(parse(&["k", "v", "ex", "0"]).is_err());
+        assert!(parse(&["k", "v", "ex"]).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use crate::db::{self, Keyspace, Value, WRONGTYPE};
+use crate::frame::Frame;
+
+use super::{exact, int_arg, positive, wrong_args};
+
+/// SINTER / SUNION / SDIFF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// set 的命令
+#[derive(Debug)]
+pub enum SetCommand {
+    Add { key: Bytes, members: Vec<Bytes> },
+    Rem { key: Bytes, members: Vec<Bytes> },
+    Members { key: Bytes },
+    IsMember { key: Bytes, member: Bytes },
+    Card { key: Bytes },
+    Combine { op: SetOp, keys: Vec<Bytes> },
+    RandMember { key: Bytes, count: Option<i64> },
+    Pop { key: Bytes, count: Option<usize> },
+}
+
+impl SetCommand {
+    /// 这一组命令的命令名
+    pub const NAMES: &'static [&'static str] = &[
+        "sadd",
+        "scard",
+        "sdiff",
+        "sinter",
+        "sismember",
+        "smembers",
+        "spop",
+        "srandmember",
+        "srem",
+        "sunion",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SetCommand::Add { .. } => "sadd",
+            SetCommand::Rem { .. } => "srem",
+            SetCommand::Members { .. } => "smembers",
+            SetCommand::IsMember { .. } => "sismember",
+            SetCommand::Card { .. } => "scard",
+            SetCommand::Combine { op, .. } => match op {
+                SetOp::Inter => "sinter",
+                SetOp::Union => "sunion",
+                SetOp::Diff => "sdiff",
+            },
+            SetCommand::RandMember { .. } => "srandmember",
+            SetCommand::Pop { .. } => "spop",
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            SetCommand::Add { key, .. }
+            | SetCommand::Rem { key, .. }
+            | SetCommand::Members { key }
+            | SetCommand::IsMember { key, .. }
+            | SetCommand::Card { key }
+            | SetCommand::RandMember { key, .. }
+            | SetCommand::Pop { key, .. } => vec![key],
+            SetCommand::Combine { keys, .. } => keys.iter().map(|key| key.as_ref()).collect(),
+        }
+    }
+
+    /// `name` 是 `NAMES` 里的一个
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<SetCommand, String> {
+        let cmd = match name {
+            "sadd" | "srem" => {
+                if args.len() < 2 {
+                    return Err(wrong_args(name));
+                }
+                let mut args = args.into_iter();
+                let key = args.next().unwrap_or_default();
+                let members = args.collect();
+                if name == "sadd" {
+                    SetCommand::Add { key, members }
+                } else {
+                    SetCommand::Rem { key, members }
+                }
+            }
+            "smembers" | "scard" => {
+                let [key] = exact(name, args)?;
+                if name == "smembers" {
+                    SetCommand::Members { key }
+                } else {
+                    SetCommand::Card { key }
+                }
+            }
+            "sismember" => {
+                let [key, member] = exact(name, args)?;
+                SetCommand::IsMember { key, member }
+            }
+            "sinter" | "sunion" | "sdiff" => {
+                if args.is_empty() {
+                    return Err(wrong_args(name));
+                }
+                let op = match name {
+                    "sinter" => SetOp::Inter,
+                    "sunion" => SetOp::Union,
+                    _ => SetOp::Diff,
+                };
+                SetCommand::Combine { op, keys: args }
+            }
+            _ => {
+                let mut args = args.into_iter();
+                let key = args.next().ok_or_else(|| wrong_args(name))?;
+                let count = args.next();
+                if args.next().is_some() {
+                    return Err(wrong_args(name));
+                }
+                if name == "srandmember" {
+                    let count = match count {
+                        Some(count) => Some(int_arg(&count)?),
+                        None => None,
+                    };
+                    SetCommand::RandMember { key, count }
+                } else {
+                    let count = match count {
+                        Some(count) => Some(positive(&count)?),
+                        None => None,
+                    };
+                    SetCommand::Pop { key, count }
+                }
+            }
+        };
+        Ok(cmd)
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self.run(keyspace) {
+            Ok(frame) => frame,
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn run(self, keyspace: &mut Keyspace) -> Result<Frame, String> {
+        let frame = match self {
+            SetCommand::Add { key, members } => {
+                let added = update(keyspace, &key, |set| {
+                    members
+                        .into_iter()
+                        .map(|member| set.insert(member))
+                        .filter(|added| *added)
+                        .count()
+                })?;
+                Frame::Integer(added as i64)
+            }
+            SetCommand::Rem { key, members } => {
+                if set(keyspace.peek(&key))?.is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let removed = update(keyspace, &key, |set| {
+                    members
+                        .iter()
+                        .map(|member| set.remove(member))
+                        .filter(|removed| *removed)
+                        .count()
+                })?;
+                Frame::Integer(removed as i64)
+            }
+            SetCommand::Members { key } => Frame::Array(
+                set(keyspace.get(&key))?
+                    .into_iter()
+                    .flat_map(|set| set.iter())
+                    .map(|member| Frame::Bulk(member.clone()))
+                    .collect(),
+            ),
+            SetCommand::IsMember { key, member } => {
+                let found = set(keyspace.get(&key))?.is_some_and(|set| set.contains(&member));
+                Frame::Integer(found as i64)
+            }
+            SetCommand::Card { key } => {
+                Frame::Integer(set(keyspace.get(&key))?.map_or(0, |set| set.len()) as i64)
+            }
+            SetCommand::Combine { op, keys } => Frame::Array(
+                combine(keyspace, op, &keys)?
+                    .into_iter()
+                    .map(Frame::Bulk)
+                    .collect(),
+            ),
+            SetCommand::RandMember { key, count } => {
+                let set = set(keyspace.get(&key))?;
+                let mut rng = rand::thread_rng();
+                match (set, count) {
+                    (None, None) => Frame::Null,
+                    (None, Some(_)) => Frame::Array(Vec::new()),
+                    (Some(set), None) => set
+                        .iter()
+                        .choose(&mut rng)
+                        .map_or(Frame::Null, |member| Frame::Bulk(member.clone())),
+                    // count 是负数时可以重复
+                    (Some(set), Some(count)) if count < 0 => {
+                        let members: Vec<&Bytes> = set.iter().collect();
+                        Frame::Array(
+                            (0..count.unsigned_abs())
+                                .filter_map(|_| members.choose(&mut rng))
+                                .map(|member| Frame::Bulk((*member).clone()))
+                                .collect(),
+                        )
+                    }
+                    (Some(set), Some(count)) => Frame::Array(
+                        set.iter()
+                            .choose_multiple(&mut rng, count as usize)
+                            .into_iter()
+                            .map(|member| Frame::Bulk(member.clone()))
+                            .collect(),
+                    ),
+                }
+            }
+            SetCommand::Pop { key, count } => {
+                let picked: Vec<Bytes> = match set(keyspace.peek(&key))? {
+                    Some(set) => set
+                        .iter()
+                        .cloned()
+                        .choose_multiple(&mut rand::thread_rng(), count.unwrap_or(1)),
+                    None if count.is_some() => return Ok(Frame::Array(Vec::new())),
+                    None => return Ok(Frame::Null),
+                };
+                update(keyspace, &key, |set| {
+                    for member in &picked {
+                        set.remove(member);
+                    }
+                })?;
+
+                match count {
+                    Some(_) => Frame::Array(picked.into_iter().map(Frame::Bulk).collect()),
+                    None => picked.into_iter().next().map_or(Frame::Null, Frame::Bulk),
+                }
+            }
+        };
+
+        Ok(frame)
+    }
+}
+
+// SINTER / SUNION / SDIFF：不存在的 key 当作空的 set
+fn combine(keyspace: &mut Keyspace, op: SetOp, keys: &[Bytes]) -> Result<Vec<Bytes>, String> {
+    // 先检查所有 key 的类型（同时删掉过期的），再一起借出来计算
+    for key in keys {
+        set(keyspace.get(key))?;
+    }
+    let sets: Vec<Option<&db::Set>> = keys
+        .iter()
+        .map(|key| keyspace.value(key).and_then(|value| value.as_set()))
+        .collect();
+
+    let members = match op {
+        SetOp::Inter => {
+            if sets.iter().any(|set| set.is_none()) {
+                return Ok(Vec::new());
+            }
+            let mut sets: Vec<&db::Set> = sets.into_iter().flatten().collect();
+            // 从最小的 set 开始检查
+            sets.sort_by_key(|set| set.len());
+            sets[0]
+                .iter()
+                .filter(|member| sets[1..].iter().all(|set| set.contains(member)))
+                .cloned()
+                .collect()
+        }
+        SetOp::Union => {
+            let members: HashSet<&Bytes> = sets
+                .into_iter()
+                .flatten()
+                .flat_map(|set| set.iter())
+                .collect();
+            members.into_iter().cloned().collect()
+        }
+        SetOp::Diff => match sets[0] {
+            Some(first) => first
+                .iter()
+                .filter(|member| sets[1..].iter().flatten().all(|set| !set.contains(member)))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        },
+    };
+    Ok(members)
+}
+
+// 取出 set；key 存在但不是 set 时是 WRONGTYPE 错误
+fn set(value: Option<&Value>) -> Result<Option<&db::Set>, String> {
+    match value {
+        Some(value) => value
+            .as_set()
+            .map(Some)
+            .ok_or_else(|| WRONGTYPE.to_string()),
+        None => Ok(None),
+    }
+}
+
+// 修改 set，key 不存在时先建一个空的。改完之后 set 空了的话 key 会被删除
+fn update<R>(
+    keyspace: &mut Keyspace,
+    key: &Bytes,
+    f: impl FnOnce(&mut db::Set) -> R,
+) -> Result<R, String> {
+    if !keyspace.contains(key) {
+        keyspace.insert(key.clone(), Value::Set(db::Set::default()), None);
+    }
+    keyspace
+        .modify(key, |value| value.as_set_mut().map(f))
+        .flatten()
+        .ok_or_else(|| WRONGTYPE.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    fn run(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let name = SetCommand::NAMES.iter().find(|n| **n == args[0]).unwrap();
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        match SetCommand::parse(name, args) {
+            Ok(cmd) => cmd.execute(keyspace),
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    // 结果的顺序不固定，排序之后再比较
+    fn sorted(frame: Frame) -> Vec<String> {
+        let mut members: Vec<String> = match frame {
+            Frame::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Frame::Bulk(s) => String::from_utf8(s.to_vec()).unwrap(),
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected {:?}", other),
+        };
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn ts_set_commands() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        assert_eq!(
+            run(&mut ks, &["sadd", "a", "x", "y", "z", "x"]),
+            Frame::Integer(3)
+        );
+        run(&mut ks, &["sadd", "b", "y", "w"]);
+        assert_eq!(run(&mut ks, &["sismember", "a", "y"]), Frame::Integer(1));
+        assert_eq!(run(&mut ks, &["scard", "missing"]), Frame::Integer(0));
+
+        assert_eq!(sorted(run(&mut ks, &["sinter", "a", "b"])), ["y"]);
+        assert_eq!(
+            sorted(run(&mut ks, &["sunion", "a", "b", "missing"])),
+            ["w", "x", "y", "z"]
+        );
+        assert_eq!(sorted(run(&mut ks, &["sdiff", "a", "b"])), ["x", "z"]);
+        assert!(sorted(run(&mut ks, &["sinter", "a", "missing"])).is_empty());
+
+        assert_eq!(sorted(run(&mut ks, &["srandmember", "b", "5"])), ["w", "y"]);
+        assert_eq!(sorted(run(&mut ks, &["srandmember", "b", "-5"])).len(), 5);
+
+        // 全部弹出之后 key 被删除
+        assert_eq!(sorted(run(&mut ks, &["spop", "b", "10"])), ["w", "y"]);
+        assert!(!ks.contains(b"b"));
+        assert_eq!(run(&mut ks, &["spop", "b"]), Frame::Null);
+        assert_eq!(
+            run(&mut ks, &["spop", "a", "-1"]),
+            Frame::Error("ERR value is out of range, must be positive".to_string())
+        );
+
+        assert_eq!(run(&mut ks, &["srem", "a", "x", "nope"]), Frame::Integer(1));
+        ks.insert(Bytes::from("s"), Value::String(Bytes::from("v")), None);
+        assert_eq!(
+            run(&mut ks, &["sinter", "a", "s"]),
+            Frame::Error(WRONGTYPE.to_string())
+        );
+    }
+}
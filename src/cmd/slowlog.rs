@@ -0,0 +1,112 @@
+use bytes::Bytes;
+
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::parser;
+use crate::slowlog::Entry;
+use crate::state::State;
+
+use super::{CommandSnafu, ConnectSnafu, Result};
+use snafu::ResultExt;
+
+// SLOWLOG GET 不带个数时返回的条数
+const DEFAULT_GET_COUNT: usize = 10;
+
+/// SLOWLOG GET [n] / LEN / RESET
+#[derive(Debug)]
+pub enum SlowlogCommand {
+    Get(usize),
+    Len,
+    Reset,
+    // 子命令或者参数不对，直接回复这个错误
+    Invalid(String),
+}
+
+impl SlowlogCommand {
+    pub fn parse_frame(parser: &mut parser::Parser) -> Result<SlowlogCommand> {
+        let sub = parser.next_string().context(CommandSnafu)?.to_lowercase();
+
+        let mut args = Vec::new();
+        while parser.has_next() {
+            args.push(parser.next_string().context(CommandSnafu)?);
+        }
+
+        let cmd = match (sub.as_str(), args.as_slice()) {
+            ("get", []) => SlowlogCommand::Get(DEFAULT_GET_COUNT),
+            // 和 Redis 一样，-1 表示返回所有记录
+            ("get", [n]) => match n.parse::<i64>() {
+                Ok(-1) => SlowlogCommand::Get(usize::MAX),
+                Ok(n) if n >= 0 => SlowlogCommand::Get(n as usize),
+                _ => SlowlogCommand::Invalid(
+                    "ERR count should be greater than or equal to -1".to_string(),
+                ),
+            },
+            ("len", []) => SlowlogCommand::Len,
+            ("reset", []) => SlowlogCommand::Reset,
+            ("get" | "len" | "reset", _) => SlowlogCommand::Invalid(format!(
+                "ERR wrong number of arguments for 'slowlog|{}' command",
+                sub
+            )),
+            _ => SlowlogCommand::Invalid(format!(
+                "ERR unknown subcommand '{}'. Try SLOWLOG HELP.",
+                sub
+            )),
+        };
+
+        Ok(cmd)
+    }
+
+    /// 检查权限时用的命令 id
+    pub fn acl_id(&self) -> &'static str {
+        match self {
+            SlowlogCommand::Get(_) => "slowlog|get",
+            SlowlogCommand::Len => "slowlog|len",
+            SlowlogCommand::Reset => "slowlog|reset",
+            SlowlogCommand::Invalid(_) => "slowlog",
+        }
+    }
+
+    pub async fn apply(self, state: &State, connection: &mut Connection) -> Result<()> {
+        let response = match self {
+            SlowlogCommand::Get(n) => {
+                Frame::Array(state.slowlog.get(n).into_iter().map(to_frame).collect())
+            }
+            SlowlogCommand::Len => Frame::Integer(state.slowlog.len() as i64),
+            SlowlogCommand::Reset => {
+                state.slowlog.reset();
+                Frame::Simple("OK".to_string())
+            }
+            SlowlogCommand::Invalid(msg) => Frame::Error(msg),
+        };
+
+        connection
+            .write_frame(&response)
+            .await
+            .context(ConnectSnafu)?;
+
+        Ok(())
+    }
+}
+
+// 前 6 项和 Redis 一样：id、时间、耗时（微秒）、参数、客户端地址、客户端名字；
+// 后 2 项是上游的 url 和状态，没有访问上游时是 nil
+fn to_frame(entry: Entry) -> Frame {
+    let (url, status) = match entry.upstream {
+        Some(upstream) => (
+            Frame::Bulk(Bytes::from(upstream.url)),
+            Frame::Bulk(Bytes::from(upstream.status)),
+        ),
+        None => (Frame::Null, Frame::Null),
+    };
+
+    Frame::Array(vec![
+        Frame::Integer(entry.id as i64),
+        Frame::Integer(entry.timestamp as i64),
+        Frame::Integer(entry.duration.as_micros() as i64),
+        Frame::Array(entry.args.into_iter().map(Frame::Bulk).collect()),
+        Frame::Bulk(Bytes::from(entry.addr)),
+        Frame::Bulk(Bytes::from(entry.name)),
+        url,
+        status,
+    ])
+}
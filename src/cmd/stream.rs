@@ -0,0 +1,1076 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use snafu::ResultExt;
+use tokio::time::{self, Instant};
+
+use crate::connection::Connection;
+use crate::db::{now_millis, Fields, Keyspace, NewId, Stream, StreamId, Trim, Value, WRONGTYPE};
+use crate::frame::Frame;
+
+use super::{exact, int_arg, reply, wrong_args, ConnectSnafu, Context, Result, SYNTAX_ERROR};
+
+const INVALID_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+
+/// stream 的命令（不包括会阻塞的 XREAD / XREADGROUP）
+#[derive(Debug)]
+pub enum StreamCommand {
+    Add {
+        key: Bytes,
+        nomkstream: bool,
+        trim: Option<Trim>,
+        id: NewId,
+        fields: Fields,
+    },
+    /// XRANGE / XREVRANGE。开区间换算之后范围是空的话，start / end 是 None
+    Range {
+        key: Bytes,
+        start: Option<StreamId>,
+        end: Option<StreamId>,
+        count: Option<usize>,
+        rev: bool,
+    },
+    Len {
+        key: Bytes,
+    },
+    Trim {
+        key: Bytes,
+        trim: Trim,
+    },
+    /// XGROUP CREATE，id 是 None 表示 `$`（只读之后加入的 entry）
+    CreateGroup {
+        key: Bytes,
+        group: Bytes,
+        id: Option<StreamId>,
+        mkstream: bool,
+    },
+    Ack {
+        key: Bytes,
+        group: Bytes,
+        ids: Vec<StreamId>,
+    },
+    Pending {
+        key: Bytes,
+        group: Bytes,
+        range: Option<PendingRange>,
+    },
+    Claim {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        just_id: bool,
+    },
+}
+
+/// XPENDING 的扩展形式：列出一个范围内的 pending entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRange {
+    min_idle: u64,
+    start: StreamId,
+    end: StreamId,
+    count: usize,
+    consumer: Option<Bytes>,
+}
+
+impl StreamCommand {
+    /// 这一组命令的命令名
+    pub const NAMES: &'static [&'static str] = &[
+        "xack",
+        "xadd",
+        "xclaim",
+        "xgroup",
+        "xlen",
+        "xpending",
+        "xrange",
+        "xrevrange",
+        "xtrim",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamCommand::Add { .. } => "xadd",
+            StreamCommand::Range { rev: false, .. } => "xrange",
+            StreamCommand::Range { rev: true, .. } => "xrevrange",
+            StreamCommand::Len { .. } => "xlen",
+            StreamCommand::Trim { .. } => "xtrim",
+            StreamCommand::CreateGroup { .. } => "xgroup",
+            StreamCommand::Ack { .. } => "xack",
+            StreamCommand::Pending { .. } => "xpending",
+            StreamCommand::Claim { .. } => "xclaim",
+        }
+    }
+
+    /// 检查权限时用的命令 id
+    pub fn acl_id(&self) -> &'static str {
+        match self {
+            StreamCommand::CreateGroup { .. } => "xgroup|create",
+            _ => self.name(),
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            StreamCommand::Add { key, .. }
+            | StreamCommand::Range { key, .. }
+            | StreamCommand::Len { key }
+            | StreamCommand::Trim { key, .. }
+            | StreamCommand::CreateGroup { key, .. }
+            | StreamCommand::Ack { key, .. }
+            | StreamCommand::Pending { key, .. }
+            | StreamCommand::Claim { key, .. } => key,
+        }
+    }
+
+    /// `name` 是 `NAMES` 里的一个
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<StreamCommand, String> {
+        let cmd = match name {
+            "xadd" => parse_xadd(args)?,
+            "xrange" | "xrevrange" => {
+                let rev = name == "xrevrange";
+                let mut args = args.into_iter();
+                let (key, first, second) = match (args.next(), args.next(), args.next()) {
+                    (Some(key), Some(first), Some(second)) => (key, first, second),
+                    _ => return Err(wrong_args(name)),
+                };
+                // XREVRANGE 先写 end 再写 start
+                let (start, end) = if rev {
+                    (second, first)
+                } else {
+                    (first, second)
+                };
+                let count = match (args.next(), args.next()) {
+                    (None, _) => None,
+                    (Some(opt), Some(count)) if opt.eq_ignore_ascii_case(b"count") => {
+                        // 和 Redis 一样，负数的 count 当作 0
+                        Some(usize::try_from(int_arg(&count)?).unwrap_or(0))
+                    }
+                    _ => return Err(SYNTAX_ERROR.to_string()),
+                };
+                if args.next().is_some() {
+                    return Err(SYNTAX_ERROR.to_string());
+                }
+                StreamCommand::Range {
+                    key,
+                    start: range_bound(&start, true)?,
+                    end: range_bound(&end, false)?,
+                    count,
+                    rev,
+                }
+            }
+            "xlen" => {
+                let [key] = exact(name, args)?;
+                StreamCommand::Len { key }
+            }
+            "xtrim" => {
+                let mut args = args.into_iter();
+                let key = args.next().ok_or_else(|| wrong_args(name))?;
+                let trim = match args.next() {
+                    Some(strategy) => parse_trim(&strategy, &mut args)?,
+                    None => return Err(wrong_args(name)),
+                };
+                if args.next().is_some() {
+                    return Err(SYNTAX_ERROR.to_string());
+                }
+                StreamCommand::Trim { key, trim }
+            }
+            "xgroup" => parse_xgroup(args)?,
+            "xack" => {
+                if args.len() < 3 {
+                    return Err(wrong_args(name));
+                }
+                let mut args = args.into_iter();
+                let key = args.next().unwrap_or_default();
+                let group = args.next().unwrap_or_default();
+                let ids = args
+                    .map(|id| StreamId::parse(&id, 0).ok_or_else(|| INVALID_ID.to_string()))
+                    .collect::<Result<_, String>>()?;
+                StreamCommand::Ack { key, group, ids }
+            }
+            "xpending" => parse_xpending(args)?,
+            _ => parse_xclaim(args)?,
+        };
+        Ok(cmd)
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self.run(keyspace) {
+            Ok(frame) => frame,
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn run(self, keyspace: &mut Keyspace) -> Result<Frame, String> {
+        let frame = match self {
+            StreamCommand::Add {
+                key,
+                nomkstream,
+                trim,
+                id,
+                fields,
+            } => match keyspace.add_entry(&key, id, fields, !nomkstream, trim)? {
+                Some(id) => Frame::Bulk(Bytes::from(id.to_string())),
+                None => Frame::Null,
+            },
+            StreamCommand::Range {
+                key,
+                start,
+                end,
+                count,
+                rev,
+            } => {
+                let (stream, start, end) = match (stream(keyspace.get(&key))?, start, end) {
+                    (Some(stream), Some(start), Some(end)) => (stream, start, end),
+                    _ => return Ok(Frame::Array(Vec::new())),
+                };
+                let count = count.unwrap_or(usize::MAX);
+                let entries: Vec<Frame> = if rev {
+                    stream
+                        .range(start, end)
+                        .rev()
+                        .take(count)
+                        .map(|(id, fields)| entry(id, Some(fields)))
+                        .collect()
+                } else {
+                    stream
+                        .range(start, end)
+                        .take(count)
+                        .map(|(id, fields)| entry(id, Some(fields)))
+                        .collect()
+                };
+                Frame::Array(entries)
+            }
+            StreamCommand::Len { key } => {
+                Frame::Integer(stream(keyspace.get(&key))?.map_or(0, |stream| stream.len()) as i64)
+            }
+            StreamCommand::Trim { key, trim } => {
+                if stream(keyspace.peek(&key))?.is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let removed = update(keyspace, &key, |stream| stream.trim(trim))?;
+                Frame::Integer(removed as i64)
+            }
+            StreamCommand::CreateGroup {
+                key,
+                group,
+                id,
+                mkstream,
+            } => {
+                if stream(keyspace.peek(&key))?.is_none() {
+                    if !mkstream {
+                        return Err("ERR The XGROUP subcommand requires the key to exist. \
+                            Note that for CREATE you may want to use the MKSTREAM option \
+                            to create an empty stream automatically."
+                            .to_string());
+                    }
+                    keyspace.insert(key.clone(), Value::Stream(Stream::default()), None);
+                }
+                let created = update(keyspace, &key, |stream| {
+                    let id = id.unwrap_or_else(|| stream.last_id());
+                    stream.create_group(group, id)
+                })?;
+                if !created {
+                    return Err("BUSYGROUP Consumer Group name already exists".to_string());
+                }
+                Frame::Simple("OK".to_string())
+            }
+            StreamCommand::Ack { key, group, ids } => {
+                if stream(keyspace.peek(&key))?.is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let acked = update(keyspace, &key, |stream| stream.ack(&group, &ids))?;
+                Frame::Integer(acked as i64)
+            }
+            StreamCommand::Pending { key, group, range } => {
+                let stream = stream(keyspace.get(&key))?;
+                let group = match stream.and_then(|stream| stream.group(&group)) {
+                    Some(group) => group,
+                    None => return Err(no_group(&key, &group)),
+                };
+                let pending = group.pending();
+                let now = now_millis();
+                match range {
+                    // 概要：pending 的个数、最小和最大的 id、每个消费者各有几个
+                    None => match (pending.first_key_value(), pending.last_key_value()) {
+                        (Some((min, _)), Some((max, _))) => Frame::Array(vec![
+                            Frame::Integer(pending.len() as i64),
+                            Frame::Bulk(Bytes::from(min.to_string())),
+                            Frame::Bulk(Bytes::from(max.to_string())),
+                            Frame::Array(
+                                group
+                                    .consumers()
+                                    .into_iter()
+                                    .filter(|(_, count)| *count > 0)
+                                    .map(|(name, count)| {
+                                        Frame::Array(vec![
+                                            Frame::Bulk(name.clone()),
+                                            Frame::Bulk(Bytes::from(count.to_string())),
+                                        ])
+                                    })
+                                    .collect(),
+                            ),
+                        ]),
+                        _ => Frame::Array(vec![
+                            Frame::Integer(0),
+                            Frame::Null,
+                            Frame::Null,
+                            Frame::Null,
+                        ]),
+                    },
+                    Some(range) if range.start > range.end => Frame::Array(Vec::new()),
+                    Some(range) => Frame::Array(
+                        pending
+                            .range(range.start..=range.end)
+                            .filter(|(_, p)| now.saturating_sub(p.delivered_at) >= range.min_idle)
+                            .filter(|(_, p)| {
+                                range.consumer.as_ref().is_none_or(|c| *c == p.consumer)
+                            })
+                            .take(range.count)
+                            .map(|(id, p)| {
+                                Frame::Array(vec![
+                                    Frame::Bulk(Bytes::from(id.to_string())),
+                                    Frame::Bulk(p.consumer.clone()),
+                                    Frame::Integer(now.saturating_sub(p.delivered_at) as i64),
+                                    Frame::Integer(p.deliveries as i64),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                }
+            }
+            StreamCommand::Claim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                ids,
+                just_id,
+            } => {
+                if stream(keyspace.peek(&key))?.is_none() {
+                    return Err(no_group(&key, &group));
+                }
+                let claimed = update(keyspace, &key, |stream| {
+                    stream.claim(&group, &consumer, min_idle, &ids, just_id, now_millis())
+                })?
+                .ok_or_else(|| no_group(&key, &group))?;
+                Frame::Array(
+                    claimed
+                        .iter()
+                        .map(|(id, fields)| {
+                            if just_id {
+                                Frame::Bulk(Bytes::from(id.to_string()))
+                            } else {
+                                entry(id, Some(fields))
+                            }
+                        })
+                        .collect(),
+                )
+            }
+        };
+
+        Ok(frame)
+    }
+}
+
+/// XREAD / XREADGROUP 从每个 stream 的哪里开始读
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadFrom {
+    /// 这个 id 之后的 entry。XREADGROUP 指定 id 时是 consumer 自己 pending 列表里的
+    After(StreamId),
+    /// `$`：XREAD 开始执行之后才加入的 entry
+    Last,
+    /// `>`：XREADGROUP 组里还没有交给过任何消费者的 entry
+    New,
+}
+
+/// XREAD / XREADGROUP。指定了 BLOCK 而且还没有可以读的 entry 时，
+/// 等到有新的 entry、超时或者 server 开始 shutdown
+#[derive(Debug)]
+pub struct XRead {
+    /// XREADGROUP 的组和消费者
+    group: Option<(Bytes, Bytes)>,
+    count: Option<usize>,
+    /// None 表示不阻塞，Some(None) 表示一直等
+    block: Option<Option<Duration>>,
+    noack: bool,
+    pub(crate) keys: Vec<Bytes>,
+    from: Vec<ReadFrom>,
+}
+
+impl XRead {
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<XRead, String> {
+        let mut read = XRead {
+            group: None,
+            count: None,
+            block: None,
+            noack: false,
+            keys: Vec::new(),
+            from: Vec::new(),
+        };
+
+        let mut args = args.into_iter();
+        let mut streams = None;
+        while let Some(arg) = args.next() {
+            match String::from_utf8_lossy(&arg).to_lowercase().as_str() {
+                "count" => {
+                    let count = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+                    // 和 Redis 一样，0 和负数表示不限制
+                    read.count = usize::try_from(int_arg(&count)?)
+                        .ok()
+                        .filter(|count| *count > 0);
+                }
+                "block" => {
+                    let ms = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+                    let ms = u64::try_from(int_arg(&ms)?)
+                        .map_err(|_| "ERR timeout is negative".to_string())?;
+                    read.block = Some((ms > 0).then(|| Duration::from_millis(ms)));
+                }
+                "group" if name == "xreadgroup" => match (args.next(), args.next()) {
+                    (Some(group), Some(consumer)) => read.group = Some((group, consumer)),
+                    _ => return Err(SYNTAX_ERROR.to_string()),
+                },
+                "noack" if name == "xreadgroup" => read.noack = true,
+                "streams" => {
+                    streams = Some(args.by_ref().collect::<Vec<_>>());
+                }
+                _ => return Err(SYNTAX_ERROR.to_string()),
+            }
+        }
+
+        if name == "xreadgroup" && read.group.is_none() {
+            return Err("ERR Missing GROUP option for XREADGROUP".to_string());
+        }
+        let mut streams = streams.ok_or_else(|| SYNTAX_ERROR.to_string())?;
+        if streams.is_empty() || !streams.len().is_multiple_of(2) {
+            return Err(format!(
+                "ERR Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+                name
+            ));
+        }
+        let ids = streams.split_off(streams.len() / 2);
+        read.keys = streams;
+        for id in ids {
+            let from =
+                match (id.as_ref(), &read.group) {
+                    (b"$", None) => ReadFrom::Last,
+                    (b">", Some(_)) => ReadFrom::New,
+                    (b"$", Some(_)) => return Err(
+                        "ERR The $ ID is meaningless in the context of XREADGROUP: you want to \
+                         read the history of this consumer by specifying a proper ID, or use the \
+                         > ID to get new messages."
+                            .to_string(),
+                    ),
+                    (b">", None) => {
+                        return Err(
+                            "ERR The > ID can be specified only when calling XREADGROUP \
+                         using the GROUP <group> <consumer> option."
+                                .to_string(),
+                        )
+                    }
+                    (id, _) => ReadFrom::After(
+                        StreamId::parse(id, 0).ok_or_else(|| INVALID_ID.to_string())?,
+                    ),
+                };
+            read.from.push(from);
+        }
+        Ok(read)
+    }
+
+    pub fn name(&self) -> &'static str {
+        if self.group.is_some() {
+            "xreadgroup"
+        } else {
+            "xread"
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&[u8]> {
+        self.keys.iter().map(|key| key.as_ref()).collect()
+    }
+
+    pub async fn apply(mut self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        let deadline = self
+            .block
+            .map(|timeout| timeout.and_then(|timeout| Instant::now().checked_add(timeout)));
+        let mut wait = None;
+
+        let response = loop {
+            {
+                // 读和开始等待在同一次加锁里完成，中间加入的 entry 不会错过
+                let mut keyspace = ctx.state.db.lock_keys(&self.keys()).await;
+                match self.read(&mut keyspace) {
+                    Ok(Some(frame)) => break frame,
+                    Err(msg) => break Frame::Error(msg),
+                    Ok(None) if deadline.is_none() => break Frame::Null,
+                    Ok(None) => {
+                        if wait.is_none() {
+                            wait = Some(keyspace.wait_streams(self.keys.clone()));
+                        }
+                    }
+                }
+            }
+
+            let changed = match &wait {
+                Some(wait) => wait.changed(),
+                None => break Frame::Null,
+            };
+            let timeout = async {
+                match deadline.flatten() {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                // 有新的 entry 了，再读一次
+                _ = changed => {}
+                _ = timeout => break Frame::Null,
+                _ = ctx.shutdown.recv() => break Frame::Null,
+                // 客户端走了，不用再等，也不用回复
+                res = connection.closed() => {
+                    res.context(ConnectSnafu)?;
+                    return Ok(());
+                }
+            }
+        };
+        drop(wait);
+
+        reply(connection, response).await
+    }
+
+    /// 在事务里不阻塞，没有可以读的 entry 时马上回复 nil
+    pub fn execute(mut self, keyspace: &mut Keyspace) -> Frame {
+        match self.read(keyspace) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => Frame::Null,
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    // 读一次。所有的 stream 都没有可以读的 entry 时返回 None，阻塞的话要接着等
+    fn read(&mut self, keyspace: &mut Keyspace) -> Result<Option<Frame>, String> {
+        // 先检查所有 key 的类型和消费组
+        for key in &self.keys {
+            let stream = stream(keyspace.peek(key))?;
+            if let Some((group, _)) = &self.group {
+                if stream.and_then(|stream| stream.group(group)).is_none() {
+                    return Err(no_group(key, group));
+                }
+            }
+        }
+
+        let mut found = Vec::new();
+        let mut history = false;
+        for (key, from) in self.keys.iter().zip(self.from.iter_mut()) {
+            let entries: Vec<Frame> = match (&self.group, *from) {
+                (None, ReadFrom::Last) => {
+                    // `$` 在第一次读的时候换成当时最后的 id，之后的等待都从这里开始
+                    let last = stream(keyspace.get(key))?.map_or(StreamId::MIN, |s| s.last_id());
+                    *from = ReadFrom::After(last);
+                    continue;
+                }
+                (None, ReadFrom::After(after)) => match stream(keyspace.get(key))? {
+                    Some(stream) => stream
+                        .after(after)
+                        .take(self.count.unwrap_or(usize::MAX))
+                        .map(|(id, fields)| entry(id, Some(fields)))
+                        .collect(),
+                    None => continue,
+                },
+                (Some((group, consumer)), ReadFrom::New) => {
+                    let (count, noack) = (self.count, self.noack);
+                    update(keyspace, key, |stream| {
+                        stream.deliver(group, consumer, count, noack, now_millis())
+                    })?
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(id, fields)| entry(id, Some(fields)))
+                    .collect()
+                }
+                (Some((group, consumer)), ReadFrom::After(after)) => {
+                    // 读 consumer 自己的历史消息，没有的话也要回复（空的列表）
+                    history = true;
+                    let count = self.count;
+                    update(keyspace, key, |stream| {
+                        stream.history(group, consumer, after, count, now_millis())
+                    })?
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(id, fields)| entry(id, fields.as_ref()))
+                    .collect()
+                }
+                (_, from) => unreachable!("{:?} is rejected by parse", from),
+            };
+            if !entries.is_empty() || history {
+                found.push(Frame::Array(vec![
+                    Frame::Bulk(key.clone()),
+                    Frame::Array(entries),
+                ]));
+            }
+        }
+
+        Ok((!found.is_empty()).then_some(Frame::Array(found)))
+    }
+}
+
+// XADD key [NOMKSTREAM] [MAXLEN | MINID [= | ~] threshold] <* | id> field value [field value ...]
+fn parse_xadd(args: Vec<Bytes>) -> Result<StreamCommand, String> {
+    let mut args = args.into_iter().peekable();
+    let key = args.next().ok_or_else(|| wrong_args("xadd"))?;
+
+    let mut nomkstream = false;
+    let mut trim = None;
+    let id = loop {
+        let arg = args.next().ok_or_else(|| wrong_args("xadd"))?;
+        match String::from_utf8_lossy(&arg).to_lowercase().as_str() {
+            "nomkstream" => nomkstream = true,
+            "maxlen" | "minid" => trim = Some(parse_trim(&arg, &mut args)?),
+            _ => break new_id(&arg)?,
+        }
+    };
+
+    let rest: Vec<Bytes> = args.collect();
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err(wrong_args("xadd"));
+    }
+    let fields = rest
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+
+    Ok(StreamCommand::Add {
+        key,
+        nomkstream,
+        trim,
+        id,
+        fields,
+    })
+}
+
+// MAXLEN | MINID [= | ~] threshold。`~` 在 Redis 里是近似裁剪，这里一律精确裁剪
+fn parse_trim(strategy: &[u8], args: &mut impl Iterator<Item = Bytes>) -> Result<Trim, String> {
+    let mut threshold = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+    if threshold.as_ref() == b"=" || threshold.as_ref() == b"~" {
+        threshold = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+    }
+    if strategy.eq_ignore_ascii_case(b"maxlen") {
+        let max = usize::try_from(int_arg(&threshold)?)
+            .map_err(|_| "ERR The MAXLEN argument must be >= 0.".to_string())?;
+        Ok(Trim::MaxLen(max))
+    } else if strategy.eq_ignore_ascii_case(b"minid") {
+        let min = StreamId::parse(&threshold, 0).ok_or_else(|| INVALID_ID.to_string())?;
+        Ok(Trim::MinId(min))
+    } else {
+        Err(SYNTAX_ERROR.to_string())
+    }
+}
+
+// XGROUP CREATE key group <id | $> [MKSTREAM]
+fn parse_xgroup(args: Vec<Bytes>) -> Result<StreamCommand, String> {
+    let mut args = args.into_iter();
+    let sub = args.next().ok_or_else(|| wrong_args("xgroup"))?;
+    if !sub.eq_ignore_ascii_case(b"create") {
+        return Err(format!(
+            "ERR unknown subcommand '{}'. Try XGROUP HELP.",
+            String::from_utf8_lossy(&sub)
+        ));
+    }
+
+    let (key, group, id) = match (args.next(), args.next(), args.next()) {
+        (Some(key), Some(group), Some(id)) => (key, group, id),
+        _ => return Err(wrong_args("xgroup|create")),
+    };
+    let id = match id.as_ref() {
+        b"$" => None,
+        id => Some(StreamId::parse(id, 0).ok_or_else(|| INVALID_ID.to_string())?),
+    };
+    let mkstream = match args.next() {
+        None => false,
+        Some(opt) if opt.eq_ignore_ascii_case(b"mkstream") => true,
+        Some(_) => return Err(SYNTAX_ERROR.to_string()),
+    };
+    if args.next().is_some() {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+
+    Ok(StreamCommand::CreateGroup {
+        key,
+        group,
+        id,
+        mkstream,
+    })
+}
+
+// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+fn parse_xpending(args: Vec<Bytes>) -> Result<StreamCommand, String> {
+    let mut args = args.into_iter().peekable();
+    let (key, group) = match (args.next(), args.next()) {
+        (Some(key), Some(group)) => (key, group),
+        _ => return Err(wrong_args("xpending")),
+    };
+    if args.peek().is_none() {
+        return Ok(StreamCommand::Pending {
+            key,
+            group,
+            range: None,
+        });
+    }
+
+    let mut min_idle = 0;
+    if args
+        .peek()
+        .is_some_and(|arg| arg.eq_ignore_ascii_case(b"idle"))
+    {
+        args.next();
+        let idle = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+        min_idle = u64::try_from(int_arg(&idle)?).unwrap_or(0);
+    }
+    let (start, end, count) = match (args.next(), args.next(), args.next()) {
+        (Some(start), Some(end), Some(count)) => (start, end, count),
+        _ => return Err(SYNTAX_ERROR.to_string()),
+    };
+    let consumer = args.next();
+    if args.next().is_some() {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+
+    // 开区间换算之后是空的范围，用一个 start > end 的范围表示
+    let empty = (StreamId::MAX, StreamId::MIN);
+    let (start, end) = match (range_bound(&start, true)?, range_bound(&end, false)?) {
+        (Some(start), Some(end)) => (start, end),
+        _ => empty,
+    };
+    Ok(StreamCommand::Pending {
+        key,
+        group,
+        range: Some(PendingRange {
+            min_idle,
+            start,
+            end,
+            count: usize::try_from(int_arg(&count)?).unwrap_or(0),
+            consumer,
+        }),
+    })
+}
+
+// XCLAIM key group consumer min-idle-time id [id ...] [JUSTID]
+fn parse_xclaim(args: Vec<Bytes>) -> Result<StreamCommand, String> {
+    if args.len() < 5 {
+        return Err(wrong_args("xclaim"));
+    }
+    let mut args = args.into_iter();
+    let key = args.next().unwrap_or_default();
+    let group = args.next().unwrap_or_default();
+    let consumer = args.next().unwrap_or_default();
+    let min_idle = args.next().unwrap_or_default();
+    let min_idle = u64::try_from(int_arg(&min_idle)?).unwrap_or(0);
+
+    let mut ids = Vec::new();
+    let mut just_id = false;
+    for arg in args {
+        if arg.eq_ignore_ascii_case(b"justid") {
+            just_id = true;
+        } else if just_id {
+            return Err(SYNTAX_ERROR.to_string());
+        } else {
+            ids.push(StreamId::parse(&arg, 0).ok_or_else(|| INVALID_ID.to_string())?);
+        }
+    }
+
+    Ok(StreamCommand::Claim {
+        key,
+        group,
+        consumer,
+        min_idle,
+        ids,
+        just_id,
+    })
+}
+
+// XADD 的 id：`*`、`<ms>-*` 或者完整的 id
+fn new_id(arg: &[u8]) -> Result<NewId, String> {
+    if arg == b"*" {
+        return Ok(NewId::Auto);
+    }
+    if let Some(ms) = arg.strip_suffix(b"-*") {
+        let ms = std::str::from_utf8(ms)
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .ok_or_else(|| INVALID_ID.to_string())?;
+        return Ok(NewId::AutoSeq(ms));
+    }
+    StreamId::parse(arg, 0)
+        .map(NewId::Exact)
+        .ok_or_else(|| INVALID_ID.to_string())
+}
+
+// XRANGE 的边界：`-` / `+` 是最小 / 最大的 id，`(` 开头的不包括边界本身。
+// 只写了时间戳时，开始的序号是 0，结束的序号是最大值。开区间之后没有 id 了的话返回 None
+fn range_bound(arg: &[u8], start: bool) -> Result<Option<StreamId>, String> {
+    match arg {
+        b"-" => return Ok(Some(StreamId::MIN)),
+        b"+" => return Ok(Some(StreamId::MAX)),
+        _ => {}
+    }
+    let (exclusive, arg) = match arg.strip_prefix(b"(") {
+        Some(arg) => (true, arg),
+        None => (false, arg),
+    };
+    let default_seq = if start { 0 } else { u64::MAX };
+    let id = StreamId::parse(arg, default_seq).ok_or_else(|| INVALID_ID.to_string())?;
+    Ok(match (exclusive, start) {
+        (false, _) => Some(id),
+        (true, true) => id.next(),
+        (true, false) => id.prev(),
+    })
+}
+
+// 一个 entry 的回复：[id, [field, value, ...]]。XREADGROUP 读历史消息时，
+// 已经被删掉的 entry 的字段是 nil
+fn entry(id: &StreamId, fields: Option<&Fields>) -> Frame {
+    let fields = match fields {
+        Some(fields) => Frame::Array(
+            fields
+                .iter()
+                .flat_map(|(field, value)| [Frame::Bulk(field.clone()), Frame::Bulk(value.clone())])
+                .collect(),
+        ),
+        None => Frame::Null,
+    };
+    Frame::Array(vec![Frame::Bulk(Bytes::from(id.to_string())), fields])
+}
+
+fn no_group(key: &[u8], group: &[u8]) -> String {
+    format!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    )
+}
+
+// 取出 stream；key 存在但不是 stream 时是 WRONGTYPE 错误
+fn stream(value: Option<&Value>) -> Result<Option<&Stream>, String> {
+    match value {
+        Some(value) => value
+            .as_stream()
+            .map(Some)
+            .ok_or_else(|| WRONGTYPE.to_string()),
+        None => Ok(None),
+    }
+}
+
+// 修改已经存在的 stream。stream 空了也不会删除 key
+fn update<R>(
+    keyspace: &mut Keyspace,
+    key: &Bytes,
+    f: impl FnOnce(&mut Stream) -> R,
+) -> Result<R, String> {
+    keyspace
+        .modify(key, |value| value.as_stream_mut().map(f))
+        .flatten()
+        .ok_or_else(|| WRONGTYPE.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    fn run(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let name = StreamCommand::NAMES
+            .iter()
+            .find(|n| **n == args[0])
+            .unwrap();
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        match StreamCommand::parse(name, args) {
+            Ok(cmd) => cmd.execute(keyspace),
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn read(keyspace: &mut Keyspace, args: &[&str]) -> Result<Option<Frame>, String> {
+        let name = if args[0] == "xreadgroup" {
+            "xreadgroup"
+        } else {
+            "xread"
+        };
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        XRead::parse(name, args)?.read(keyspace)
+    }
+
+    // 回复里的 id 列表
+    fn ids(frame: Frame) -> Vec<String> {
+        let entries = match frame {
+            Frame::Array(entries) => entries,
+            other => panic!("unexpected {:?}", other),
+        };
+        entries
+            .into_iter()
+            .map(|entry| match entry {
+                Frame::Array(mut parts) => match parts.swap_remove(0) {
+                    Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+                    other => panic!("unexpected {:?}", other),
+                },
+                Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ts_stream_commands() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        for id in ["1-1", "1-2", "2-0", "3-0"] {
+            run(&mut ks, &["xadd", "s", id, "f", "v"]);
+        }
+        assert_eq!(
+            run(&mut ks, &["xadd", "s", "2-5", "f", "v"]),
+            Frame::Error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            run(&mut ks, &["xadd", "s", "3-*", "f", "v"]),
+            Frame::Bulk(Bytes::from("3-1"))
+        );
+        assert_eq!(
+            run(&mut ks, &["xadd", "missing", "nomkstream", "*", "f", "v"]),
+            Frame::Null
+        );
+        assert_eq!(run(&mut ks, &["xlen", "s"]), Frame::Integer(5));
+
+        assert_eq!(
+            ids(run(&mut ks, &["xrange", "s", "1", "2"])),
+            ["1-1", "1-2", "2-0"]
+        );
+        assert_eq!(
+            ids(run(&mut ks, &["xrange", "s", "(1-1", "+", "count", "2"])),
+            ["1-2", "2-0"]
+        );
+        assert_eq!(
+            ids(run(&mut ks, &["xrevrange", "s", "+", "(2", "count", "1"])),
+            ["3-1"]
+        );
+
+        assert!(matches!(
+            run(&mut ks, &["xadd", "s", "maxlen", "~", "3", "*", "f", "v"]),
+            Frame::Bulk(_)
+        ));
+        assert_eq!(run(&mut ks, &["xlen", "s"]), Frame::Integer(3));
+        assert_eq!(
+            run(&mut ks, &["xtrim", "s", "minid", "3-1"]),
+            Frame::Integer(1)
+        );
+        assert_eq!(
+            run(&mut ks, &["xtrim", "s", "maxlen", "0"]),
+            Frame::Integer(2)
+        );
+        // entry 删光了，key 还在
+        assert_eq!(run(&mut ks, &["xlen", "s"]), Frame::Integer(0));
+        assert!(ks.contains(b"s"));
+    }
+
+    #[test]
+    fn ts_consumer_group_commands() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        assert_eq!(
+            run(&mut ks, &["xgroup", "create", "s", "g", "$", "mkstream"]),
+            Frame::Simple("OK".to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["xgroup", "create", "s", "g", "0"]),
+            Frame::Error("BUSYGROUP Consumer Group name already exists".to_string())
+        );
+        for id in ["1-0", "2-0", "3-0"] {
+            run(&mut ks, &["xadd", "s", id, "url", "http://a"]);
+        }
+
+        let delivered = read(
+            &mut ks,
+            &[
+                "xreadgroup",
+                "group",
+                "g",
+                "alice",
+                "count",
+                "2",
+                "streams",
+                "s",
+                ">",
+            ],
+        );
+        let frame = delivered.unwrap().unwrap();
+        let streams = match frame {
+            Frame::Array(streams) => streams,
+            other => panic!("unexpected {:?}", other),
+        };
+        match &streams[0] {
+            Frame::Array(parts) => assert_eq!(ids(parts[1].clone()), ["1-0", "2-0"]),
+            other => panic!("unexpected {:?}", other),
+        }
+        read(
+            &mut ks,
+            &["xreadgroup", "group", "g", "bob", "streams", "s", ">"],
+        )
+        .unwrap();
+        // 都交出去了，再读是空的
+        assert_eq!(
+            read(
+                &mut ks,
+                &["xreadgroup", "group", "g", "bob", "streams", "s", ">"]
+            ),
+            Ok(None)
+        );
+
+        assert_eq!(
+            run(&mut ks, &["xack", "s", "g", "1-0", "9-0"]),
+            Frame::Integer(1)
+        );
+        match run(&mut ks, &["xpending", "s", "g"]) {
+            Frame::Array(summary) => {
+                assert_eq!(summary[0], Frame::Integer(2));
+                assert_eq!(summary[1], Frame::Bulk(Bytes::from("2-0")));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            ids(run(&mut ks, &["xpending", "s", "g", "-", "+", "10", "bob"])),
+            ["3-0"]
+        );
+
+        assert_eq!(
+            ids(run(
+                &mut ks,
+                &["xclaim", "s", "g", "bob", "0", "2-0", "justid"]
+            )),
+            ["2-0"]
+        );
+        assert_eq!(
+            ids(run(
+                &mut ks,
+                &["xpending", "s", "g", "-", "+", "10", "alice"]
+            )),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            run(&mut ks, &["xclaim", "s", "nope", "bob", "0", "2-0"]),
+            Frame::Error("NOGROUP No such key 's' or consumer group 'nope'".to_string())
+        );
+        assert!(read(
+            &mut ks,
+            &["xreadgroup", "group", "g", "bob", "streams", "s", "$"]
+        )
+        .is_err());
+    }
+}
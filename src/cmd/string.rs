@@ -0,0 +1,552 @@
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+
+use crate::connection::Connection;
+use crate::db::{self, Keyspace, Value, WRONGTYPE};
+use crate::frame::Frame;
+
+use super::expire::Deadline;
+use super::{
+    call_api, exact, int_arg, parse_int, upstream_reply, wrong_args, ConnectSnafu, Context, Result,
+    NOT_FLOAT, NOT_INTEGER, SYNTAX_ERROR,
+};
+use snafu::ResultExt;
+
+// MGET 同时访问上游的请求数上限，避免一个很长的 MGET 一下子打开大量连接
+const MGET_CONCURRENCY: usize = 16;
+
+// 和 Redis 的 proto-max-bulk-len 默认值一样，SETRANGE 不能把字符串变得比它还长
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// 除了 GET / SET / MGET 之外的 string 命令
+#[derive(Debug)]
+pub enum StringCommand {
+    MSet {
+        pairs: Vec<(Bytes, Bytes)>,
+        nx: bool,
+    },
+    IncrBy {
+        name: &'static str,
+        key: Bytes,
+        delta: i64,
+    },
+    IncrByFloat {
+        key: Bytes,
+        delta: f64,
+    },
+    Append {
+        key: Bytes,
+        value: Bytes,
+    },
+    Strlen {
+        key: Bytes,
+    },
+    GetRange {
+        key: Bytes,
+        start: i64,
+        end: i64,
+    },
+    SetRange {
+        key: Bytes,
+        offset: usize,
+        value: Bytes,
+    },
+    GetDel {
+        key: Bytes,
+    },
+    GetEx {
+        key: Bytes,
+        ttl: Option<GetExTtl>,
+    },
+}
+
+/// GETEX 修改过期时间的方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GetExTtl {
+    Persist,
+    Deadline(Deadline),
+}
+
+impl StringCommand {
+    /// 这一组命令的命令名
+    pub const NAMES: &'static [&'static str] = &[
+        "append",
+        "decr",
+        "decrby",
+        "getdel",
+        "getex",
+        "getrange",
+        "incr",
+        "incrby",
+        "incrbyfloat",
+        "mset",
+        "msetnx",
+        "setrange",
+        "strlen",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StringCommand::MSet { nx: false, .. } => "mset",
+            StringCommand::MSet { nx: true, .. } => "msetnx",
+            StringCommand::IncrBy { name, .. } => name,
+            StringCommand::IncrByFloat { .. } => "incrbyfloat",
+            StringCommand::Append { .. } => "append",
+            StringCommand::Strlen { .. } => "strlen",
+            StringCommand::GetRange { .. } => "getrange",
+            StringCommand::SetRange { .. } => "setrange",
+            StringCommand::GetDel { .. } => "getdel",
+            StringCommand::GetEx { .. } => "getex",
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            StringCommand::MSet { pairs, .. } => pairs.iter().map(|(k, _)| k.as_ref()).collect(),
+            StringCommand::IncrBy { key, .. }
+            | StringCommand::IncrByFloat { key, .. }
+            | StringCommand::Append { key, .. }
+            | StringCommand::Strlen { key }
+            | StringCommand::GetRange { key, .. }
+            | StringCommand::SetRange { key, .. }
+            | StringCommand::GetDel { key }
+            | StringCommand::GetEx { key, .. } => vec![key],
+        }
+    }
+
+    /// `name` 是 `NAMES` 里的一个
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<StringCommand, String> {
+        let cmd = match name {
+            "mset" | "msetnx" => {
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Err(wrong_args(name));
+                }
+                let mut args = args.into_iter();
+                let mut pairs = Vec::new();
+                while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                    pairs.push((key, value));
+                }
+                StringCommand::MSet {
+                    pairs,
+                    nx: name == "msetnx",
+                }
+            }
+            "incr" | "decr" => {
+                let [key] = exact(name, args)?;
+                let delta = if name == "incr" { 1 } else { -1 };
+                StringCommand::IncrBy { name, key, delta }
+            }
+            "incrby" | "decrby" => {
+                let [key, delta] = exact(name, args)?;
+                let mut delta = int_arg(&delta)?;
+                if name == "decrby" {
+                    delta = delta
+                        .checked_neg()
+                        .ok_or_else(|| "ERR decrement would overflow".to_string())?;
+                }
+                StringCommand::IncrBy { name, key, delta }
+            }
+            "incrbyfloat" => {
+                let [key, delta] = exact(name, args)?;
+                let delta = parse_float(&delta).ok_or_else(|| NOT_FLOAT.to_string())?;
+                StringCommand::IncrByFloat { key, delta }
+            }
+            "append" => {
+                let [key, value] = exact(name, args)?;
+                StringCommand::Append { key, value }
+            }
+            "strlen" => {
+                let [key] = exact(name, args)?;
+                StringCommand::Strlen { key }
+            }
+            "getrange" => {
+                let [key, start, end] = exact(name, args)?;
+                StringCommand::GetRange {
+                    key,
+                    start: int_arg(&start)?,
+                    end: int_arg(&end)?,
+                }
+            }
+            "setrange" => {
+                let [key, offset, value] = exact(name, args)?;
+                let offset = usize::try_from(int_arg(&offset)?)
+                    .map_err(|_| "ERR offset is out of range".to_string())?;
+                StringCommand::SetRange { key, offset, value }
+            }
+            "getdel" => {
+                let [key] = exact(name, args)?;
+                StringCommand::GetDel { key }
+            }
+            _ => {
+                let mut args = args.into_iter();
+                let key = args.next().ok_or_else(|| wrong_args(name))?;
+                let mut ttl = None;
+                while let Some(arg) = args.next() {
+                    let option = String::from_utf8_lossy(&arg).to_lowercase();
+                    ttl = match option.as_str() {
+                        _ if ttl.is_some() => return Err(SYNTAX_ERROR.to_string()),
+                        "persist" => Some(GetExTtl::Persist),
+                        "ex" | "px" | "exat" | "pxat" => {
+                            let value = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+                            let deadline = Deadline::parse_option(&option, &value, name)?;
+                            Some(GetExTtl::Deadline(deadline))
+                        }
+                        _ => return Err(SYNTAX_ERROR.to_string()),
+                    };
+                }
+                StringCommand::GetEx { key, ttl }
+            }
+        };
+
+        Ok(cmd)
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self.run(keyspace) {
+            Ok(frame) => frame,
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn run(self, keyspace: &mut Keyspace) -> Result<Frame, String> {
+        let frame = match self {
+            StringCommand::MSet { pairs, nx } => {
+                // MSETNX 只要有一个 key 已经存在，就一个都不写
+                if nx && pairs.iter().any(|(key, _)| keyspace.contains(key)) {
+                    return Ok(Frame::Integer(0));
+                }
+                for (key, value) in pairs {
+                    keyspace.insert(key, Value::String(value), None);
+                }
+                if nx {
+                    Frame::Integer(1)
+                } else {
+                    Frame::Simple("OK".to_string())
+                }
+            }
+            StringCommand::IncrBy { key, delta, .. } => {
+                let current = match string(keyspace.peek(&key))? {
+                    Some(s) => parse_int(s).ok_or_else(|| NOT_INTEGER.to_string())?,
+                    None => 0,
+                };
+                let n = current
+                    .checked_add(delta)
+                    .ok_or_else(|| "ERR increment or decrement would overflow".to_string())?;
+                keyspace.set_value(key, Value::String(Bytes::from(n.to_string())));
+                Frame::Integer(n)
+            }
+            StringCommand::IncrByFloat { key, delta } => {
+                let current = match string(keyspace.peek(&key))? {
+                    Some(s) => parse_float(s).ok_or_else(|| NOT_FLOAT.to_string())?,
+                    None => 0.0,
+                };
+                let n = current + delta;
+                if !n.is_finite() {
+                    return Err("ERR increment would produce NaN or Infinity".to_string());
+                }
+                let n = Bytes::from(n.to_string());
+                keyspace.set_value(key, Value::String(n.clone()));
+                Frame::Bulk(n)
+            }
+            StringCommand::Append { key, value } => {
+                let mut s = string(keyspace.peek(&key))?
+                    .map(|s| s.to_vec())
+                    .unwrap_or_default();
+                s.extend_from_slice(&value);
+                let len = s.len();
+                keyspace.set_value(key, Value::String(Bytes::from(s)));
+                Frame::Integer(len as i64)
+            }
+            StringCommand::Strlen { key } => {
+                Frame::Integer(string(keyspace.get(&key))?.map_or(0, |s| s.len()) as i64)
+            }
+            StringCommand::GetRange { key, start, end } => {
+                let s = string(keyspace.get(&key))?.cloned().unwrap_or_default();
+                match range(s.len(), start, end) {
+                    Some((start, end)) => Frame::Bulk(s.slice(start..=end)),
+                    None => Frame::Bulk(Bytes::new()),
+                }
+            }
+            StringCommand::SetRange { key, offset, value } => {
+                let current = string(keyspace.peek(&key))?.cloned();
+                // 什么都不写的话不会创建 key
+                if value.is_empty() {
+                    return Ok(Frame::Integer(current.map_or(0, |s| s.len()) as i64));
+                }
+                if offset.saturating_add(value.len()) > MAX_STRING_LEN {
+                    return Err(
+                        "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+                    );
+                }
+
+                let mut s = current.map(|s| s.to_vec()).unwrap_or_default();
+                if s.len() < offset + value.len() {
+                    s.resize(offset + value.len(), 0);
+                }
+                s[offset..offset + value.len()].copy_from_slice(&value);
+                let len = s.len();
+                keyspace.set_value(key, Value::String(Bytes::from(s)));
+                Frame::Integer(len as i64)
+            }
+            StringCommand::GetDel { key } => match string(keyspace.get(&key))?.cloned() {
+                Some(s) => {
+                    keyspace.remove(&key);
+                    Frame::Bulk(s)
+                }
+                None => Frame::Null,
+            },
+            StringCommand::GetEx { key, ttl } => {
+                let s = match string(keyspace.get(&key))?.cloned() {
+                    Some(s) => s,
+                    None => return Ok(Frame::Null),
+                };
+                match ttl {
+                    None => {}
+                    Some(GetExTtl::Persist) => {
+                        keyspace.set_expires_at(&key, None);
+                    }
+                    Some(GetExTtl::Deadline(deadline)) => match deadline.instant() {
+                        Some(when) => {
+                            keyspace.set_expires_at(&key, Some(when));
+                        }
+                        None => {
+                            keyspace.remove(&key);
+                        }
+                    },
+                }
+                Frame::Bulk(s)
+            }
+        };
+
+        Ok(frame)
+    }
+}
+
+/// MGET key [key ...]：keyspace 里没有的 http(s) key 并发访问上游
+#[derive(Debug)]
+pub struct MGet {
+    pub(crate) keys: Vec<Bytes>,
+}
+
+impl MGet {
+    pub fn parse(args: Vec<Bytes>) -> Result<MGet, String> {
+        if args.is_empty() {
+            return Err(wrong_args("mget"));
+        }
+        Ok(MGet { keys: args })
+    }
+
+    pub async fn apply(self, ctx: &mut Context, connection: &mut Connection) -> Result<()> {
+        // 不是 string 的 key 和 Redis 一样当作不存在，回复 nil
+        let mut replies = {
+            let keys: Vec<&[u8]> = self.keys.iter().map(|key| key.as_ref()).collect();
+            self.cached(&mut *ctx.state.db.lock_keys(&keys).await)
+        };
+
+        let urls: Vec<(usize, &str)> = self
+            .keys
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| replies[*i].is_none())
+            .filter_map(|(i, key)| {
+                let url = std::str::from_utf8(key).ok()?;
+                db::is_url(key).then_some((i, url))
+            })
+            .collect();
+
+        // 先把 future 都建好再交给 stream。stream 里放借用参数的闭包的话，
+        // 编译器推导不出连接的任务满足 tokio::spawn 的要求
+        let shared: &Context = ctx;
+        let requests: Vec<_> = urls.iter().map(|(_, url)| call_api(url, shared)).collect();
+        let fetched: Vec<_> = stream::iter(requests)
+            .buffered(MGET_CONCURRENCY)
+            .collect()
+            .await;
+
+        // slowlog 里记最后一个访问的上游
+        for ((i, _), (upstream, res)) in urls.iter().zip(fetched) {
+            replies[*i] = Some(upstream_reply(res));
+            if upstream.is_some() {
+                ctx.upstream = upstream;
+            }
+        }
+
+        let response = Frame::Array(
+            replies
+                .into_iter()
+                .map(|reply| reply.unwrap_or(Frame::Null))
+                .collect(),
+        );
+        connection
+            .write_frame(&response)
+            .await
+            .context(ConnectSnafu)?;
+
+        Ok(())
+    }
+
+    /// 在事务里只读 keyspace，不访问上游
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        Frame::Array(
+            self.cached(keyspace)
+                .into_iter()
+                .map(|reply| reply.unwrap_or(Frame::Null))
+                .collect(),
+        )
+    }
+
+    fn cached(&self, keyspace: &mut Keyspace) -> Vec<Option<Frame>> {
+        self.keys
+            .iter()
+            .map(|key| {
+                keyspace.get(key).map(|value| match value.as_string() {
+                    Some(s) => Frame::Bulk(s.clone()),
+                    None => Frame::Null,
+                })
+            })
+            .collect()
+    }
+}
+
+// 取出 string 的值；key 存在但不是 string 时是 WRONGTYPE 错误
+fn string(value: Option<&Value>) -> Result<Option<&Bytes>, String> {
+    match value {
+        Some(value) => value
+            .as_string()
+            .map(Some)
+            .ok_or_else(|| WRONGTYPE.to_string()),
+        None => Ok(None),
+    }
+}
+
+fn parse_float(arg: &[u8]) -> Option<f64> {
+    let n: f64 = std::str::from_utf8(arg).ok()?.parse().ok()?;
+    n.is_finite().then_some(n)
+}
+
+// GETRANGE 的下标：负数从末尾算起，超出范围的截断。结果为空时返回 None
+fn range(len: usize, start: i64, end: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    if len == 0 || (start < 0 && end < 0 && start > end) {
+        return None;
+    }
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 {
+        (len + end).max(0)
+    } else {
+        end.min(len - 1)
+    };
+    (start <= end).then_some((start as usize, end as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    fn run(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let name = StringCommand::NAMES
+            .iter()
+            .find(|n| **n == args[0])
+            .unwrap();
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        match StringCommand::parse(name, args) {
+            Ok(cmd) => cmd.execute(keyspace),
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn reply(frame: Frame) -> String {
+        match frame {
+            Frame::Integer(n) => n.to_string(),
+            Frame::Bulk(s) => String::from_utf8(s.to_vec()).unwrap(),
+            Frame::Simple(s) | Frame::Error(s) => s,
+            Frame::Null => "nil".to_string(),
+            Frame::Array(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn ts_counters() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        assert_eq!(reply(run(&mut ks, &["incr", "n"])), "1");
+        assert_eq!(reply(run(&mut ks, &["incrby", "n", "41"])), "42");
+        assert_eq!(reply(run(&mut ks, &["decrby", "n", "50"])), "-8");
+        assert_eq!(reply(run(&mut ks, &["decr", "n"])), "-9");
+        assert_eq!(reply(run(&mut ks, &["incrbyfloat", "n", "1.5"])), "-7.5");
+        assert_eq!(reply(run(&mut ks, &["incr", "n"])), NOT_INTEGER);
+
+        run(
+            &mut ks,
+            &["mset", "big", "9223372036854775807", "text", "abc"],
+        );
+        assert_eq!(
+            reply(run(&mut ks, &["incr", "big"])),
+            "ERR increment or decrement would overflow"
+        );
+        assert_eq!(reply(run(&mut ks, &["incr", "text"])), NOT_INTEGER);
+        assert_eq!(reply(run(&mut ks, &["incrby", "n", "+1"])), NOT_INTEGER);
+        assert_eq!(
+            reply(run(&mut ks, &["decrby", "n", "-9223372036854775808"])),
+            "ERR decrement would overflow"
+        );
+        assert_eq!(reply(run(&mut ks, &["incrbyfloat", "n", "inf"])), NOT_FLOAT);
+    }
+
+    #[test]
+    fn ts_strings() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        assert_eq!(reply(run(&mut ks, &["append", "s", "Hello"])), "5");
+        assert_eq!(reply(run(&mut ks, &["append", "s", " World"])), "11");
+        assert_eq!(reply(run(&mut ks, &["strlen", "s"])), "11");
+        assert_eq!(reply(run(&mut ks, &["getrange", "s", "0", "4"])), "Hello");
+        assert_eq!(reply(run(&mut ks, &["getrange", "s", "-5", "-1"])), "World");
+        assert_eq!(reply(run(&mut ks, &["getrange", "s", "5", "3"])), "");
+        assert_eq!(
+            reply(run(&mut ks, &["getrange", "s", "0", "100"])),
+            "Hello World"
+        );
+
+        assert_eq!(reply(run(&mut ks, &["setrange", "s", "6", "Redis"])), "11");
+        assert_eq!(reply(run(&mut ks, &["setrange", "p", "2", "x"])), "3");
+        assert_eq!(reply(run(&mut ks, &["getdel", "p"])), "\0\0x");
+        assert_eq!(reply(run(&mut ks, &["getdel", "p"])), "nil");
+        assert_eq!(reply(run(&mut ks, &["setrange", "p", "0", ""])), "0");
+        assert!(!ks.contains(b"p"));
+
+        assert_eq!(reply(run(&mut ks, &["msetnx", "s", "1", "t", "2"])), "0");
+        assert!(!ks.contains(b"t"));
+        assert_eq!(reply(run(&mut ks, &["msetnx", "t", "1", "u", "2"])), "1");
+
+        assert_eq!(
+            reply(run(&mut ks, &["getex", "s", "ex", "100"])),
+            "Hello Redis"
+        );
+        assert!(ks.expires_at(b"s").unwrap().is_some());
+        // 修改值的命令保留过期时间
+        run(&mut ks, &["append", "s", "!"]);
+        assert!(ks.expires_at(b"s").unwrap().is_some());
+        run(&mut ks, &["getex", "s", "persist"]);
+        assert_eq!(ks.expires_at(b"s"), Some(None));
+
+        assert_eq!(
+            reply(run(&mut ks, &["getex", "s", "persist", "ex", "1"])),
+            SYNTAX_ERROR
+        );
+        assert_eq!(
+            reply(run(&mut ks, &["mset", "a"])),
+            "ERR wrong number of arguments for 'mset' command"
+        );
+    }
+}
@@ -0,0 +1,679 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+
+use crate::db::{Keyspace, SortedSet, Value, WRONGTYPE};
+use crate::frame::Frame;
+
+use super::{exact, int_arg, positive, wrong_args, NOT_FLOAT, SYNTAX_ERROR};
+
+/// ZADD 的选项
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ZAddFlags {
+    /// 只加入新成员，不修改已有的
+    nx: bool,
+    /// 只修改已有的成员，不加入新的
+    xx: bool,
+    /// 新的分数比原来的大才修改
+    gt: bool,
+    /// 新的分数比原来的小才修改
+    lt: bool,
+    /// 回复加入和修改了的成员数，而不只是加入的
+    ch: bool,
+    /// 像 ZINCRBY 一样把分数加上去，回复新的分数
+    incr: bool,
+}
+
+/// ZRANGE 按什么取范围
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeBy {
+    Index(i64, i64),
+    Score(Bound<f64>, Bound<f64>),
+    Lex(Bound<Bytes>, Bound<Bytes>),
+    /// BYLEX 的 min 是 `+` 或者 max 是 `-`，结果一定是空的
+    Nothing,
+}
+
+/// sorted set 的命令
+#[derive(Debug)]
+pub enum ZSetCommand {
+    Add {
+        key: Bytes,
+        flags: ZAddFlags,
+        pairs: Vec<(f64, Bytes)>,
+    },
+    Range {
+        key: Bytes,
+        by: RangeBy,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+        with_scores: bool,
+    },
+    Rank {
+        key: Bytes,
+        member: Bytes,
+    },
+    Score {
+        key: Bytes,
+        member: Bytes,
+    },
+    Rem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    IncrBy {
+        key: Bytes,
+        delta: f64,
+        member: Bytes,
+    },
+    PopMin {
+        key: Bytes,
+        count: Option<usize>,
+    },
+    Card {
+        key: Bytes,
+    },
+}
+
+impl ZSetCommand {
+    /// 这一组命令的命令名
+    pub const NAMES: &'static [&'static str] = &[
+        "zadd", "zcard", "zincrby", "zpopmin", "zrange", "zrank", "zrem", "zscore",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZSetCommand::Add { .. } => "zadd",
+            ZSetCommand::Range { .. } => "zrange",
+            ZSetCommand::Rank { .. } => "zrank",
+            ZSetCommand::Score { .. } => "zscore",
+            ZSetCommand::Rem { .. } => "zrem",
+            ZSetCommand::IncrBy { .. } => "zincrby",
+            ZSetCommand::PopMin { .. } => "zpopmin",
+            ZSetCommand::Card { .. } => "zcard",
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            ZSetCommand::Add { key, .. }
+            | ZSetCommand::Range { key, .. }
+            | ZSetCommand::Rank { key, .. }
+            | ZSetCommand::Score { key, .. }
+            | ZSetCommand::Rem { key, .. }
+            | ZSetCommand::IncrBy { key, .. }
+            | ZSetCommand::PopMin { key, .. }
+            | ZSetCommand::Card { key } => key,
+        }
+    }
+
+    /// `name` 是 `NAMES` 里的一个
+    pub fn parse(name: &'static str, args: Vec<Bytes>) -> Result<ZSetCommand, String> {
+        let cmd = match name {
+            "zadd" => parse_zadd(args)?,
+            "zrange" => parse_zrange(args)?,
+            "zrank" | "zscore" => {
+                let [key, member] = exact(name, args)?;
+                if name == "zrank" {
+                    ZSetCommand::Rank { key, member }
+                } else {
+                    ZSetCommand::Score { key, member }
+                }
+            }
+            "zrem" => {
+                if args.len() < 2 {
+                    return Err(wrong_args(name));
+                }
+                let mut args = args.into_iter();
+                let key = args.next().unwrap_or_default();
+                ZSetCommand::Rem {
+                    key,
+                    members: args.collect(),
+                }
+            }
+            "zincrby" => {
+                let [key, delta, member] = exact(name, args)?;
+                let delta = parse_score(&delta)?;
+                ZSetCommand::IncrBy { key, delta, member }
+            }
+            "zpopmin" => {
+                let mut args = args.into_iter();
+                let key = args.next().ok_or_else(|| wrong_args(name))?;
+                let count = match args.next() {
+                    Some(count) => Some(positive(&count)?),
+                    None => None,
+                };
+                if args.next().is_some() {
+                    return Err(SYNTAX_ERROR.to_string());
+                }
+                ZSetCommand::PopMin { key, count }
+            }
+            _ => {
+                let [key] = exact(name, args)?;
+                ZSetCommand::Card { key }
+            }
+        };
+        Ok(cmd)
+    }
+
+    pub fn execute(self, keyspace: &mut Keyspace) -> Frame {
+        match self.run(keyspace) {
+            Ok(frame) => frame,
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn run(self, keyspace: &mut Keyspace) -> Result<Frame, String> {
+        let frame = match self {
+            ZSetCommand::Add { key, flags, pairs } => add(keyspace, key, flags, pairs)?,
+            ZSetCommand::Range {
+                key,
+                by,
+                rev,
+                limit,
+                with_scores,
+            } => {
+                let zset = match zset(keyspace.get(&key))? {
+                    Some(zset) => zset,
+                    None => return Ok(Frame::Array(Vec::new())),
+                };
+                let mut members = range(zset, by, rev);
+                if let Some((offset, count)) = limit {
+                    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+                    // count 是负数表示 offset 之后的全部
+                    let count = usize::try_from(count).unwrap_or(usize::MAX);
+                    members = members.into_iter().skip(offset).take(count).collect();
+                }
+                reply(members, with_scores)
+            }
+            ZSetCommand::Rank { key, member } => {
+                match zset(keyspace.get(&key))?.and_then(|zset| zset.rank(&member)) {
+                    Some(rank) => Frame::Integer(rank as i64),
+                    None => Frame::Null,
+                }
+            }
+            ZSetCommand::Score { key, member } => {
+                match zset(keyspace.get(&key))?.and_then(|zset| zset.score(&member)) {
+                    Some(score) => score_frame(score),
+                    None => Frame::Null,
+                }
+            }
+            ZSetCommand::Rem { key, members } => {
+                if zset(keyspace.peek(&key))?.is_none() {
+                    return Ok(Frame::Integer(0));
+                }
+                let removed = update(keyspace, &key, |zset| {
+                    members
+                        .iter()
+                        .filter(|member| zset.remove(member).is_some())
+                        .count()
+                })?;
+                Frame::Integer(removed as i64)
+            }
+            ZSetCommand::IncrBy { key, delta, member } => {
+                let flags = ZAddFlags {
+                    incr: true,
+                    ..ZAddFlags::default()
+                };
+                add(keyspace, key, flags, vec![(delta, member)])?
+            }
+            ZSetCommand::PopMin { key, count } => {
+                if zset(keyspace.peek(&key))?.is_none() {
+                    return Ok(Frame::Array(Vec::new()));
+                }
+                let popped = update(keyspace, &key, |zset| {
+                    let first: Vec<(Bytes, f64)> = zset
+                        .iter()
+                        .take(count.unwrap_or(1))
+                        .map(|(member, score)| (member.clone(), score))
+                        .collect();
+                    for (member, _) in &first {
+                        zset.remove(member);
+                    }
+                    first
+                })?;
+                reply(popped, true)
+            }
+            ZSetCommand::Card { key } => {
+                Frame::Integer(zset(keyspace.get(&key))?.map_or(0, |zset| zset.len()) as i64)
+            }
+        };
+
+        Ok(frame)
+    }
+}
+
+// ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]
+fn parse_zadd(args: Vec<Bytes>) -> Result<ZSetCommand, String> {
+    let mut args = args.into_iter().peekable();
+    let key = args.next().ok_or_else(|| wrong_args("zadd"))?;
+
+    let mut flags = ZAddFlags::default();
+    while let Some(arg) = args.peek() {
+        match String::from_utf8_lossy(arg).to_lowercase().as_str() {
+            "nx" => flags.nx = true,
+            "xx" => flags.xx = true,
+            "gt" => flags.gt = true,
+            "lt" => flags.lt = true,
+            "ch" => flags.ch = true,
+            "incr" => flags.incr = true,
+            _ => break,
+        }
+        args.next();
+    }
+    if flags.nx && flags.xx {
+        return Err("ERR XX and NX options at the same time are not compatible".to_string());
+    }
+    if (flags.gt && flags.lt) || (flags.nx && (flags.gt || flags.lt)) {
+        return Err(
+            "ERR GT, LT, and/or NX options at the same time are not compatible".to_string(),
+        );
+    }
+
+    let rest: Vec<Bytes> = args.collect();
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+    if flags.incr && rest.len() > 2 {
+        return Err("ERR INCR option supports a single increment-element pair".to_string());
+    }
+    let pairs = rest
+        .chunks(2)
+        .map(|pair| Ok((parse_score(&pair[0])?, pair[1].clone())))
+        .collect::<Result<_, String>>()?;
+
+    Ok(ZSetCommand::Add { key, flags, pairs })
+}
+
+// ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+fn parse_zrange(args: Vec<Bytes>) -> Result<ZSetCommand, String> {
+    if args.len() < 3 {
+        return Err(wrong_args("zrange"));
+    }
+    let mut args = args.into_iter();
+    let (key, start, stop) = match (args.next(), args.next(), args.next()) {
+        (Some(key), Some(start), Some(stop)) => (key, start, stop),
+        _ => return Err(wrong_args("zrange")),
+    };
+
+    let (mut by_score, mut by_lex, mut rev, mut with_scores) = (false, false, false, false);
+    let mut limit = None;
+    while let Some(arg) = args.next() {
+        match String::from_utf8_lossy(&arg).to_lowercase().as_str() {
+            "byscore" => by_score = true,
+            "bylex" => by_lex = true,
+            "rev" => rev = true,
+            "withscores" => with_scores = true,
+            "limit" => match (args.next(), args.next()) {
+                (Some(offset), Some(count)) => limit = Some((int_arg(&offset)?, int_arg(&count)?)),
+                _ => return Err(SYNTAX_ERROR.to_string()),
+            },
+            _ => return Err(SYNTAX_ERROR.to_string()),
+        }
+    }
+    if by_score && by_lex {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+    if limit.is_some() && !by_score && !by_lex {
+        return Err(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .to_string(),
+        );
+    }
+    if with_scores && by_lex {
+        return Err(
+            "ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+        );
+    }
+
+    // REV 的时候，BYSCORE / BYLEX 的参数是先写 max 再写 min
+    let (min, max) = if rev && (by_score || by_lex) {
+        (stop, start)
+    } else {
+        (start, stop)
+    };
+    let by = if by_score {
+        RangeBy::Score(score_bound(&min)?, score_bound(&max)?)
+    } else if by_lex {
+        match (lex_bound(&min)?, lex_bound(&max)?) {
+            (LexBound::Max, _) | (_, LexBound::Min) => RangeBy::Nothing,
+            (min, max) => RangeBy::Lex(min.into(), max.into()),
+        }
+    } else {
+        RangeBy::Index(int_arg(&min)?, int_arg(&max)?)
+    };
+
+    Ok(ZSetCommand::Range {
+        key,
+        by,
+        rev,
+        limit,
+        with_scores,
+    })
+}
+
+// ZADD / ZINCRBY：按选项加入或者修改成员
+fn add(
+    keyspace: &mut Keyspace,
+    key: Bytes,
+    flags: ZAddFlags,
+    pairs: Vec<(f64, Bytes)>,
+) -> Result<Frame, String> {
+    if zset(keyspace.peek(&key))?.is_none() && flags.xx {
+        // XX 不会创建 key
+        return Ok(if flags.incr {
+            Frame::Null
+        } else {
+            Frame::Integer(0)
+        });
+    }
+
+    let (changed, incremented) = update(keyspace, &key, |zset| {
+        let (mut added, mut updated) = (0, 0);
+        let mut incremented = None;
+        for (score, member) in pairs {
+            let old = zset.score(&member);
+            let new = match (old, flags.incr) {
+                (Some(old), true) => old + score,
+                _ => score,
+            };
+            if new.is_nan() {
+                return Err("ERR resulting score is not a number (NaN)".to_string());
+            }
+            let skip = match old {
+                None => flags.xx,
+                Some(old) => flags.nx || (flags.gt && new <= old) || (flags.lt && new >= old),
+            };
+            if skip {
+                continue;
+            }
+            match old {
+                None => added += 1,
+                Some(old) if old != new => updated += 1,
+                Some(_) => {}
+            }
+            zset.insert(member, new);
+            incremented = Some(new);
+        }
+        let changed = if flags.ch { added + updated } else { added };
+        Ok((changed, incremented))
+    })??;
+
+    Ok(if flags.incr {
+        incremented.map_or(Frame::Null, score_frame)
+    } else {
+        Frame::Integer(changed)
+    })
+}
+
+// ZRANGE 的结果，按要求的顺序
+fn range(zset: &SortedSet, by: RangeBy, rev: bool) -> Vec<(Bytes, f64)> {
+    let mut members: Vec<(Bytes, f64)> = match by {
+        RangeBy::Index(start, stop) => {
+            let len = zset.len() as i64;
+            let start = if start < 0 { len + start } else { start }.max(0);
+            let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+            if start > stop {
+                return Vec::new();
+            }
+            let (skip, take) = (start as usize, (stop - start + 1) as usize);
+            let pick = |(member, score): (&Bytes, f64)| (member.clone(), score);
+            // REV 时下标从分数最大的开始算
+            return if rev {
+                zset.iter().rev().skip(skip).take(take).map(pick).collect()
+            } else {
+                zset.iter().skip(skip).take(take).map(pick).collect()
+            };
+        }
+        RangeBy::Score(min, max) => zset
+            .range_by_score(min, max)
+            .map(|(member, score)| (member.clone(), score))
+            .collect(),
+        RangeBy::Lex(min, max) => zset
+            .range_by_lex(min, max)
+            .map(|(member, score)| (member.clone(), score))
+            .collect(),
+        RangeBy::Nothing => Vec::new(),
+    };
+    if rev {
+        members.reverse();
+    }
+    members
+}
+
+fn reply(members: Vec<(Bytes, f64)>, with_scores: bool) -> Frame {
+    Frame::Array(
+        members
+            .into_iter()
+            .flat_map(|(member, score)| {
+                let score = with_scores.then(|| score_frame(score));
+                std::iter::once(Frame::Bulk(member)).chain(score)
+            })
+            .collect(),
+    )
+}
+
+// 分数的格式和 Redis 一样是最短的能精确表示的写法，比如 1.5、3、inf
+fn score_frame(score: f64) -> Frame {
+    Frame::Bulk(Bytes::from(score.to_string()))
+}
+
+// 分数可以是 inf / -inf，但不能是 NaN
+fn parse_score(arg: &[u8]) -> Result<f64, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| NOT_FLOAT.to_string())
+}
+
+// BYSCORE 的边界：`(` 开头的不包括边界本身
+fn score_bound(arg: &[u8]) -> Result<Bound<f64>, String> {
+    let invalid = || "ERR min or max is not a float".to_string();
+    match arg.strip_prefix(b"(") {
+        Some(score) => Ok(Bound::Excluded(parse_score(score).map_err(|_| invalid())?)),
+        None => Ok(Bound::Included(parse_score(arg).map_err(|_| invalid())?)),
+    }
+}
+
+// BYLEX 的边界：`[` 包括、`(` 不包括边界本身，`-` / `+` 是最小 / 最大
+enum LexBound {
+    Min,
+    Max,
+    Included(Bytes),
+    Excluded(Bytes),
+}
+
+impl From<LexBound> for Bound<Bytes> {
+    fn from(bound: LexBound) -> Bound<Bytes> {
+        match bound {
+            LexBound::Included(m) => Bound::Included(m),
+            LexBound::Excluded(m) => Bound::Excluded(m),
+            LexBound::Min | LexBound::Max => Bound::Unbounded,
+        }
+    }
+}
+
+fn lex_bound(arg: &Bytes) -> Result<LexBound, String> {
+    match arg.first() {
+        Some(b'-') if arg.len() == 1 => Ok(LexBound::Min),
+        Some(b'+') if arg.len() == 1 => Ok(LexBound::Max),
+        Some(b'[') => Ok(LexBound::Included(arg.slice(1..))),
+        Some(b'(') => Ok(LexBound::Excluded(arg.slice(1..))),
+        _ => Err("ERR min or max not valid string range item".to_string()),
+    }
+}
+
+// 取出 sorted set；key 存在但不是 sorted set 时是 WRONGTYPE 错误
+fn zset(value: Option<&Value>) -> Result<Option<&SortedSet>, String> {
+    match value {
+        Some(value) => value
+            .as_sorted_set()
+            .map(Some)
+            .ok_or_else(|| WRONGTYPE.to_string()),
+        None => Ok(None),
+    }
+}
+
+// 修改 sorted set，key 不存在时先建一个空的。改完之后空了的话 key 会被删除
+fn update<R>(
+    keyspace: &mut Keyspace,
+    key: &Bytes,
+    f: impl FnOnce(&mut SortedSet) -> R,
+) -> Result<R, String> {
+    if !keyspace.contains(key) {
+        keyspace.insert(key.clone(), Value::SortedSet(SortedSet::default()), None);
+    }
+    keyspace
+        .modify(key, |value| value.as_sorted_set_mut().map(f))
+        .flatten()
+        .ok_or_else(|| WRONGTYPE.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    fn run(keyspace: &mut Keyspace, args: &[&str]) -> Frame {
+        let name = ZSetCommand::NAMES.iter().find(|n| **n == args[0]).unwrap();
+        let args = args[1..]
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect();
+        match ZSetCommand::parse(name, args) {
+            Ok(cmd) => cmd.execute(keyspace),
+            Err(msg) => Frame::Error(msg),
+        }
+    }
+
+    fn strings(frame: Frame) -> Vec<String> {
+        match frame {
+            Frame::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Frame::Bulk(s) => String::from_utf8(s.to_vec()).unwrap(),
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ts_zadd() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+
+        assert_eq!(
+            run(&mut ks, &["zadd", "z", "1", "a", "2", "b", "3", "c"]),
+            Frame::Integer(3)
+        );
+        // GT 只会调大分数；CH 把修改的也算进去
+        assert_eq!(
+            run(
+                &mut ks,
+                &["zadd", "z", "gt", "ch", "0", "a", "5", "b", "4", "d"]
+            ),
+            Frame::Integer(2)
+        );
+        assert_eq!(
+            run(&mut ks, &["zscore", "z", "b"]),
+            Frame::Bulk(Bytes::from("5"))
+        );
+        assert_eq!(
+            run(&mut ks, &["zadd", "z", "xx", "incr", "1.5", "a"]),
+            Frame::Bulk(Bytes::from("2.5"))
+        );
+        assert_eq!(
+            run(&mut ks, &["zadd", "z", "nx", "incr", "1", "a"]),
+            Frame::Null
+        );
+        assert_eq!(
+            run(&mut ks, &["zincrby", "z", "-10", "new"]),
+            Frame::Bulk(Bytes::from("-10"))
+        );
+        assert_eq!(run(&mut ks, &["zrank", "z", "a"]), Frame::Integer(1));
+        assert_eq!(run(&mut ks, &["zcard", "z"]), Frame::Integer(5));
+
+        assert_eq!(
+            run(&mut ks, &["zadd", "z", "nx", "xx", "1", "a"]),
+            Frame::Error("ERR XX and NX options at the same time are not compatible".to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["zadd", "z", "1", "a", "nan", "b"]),
+            Frame::Error(NOT_FLOAT.to_string())
+        );
+        assert_eq!(
+            run(&mut ks, &["zadd", "z", "incr", "inf", "c"]),
+            Frame::Bulk(Bytes::from("inf"))
+        );
+        assert_eq!(
+            run(&mut ks, &["zincrby", "z", "-inf", "c"]),
+            Frame::Error("ERR resulting score is not a number (NaN)".to_string())
+        );
+    }
+
+    #[test]
+    fn ts_zrange() {
+        let db = Db::new();
+        let mut ks = db.blocking_lock();
+        run(
+            &mut ks,
+            &["zadd", "z", "1", "a", "2", "b", "3", "c", "4", "d"],
+        );
+
+        assert_eq!(
+            strings(run(&mut ks, &["zrange", "z", "1", "-2"])),
+            ["b", "c"]
+        );
+        assert_eq!(
+            strings(run(
+                &mut ks,
+                &["zrange", "z", "0", "1", "rev", "withscores"]
+            )),
+            ["d", "4", "c", "3"]
+        );
+        assert_eq!(
+            strings(run(
+                &mut ks,
+                &["zrange", "z", "(1", "+inf", "byscore", "limit", "1", "-1"]
+            )),
+            ["c", "d"]
+        );
+        assert_eq!(
+            strings(run(
+                &mut ks,
+                &["zrange", "z", "3", "-inf", "byscore", "rev"]
+            )),
+            ["c", "b", "a"]
+        );
+
+        run(&mut ks, &["zadd", "l", "0", "a", "0", "b", "0", "c"]);
+        assert_eq!(
+            strings(run(&mut ks, &["zrange", "l", "[b", "-", "bylex", "rev"])),
+            ["b", "a"]
+        );
+        assert_eq!(
+            strings(run(&mut ks, &["zrange", "l", "(a", "+", "bylex"])),
+            ["b", "c"]
+        );
+        assert!(strings(run(&mut ks, &["zrange", "l", "+", "-", "bylex"])).is_empty());
+
+        assert_eq!(
+            strings(run(&mut ks, &["zpopmin", "z", "2"])),
+            ["a", "1", "b", "2"]
+        );
+        assert_eq!(
+            run(&mut ks, &["zrange", "z", "0", "1", "limit", "0", "1"]),
+            Frame::Error(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            run(&mut ks, &["zrange", "z", "x", "1", "byscore"]),
+            Frame::Error("ERR min or max is not a float".to_string())
+        );
+    }
+}
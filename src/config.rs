@@ -0,0 +1,591 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use snafu::{prelude::*, ResultExt};
+
+use crate::acl::User;
+use crate::db::{Policy, DEFAULT_SHARDS};
+use crate::frame::Limits;
+use crate::ratelimit::{Limit, Rule, Scope};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("failed to read config file {}. {}", path.display(), source))]
+    ReadError { path: PathBuf, source: io::Error },
+    #[snafu(display("bad config directive at line {}: {}", line, msg))]
+    DirectiveError { line: usize, msg: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// 监听地址：TCP 地址（host:port）或者 unix socket 的路径
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// 单个监听端点的配置。这里的设置只对从这个端点进来的连接生效
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub address: Address,
+    pub tls: bool,
+    // 从这个端点进来的连接不需要 AUTH，直接以 default 用户执行命令
+    pub noauth: bool,
+}
+
+/// server 的配置。配置文件的格式和 redis.conf 一样：每行一个指令，`#` 开头的是注释
+///
+/// ```text
+/// bind 127.0.0.1:6379 noauth
+/// bind 0.0.0.0:6380 tls
+/// unixsocket /tmp/rmr.sock
+/// tls-cert-file /etc/rmr/server.crt
+/// tls-key-file /etc/rmr/server.key
+/// maxclients 10000
+/// timeout 300
+/// tcp-keepalive 300
+/// proto-max-bulk-len 512mb
+/// client-query-buffer-limit 1gb
+/// shutdown-timeout 10
+/// metrics-bind 127.0.0.1:9121
+/// slowlog-log-slower-than 10000
+/// slowlog-max-len 128
+/// otlp-endpoint http://127.0.0.1:4318
+/// otlp-service-name rmr
+/// log-format json
+/// log-level info,rmr::server=debug
+/// logfile /var/log/rmr/rmr.log
+/// logfile-rotation daily
+/// requirepass foobared
+/// user alice on >secret +@read ~* url~https://api.example.com/*
+/// ratelimit-user batch 10 20
+/// ratelimit-client * 100
+/// ratelimit-upstream api.example.com 50
+/// upstream-stream upstream:responses
+/// upstream-stream-maxlen 10000
+/// keyspace-shards 16
+/// maxmemory 256mb
+/// maxmemory-policy allkeys-lru
+/// lua-time-limit 5000
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    /// 所有端点加起来最多同时服务多少个客户端
+    pub maxclients: usize,
+    /// 客户端空闲（没有发送任何命令）超过这么多秒就关闭连接，0 表示不限制
+    pub timeout: u64,
+    /// TCP keepalive 的间隔秒数，0 表示不开启
+    pub tcp_keepalive: u64,
+    /// 请求里单个 Bulk String 的最大字节数
+    pub proto_max_bulk_len: usize,
+    /// 请求里单个数组的最大元素个数
+    pub proto_max_array_len: usize,
+    /// 请求里数组最多嵌套几层
+    pub proto_max_nesting: usize,
+    /// 每个客户端还没有解析的输入最多能占多少字节
+    pub client_query_buffer_limit: usize,
+    /// 关闭 server 时，最多等待多少秒让客户端完成手上的命令，之后强制关闭
+    pub shutdown_timeout: u64,
+    /// admin 端口的地址，在上面通过 http 提供 `/metrics`。不设置就不开启
+    pub metrics_bind: Option<String>,
+    /// 执行时间（微秒，包括访问上游的时间）超过它的命令记到 slowlog 里。
+    /// 负数表示不记录，0 表示记录所有命令
+    pub slowlog_log_slower_than: i64,
+    /// slowlog 最多保留的条数，超过后丢掉最老的
+    pub slowlog_max_len: usize,
+    /// OTLP/HTTP collector 的地址，设置了才导出 trace
+    pub otlp_endpoint: Option<String>,
+    pub otlp_service_name: String,
+    pub log_format: LogFormat,
+    /// 日志级别，可以按模块设置，写法和 RUST_LOG 一样。设置了 RUST_LOG 的话以它为准
+    pub log_level: String,
+    /// 日志写到这个文件里（按 logfile-rotation 切分），不设置就写到标准输出
+    pub logfile: Option<PathBuf>,
+    pub logfile_rotation: LogRotation,
+    /// default 用户的密码。不设置的话 default 用户不需要密码，连接上来就自动登录
+    pub requirepass: Option<String>,
+    /// `user <name> <rules...>` 定义的 ACL 用户，规则和 ACL SETUSER 一样
+    pub users: Vec<User>,
+    /// `ratelimit-user` / `ratelimit-client` / `ratelimit-upstream <target> <rate> [burst]`
+    /// 定义的限流规则，在访问上游之前检查
+    pub ratelimits: Vec<Rule>,
+    /// 把每次上游的回复（url、状态码、内容）追加到这个 stream 里，
+    /// worker 可以用消费组分摊处理。不设置就不记录
+    pub upstream_stream: Option<String>,
+    /// upstream-stream 最多保留的 entry 数，超过后丢掉最老的。0 表示不限制
+    pub upstream_stream_maxlen: usize,
+    /// keyspace 分成多少个 shard，每个 shard 一把锁。访问不同 shard 的命令可以在多个线程上同时执行
+    pub keyspace_shards: usize,
+    /// keyspace 最多占用多少内存（包括 worker 缓存的上游结果和 upstream-stream），0 表示不限制
+    pub maxmemory: usize,
+    /// 超过 maxmemory 时怎么腾出空间
+    pub maxmemory_policy: Policy,
+    /// 淘汰 key 时每次抽样多少个 key，越大越接近真正的 LRU / LFU，也越慢
+    pub maxmemory_samples: usize,
+    /// EVAL 的脚本最多执行多少毫秒（包括脚本里 http.get 等待上游的时间），超过后中止脚本。
+    /// 脚本执行时锁住它访问的 shard，不能让它一直占着
+    pub lua_time_limit: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// 每行一个 JSON 对象，带上当前 span 的字段（客户端 id、地址、命令等）
+    Json,
+}
+
+/// 日志文件多久切分一次。切分后的文件名带上时间后缀
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogFormat, ()> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for LogRotation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogRotation, ()> {
+        match s.to_lowercase().as_str() {
+            "never" => Ok(LogRotation::Never),
+            "minutely" => Ok(LogRotation::Minutely),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let limits = Limits::default();
+        Config {
+            listeners: vec![ListenerConfig {
+                address: Address::Tcp("127.0.0.1:6379".to_string()),
+                tls: false,
+                noauth: false,
+            }],
+            tls_cert_file: None,
+            tls_key_file: None,
+            maxclients: 10000,
+            timeout: 0,
+            tcp_keepalive: 300,
+            proto_max_bulk_len: limits.max_bulk_len,
+            proto_max_array_len: limits.max_array_len,
+            proto_max_nesting: limits.max_depth,
+            client_query_buffer_limit: 1024 * 1024 * 1024,
+            shutdown_timeout: 10,
+            metrics_bind: None,
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            otlp_endpoint: None,
+            otlp_service_name: "rmr".to_string(),
+            log_format: LogFormat::Text,
+            log_level: "warn".to_string(),
+            logfile: None,
+            logfile_rotation: LogRotation::Daily,
+            requirepass: None,
+            users: Vec::new(),
+            ratelimits: Vec::new(),
+            upstream_stream: None,
+            upstream_stream_maxlen: 10000,
+            keyspace_shards: DEFAULT_SHARDS,
+            maxmemory: 0,
+            maxmemory_policy: Policy::NoEviction,
+            maxmemory_samples: 5,
+            lua_time_limit: 5000,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).context(ReadSnafu { path })?;
+        content.parse()
+    }
+
+    /// 解析客户端请求时使用的上限
+    pub fn frame_limits(&self) -> Limits {
+        Limits {
+            max_bulk_len: self.proto_max_bulk_len,
+            max_array_len: self.proto_max_array_len,
+            max_depth: self.proto_max_nesting,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Config> {
+        let mut config = Config::default();
+        // 只要配置文件里出现了 bind / unixsocket，就不再使用默认的监听地址
+        let mut listeners = Vec::new();
+
+        for (idx, raw) in s.lines().enumerate() {
+            let line = idx + 1;
+            let raw = raw.trim();
+            if raw.is_empty() || raw.starts_with('#') {
+                continue;
+            }
+
+            let mut args = raw.split_whitespace();
+            // 指令名不区分大小写
+            let directive = args.next().unwrap_or_default().to_lowercase();
+            let args: Vec<&str> = args.collect();
+
+            match directive.as_str() {
+                "bind" => {
+                    let (addr, options) = match args.split_first() {
+                        Some(v) => v,
+                        None => DirectiveSnafu {
+                            line,
+                            msg: "bind needs an address",
+                        }
+                        .fail()?,
+                    };
+                    let mut listener = ListenerConfig {
+                        address: Address::Tcp(addr.to_string()),
+                        tls: false,
+                        noauth: false,
+                    };
+                    for opt in options {
+                        match opt.to_lowercase().as_str() {
+                            "tls" => listener.tls = true,
+                            "noauth" => listener.noauth = true,
+                            other => DirectiveSnafu {
+                                line,
+                                msg: format!("unknown bind option '{}'", other),
+                            }
+                            .fail()?,
+                        }
+                    }
+                    listeners.push(listener);
+                }
+                "unixsocket" => {
+                    let path = single_arg(&args, line, &directive)?;
+                    listeners.push(ListenerConfig {
+                        address: Address::Unix(PathBuf::from(path)),
+                        tls: false,
+                        noauth: false,
+                    });
+                }
+                "tls-cert-file" => {
+                    config.tls_cert_file = Some(single_arg(&args, line, &directive)?.into());
+                }
+                "tls-key-file" => {
+                    config.tls_key_file = Some(single_arg(&args, line, &directive)?.into());
+                }
+                "maxclients" => {
+                    config.maxclients = parse_arg(&args, line, &directive)?;
+                }
+                "timeout" => {
+                    config.timeout = parse_arg(&args, line, &directive)?;
+                }
+                "tcp-keepalive" => {
+                    config.tcp_keepalive = parse_arg(&args, line, &directive)?;
+                }
+                "proto-max-bulk-len" => {
+                    config.proto_max_bulk_len = memory_arg(&args, line, &directive)?;
+                }
+                "proto-max-array-len" => {
+                    config.proto_max_array_len = parse_arg(&args, line, &directive)?;
+                }
+                "proto-max-nesting" => {
+                    config.proto_max_nesting = parse_arg(&args, line, &directive)?;
+                }
+                "client-query-buffer-limit" => {
+                    config.client_query_buffer_limit = memory_arg(&args, line, &directive)?;
+                }
+                "shutdown-timeout" => {
+                    config.shutdown_timeout = parse_arg(&args, line, &directive)?;
+                }
+                "metrics-bind" => {
+                    config.metrics_bind = Some(single_arg(&args, line, &directive)?.to_string());
+                }
+                "slowlog-log-slower-than" => {
+                    config.slowlog_log_slower_than = parse_arg(&args, line, &directive)?;
+                }
+                "slowlog-max-len" => {
+                    config.slowlog_max_len = parse_arg(&args, line, &directive)?;
+                }
+                "otlp-endpoint" => {
+                    config.otlp_endpoint = Some(single_arg(&args, line, &directive)?.to_string());
+                }
+                "otlp-service-name" => {
+                    config.otlp_service_name = single_arg(&args, line, &directive)?.to_string();
+                }
+                "log-format" => {
+                    config.log_format = parse_arg(&args, line, &directive)?;
+                }
+                "log-level" => {
+                    config.log_level = single_arg(&args, line, &directive)?.to_string();
+                }
+                "logfile" => {
+                    config.logfile = Some(PathBuf::from(single_arg(&args, line, &directive)?));
+                }
+                "logfile-rotation" => {
+                    config.logfile_rotation = parse_arg(&args, line, &directive)?;
+                }
+                "requirepass" => {
+                    config.requirepass = Some(single_arg(&args, line, &directive)?.to_string());
+                }
+                "user" => {
+                    let (name, rules) = match args.split_first() {
+                        Some(v) => v,
+                        None => DirectiveSnafu {
+                            line,
+                            msg: "user needs a name",
+                        }
+                        .fail()?,
+                    };
+                    let mut user = User::new(name);
+                    for rule in rules {
+                        if let Err(msg) = user.apply(rule) {
+                            DirectiveSnafu {
+                                line,
+                                msg: format!("bad rule '{}' for user {}: {}", rule, name, msg),
+                            }
+                            .fail()?;
+                        }
+                    }
+                    config.users.push(user);
+                }
+                "upstream-stream" => {
+                    config.upstream_stream = Some(single_arg(&args, line, &directive)?.to_string());
+                }
+                "upstream-stream-maxlen" => {
+                    config.upstream_stream_maxlen = parse_arg(&args, line, &directive)?;
+                }
+                "maxmemory" => {
+                    config.maxmemory = memory_arg(&args, line, &directive)?;
+                }
+                "maxmemory-policy" => {
+                    config.maxmemory_policy = parse_arg(&args, line, &directive)?;
+                }
+                "maxmemory-samples" => {
+                    config.maxmemory_samples = parse_arg(&args, line, &directive)?;
+                }
+                "lua-time-limit" => {
+                    config.lua_time_limit = parse_arg(&args, line, &directive)?;
+                }
+                "keyspace-shards" => {
+                    config.keyspace_shards = parse_arg(&args, line, &directive)?;
+                    if config.keyspace_shards == 0 {
+                        return DirectiveSnafu {
+                            line,
+                            msg: "keyspace-shards must be positive".to_string(),
+                        }
+                        .fail();
+                    }
+                }
+                "ratelimit-user" | "ratelimit-client" | "ratelimit-upstream" => {
+                    let scope = match directive.as_str() {
+                        "ratelimit-user" => Scope::User,
+                        "ratelimit-client" => Scope::Client,
+                        _ => Scope::Upstream,
+                    };
+                    let limit = match args.split_first() {
+                        Some((_, limit)) => limit.join(" ").parse::<Limit>().ok(),
+                        None => None,
+                    };
+                    match limit {
+                        Some(limit) => config.ratelimits.push(Rule {
+                            scope,
+                            target: args[0].to_string(),
+                            limit,
+                        }),
+                        None => DirectiveSnafu {
+                            line,
+                            msg: format!("{} needs <target> <rate> [burst]", directive),
+                        }
+                        .fail()?,
+                    }
+                }
+                other => DirectiveSnafu {
+                    line,
+                    msg: format!("unknown directive '{}'", other),
+                }
+                .fail()?,
+            }
+        }
+
+        if !listeners.is_empty() {
+            config.listeners = listeners;
+        }
+
+        Ok(config)
+    }
+}
+
+fn single_arg<'a>(args: &[&'a str], line: usize, directive: &str) -> Result<&'a str> {
+    match args {
+        [arg] => Ok(arg),
+        _ => DirectiveSnafu {
+            line,
+            msg: format!("{} needs exactly one argument", directive),
+        }
+        .fail(),
+    }
+}
+
+fn parse_arg<T: FromStr>(args: &[&str], line: usize, directive: &str) -> Result<T> {
+    let arg = single_arg(args, line, directive)?;
+    match arg.parse() {
+        Ok(v) => Ok(v),
+        Err(_) => DirectiveSnafu {
+            line,
+            msg: format!("bad value '{}' for {}", arg, directive),
+        }
+        .fail(),
+    }
+}
+
+// 和 redis 一样，内存大小可以带单位：1k = 1000，1kb = 1024，m / mb、g / gb 同理
+fn memory_arg(args: &[&str], line: usize, directive: &str) -> Result<usize> {
+    let arg = single_arg(args, line, directive)?;
+    match parse_memory(arg) {
+        Some(v) => Ok(v),
+        None => DirectiveSnafu {
+            line,
+            msg: format!("bad memory size '{}' for {}", arg, directive),
+        }
+        .fail(),
+    }
+}
+
+fn parse_memory(s: &str) -> Option<usize> {
+    let s = s.to_lowercase();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &s[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ts_default_listener() {
+        let config: Config = "# nothing but a comment\n".parse().unwrap();
+        assert_eq!(config.listeners, Config::default().listeners);
+    }
+
+    #[test]
+    fn ts_multi_listeners() {
+        let text = "
+            bind 127.0.0.1:6379 noauth
+            bind 0.0.0.0:6380 tls
+            unixsocket /tmp/rmr.sock
+            tls-cert-file /etc/rmr/server.crt
+            tls-key-file /etc/rmr/server.key
+            maxclients 128
+            timeout 60
+            metrics-bind 127.0.0.1:9121
+            log-format json
+            logfile-rotation hourly
+            requirepass foobared
+            user alice on >secret +@read ~*
+            ratelimit-upstream * 10 20
+            upstream-stream upstream:responses
+            maxmemory 256mb
+            maxmemory-policy allkeys-lfu
+            lua-time-limit 100
+        ";
+        let config: Config = text.parse().unwrap();
+
+        assert_eq!(config.listeners.len(), 3);
+        assert!(config.listeners[0].noauth);
+        assert_eq!(
+            config.listeners[1],
+            ListenerConfig {
+                address: Address::Tcp("0.0.0.0:6380".to_string()),
+                tls: true,
+                noauth: false,
+            }
+        );
+        assert_eq!(
+            config.listeners[2].address,
+            Address::Unix(PathBuf::from("/tmp/rmr.sock"))
+        );
+        assert_eq!(
+            config.tls_key_file,
+            Some(PathBuf::from("/etc/rmr/server.key"))
+        );
+        assert_eq!(config.maxclients, 128);
+        assert_eq!(config.timeout, 60);
+        assert_eq!(config.tcp_keepalive, 300);
+        assert_eq!(config.metrics_bind.as_deref(), Some("127.0.0.1:9121"));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.logfile_rotation, LogRotation::Hourly);
+        assert_eq!(config.requirepass.as_deref(), Some("foobared"));
+        assert_eq!(config.users[0].name, "alice");
+        assert_eq!(config.ratelimits[0].scope, Scope::Upstream);
+        assert_eq!(config.ratelimits[0].limit.burst, 20.0);
+        assert_eq!(
+            config.upstream_stream.as_deref(),
+            Some("upstream:responses")
+        );
+        assert_eq!(config.upstream_stream_maxlen, 10000);
+        assert_eq!(config.keyspace_shards, DEFAULT_SHARDS);
+        assert_eq!(config.maxmemory, 256 * 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, Policy::AllKeysLfu);
+        assert_eq!(config.lua_time_limit, 100);
+    }
+
+    #[test]
+    fn ts_parse_memory() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1KB"), Some(1024));
+        assert_eq!(parse_memory("512mb"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_memory("2g"), Some(2_000_000_000));
+        assert_eq!(parse_memory("mb"), None);
+        assert_eq!(parse_memory("1tb"), None);
+    }
+
+    #[test]
+    fn ts_bad_directive() {
+        assert!("bind".parse::<Config>().is_err());
+        assert!("bind 127.0.0.1:6379 plain".parse::<Config>().is_err());
+        assert!("bind 127.0.0.1:6379 logfile".parse::<Config>().is_err());
+        assert!("unixsocket a b".parse::<Config>().is_err());
+        assert!("no-such-thing yes".parse::<Config>().is_err());
+        assert!("maxclients many".parse::<Config>().is_err());
+        assert!("user".parse::<Config>().is_err());
+        assert!("user alice +no-such-command".parse::<Config>().is_err());
+        assert!("ratelimit-client *".parse::<Config>().is_err());
+        assert!("ratelimit-client * 0".parse::<Config>().is_err());
+        assert!("keyspace-shards 0".parse::<Config>().is_err());
+        assert!("maxmemory-policy lru".parse::<Config>().is_err());
+    }
+}
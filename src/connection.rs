@@ -1,12 +1,14 @@
 use bytes::Buf;
 
-use snafu::prelude::*;
+use snafu::{prelude::*, ResultExt};
 
+use std::fmt;
 use std::io::{self, Cursor};
 
-use crate::frame::Frame;
+use crate::frame::{self, Frame, Limits};
+use crate::metrics::METRICS;
 
-use tracing::{error, info};
+use tracing::{debug, error};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -14,43 +16,72 @@ pub enum Error {
     Reset,
     #[snafu(display("failed for io error {}", source))]
     Io { source: io::Error },
-    #[snafu(display("failed for bad frame"))]
-    Frame,
+    #[snafu(display("failed for bad frame. {}", source))]
+    Frame { source: frame::Error },
+    #[snafu(display("failed for query buffer over the limit of {} bytes", limit))]
+    QueryBuffer { limit: usize },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 use bytes::BytesMut;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+/// 连接底层的字节流：TCP、TLS 或者 unix socket
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug> Stream for T {}
 
 #[derive(Debug)]
 pub struct Connection {
-    stream: BufWriter<TcpStream>,
+    stream: BufWriter<Box<dyn Stream>>,
 
     buffer: BytesMut,
+
+    limits: Limits,
+    // 还没有解析成 Frame 的数据最多能有多少字节
+    max_buffer: usize,
 }
 
 impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+    pub fn new(socket: impl Stream + 'static) -> Connection {
         Connection {
-            stream: BufWriter::new(socket),
+            stream: BufWriter::new(Box::new(socket)),
             buffer: BytesMut::with_capacity(4 * 1024),
+            limits: Limits::default(),
+            max_buffer: 1024 * 1024 * 1024,
         }
     }
 
+    /// 设置解析请求时的上限。超过上限的请求会让 read_frame 返回错误
+    pub fn with_limits(mut self, limits: Limits, max_buffer: usize) -> Connection {
+        self.limits = limits;
+        self.max_buffer = max_buffer;
+        self
+    }
+
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
         loop {
             if let Some(frame) = self.parse_frame()? {
                 return Ok(Some(frame));
             }
 
+            // 客户端一直发送数据，但是始终组不成一个完整的 Frame
+            if self.buffer.len() >= self.max_buffer {
+                QueryBufferSnafu {
+                    limit: self.max_buffer,
+                }
+                .fail()?;
+            }
+
             let len = self
                 .stream
                 .read_buf(&mut self.buffer)
                 .await
                 .context(IoSnafu)?;
 
+            METRICS.net_input_bytes.inc_by(len as u64);
+
             if 0 == len {
                 if self.buffer.is_empty() {
                     return Ok(None);
@@ -61,12 +92,44 @@ impl Connection {
         }
     }
 
+    /// 等到客户端断开连接。期间收到的数据留在读缓冲里，之后照常处理。
+    /// 阻塞的命令用它发现等待中的客户端已经走了
+    pub async fn closed(&mut self) -> Result<()> {
+        loop {
+            // 缓冲满了就不再读，交给之后的 read_frame 报错
+            if self.buffer.len() >= self.max_buffer {
+                return std::future::pending().await;
+            }
+
+            let len = self
+                .stream
+                .read_buf(&mut self.buffer)
+                .await
+                .context(IoSnafu)?;
+            METRICS.net_input_bytes.inc_by(len as u64);
+            if len == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 读缓冲里已经收到、还没有处理的字节数
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// 只从已经读到的数据里解析一个 Frame，不会再从 socket 读取。
+    /// 没有完整的 Frame 时返回 None
+    pub fn buffered_frame(&mut self) -> Result<Option<Frame>> {
+        self.parse_frame()
+    }
+
     fn parse_frame(&mut self) -> Result<Option<Frame>> {
         let mut buf = Cursor::new(&self.buffer[..]);
 
         // 先快速判断是否可以从 buffer 里面解析出一个完整的 Frame
         // 只有返回 true 的时候，才会真正的做解析 Frame 动作（避免不必要的工作）
-        match Frame::check(&mut buf) {
+        match Frame::check_limited(&mut buf, &self.limits) {
             Ok(_) => {
                 // 完整 Frame 的 size 就是：buf.position
                 let len = buf.position() as usize;
@@ -76,7 +139,7 @@ impl Connection {
                     Ok(f) => f,
                     Err(e) => {
                         error!("io error. {:?}", e);
-                        FrameSnafu.fail()?
+                        Err(e).context(FrameSnafu)?
                     }
                 };
                 self.buffer.advance(len);
@@ -86,7 +149,7 @@ impl Connection {
                 crate::frame::Error::IncompleteError => Ok(None),
                 other => {
                     error!("io error. {:?}", other);
-                    FrameSnafu.fail()?
+                    Err(other).context(FrameSnafu)?
                 }
             },
         }
@@ -94,94 +157,20 @@ impl Connection {
 
     /// Write a single `Frame` value to the underlying stream.
     ///
-    /// The `Frame` value is written to the socket using the various `write_*`
-    /// functions provided by `AsyncWrite`. Calling these functions directly on
-    /// a `TcpStream` is **not** advised, as this will result in a large number of
-    /// syscalls. However, it is fine to call these functions on a *buffered*
-    /// write stream. The data will be written to the buffer. Once the buffer is
-    /// full, it is flushed to the underlying socket.
+    /// The whole frame is encoded into memory first (see `Frame::encode`), so
+    /// nested arrays are supported, and then written to the buffered stream in
+    /// one call. Calling `flush` afterwards makes sure the reply actually
+    /// reaches the socket.
     pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
-        info!("try to write the frame to client: {:?}", frame);
-        // Arrays are encoded by encoding each entry. All other frame types are
-        // considered literals. For now, mini-redis is not able to encode
-        // recursive frame structures. See below for more details.
-        match frame {
-            Frame::Array(val) => {
-                // Encode the frame type prefix. For an array, it is `*`.
-                self.stream.write_u8(b'*').await.context(IoSnafu)?;
-
-                // Encode the length of the array.
-                self.write_decimal(val.len() as u64)
-                    .await
-                    .context(IoSnafu)?;
-
-                // Iterate and encode each entry in the array.
-                for entry in &**val {
-                    self.write_value(entry).await.context(IoSnafu)?;
-                }
-            }
-            // The frame type is a literal. Encode the value directly.
-            _ => self.write_value(frame).await.context(IoSnafu)?,
-        }
-
-        // Ensure the encoded frame is written to the socket. The calls above
-        // are to the buffered stream and writes. Calling `flush` writes the
-        // remaining contents of the buffer to the socket.
-        self.stream.flush().await.context(IoSnafu)?;
-
-        Ok(())
-    }
-
-    /// Write a decimal frame to the stream
-    async fn write_decimal(&mut self, val: u64) -> io::Result<()> {
-        use std::io::Write;
+        debug!("try to write the frame to client: {:?}", frame);
 
-        // Convert the value to a string
-        let mut buf = [0u8; 20];
-        let mut buf = Cursor::new(&mut buf[..]);
-        write!(&mut buf, "{}", val)?;
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
 
-        let pos = buf.position() as usize;
-        self.stream.write_all(&buf.get_ref()[..pos]).await?;
-        self.stream.write_all(b"\r\n").await?;
-
-        Ok(())
-    }
-
-    /// Write a frame literal to the stream
-    async fn write_value(&mut self, frame: &Frame) -> io::Result<()> {
-        match frame {
-            Frame::Simple(val) => {
-                self.stream.write_u8(b'+').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Error(val) => {
-                self.stream.write_u8(b'-').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Integer(val) => {
-                self.stream.write_u8(b':').await?;
-                self.write_decimal(*val).await?;
-            }
-            Frame::Null => {
-                self.stream.write_all(b"$-1\r\n").await?;
-            }
-            Frame::Bulk(val) => {
-                let len = val.len();
+        self.stream.write_all(&buf).await.context(IoSnafu)?;
+        self.stream.flush().await.context(IoSnafu)?;
 
-                self.stream.write_u8(b'$').await?;
-                self.write_decimal(len as u64).await?;
-                self.stream.write_all(val).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            // Encoding an `Array` from within a value cannot be done using a
-            // recursive strategy. In general, async fns do not support
-            // recursion. Mini-redis has not needed to encode nested arrays yet,
-            // so for now it is skipped.
-            Frame::Array(_val) => unreachable!(),
-        }
+        METRICS.net_output_bytes.inc_by(buf.len() as u64);
 
         Ok(())
     }
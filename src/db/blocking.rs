@@ -0,0 +1,344 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::{oneshot, Notify};
+
+use crate::metrics::METRICS;
+
+use super::{shard_index, Db, Keyspace, KeyspaceGuard, Side, Value, WRONGTYPE};
+
+/// 阻塞的客户端等到的结果：从哪个 key 取到了哪个元素，或者要回复给它的错误
+pub type Served = Result<(Bytes, Bytes), String>;
+
+// 一个阻塞的客户端在某个 key 上排的队，放在 key 所在的 shard 里。
+// 阻塞在多个 key 上时，每个 key 的队列里各有一个，共用同一个 sender：先被哪个 key 服务就取走 sender，其他队列里剩下的会被跳过
+#[derive(Debug)]
+pub(super) struct Waiter {
+    id: u64,
+    pop: Side,
+    // BLMOVE 把取到的元素放到哪个 list 的哪一端
+    push: Option<(Bytes, Side)>,
+    tx: Arc<Mutex<Option<oneshot::Sender<Served>>>>,
+}
+
+/// 阻塞在一组 key 上的客户端。被 drop 时（超时、断开、被 kill）从所有的队列里退出；
+/// 如果刚好已经分到了元素但是还没有回复，把元素放回原来的 list
+#[derive(Debug)]
+pub struct Blocked {
+    id: u64,
+    keys: Vec<Bytes>,
+    pop: Side,
+    moves: bool,
+    rx: oneshot::Receiver<Served>,
+    db: Db,
+}
+
+/// 等待一组 stream 有新 entry 的 XREAD / XREADGROUP。
+/// 和 list 不一样，stream 的 entry 不会被取走，所以有新 entry 时唤醒所有在等的客户端，
+/// 由它们自己重新读一次（XREADGROUP 可能被同组的其他消费者抢先，读不到就接着等）
+#[derive(Debug)]
+pub struct StreamWait {
+    id: u64,
+    keys: Vec<Bytes>,
+    notify: Arc<Notify>,
+    db: Db,
+}
+
+impl KeyspaceGuard<'_> {
+    /// 在 keys 上排队，等其中一个 list 有了元素。
+    /// 和 Redis 一样先来先得：同一个 key 上的客户端按阻塞的先后顺序拿到元素
+    pub fn block(&mut self, keys: Vec<Bytes>, pop: Side, push: Option<(Bytes, Side)>) -> Blocked {
+        let id = self.db.shared.next_waiter.fetch_add(1, Ordering::Relaxed);
+
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        for key in &keys {
+            self.keyspace
+                .shard_mut(key)
+                .blocked
+                .entry(key.clone())
+                .or_default()
+                .push_back(Waiter {
+                    id,
+                    pop,
+                    push: push.clone(),
+                    tx: tx.clone(),
+                });
+        }
+
+        METRICS.blocked_clients.inc();
+        Blocked {
+            id,
+            keys,
+            pop,
+            moves: push.is_some(),
+            rx,
+            db: self.db.clone(),
+        }
+    }
+
+    /// 开始等待 keys 里的 stream 有新 entry。要和检查 stream 在同一次加锁里调用，才不会错过
+    pub fn wait_streams(&mut self, keys: Vec<Bytes>) -> StreamWait {
+        let id = self.db.shared.next_waiter.fetch_add(1, Ordering::Relaxed);
+
+        let notify = Arc::new(Notify::new());
+        for key in &keys {
+            self.keyspace
+                .shard_mut(key)
+                .stream_waiters
+                .entry(key.clone())
+                .or_default()
+                .push((id, notify.clone()));
+        }
+
+        METRICS.blocked_clients.inc();
+        StreamWait {
+            id,
+            keys,
+            notify,
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl StreamWait {
+    /// 等到某个 stream 加入了新 entry
+    pub async fn changed(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for StreamWait {
+    fn drop(&mut self) {
+        METRICS.blocked_clients.dec();
+        let id = self.id;
+        let keys = std::mem::take(&mut self.keys);
+        let shards = self
+            .db
+            .shards(&keys.iter().map(|key| key.as_ref()).collect::<Vec<_>>());
+        self.db.lock_shards_then(shards, move |keyspace| {
+            for key in &keys {
+                let shard = keyspace.shard_mut(key);
+                if let Some(waiters) = shard.stream_waiters.get_mut(key) {
+                    waiters.retain(|(waiter, _)| *waiter != id);
+                    if waiters.is_empty() {
+                        shard.stream_waiters.remove(key);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Blocked {
+    /// 等到分给自己的元素
+    pub async fn recv(&mut self) -> Option<Served> {
+        (&mut self.rx).await.ok()
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        METRICS.blocked_clients.dec();
+        let (id, pop, moves) = (self.id, self.pop, self.moves);
+        let keys = std::mem::take(&mut self.keys);
+        // 退出队列之前还可能分到元素，receiver 要留到那时候再看
+        let mut rx = std::mem::replace(&mut self.rx, oneshot::channel().1);
+        let shards = self
+            .db
+            .shards(&keys.iter().map(|key| key.as_ref()).collect::<Vec<_>>());
+        self.db.lock_shards_then(shards, move |keyspace| {
+            for key in &keys {
+                let shard = keyspace.shard_mut(key);
+                if let Some(queue) = shard.blocked.get_mut(key) {
+                    queue.retain(|waiter| waiter.id != id);
+                    if queue.is_empty() {
+                        shard.blocked.remove(key);
+                    }
+                }
+            }
+
+            // BLMOVE 的元素已经放到了目标 list 里，不用放回去
+            if let Ok(Ok((key, item))) = rx.try_recv() {
+                if !moves
+                    && !matches!(keyspace.peek(&key), Some(value) if value.as_list().is_none())
+                {
+                    let _ = keyspace.push(&key, pop, [item]);
+                }
+            }
+        });
+    }
+}
+
+impl Keyspace<'_> {
+    // list 有了新的元素。有客户端阻塞在这个 key 上的话，命令结束释放锁之前交给它们
+    pub(super) fn signal_ready(&mut self, key: &Bytes) {
+        let shard = self.shard_mut(key);
+        if shard.blocked.contains_key(key) && !shard.ready.contains(key) {
+            shard.ready.push_back(key.clone());
+        }
+    }
+
+    // stream 加入了新 entry，唤醒在这个 key 上等待的客户端。
+    // 它们要等当前命令释放锁之后才能读，读到的是完整的结果
+    pub(super) fn signal_stream(&self, key: &Bytes) {
+        for (_, notify) in self
+            .shard(key)
+            .stream_waiters
+            .get(key)
+            .into_iter()
+            .flatten()
+        {
+            // 还没开始等的话会留下一个 permit，下次等待时马上返回
+            notify.notify_one();
+        }
+    }
+
+    // 按排队的顺序把 ready 的 list 里的元素交给阻塞的客户端。
+    // BLMOVE 放到目标 list 的元素又会让目标 key ready，一直处理到没有 ready 的 key 为止。
+    // BLMOVE 的目标 list 所在的 shard 没有锁住时停下来，返回这个 shard，换一组锁再接着处理
+    pub(super) fn serve_blocked(&mut self) -> Option<usize> {
+        while let Some(key) = self.next_ready() {
+            while matches!(self.peek(&key), Some(Value::List(_))) {
+                let shard = self.shard_mut(&key);
+                let waiter = match shard.blocked.get_mut(&key).and_then(|q| q.pop_front()) {
+                    Some(waiter) => waiter,
+                    None => break,
+                };
+                if let Some((dest, _)) = &waiter.push {
+                    let index = shard_index(dest, self.shards.len());
+                    if self.shards[index].is_none() {
+                        let shard = self.shard_mut(&key);
+                        shard
+                            .blocked
+                            .entry(key.clone())
+                            .or_default()
+                            .push_front(waiter);
+                        shard.ready.push_front(key);
+                        return Some(index);
+                    }
+                }
+                let tx = match waiter.tx.lock().unwrap().take() {
+                    Some(tx) if !tx.is_closed() => tx,
+                    // 已经在别的 key 上拿到了，或者已经不等了
+                    _ => continue,
+                };
+                let served = self.serve(&key, &waiter);
+                if let Err(Ok((_, item))) = tx.send(served) {
+                    if waiter.push.is_none() {
+                        let _ = self.push(&key, waiter.pop, [item]);
+                    }
+                }
+            }
+            let shard = self.shard_mut(&key);
+            if shard
+                .blocked
+                .get(&key)
+                .is_some_and(|queue| queue.is_empty())
+            {
+                shard.blocked.remove(&key);
+            }
+        }
+        None
+    }
+
+    fn next_ready(&mut self) -> Option<Bytes> {
+        self.locked_shards_mut()
+            .find_map(|shard| shard.ready.pop_front())
+    }
+
+    fn serve(&mut self, key: &Bytes, waiter: &Waiter) -> Served {
+        if let Some((dest, _)) = &waiter.push {
+            if matches!(self.peek(dest), Some(value) if value.as_list().is_none()) {
+                return Err(WRONGTYPE.to_string());
+            }
+        }
+        let item = match self.pop(key, waiter.pop) {
+            Some(item) => item,
+            None => return Err(WRONGTYPE.to_string()),
+        };
+        if let Some((dest, side)) = &waiter.push {
+            let _ = self.push(dest, *side, [item.clone()]);
+        }
+        Ok((key.clone(), item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ts_serve_in_order() {
+        let db = Db::new();
+        let key = Bytes::from("q");
+        let mut first = db.lock().await.block(vec![key.clone()], Side::Left, None);
+        let mut second =
+            db.lock()
+                .await
+                .block(vec![Bytes::from("other"), key.clone()], Side::Left, None);
+        let third = db.lock().await.block(vec![key.clone()], Side::Left, None);
+
+        // 一次放入两个元素，先阻塞的两个客户端各拿到一个
+        db.lock()
+            .await
+            .push(&key, Side::Right, [Bytes::from("a"), Bytes::from("b")])
+            .unwrap();
+        assert_eq!(
+            first.recv().await,
+            Some(Ok((key.clone(), Bytes::from("a"))))
+        );
+        assert_eq!(
+            second.recv().await,
+            Some(Ok((key.clone(), Bytes::from("b"))))
+        );
+        assert!(!db.lock().await.contains(&key));
+
+        // 不等了的客户端分到的元素放回去
+        db.lock()
+            .await
+            .push(&key, Side::Right, [Bytes::from("c")])
+            .unwrap();
+        drop(third);
+        assert_eq!(
+            db.lock().await.pop(&key, Side::Left),
+            Some(Bytes::from("c"))
+        );
+        drop((first, second));
+        assert!(db
+            .lock()
+            .await
+            .locked_shards()
+            .all(|shard| shard.blocked.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn ts_move_across_shards() {
+        let db = Db::with_shards(4);
+        // 找两个在不同 shard 里的 key
+        let source = Bytes::from("source");
+        let dest = (0..)
+            .map(|i| Bytes::from(format!("dest{}", i)))
+            .find(|key| shard_index(key, 4) != shard_index(&source, 4))
+            .unwrap();
+
+        let mut blocked = db.lock_keys(&[&source, &dest]).await.block(
+            vec![source.clone()],
+            Side::Left,
+            Some((dest.clone(), Side::Right)),
+        );
+        // 只锁住了 source 所在的 shard，释放时换一组锁把元素放到 dest 里
+        db.lock_keys(&[&source])
+            .await
+            .push(&source, Side::Right, [Bytes::from("a")])
+            .unwrap();
+        assert_eq!(
+            blocked.recv().await,
+            Some(Ok((source.clone(), Bytes::from("a"))))
+        );
+        let mut keyspace = db.lock().await;
+        assert!(!keyspace.contains(&source));
+        assert_eq!(keyspace.pop(&dest, Side::Left), Some(Bytes::from("a")));
+    }
+}
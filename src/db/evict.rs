@@ -0,0 +1,308 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::metrics::METRICS;
+
+use super::{drop_lazily, Db, Entry, Keyspace, Shard, Shards, Value};
+
+// 新 key 的 LFU 计数，和 Redis 一样不从 0 开始，刚写入的 key 不会马上被淘汰
+const LFU_INIT: u8 = 5;
+// 计数越大越难增加：计数为 n 时，一次访问让它加一的概率是 1 / ((n - LFU_INIT) * LFU_LOG_FACTOR + 1)
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// 占用的内存超过 maxmemory 时怎么腾出空间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// 不淘汰，拒绝可能增加内存的命令
+    NoEviction,
+    /// 在所有 key 里淘汰最久没有访问的
+    AllKeysLru,
+    /// 在所有 key 里淘汰访问频率最低的
+    AllKeysLfu,
+    /// 在设置了过期时间的 key 里淘汰最久没有访问的
+    VolatileLru,
+    /// 淘汰最早过期的 key
+    VolatileTtl,
+    AllKeysRandom,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Policy, String> {
+        let policy = match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Policy::NoEviction,
+            "allkeys-lru" => Policy::AllKeysLru,
+            "allkeys-lfu" => Policy::AllKeysLfu,
+            "volatile-lru" => Policy::VolatileLru,
+            "volatile-ttl" => Policy::VolatileTtl,
+            "allkeys-random" => Policy::AllKeysRandom,
+            _ => return Err(format!("unknown maxmemory policy '{}'", s)),
+        };
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::VolatileLru => "volatile-lru",
+            Policy::VolatileTtl => "volatile-ttl",
+            Policy::AllKeysRandom => "allkeys-random",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Entry {
+    pub(super) fn new(value: Value, expires_at: Option<Instant>, slot: usize) -> Entry {
+        Entry {
+            value,
+            expires_at,
+            accessed: Instant::now(),
+            hits: LFU_INIT,
+            slot,
+        }
+    }
+
+    // 访问了一次：记下访问时间，按对数的概率增加访问计数
+    pub(super) fn touch(&mut self, now: Instant) {
+        let hits = self.frequency(now);
+        let p = 1.0 / (hits.saturating_sub(LFU_INIT) as f64 * LFU_LOG_FACTOR + 1.0);
+        self.hits = if hits < u8::MAX && (p >= 1.0 || rand::random::<f64>() < p) {
+            hits + 1
+        } else {
+            hits
+        };
+        self.accessed = now;
+    }
+
+    // 访问计数每分钟没有访问就减一，以前很热、现在没人访问的 key 也会被淘汰
+    fn frequency(&self, now: Instant) -> u8 {
+        let idle = now.saturating_duration_since(self.accessed).as_secs() / 60;
+        self.hits.saturating_sub(idle.min(u8::MAX as u64) as u8)
+    }
+}
+
+// 候选 key 的排序依据，越小越先淘汰
+type Rank = (u64, Instant);
+
+impl Db {
+    /// 占用的内存超过 maxmemory 时，按 policy 淘汰 key 直到降到 maxmemory 以下。
+    /// 和 Redis 一样是近似的：每个 shard 随机抽 samples 个 key 选出一个候选，
+    /// 再在所有 shard 的候选里淘汰最合适的一个，这样 policy 是对所有的 key 生效的。
+    /// 腾不出空间时（noeviction，或者没有可以淘汰的 key）返回 false。maxmemory 为 0 表示不限制
+    pub async fn evict(&self, maxmemory: usize, policy: Policy, samples: usize) -> bool {
+        if maxmemory == 0 {
+            return true;
+        }
+
+        while self.used_memory() > maxmemory {
+            if policy == Policy::NoEviction {
+                return false;
+            }
+            // 一次只锁一个 shard，不会让别的命令等太久
+            let mut best: Option<(Rank, Bytes)> = None;
+            for index in 0..self.shared.shards.len() {
+                let keyspace = self.lock_shards(&Shards(vec![index])).await;
+                let candidate = keyspace.locked_shards().next().and_then(|shard| {
+                    shard.candidate(policy, samples.max(1), &mut rand::thread_rng())
+                });
+                if let Some(candidate) = candidate {
+                    if best.as_ref().is_none_or(|(rank, _)| candidate.0 < *rank) {
+                        best = Some(candidate);
+                    }
+                }
+            }
+            let key = match best {
+                Some((_, key)) => key,
+                None => return false,
+            };
+            // 选出来之后、重新加锁之前 key 可能已经被删掉了，那就下一轮重新选
+            self.lock_keys(&[&key]).await.evict_key(&key);
+        }
+        true
+    }
+}
+
+impl Keyspace<'_> {
+    fn evict_key(&mut self, key: &[u8]) {
+        match self.shard_mut(key).remove_entry(key) {
+            None => return,
+            Some(Value::String(_)) => {}
+            Some(value) => drop_lazily(value),
+        }
+        METRICS.evicted_keys.inc();
+    }
+}
+
+impl Shard {
+    // 按 policy 选出这个 shard 里最应该淘汰的 key
+    fn candidate(
+        &self,
+        policy: Policy,
+        samples: usize,
+        rng: &mut impl Rng,
+    ) -> Option<(Rank, Bytes)> {
+        let now = Instant::now();
+        let sampled: Vec<&Bytes> = match policy {
+            Policy::NoEviction => return None,
+            // expirations 是按过期时间排好序的，不用抽样
+            Policy::VolatileTtl => {
+                return self
+                    .expirations
+                    .first()
+                    .map(|(when, key)| ((0, *when), key.clone()))
+            }
+            Policy::AllKeysRandom => self.sample(1, rng),
+            Policy::AllKeysLru | Policy::AllKeysLfu => self.sample(samples, rng),
+            Policy::VolatileLru => self.sample_volatile(samples, rng),
+        };
+
+        sampled
+            .into_iter()
+            .filter_map(|key| self.entries.get(key).map(|entry| (key, entry)))
+            .map(|(key, entry)| {
+                let rank = match policy {
+                    Policy::AllKeysLfu => (entry.frequency(now) as u64, entry.accessed),
+                    // 随机淘汰时各个 shard 的候选也随机比较
+                    Policy::AllKeysRandom => (rng.gen(), now),
+                    _ => (0, entry.accessed),
+                };
+                (rank, key.clone())
+            })
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+    }
+
+    fn sample(&self, samples: usize, rng: &mut impl Rng) -> Vec<&Bytes> {
+        if self.keys.is_empty() {
+            return Vec::new();
+        }
+        (0..samples)
+            .map(|_| &self.keys[rng.gen_range(0..self.keys.len())])
+            .collect()
+    }
+
+    // 在过期时间的范围里随机取一些时间点，抽出每个时间点之后第一个过期的 key
+    fn sample_volatile(&self, samples: usize, rng: &mut impl Rng) -> Vec<&Bytes> {
+        let (first, last) = match (self.expirations.first(), self.expirations.last()) {
+            (Some((first, _)), Some((last, _))) => (*first, *last),
+            _ => return Vec::new(),
+        };
+        (0..samples)
+            .filter_map(|_| {
+                let at = first + (last - first).mul_f64(rng.gen());
+                self.expirations
+                    .range((at, Bytes::new())..)
+                    .next()
+                    .map(|(_, key)| key)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn string(s: &'static str) -> Value {
+        Value::String(Bytes::from(s))
+    }
+
+    #[test]
+    fn ts_policy() {
+        assert_eq!("allkeys-LRU".parse::<Policy>(), Ok(Policy::AllKeysLru));
+        assert_eq!(Policy::VolatileTtl.to_string(), "volatile-ttl");
+        assert!("lru".parse::<Policy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn ts_evict_lru() {
+        let db = Db::with_shards(1);
+        for key in ["a", "b", "c", "d"] {
+            db.lock().await.insert(Bytes::from(key), string("v"), None);
+        }
+        let per_key = db.used_memory() / 4;
+        // 访问过的 key 留下来，淘汰最久没有访问的两个
+        db.lock().await.get(b"a");
+        db.lock().await.get(b"b");
+        assert!(db.evict(per_key * 2, Policy::AllKeysLru, 100).await);
+
+        let mut keyspace = db.lock().await;
+        assert_eq!(keyspace.len(), 2);
+        assert!(keyspace.contains(b"a") && keyspace.contains(b"b"));
+        assert_eq!(keyspace.used_memory(), per_key * 2);
+    }
+
+    #[tokio::test]
+    async fn ts_evict_across_shards() {
+        let db = Db::new();
+        let keys: Vec<String> = (0..32).map(|i| format!("key:{:02}", i)).collect();
+        for key in &keys {
+            db.lock()
+                .await
+                .insert(Bytes::from(key.clone()), string("v"), None);
+        }
+        let per_key = db.used_memory() / keys.len();
+        // 每个 shard 里只有一两个 key，只看一个 shard 选不出所有 key 里最久没有访问的
+        for key in &keys[..16] {
+            db.lock_keys(&[key.as_bytes()]).await.get(key.as_bytes());
+        }
+        assert!(db.evict(per_key * 16, Policy::AllKeysLru, 100).await);
+
+        let mut keyspace = db.lock().await;
+        assert_eq!(keyspace.len(), 16);
+        assert!(keys[..16]
+            .iter()
+            .all(|key| keyspace.contains(key.as_bytes())));
+        drop(keyspace);
+
+        // volatile-ttl 淘汰所有 shard 里最早过期的 key
+        let now = Instant::now();
+        for (i, key) in keys[..16].iter().enumerate() {
+            let when = now + Duration::from_secs(60 + i as u64);
+            db.lock_keys(&[key.as_bytes()]).await.insert(
+                Bytes::from(key.clone()),
+                string("v"),
+                Some(when),
+            );
+        }
+        assert!(db.evict(db.used_memory() - 1, Policy::VolatileTtl, 5).await);
+        assert!(!db.lock().await.contains(keys[0].as_bytes()));
+        assert_eq!(db.lock().await.len(), 15);
+    }
+
+    #[tokio::test]
+    async fn ts_evict_volatile() {
+        let db = Db::with_shards(1);
+        let later = Instant::now() + Duration::from_secs(60);
+        db.lock()
+            .await
+            .insert(Bytes::from("soon"), string("v"), Some(later));
+        db.lock().await.insert(
+            Bytes::from("later"),
+            string("v"),
+            Some(later + Duration::from_secs(60)),
+        );
+        db.lock()
+            .await
+            .insert(Bytes::from("kept"), string("v"), None);
+
+        assert!(db.evict(db.used_memory() - 1, Policy::VolatileTtl, 5).await);
+        assert!(!db.lock().await.contains(b"soon"));
+        // 没有设置过期时间的 key 不会被淘汰
+        assert!(!db.evict(1, Policy::VolatileLru, 5).await);
+        assert!(db.lock().await.contains(b"kept"));
+        assert!(!db.evict(1, Policy::NoEviction, 5).await);
+        assert!(db.evict(1, Policy::AllKeysRandom, 5).await);
+        assert_eq!(db.used_memory(), 0);
+    }
+}
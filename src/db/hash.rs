@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+// 每个 field 除了 field 和 value 本身之外的大致开销，统计内存时用
+const FIELD_OVERHEAD: usize = 32;
+
+/// hash 类型的值。随着修改记录占用的内存，统计整个 keyspace 的内存时不用遍历所有 field
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hash {
+    fields: HashMap<Bytes, Bytes>,
+    size: usize,
+}
+
+fn field_size(field: &[u8], value: &[u8]) -> usize {
+    field.len() + value.len() + FIELD_OVERHEAD
+}
+
+impl Hash {
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        self.fields.get(field)
+    }
+
+    pub fn contains(&self, field: &[u8]) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    /// 写入一个 field，返回原来的值
+    pub fn insert(&mut self, field: Bytes, value: Bytes) -> Option<Bytes> {
+        self.size += field_size(&field, &value);
+        let old = self.fields.insert(field.clone(), value);
+        if let Some(old) = &old {
+            self.size -= field_size(&field, old);
+        }
+        old
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> Option<Bytes> {
+        let (field, value) = self.fields.remove_entry(field)?;
+        self.size -= field_size(&field, &value);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        self.fields.iter()
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl FromIterator<(Bytes, Bytes)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (Bytes, Bytes)>>(iter: I) -> Hash {
+        let mut hash = Hash::default();
+        for (field, value) in iter {
+            hash.insert(field, value);
+        }
+        hash
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+use super::{Keyspace, Value, WRONGTYPE};
+
+// 每个元素除了内容之外的大致开销，统计内存时用
+const ITEM_OVERHEAD: usize = 16;
+
+/// list 的一端：LEFT 是头部，RIGHT 是尾部
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// list 类型的值。和 hash 一样随着修改记录占用的内存
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct List {
+    items: VecDeque<Bytes>,
+    size: usize,
+}
+
+impl List {
+    pub fn push(&mut self, side: Side, item: Bytes) {
+        self.size += item.len() + ITEM_OVERHEAD;
+        match side {
+            Side::Left => self.items.push_front(item),
+            Side::Right => self.items.push_back(item),
+        }
+    }
+
+    pub fn pop(&mut self, side: Side) -> Option<Bytes> {
+        let item = match side {
+            Side::Left => self.items.pop_front(),
+            Side::Right => self.items.pop_back(),
+        }?;
+        self.size -= item.len() + ITEM_OVERHEAD;
+        Some(item)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Bytes> {
+        self.items.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Bytes> {
+        self.items.iter()
+    }
+
+    /// 只保留满足条件的元素
+    pub fn retain(&mut self, mut f: impl FnMut(&Bytes) -> bool) {
+        let size = &mut self.size;
+        self.items.retain(|item| {
+            let keep = f(item);
+            if !keep {
+                *size -= item.len() + ITEM_OVERHEAD;
+            }
+            keep
+        });
+    }
+
+    /// 只保留下标在 `start..=end` 之间的元素
+    pub fn truncate(&mut self, start: usize, end: usize) {
+        let len = self.items.len();
+        for _ in (end + 1).min(len)..len {
+            self.pop(Side::Right);
+        }
+        for _ in 0..start.min(self.items.len()) {
+            self.pop(Side::Left);
+        }
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Keyspace<'_> {
+    /// 往 list 的一端依次放入元素，key 不存在时新建一个 list，返回放入之后的长度。
+    /// 有客户端阻塞在这个 key 上的话，命令结束时把元素交给它们
+    pub fn push(
+        &mut self,
+        key: &Bytes,
+        side: Side,
+        items: impl IntoIterator<Item = Bytes>,
+    ) -> Result<usize, String> {
+        match self.peek(key) {
+            Some(Value::List(_)) => {}
+            Some(_) => return Err(WRONGTYPE.to_string()),
+            None => {
+                self.insert(key.clone(), Value::List(List::default()), None);
+            }
+        }
+
+        let len = self
+            .modify(key, |value| match value {
+                Value::List(list) => {
+                    for item in items {
+                        list.push(side, item);
+                    }
+                    list.len()
+                }
+                _ => 0,
+            })
+            .unwrap_or(0);
+        self.signal_ready(key);
+        Ok(len)
+    }
+
+    /// 从 list 的一端取出一个元素。key 不存在、不是 list 时返回 None
+    pub fn pop(&mut self, key: &[u8], side: Side) -> Option<Bytes> {
+        self.modify(key, |value| match value {
+            Value::List(list) => list.pop(side),
+            _ => None,
+        })
+        .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ts_list() {
+        let mut list = List::default();
+        for i in 0..5 {
+            list.push(Side::Right, Bytes::from(i.to_string()));
+        }
+        list.push(Side::Left, Bytes::from("x"));
+        assert_eq!(list.size(), 6 * (1 + ITEM_OVERHEAD));
+
+        list.retain(|item| item != "x");
+        list.truncate(1, 2);
+        let items: Vec<_> = list.iter().cloned().collect();
+        assert_eq!(items, vec![Bytes::from("1"), Bytes::from("2")]);
+        assert_eq!(list.size(), 2 * (1 + ITEM_OVERHEAD));
+
+        assert_eq!(list.pop(Side::Right), Some(Bytes::from("2")));
+        assert_eq!(list.pop(Side::Left), Some(Bytes::from("1")));
+        assert_eq!(list.pop(Side::Left), None);
+        assert_eq!(list.size(), 0);
+    }
+}
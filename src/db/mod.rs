@@ -0,0 +1,849 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{Hash as _, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use tokio::time;
+
+use crate::metrics::METRICS;
+use crate::shutdown::Shutdown;
+
+mod blocking;
+use blocking::Waiter;
+pub use blocking::{Blocked, Served, StreamWait};
+
+mod evict;
+pub use evict::Policy;
+
+mod hash;
+pub use hash::Hash;
+
+mod list;
+pub use list::{List, Side};
+
+mod set;
+pub use set::Set;
+
+mod stream;
+pub use stream::{now_millis, Fields, Group, NewId, Pending, Stream, StreamId, Trim};
+
+mod watch;
+use watch::Version;
+pub use watch::Watched;
+
+mod zset;
+pub use zset::SortedSet;
+
+// 后台任务每次持有锁时最多删除这么多个过期的 key，避免长时间挡住其他命令
+const PURGE_BATCH: usize = 1000;
+
+/// 默认把 keyspace 分成多少个 shard
+pub const DEFAULT_SHARDS: usize = 16;
+
+// 每个 key 除了 key 和 value 本身之外的大致开销（HashMap 的槽位、Entry 等），统计内存时用
+const ENTRY_OVERHEAD: usize = 64;
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// http(s) 开头的 key 对应上游的 url：GET 在 keyspace 里找不到时访问上游
+pub fn is_url(key: &[u8]) -> bool {
+    key.starts_with(b"http://") || key.starts_with(b"https://")
+}
+
+/// 保存 SET 等命令写入的数据。clone 出来的句柄共享同一份数据。
+/// key 按 hash 分到多个 shard 里，每个 shard 一把锁，访问不同 shard 的命令可以同时执行
+#[derive(Debug, Clone)]
+pub struct Db {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    shards: Vec<Mutex<Shard>>,
+    // 最早的过期时间提前了，通知后台任务重新计算要睡多久
+    background_task: Notify,
+    next_waiter: AtomicU64,
+    // 所有 shard 加起来的 used_memory，不用锁住所有的 shard 就能和 maxmemory 比较
+    used_memory: AtomicUsize,
+}
+
+// 一部分 key，以及和这些 key 有关的阻塞的客户端
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<Bytes, Entry>,
+    // 所有的 key，淘汰时从里面随机抽样。Entry::slot 是 key 在里面的下标
+    keys: Vec<Bytes>,
+    // 按过期时间排序的索引，后台任务从最早的开始删除，不用扫描所有的 key
+    expirations: BTreeSet<(Instant, Bytes)>,
+    used_memory: usize,
+    // 阻塞在每个 key 上的客户端，按阻塞的先后顺序排队
+    blocked: HashMap<Bytes, VecDeque<Waiter>>,
+    // 这条命令里有了新元素、而且有客户端在等的 list
+    ready: VecDeque<Bytes>,
+    // 等待 stream 有新 entry 的 XREAD / XREADGROUP
+    stream_waiters: HashMap<Bytes, Vec<(u64, Arc<Notify>)>>,
+    // 被 WATCH 的 key 的版本
+    watched: HashMap<Bytes, Version>,
+}
+
+/// 一条命令锁住的 shard，通过 `Db::lock` / `Db::lock_keys` 拿到。
+/// 持有期间其他命令不能访问这些 shard 里的 key；访问没有锁住的 shard 里的 key 会 panic
+#[derive(Debug)]
+pub struct Keyspace<'a> {
+    // 下标就是 shard 的编号，没有锁住的是 None
+    shards: Vec<Option<MutexGuard<'a, Shard>>>,
+}
+
+/// 一组 shard 的编号，按从小到大的顺序加锁，多条命令同时加锁也不会死锁
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shards(Vec<usize>);
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+    // 最近一次访问的时间，LRU 淘汰用
+    accessed: Instant,
+    // 访问频率的对数计数，LFU 淘汰用
+    hits: u8,
+    slot: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    Hash(Hash),
+    List(List),
+    Set(Set),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// TYPE 命令返回的类型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Hash(_) => "hash",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&Bytes> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_hash(&self) -> Option<&Hash> {
+        match self {
+            Value::Hash(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Option<&mut Hash> {
+        match self {
+            Value::Hash(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&List> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut List> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_set(&self) -> Option<&Set> {
+        match self {
+            Value::Set(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Option<&mut Set> {
+        match self {
+            Value::Set(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> Option<&SortedSet> {
+        match self {
+            Value::SortedSet(zset) => Some(zset),
+            _ => None,
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Option<&mut SortedSet> {
+        match self {
+            Value::SortedSet(zset) => Some(zset),
+            _ => None,
+        }
+    }
+
+    pub fn as_stream(&self) -> Option<&Stream> {
+        match self {
+            Value::Stream(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Option<&mut Stream> {
+        match self {
+            Value::Stream(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    // hash 这样的容器删掉最后一个元素之后，key 也随之删除。stream 例外，
+    // 和 Redis 一样 entry 删光之后还保留着（消费组和最后的 id 都还在）
+    fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::Hash(hash) => hash.is_empty(),
+            Value::List(list) => list.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(zset) => zset.is_empty(),
+            Value::Stream(_) => false,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Value::String(s) => s.len(),
+            Value::Hash(hash) => hash.size(),
+            Value::List(list) => list.size(),
+            Value::Set(set) => set.size(),
+            Value::SortedSet(zset) => zset.size(),
+            Value::Stream(stream) => stream.size(),
+        }
+    }
+}
+
+// 在后台线程里释放一个可能很大的值（有很多元素的容器、整个 keyspace）。
+// 不在 tokio runtime 里的话（比如单元测试）直接释放
+fn drop_lazily<T: Send + 'static>(value: T) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            handle.spawn_blocking(move || drop(value));
+        }
+        Err(_) => drop(value),
+    }
+}
+
+fn entry_size(key: &[u8], value: &Value) -> usize {
+    key.len() + value.size() + ENTRY_OVERHEAD
+}
+
+// key 在哪个 shard 里
+fn shard_index(key: &[u8], shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+impl Db {
+    pub fn new() -> Db {
+        Db::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// 分成 `shards` 个 shard，至少一个
+    pub fn with_shards(shards: usize) -> Db {
+        Db {
+            shared: Arc::new(Shared {
+                shards: (0..shards.max(1))
+                    .map(|_| Mutex::new(Shard::default()))
+                    .collect(),
+                background_task: Notify::new(),
+                next_waiter: AtomicU64::new(0),
+                used_memory: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// 这些 key 所在的 shard。没有 key 的命令（DBSIZE、SCAN 等）访问所有的 shard
+    pub fn shards(&self, keys: &[&[u8]]) -> Shards {
+        let count = self.shared.shards.len();
+        if keys.is_empty() {
+            return Shards((0..count).collect());
+        }
+        let mut shards: Vec<usize> = keys.iter().map(|key| shard_index(key, count)).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        Shards(shards)
+    }
+
+    /// 锁住整个 keyspace
+    pub async fn lock(&self) -> KeyspaceGuard<'_> {
+        self.lock_keys(&[]).await
+    }
+
+    /// 锁住 keys 所在的 shard。一条命令的所有读写在同一次加锁里完成，所以命令是原子的
+    pub async fn lock_keys(&self, keys: &[&[u8]]) -> KeyspaceGuard<'_> {
+        self.lock_shards(&self.shards(keys)).await
+    }
+
+    /// 按编号的顺序锁住这些 shard。被占着的 shard（比如执行中的脚本一直锁着的）异步地等，
+    /// 不会占着 tokio 的 worker，其他连接照常执行
+    pub async fn lock_shards(&self, shards: &Shards) -> KeyspaceGuard<'_> {
+        let mut keyspace = self.unlocked();
+        for &index in &shards.0 {
+            keyspace.shards[index] = Some(self.shared.shards[index].lock().await);
+        }
+        self.guard(keyspace)
+    }
+
+    /// 和 `lock` 一样，但是阻塞当前的线程。不能在异步的上下文里调用
+    pub fn blocking_lock(&self) -> KeyspaceGuard<'_> {
+        self.blocking_lock_keys(&[])
+    }
+
+    /// 和 `lock_keys` 一样，但是阻塞当前的线程。不能在异步的上下文里调用
+    pub fn blocking_lock_keys(&self, keys: &[&[u8]]) -> KeyspaceGuard<'_> {
+        self.blocking_lock_shards(&self.shards(keys))
+    }
+
+    /// 和 `lock_shards` 一样，但是阻塞当前的线程。脚本在 spawn_blocking 的线程上用它加锁；
+    /// 不能在异步的上下文里调用
+    pub fn blocking_lock_shards(&self, shards: &Shards) -> KeyspaceGuard<'_> {
+        let mut keyspace = self.unlocked();
+        for &index in &shards.0 {
+            keyspace.shards[index] = Some(self.shared.shards[index].blocking_lock());
+        }
+        self.guard(keyspace)
+    }
+
+    // 锁住这些 shard，有一个被占着的话放开已经拿到的锁，返回 None
+    fn try_lock_shards(&self, shards: &Shards) -> Option<KeyspaceGuard<'_>> {
+        let mut keyspace = self.unlocked();
+        for &index in &shards.0 {
+            keyspace.shards[index] = Some(self.shared.shards[index].try_lock().ok()?);
+        }
+        Some(self.guard(keyspace))
+    }
+
+    // Drop 里要访问一些 shard 时用，Drop 不能异步地等锁。锁都空着的话马上执行 f；
+    // 有 shard 被占着而且在 tokio runtime 里的话，交给一个任务等到锁之后再执行，不卡住当前的 worker
+    fn lock_shards_then<F>(&self, shards: Shards, f: F)
+    where
+        F: FnOnce(&mut Keyspace<'_>) + Send + 'static,
+    {
+        if let Some(mut keyspace) = self.try_lock_shards(&shards) {
+            return f(&mut keyspace);
+        }
+        match Handle::try_current() {
+            Ok(handle) => {
+                let db = self.clone();
+                handle.spawn(async move { f(&mut *db.lock_shards(&shards).await) });
+            }
+            Err(_) => f(&mut self.blocking_lock_shards(&shards)),
+        }
+    }
+
+    // 还没有锁住任何 shard 的 keyspace
+    fn unlocked(&self) -> Keyspace<'_> {
+        Keyspace {
+            shards: self.shared.shards.iter().map(|_| None).collect(),
+        }
+    }
+
+    fn guard<'a>(&'a self, keyspace: Keyspace<'a>) -> KeyspaceGuard<'a> {
+        let next = keyspace.next_expiration();
+        let used_memory = keyspace.used_memory();
+        KeyspaceGuard {
+            keyspace,
+            next,
+            used_memory,
+            db: self,
+        }
+    }
+
+    /// 所有 key 和 value 大致占用的内存，不用加锁
+    pub fn used_memory(&self) -> usize {
+        self.shared.used_memory.load(Ordering::Relaxed)
+    }
+
+    // 每个 shard 删除一批已经过期的 key，返回下一个 key 的过期时间。
+    // 还有没删完的过期 key 时，返回的时间已经过去了，后台任务会马上再来一次
+    async fn purge_expired_keys(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut next = None;
+
+        for index in 0..self.shared.shards.len() {
+            let mut keyspace = self.lock_shards(&Shards(vec![index])).await;
+            for shard in keyspace.locked_shards_mut() {
+                for _ in 0..PURGE_BATCH {
+                    match shard.expirations.first() {
+                        Some((when, key)) if *when <= now => {
+                            let key = key.clone();
+                            shard.remove_entry(&key);
+                            METRICS.expired_keys.inc();
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            next = earliest(next, keyspace.next_expiration());
+        }
+
+        next
+    }
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Default for Db {
+    fn default() -> Db {
+        Db::new()
+    }
+}
+
+/// 后台删除过期 key 的任务。没有被访问到的过期 key 也会按时删除，释放内存；
+/// server shutdown 时结束
+pub async fn purge_expired_keys(db: Db, mut shutdown: Shutdown) {
+    while !shutdown.is_shutdown() {
+        let next = db.purge_expired_keys().await;
+        let sleep = async {
+            match next {
+                Some(when) => time::sleep_until(when.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = sleep => {}
+            _ = db.shared.background_task.notified() => {}
+            _ = shutdown.recv() => {}
+        }
+    }
+}
+
+/// 持有一组 shard 的锁。释放时先把新放入 list 的元素交给阻塞的客户端，
+/// 再把这些 shard 占用内存的变化加到总数上；如果最早的过期时间提前了，唤醒后台任务
+pub struct KeyspaceGuard<'a> {
+    keyspace: Keyspace<'a>,
+    next: Option<Instant>,
+    // 加锁时这些 shard 占用的内存
+    used_memory: usize,
+    db: &'a Db,
+}
+
+impl<'a> Deref for KeyspaceGuard<'a> {
+    type Target = Keyspace<'a>;
+
+    fn deref(&self) -> &Keyspace<'a> {
+        &self.keyspace
+    }
+}
+
+impl<'a> DerefMut for KeyspaceGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Keyspace<'a> {
+        &mut self.keyspace
+    }
+}
+
+impl Drop for KeyspaceGuard<'_> {
+    fn drop(&mut self) {
+        let missing = self.keyspace.serve_blocked();
+
+        let used_memory = self.keyspace.used_memory();
+        let total = &self.db.shared.used_memory;
+        if used_memory >= self.used_memory {
+            total.fetch_add(used_memory - self.used_memory, Ordering::Relaxed);
+        } else {
+            total.fetch_sub(self.used_memory - used_memory, Ordering::Relaxed);
+        }
+
+        let next = self.keyspace.next_expiration();
+        if next.is_some() && (self.next.is_none() || next < self.next) {
+            self.db.shared.background_task.notify_one();
+        }
+
+        // BLMOVE 要把元素放到没有锁住的 shard 里：先放掉手上的锁，
+        // 再按顺序把需要的 shard 一起锁上，释放时接着交给阻塞的客户端
+        if let Some(index) = missing {
+            let mut shards = self.keyspace.locked().collect::<Vec<_>>();
+            shards.push(index);
+            shards.sort_unstable();
+            self.keyspace.shards.clear();
+            self.db.lock_shards_then(Shards(shards), |_| {});
+        }
+    }
+}
+
+impl Shard {
+    fn insert(&mut self, key: Bytes, value: Value, expires_at: Option<Instant>) {
+        self.used_memory += entry_size(&key, &value);
+        if let Some(when) = expires_at {
+            self.expirations.insert((when, key.clone()));
+        }
+        self.modified(&key);
+        let entry = Entry::new(value, expires_at, self.keys.len());
+        self.keys.push(key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.first().map(|(when, _)| *when)
+    }
+
+    // key 是否存在。已经过期的 key 在这里删除（惰性过期）
+    fn lookup(&mut self, key: &[u8]) -> bool {
+        let now = Instant::now();
+        let expired = match self.entries.get_mut(key) {
+            Some(entry) if entry.expires_at.is_some_and(|when| when <= now) => true,
+            Some(entry) => {
+                entry.touch(now);
+                false
+            }
+            None => return false,
+        };
+        if expired {
+            self.remove_entry(key);
+            METRICS.expired_keys.inc();
+        }
+        !expired
+    }
+
+    fn remove_entry(&mut self, key: &[u8]) -> Option<Value> {
+        let (key, entry) = self.entries.remove_entry(key)?;
+        self.modified(&key);
+        self.keys.swap_remove(entry.slot);
+        if let Some(moved) = self.keys.get(entry.slot) {
+            if let Some(moved) = self.entries.get_mut(moved) {
+                moved.slot = entry.slot;
+            }
+        }
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.clone()));
+        }
+        self.used_memory -= entry_size(&key, &entry.value);
+        Some(entry.value)
+    }
+}
+
+impl<'a> Keyspace<'a> {
+    // key 所在的 shard。命令访问的 key 都应该在加锁的时候声明过
+    fn shard(&self, key: &[u8]) -> &Shard {
+        let index = shard_index(key, self.shards.len());
+        self.shards[index]
+            .as_deref()
+            .expect("the shard of the key is not locked")
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut Shard {
+        let index = shard_index(key, self.shards.len());
+        self.shards[index]
+            .as_deref_mut()
+            .expect("the shard of the key is not locked")
+    }
+
+    // 锁住的 shard 的编号
+    fn locked(&self) -> impl Iterator<Item = usize> + '_ {
+        self.shards
+            .iter()
+            .enumerate()
+            .filter_map(|(index, shard)| shard.as_ref().map(|_| index))
+    }
+
+    fn locked_shards(&self) -> impl Iterator<Item = &Shard> {
+        self.shards.iter().flatten().map(|shard| &**shard)
+    }
+
+    fn locked_shards_mut(&mut self) -> impl Iterator<Item = &mut MutexGuard<'a, Shard>> {
+        self.shards.iter_mut().flatten()
+    }
+
+    /// key 所在的 shard 有没有锁住。脚本事先只声明了部分 key，访问别的 key 之前要检查
+    pub fn is_locked(&self, key: &[u8]) -> bool {
+        self.shards[shard_index(key, self.shards.len())].is_some()
+    }
+
+    /// 是不是锁住了所有的 shard。DBSIZE、SCAN 这样访问所有 key 的命令需要
+    pub fn all_locked(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_some())
+    }
+
+    /// 读命令查找一个 key，计入命中 / 未命中的统计
+    pub fn get(&mut self, key: &[u8]) -> Option<&Value> {
+        let shard = self.shard_mut(key);
+        if shard.lookup(key) {
+            METRICS.keyspace_hits.inc();
+        } else {
+            METRICS.keyspace_misses.inc();
+        }
+        shard.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// 和 `get` 一样，但是不计入统计。写命令先看看旧的值时用
+    pub fn peek(&mut self, key: &[u8]) -> Option<&Value> {
+        let shard = self.shard_mut(key);
+        shard.lookup(key);
+        shard.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// 不做过期检查的只读查找。一条命令要同时借用多个 key 的值时，
+    /// 先用 `get` / `peek` 访问一遍（过期的 key 已经删掉了），再用它一起借出来
+    pub fn value(&self, key: &[u8]) -> Option<&Value> {
+        self.shard(key).entries.get(key).map(|entry| &entry.value)
+    }
+
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.shard_mut(key).lookup(key)
+    }
+
+    /// 写入一个 key，替换掉原来的值和过期时间，返回原来的值
+    pub fn insert(
+        &mut self,
+        key: Bytes,
+        value: Value,
+        expires_at: Option<Instant>,
+    ) -> Option<Value> {
+        let old = self.remove(&key);
+        self.shard_mut(&key).insert(key, value, expires_at);
+        old
+    }
+
+    /// 修改 key 的值，保留原来的过期时间。key 不存在时写入一个不过期的 key
+    pub fn set_value(&mut self, key: Bytes, value: Value) {
+        let expires_at = self.expires_at(&key).flatten();
+        self.insert(key, value, expires_at);
+    }
+
+    /// 就地修改 key 的值（hash 等容器不用整个换掉），返回 f 的结果；key 不存在时返回 None。
+    /// 修改之后容器空了的话删除这个 key
+    pub fn modify<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Value) -> R) -> Option<R> {
+        let shard = self.shard_mut(key);
+        if !shard.lookup(key) {
+            return None;
+        }
+        let entry = shard.entries.get_mut(key)?;
+        let before = entry_size(key, &entry.value);
+
+        let res = f(&mut entry.value);
+        let empty = entry.value.is_empty();
+        let after = entry_size(key, &entry.value);
+        shard.used_memory = shard.used_memory - before + after;
+        shard.modified(key);
+
+        if empty {
+            shard.remove_entry(key);
+        }
+        Some(res)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        let shard = self.shard_mut(key);
+        if !shard.lookup(key) {
+            return None;
+        }
+        shard.remove_entry(key)
+    }
+
+    /// key 的过期时间：key 不存在时是 None，没有过期时间时是 Some(None)
+    pub fn expires_at(&mut self, key: &[u8]) -> Option<Option<Instant>> {
+        let shard = self.shard_mut(key);
+        if !shard.lookup(key) {
+            return None;
+        }
+        shard.entries.get(key).map(|entry| entry.expires_at)
+    }
+
+    /// 修改 key 的过期时间，None 表示不过期。key 不存在时返回 false
+    pub fn set_expires_at(&mut self, key: &[u8], expires_at: Option<Instant>) -> bool {
+        let shard = self.shard_mut(key);
+        if !shard.lookup(key) {
+            return false;
+        }
+        let old = match shard.entries.get_mut(key) {
+            Some(entry) => std::mem::replace(&mut entry.expires_at, expires_at),
+            None => return false,
+        };
+
+        shard.modified(key);
+        let key = Bytes::copy_from_slice(key);
+        if let Some(when) = old {
+            shard.expirations.remove(&(when, key.clone()));
+        }
+        if let Some(when) = expires_at {
+            shard.expirations.insert((when, key));
+        }
+        true
+    }
+
+    /// 把 key 改名为 to，过期时间跟着一起走；to 原来的值被覆盖。from 不存在时返回 false。
+    /// 改名过来的 list / stream 上有客户端在等的话，和写入新元素一样唤醒它们
+    pub fn rename(&mut self, from: &[u8], to: Bytes) -> bool {
+        let expires_at = match self.expires_at(from) {
+            Some(expires_at) => expires_at,
+            None => return false,
+        };
+        let value = match self.shard_mut(from).remove_entry(from) {
+            Some(value) => value,
+            None => return false,
+        };
+        self.insert(to.clone(), value, expires_at);
+
+        match self.value(&to) {
+            Some(Value::List(_)) => self.signal_ready(&to),
+            Some(Value::Stream(_)) => self.signal_stream(&to),
+            _ => {}
+        }
+        true
+    }
+
+    /// UNLINK：删除 key，容器类型的值在后台释放。key 不存在时返回 false
+    pub fn unlink(&mut self, key: &[u8]) -> bool {
+        match self.remove(key) {
+            Some(Value::String(_)) => true,
+            Some(value) => {
+                drop_lazily(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// FLUSHDB：删除锁住的 shard 里所有的 key。lazy 的话在后台释放所有的值，不占用持有锁的时间
+    pub fn flush(&mut self, lazy: bool) {
+        for shard in self.locked_shards_mut() {
+            let entries = std::mem::take(&mut shard.entries);
+            shard.keys.clear();
+            shard.expirations.clear();
+            shard.used_memory = 0;
+            shard.modified_all();
+            if lazy {
+                drop_lazily(entries);
+            }
+        }
+    }
+
+    /// 锁住的 shard 里所有没有过期的 key 和值，KEYS / SCAN 用。不做惰性删除，所以只需要 &self
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Value)> {
+        let now = Instant::now();
+        self.locked_shards()
+            .flat_map(|shard| shard.entries.iter())
+            .filter(move |(_, entry)| entry.expires_at.is_none_or(|when| when > now))
+            .map(|(key, entry)| (key, &entry.value))
+    }
+
+    /// 锁住的 shard 里 key 的个数（包括已经过期、还没有被删除的）
+    pub fn len(&self) -> usize {
+        self.locked_shards().map(|shard| shard.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 设置了过期时间的 key 的个数
+    pub fn expires(&self) -> usize {
+        self.locked_shards()
+            .map(|shard| shard.expirations.len())
+            .sum()
+    }
+
+    /// 锁住的 shard 里所有 key 和 value 大致占用的内存
+    pub fn used_memory(&self) -> usize {
+        self.locked_shards().map(|shard| shard.used_memory).sum()
+    }
+
+    fn next_expiration(&self) -> Option<Instant> {
+        self.locked_shards()
+            .map(Shard::next_expiration)
+            .fold(None, earliest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    fn string(s: &'static str) -> Value {
+        Value::String(Bytes::from(s))
+    }
+
+    #[test]
+    fn ts_insert_and_expire() {
+        let db = Db::new();
+        let mut keyspace = db.blocking_lock();
+
+        assert_eq!(keyspace.insert(Bytes::from("a"), string("1"), None), None);
+        assert_eq!(
+            keyspace.insert(Bytes::from("a"), string("2"), None),
+            Some(string("1"))
+        );
+        assert_eq!(keyspace.used_memory(), 2 + ENTRY_OVERHEAD);
+
+        let past = Instant::now() - Duration::from_millis(1);
+        keyspace.insert(Bytes::from("b"), string("1"), Some(past));
+        assert_eq!((keyspace.len(), keyspace.expires()), (2, 1));
+        // 访问时才发现过期，马上删除
+        assert_eq!(keyspace.get(b"b"), None);
+        assert_eq!((keyspace.len(), keyspace.expires()), (1, 0));
+
+        let later = Instant::now() + Duration::from_secs(60);
+        assert!(keyspace.set_expires_at(b"a", Some(later)));
+        assert_eq!(keyspace.expires_at(b"a"), Some(Some(later)));
+        assert!(keyspace.set_expires_at(b"a", None));
+        assert_eq!(keyspace.expires(), 0);
+        assert!(!keyspace.set_expires_at(b"missing", None));
+
+        keyspace.remove(b"a");
+        assert_eq!(keyspace.used_memory(), 0);
+    }
+
+    #[tokio::test]
+    async fn ts_purge_in_background() {
+        let db = Db::new();
+        let (notify, _) = broadcast::channel(1);
+        let task = tokio::spawn(purge_expired_keys(
+            db.clone(),
+            Shutdown::new(notify.subscribe()),
+        ));
+
+        let soon = Instant::now() + Duration::from_millis(20);
+        for i in 0..10 {
+            db.lock()
+                .await
+                .insert(Bytes::from(format!("k{}", i)), string("v"), Some(soon));
+        }
+        db.lock()
+            .await
+            .insert(Bytes::from("kept"), string("v"), None);
+
+        // 没有任何访问，过期的 key 也被删掉了
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(db.lock().await.len(), 1);
+
+        drop(notify);
+        task.await.unwrap();
+    }
+}
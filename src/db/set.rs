@@ -0,0 +1,63 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+// 每个成员除了内容之外的大致开销，统计内存时用
+const MEMBER_OVERHEAD: usize = 16;
+
+/// set 类型的值。和 hash 一样随着修改记录占用的内存
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Set {
+    members: HashSet<Bytes>,
+    size: usize,
+}
+
+impl Set {
+    /// 加入一个成员，原来没有的话返回 true
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        let len = member.len();
+        let added = self.members.insert(member);
+        if added {
+            self.size += len + MEMBER_OVERHEAD;
+        }
+        added
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        let removed = self.members.remove(member);
+        if removed {
+            self.size -= member.len() + MEMBER_OVERHEAD;
+        }
+        removed
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.members.contains(member)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Bytes> {
+        self.members.iter()
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl FromIterator<Bytes> for Set {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(iter: I) -> Set {
+        let mut set = Set::default();
+        for member in iter {
+            set.insert(member);
+        }
+        set
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use super::{Keyspace, Value, WRONGTYPE};
+
+// 每个 entry 除了字段内容之外的大致开销，统计内存时用
+const ENTRY_OVERHEAD: usize = 32;
+// 每个字段的开销
+const FIELD_OVERHEAD: usize = 16;
+// 消费组里每个还没有确认的 entry 的开销
+const PENDING_OVERHEAD: usize = 48;
+
+/// entry 的字段，按写入的顺序
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+/// stream entry 的 id：`<毫秒时间戳>-<序号>`，在一个 stream 里单调递增
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// 解析 `ms-seq`，只写了 `ms` 时序号用 default_seq（范围的开始是 0，结束是最大值）
+    pub fn parse(s: &[u8], default_seq: u64) -> Option<StreamId> {
+        let s = std::str::from_utf8(s).ok()?;
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().ok()?),
+            None => (s, default_seq),
+        };
+        Some(StreamId {
+            ms: ms.parse().ok()?,
+            seq,
+        })
+    }
+
+    /// 紧跟在后面的 id，已经是最大的 id 时返回 None
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    /// 紧挨在前面的 id，已经是 0-0 时返回 None
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// XADD 指定的新 entry 的 id
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewId {
+    /// `*`：用当前时间生成
+    Auto,
+    /// `<ms>-*`：时间戳是指定的，序号自动生成
+    AutoSeq(u64),
+    Exact(StreamId),
+}
+
+/// XTRIM 和 XADD 的裁剪方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trim {
+    /// 只保留最新的这么多个 entry
+    MaxLen(usize),
+    /// 删掉 id 比它小的 entry
+    MinId(StreamId),
+}
+
+/// 消费组里一个已经交给某个消费者、还没有被 XACK 确认的 entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub consumer: Bytes,
+    /// 最后一次交出去的时间（毫秒时间戳）
+    pub delivered_at: u64,
+    /// 交出去过几次。超过一定次数的一般是处理不了的消息
+    pub deliveries: u64,
+}
+
+/// 消费组：组里的消费者分摊 stream 里的 entry，每个 entry 只交给其中一个。
+/// 交出去的 entry 在确认之前一直留在 pending 列表里，消费者挂掉之后可以被别的消费者认领，
+/// 所以每个 entry 至少会被处理一次
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Group {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, Pending>,
+    // 消费者最后一次活动的时间
+    consumers: BTreeMap<Bytes, u64>,
+}
+
+impl Group {
+    pub fn last_delivered(&self) -> StreamId {
+        self.last_delivered
+    }
+
+    /// 按 id 排序的 pending 列表
+    pub fn pending(&self) -> &BTreeMap<StreamId, Pending> {
+        &self.pending
+    }
+
+    /// 所有的消费者，以及各自有多少个 pending 的 entry
+    pub fn consumers(&self) -> Vec<(&Bytes, usize)> {
+        self.consumers
+            .keys()
+            .map(|name| {
+                let count = self
+                    .pending
+                    .values()
+                    .filter(|pending| pending.consumer == name)
+                    .count();
+                (name, count)
+            })
+            .collect()
+    }
+
+    fn seen(&mut self, consumer: &Bytes, now: u64) {
+        self.consumers.insert(consumer.clone(), now);
+    }
+}
+
+/// stream 类型的值：按 id 排序、只能在末尾追加的 entry，加上消费组。
+/// 和其他容器不一样，entry 被删光之后 key 仍然保留（消费组还在）
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    groups: BTreeMap<Bytes, Group>,
+    size: usize,
+}
+
+impl Stream {
+    /// 追加一个 entry，返回它的 id。id 必须比已有的都大
+    pub fn add(&mut self, id: NewId, fields: Fields, now: u64) -> Result<StreamId, String> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if now > last.ms => Some(StreamId { ms: now, seq: 0 }),
+            NewId::Auto => last.next(),
+            NewId::AutoSeq(ms) if ms > last.ms => Some(StreamId { ms, seq: 0 }),
+            NewId::AutoSeq(ms) if ms == last.ms => {
+                last.seq.checked_add(1).map(|seq| StreamId { ms, seq })
+            }
+            NewId::AutoSeq(_) => None,
+            NewId::Exact(StreamId::MIN) => {
+                return Err("ERR The ID specified in XADD must be greater than 0-0".to_string())
+            }
+            NewId::Exact(id) => Some(id).filter(|id| *id > last),
+        };
+        let id = match id {
+            Some(id) => id,
+            None if last == StreamId::MAX => {
+                return Err(
+                    "ERR The stream has exhausted the last possible ID, unable to add more items"
+                        .to_string(),
+                )
+            }
+            None => return Err(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_string(),
+            ),
+        };
+
+        self.size += entry_size(&fields);
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 最后一次加入的 entry 的 id。entry 被删掉之后也不会变小
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn get(&self, id: &StreamId) -> Option<&Fields> {
+        self.entries.get(id)
+    }
+
+    /// id 在 start 和 end 之间（都包括）的 entry，从旧到新
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        let range = if start <= end {
+            Some(self.entries.range(start..=end))
+        } else {
+            None
+        };
+        range.into_iter().flatten()
+    }
+
+    /// id 比 after 大的 entry，XREAD 用
+    pub fn after(&self, after: StreamId) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.entries
+            .range((Bound::Excluded(after), Bound::Unbounded))
+    }
+
+    /// 裁剪掉旧的 entry，返回删掉了几个
+    pub fn trim(&mut self, trim: Trim) -> usize {
+        let mut removed = 0;
+        while let Some((id, _)) = self.entries.first_key_value() {
+            let keep = match trim {
+                Trim::MaxLen(max) => self.entries.len() <= max,
+                Trim::MinId(min) => *id >= min,
+            };
+            if keep {
+                break;
+            }
+            if let Some((_, fields)) = self.entries.pop_first() {
+                self.size -= entry_size(&fields);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// 创建消费组，从 id 之后的 entry 开始读。组已经存在时返回 false
+    pub fn create_group(&mut self, name: Bytes, id: StreamId) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        self.groups.insert(
+            name,
+            Group {
+                last_delivered: id,
+                ..Group::default()
+            },
+        );
+        true
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&Group> {
+        self.groups.get(name)
+    }
+
+    /// XREADGROUP `>`：把组里还没有交出去过的 entry 交给 consumer。
+    /// 不是 noack 的话记到 pending 列表里，等 consumer 确认。组不存在时返回 None
+    pub fn deliver(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        count: Option<usize>,
+        noack: bool,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Fields)>> {
+        let group = self.groups.get_mut(group)?;
+        group.seen(consumer, now);
+
+        let delivered: Vec<(StreamId, Fields)> = self
+            .entries
+            .range((Bound::Excluded(group.last_delivered), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect();
+        if let Some((id, _)) = delivered.last() {
+            group.last_delivered = *id;
+        }
+        if !noack {
+            for (id, _) in &delivered {
+                let old = group.pending.insert(
+                    *id,
+                    Pending {
+                        consumer: consumer.clone(),
+                        delivered_at: now,
+                        deliveries: 1,
+                    },
+                );
+                if old.is_none() {
+                    self.size += PENDING_OVERHEAD;
+                }
+            }
+        }
+        Some(delivered)
+    }
+
+    /// XREADGROUP 指定 id：consumer 自己 pending 列表里 id 之后的 entry（重新处理历史消息用）。
+    /// entry 已经被删掉了的话字段是 None。组不存在时返回 None
+    pub fn history(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        after: StreamId,
+        count: Option<usize>,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Option<Fields>)>> {
+        let group = self.groups.get_mut(group)?;
+        group.seen(consumer, now);
+
+        let history = group
+            .pending
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .filter(|(_, pending)| pending.consumer == consumer)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, _)| (*id, self.entries.get(id).cloned()))
+            .collect();
+        Some(history)
+    }
+
+    /// XACK：从 pending 列表里删掉确认了的 entry，返回删掉了几个。组不存在时返回 0
+    pub fn ack(&mut self, group: &[u8], ids: &[StreamId]) -> usize {
+        let group = match self.groups.get_mut(group) {
+            Some(group) => group,
+            None => return 0,
+        };
+        let acked = ids
+            .iter()
+            .filter(|id| group.pending.remove(id).is_some())
+            .count();
+        self.size -= acked * PENDING_OVERHEAD;
+        acked
+    }
+
+    /// XCLAIM：把空闲了至少 min_idle 毫秒的 pending entry 转给 consumer，
+    /// 返回转过来的 entry。已经被删掉的 entry 直接从 pending 列表里去掉。
+    /// just_id 时不增加交付次数。组不存在时返回 None
+    pub fn claim(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        min_idle: u64,
+        ids: &[StreamId],
+        just_id: bool,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Fields)>> {
+        let group = self.groups.get_mut(group)?;
+        group.seen(consumer, now);
+
+        let mut claimed = Vec::new();
+        for id in ids {
+            let pending = match group.pending.get_mut(id) {
+                Some(pending) if now.saturating_sub(pending.delivered_at) >= min_idle => pending,
+                _ => continue,
+            };
+            let fields = match self.entries.get(id) {
+                Some(fields) => fields,
+                None => {
+                    group.pending.remove(id);
+                    self.size -= PENDING_OVERHEAD;
+                    continue;
+                }
+            };
+            pending.consumer = consumer.clone();
+            pending.delivered_at = now;
+            if !just_id {
+                pending.deliveries += 1;
+            }
+            claimed.push((*id, fields.clone()));
+        }
+        Some(claimed)
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+fn entry_size(fields: &Fields) -> usize {
+    ENTRY_OVERHEAD
+        + fields
+            .iter()
+            .map(|(field, value)| field.len() + value.len() + FIELD_OVERHEAD)
+            .sum::<usize>()
+}
+
+/// 当前的毫秒时间戳，用来生成 entry 的 id、计算 pending entry 空闲了多久
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Keyspace<'_> {
+    /// XADD：往 stream 里追加一个 entry，之后按 trim 裁剪，并唤醒在这个 key 上等待的 XREAD。
+    /// key 不存在时，create 为 false 就什么也不做，返回 None
+    pub fn add_entry(
+        &mut self,
+        key: &Bytes,
+        id: NewId,
+        fields: Fields,
+        create: bool,
+        trim: Option<Trim>,
+    ) -> Result<Option<StreamId>, String> {
+        let created = match self.peek(key) {
+            Some(value) if value.as_stream().is_none() => return Err(WRONGTYPE.to_string()),
+            Some(_) => false,
+            None if create => {
+                self.insert(key.clone(), Value::Stream(Stream::default()), None);
+                true
+            }
+            None => return Ok(None),
+        };
+
+        let added = self.modify(key, |value| {
+            let stream = value.as_stream_mut()?;
+            let id = stream.add(id, fields, now_millis());
+            if let (Ok(_), Some(trim)) = (&id, trim) {
+                stream.trim(trim);
+            }
+            Some(id)
+        });
+        let id = match added.flatten() {
+            Some(Ok(id)) => id,
+            Some(Err(msg)) => {
+                // 新建的 stream 没能加进 entry 的话，不留下空的 key
+                if created {
+                    self.remove(key);
+                }
+                return Err(msg);
+            }
+            None => return Err(WRONGTYPE.to_string()),
+        };
+
+        self.signal_stream(key);
+        Ok(Some(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(n: usize) -> Fields {
+        (0..n)
+            .map(|i| (Bytes::from(format!("f{}", i)), Bytes::from("v")))
+            .collect()
+    }
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    fn ts_stream_ids() {
+        let mut stream = Stream::default();
+        assert_eq!(stream.add(NewId::Auto, fields(1), 100), Ok(id(100, 0)));
+        // 时钟回拨时沿用最后一个 id 的时间戳
+        assert_eq!(stream.add(NewId::Auto, fields(1), 90), Ok(id(100, 1)));
+        assert_eq!(
+            stream.add(NewId::AutoSeq(100), fields(1), 0),
+            Ok(id(100, 2))
+        );
+        assert_eq!(
+            stream.add(NewId::Exact(id(200, 5)), fields(1), 0),
+            Ok(id(200, 5))
+        );
+        assert!(stream.add(NewId::Exact(id(200, 5)), fields(1), 0).is_err());
+        assert!(stream.add(NewId::AutoSeq(150), fields(1), 0).is_err());
+
+        assert_eq!(
+            Stream::default().add(NewId::AutoSeq(0), fields(1), 0),
+            Ok(id(0, 1))
+        );
+        assert!(Stream::default()
+            .add(NewId::Exact(StreamId::MIN), fields(1), 0)
+            .is_err());
+
+        assert_eq!(StreamId::parse(b"5", u64::MAX), Some(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse(b"5-x", 0), None);
+        assert_eq!(id(5, u64::MAX).next(), Some(id(6, 0)));
+        assert_eq!(StreamId::MIN.prev(), None);
+
+        assert_eq!(stream.trim(Trim::MaxLen(2)), 2);
+        assert_eq!(stream.trim(Trim::MinId(id(200, 0))), 1);
+        assert_eq!(stream.len(), 1);
+        assert_eq!(stream.last_id(), id(200, 5));
+    }
+
+    #[test]
+    fn ts_consumer_group() {
+        let mut stream = Stream::default();
+        for ms in 1..=3 {
+            stream.add(NewId::Exact(id(ms, 0)), fields(2), 0).unwrap();
+        }
+        let (alice, bob) = (Bytes::from("alice"), Bytes::from("bob"));
+        assert!(stream.create_group(Bytes::from("g"), StreamId::MIN));
+        assert!(!stream.create_group(Bytes::from("g"), StreamId::MIN));
+        assert_eq!(stream.deliver(b"missing", &alice, None, false, 0), None);
+
+        // 每个 entry 只交给组里的一个消费者
+        let first = stream.deliver(b"g", &alice, Some(2), false, 1000).unwrap();
+        assert_eq!(first.len(), 2);
+        let second = stream.deliver(b"g", &bob, None, false, 1000).unwrap();
+        assert_eq!(second[0].0, id(3, 0));
+        assert!(stream
+            .deliver(b"g", &bob, None, false, 1000)
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(stream.ack(b"g", &[id(1, 0), id(1, 0), id(9, 0)]), 1);
+        assert_eq!(
+            stream.history(b"g", &alice, StreamId::MIN, None, 1000),
+            Some(vec![(id(2, 0), Some(fields(2)))])
+        );
+
+        // alice 挂了，bob 认领她没有确认的 entry
+        assert!(stream
+            .claim(b"g", &bob, 5000, &[id(2, 0)], false, 2000)
+            .unwrap()
+            .is_empty());
+        let claimed = stream
+            .claim(b"g", &bob, 5000, &[id(2, 0)], false, 7000)
+            .unwrap();
+        assert_eq!(claimed, vec![(id(2, 0), fields(2))]);
+        let pending = &stream.group(b"g").unwrap().pending()[&id(2, 0)];
+        assert_eq!(
+            (pending.consumer.as_ref(), pending.deliveries),
+            (&b"bob"[..], 2)
+        );
+        assert_eq!(
+            stream.group(b"g").unwrap().consumers(),
+            vec![(&alice, 0), (&bob, 2)]
+        );
+
+        stream.ack(b"g", &[id(2, 0), id(3, 0)]);
+        stream.trim(Trim::MaxLen(0));
+        assert_eq!(stream.size(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+use bytes::Bytes;
+
+use super::{Db, Keyspace, KeyspaceGuard, Shard};
+
+// 被 WATCH 的 key 的版本。key 被修改（包括删除、过期、淘汰）时加一
+#[derive(Debug, Default)]
+pub(super) struct Version {
+    version: u64,
+    // 有几个客户端在 WATCH 这个 key，没有了就不再记录
+    watchers: usize,
+}
+
+/// 一个客户端 WATCH 的 key 和当时的版本。被 drop 时（EXEC、DISCARD、UNWATCH、断开）不再记录这些 key
+#[derive(Debug)]
+pub struct Watched {
+    keys: Vec<(Bytes, u64)>,
+    db: Db,
+}
+
+impl KeyspaceGuard<'_> {
+    /// WATCH：记下这些 key 现在的版本
+    pub fn watch(&mut self, keys: Vec<Bytes>) -> Watched {
+        let keys = keys
+            .into_iter()
+            .map(|key| {
+                let version = self
+                    .keyspace
+                    .shard_mut(&key)
+                    .watched
+                    .entry(key.clone())
+                    .or_default();
+                version.watchers += 1;
+                let version = version.version;
+                (key, version)
+            })
+            .collect();
+        Watched {
+            keys,
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl Watched {
+    /// 再 WATCH 一些 key
+    pub fn extend(&mut self, mut other: Watched) {
+        self.keys.append(&mut other.keys);
+    }
+
+    pub fn keys(&self) -> Vec<&[u8]> {
+        self.keys.iter().map(|(key, _)| key.as_ref()).collect()
+    }
+
+    /// WATCH 之后有没有 key 被修改过。要在锁住了这些 key 的 keyspace 上调用；
+    /// 已经过期、还没有被删除的 key 在这里删除，也算被修改过
+    pub fn changed(&self, keyspace: &mut Keyspace) -> bool {
+        self.keys.iter().any(|(key, version)| {
+            keyspace.contains(key);
+            keyspace
+                .shard(key)
+                .watched
+                .get(key)
+                .is_none_or(|current| current.version != *version)
+        })
+    }
+}
+
+impl Drop for Watched {
+    fn drop(&mut self) {
+        if self.keys.is_empty() {
+            return;
+        }
+        let shards = self.db.shards(&self.keys());
+        let keys = std::mem::take(&mut self.keys);
+        self.db.lock_shards_then(shards, move |keyspace| {
+            for (key, _) in &keys {
+                let shard = keyspace.shard_mut(key);
+                if let Some(version) = shard.watched.get_mut(key) {
+                    version.watchers -= 1;
+                    if version.watchers == 0 {
+                        shard.watched.remove(key);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Shard {
+    // key 被修改了，WATCH 它的客户端的 EXEC 会失败
+    pub(super) fn modified(&mut self, key: &[u8]) {
+        if self.watched.is_empty() {
+            return;
+        }
+        if let Some(version) = self.watched.get_mut(key) {
+            version.version += 1;
+        }
+    }
+
+    // FLUSHDB 删除了这个 shard 里所有的 key
+    pub(super) fn modified_all(&mut self) {
+        for version in self.watched.values_mut() {
+            version.version += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Value;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn ts_watch_versions() {
+        let db = Db::new();
+        let key = Bytes::from("job:1");
+        db.blocking_lock()
+            .insert(key.clone(), Value::String(Bytes::from("queued")), None);
+
+        let watched = db.blocking_lock_keys(&[&key]).watch(vec![key.clone()]);
+        // 只是读不算修改
+        db.blocking_lock().get(&key);
+        assert!(!watched.changed(&mut db.blocking_lock()));
+        db.blocking_lock()
+            .insert(key.clone(), Value::String(Bytes::from("running")), None);
+        assert!(watched.changed(&mut db.blocking_lock()));
+        drop(watched);
+        assert!(db
+            .blocking_lock()
+            .locked_shards()
+            .all(|shard| shard.watched.is_empty()));
+
+        // WATCH 之后过期了也算修改
+        let soon = Instant::now() + Duration::from_millis(10);
+        db.blocking_lock().set_expires_at(&key, Some(soon));
+        let watched = db
+            .blocking_lock()
+            .watch(vec![key.clone(), Bytes::from("missing")]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(watched.changed(&mut db.blocking_lock()));
+    }
+}
@@ -0,0 +1,180 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+use bytes::Bytes;
+
+// 每个成员除了内容之外的大致开销，统计内存时用。成员在两个索引里各存了一份（Bytes 共享内容）
+const MEMBER_OVERHEAD: usize = 48;
+
+// 按分数排序时用的包装：分数不会是 NaN，所以可以用 total_cmp 排序
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// sorted set 类型的值：按分数（分数相同时按成员的字节序）排序的成员。
+/// 查分数用 HashMap，按顺序遍历、按分数范围查找用 BTreeSet
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    order: BTreeSet<(Score, Bytes)>,
+    size: usize,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &SortedSet) -> bool {
+        self.order == other.order
+    }
+}
+
+impl SortedSet {
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// 加入成员或者修改它的分数，返回原来的分数。分数不能是 NaN
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        debug_assert!(!score.is_nan());
+        // -0 和 0 当作同一个分数
+        let score = score + 0.0;
+        let old = self.remove(&member);
+        self.size += member.len() + MEMBER_OVERHEAD;
+        self.order.insert((Score(score), member.clone()));
+        self.scores.insert(member, score);
+        old
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let (member, score) = self.scores.remove_entry(member)?;
+        self.order.remove(&(Score(score), member.clone()));
+        self.size -= member.len() + MEMBER_OVERHEAD;
+        Some(score)
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// 按顺序遍历所有成员和分数
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.order.iter().map(|(score, member)| (member, score.0))
+    }
+
+    /// 成员的排名（从 0 开始，分数最小的是 0）。要数出前面有多少个成员，是 O(rank) 的
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let (member, score) = self.scores.get_key_value(member)?;
+        Some(self.order.range(..(Score(*score), member.clone())).count())
+    }
+
+    /// 分数在 min 和 max 之间的成员，从小到大
+    pub fn range_by_score(
+        &self,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> impl Iterator<Item = (&Bytes, f64)> {
+        let start = match min {
+            Bound::Included(s) | Bound::Excluded(s) => Bound::Included((Score(s), Bytes::new())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.order
+            .range((start, Bound::Unbounded))
+            .map(|(score, member)| (member, score.0))
+            .skip_while(move |(_, score)| matches!(min, Bound::Excluded(s) if *score == s))
+            .take_while(move |(_, score)| match max {
+                Bound::Included(s) => *score <= s,
+                Bound::Excluded(s) => *score < s,
+                Bound::Unbounded => true,
+            })
+    }
+
+    /// 成员在 min 和 max 之间（按字节序）的成员。和 Redis 一样，假定所有成员的分数相同
+    pub fn range_by_lex(
+        &self,
+        min: Bound<Bytes>,
+        max: Bound<Bytes>,
+    ) -> impl Iterator<Item = (&Bytes, f64)> {
+        let score = self.order.first().map_or(Score(0.0), |(score, _)| *score);
+        let start = match min {
+            Bound::Included(m) => Bound::Included((score, m)),
+            Bound::Excluded(m) => Bound::Excluded((score, m)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.order
+            .range((start, Bound::Unbounded))
+            .take_while(move |(_, member)| match &max {
+                Bound::Included(m) => member <= m,
+                Bound::Excluded(m) => member < m,
+                Bound::Unbounded => true,
+            })
+            .map(|(score, member)| (member, score.0))
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members<'a>(iter: impl Iterator<Item = (&'a Bytes, f64)>) -> Vec<&'a str> {
+        iter.map(|(m, _)| std::str::from_utf8(m).unwrap()).collect()
+    }
+
+    #[test]
+    fn ts_sorted_set() {
+        let mut zset = SortedSet::default();
+        zset.insert(Bytes::from("c"), 3.0);
+        zset.insert(Bytes::from("a"), 1.0);
+        zset.insert(Bytes::from("b"), 1.0);
+        assert_eq!(zset.insert(Bytes::from("d"), f64::INFINITY), None);
+        assert_eq!(zset.insert(Bytes::from("c"), -0.0), Some(3.0));
+
+        assert_eq!(members(zset.iter()), ["c", "a", "b", "d"]);
+        assert_eq!(zset.rank(b"b"), Some(2));
+        assert_eq!(zset.score(b"c"), Some(0.0));
+        assert_eq!(
+            members(zset.range_by_score(Bound::Excluded(0.0), Bound::Included(1.0))),
+            ["a", "b"]
+        );
+        assert_eq!(
+            members(zset.range_by_score(Bound::Included(1.0), Bound::Unbounded)),
+            ["a", "b", "d"]
+        );
+
+        assert_eq!(zset.remove(b"c"), Some(0.0));
+        zset.remove(b"d");
+        assert_eq!(
+            members(zset.range_by_lex(Bound::Excluded(Bytes::from("a")), Bound::Unbounded)),
+            ["b"]
+        );
+        zset.remove(b"a");
+        zset.remove(b"b");
+        assert_eq!(zset.size(), 0);
+        assert!(zset.is_empty());
+    }
+}
@@ -13,17 +13,45 @@ pub enum Error {
     ProtocolError { b: u8 },
     #[snafu(display("String to decimal error"))]
     DecimalError,
+    #[snafu(display("invalid bulk length"))]
+    BulkLengthError,
+    #[snafu(display("invalid multibulk length"))]
+    MultibulkLengthError,
+    #[snafu(display("too deep nesting"))]
+    NestingError,
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 use std::io::Cursor;
 
-#[derive(Clone, Debug)]
+/// 解析客户端请求时的上限。声明的长度超过上限的 Frame 直接当作协议错误，
+/// 避免按照客户端声明的长度去分配内存
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// 单个 Bulk String 的最大字节数
+    pub max_bulk_len: usize,
+    /// 单个数组的最大元素个数
+    pub max_array_len: usize,
+    /// 数组最多嵌套几层
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Frame {
     Simple(String),
     Error(String),
-    Integer(u64),
+    Integer(i64),
     Bulk(Bytes),
     Null,
     Array(Vec<Frame>),
@@ -31,6 +59,16 @@ pub enum Frame {
 
 impl Frame {
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        Frame::check_limited(src, &Limits::default())
+    }
+
+    /// 和 `check` 一样，但是声明的长度或者嵌套层数超过 `limits` 时返回错误
+    pub fn check_limited(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<()> {
+        Frame::check_depth(src, limits, 0)
+    }
+
+    // depth 是当前 Frame 所在的嵌套层数，最外层是 0
+    fn check_depth(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<()> {
         match get_u8(src)? {
             // 字符串类型
             b'+' => {
@@ -44,7 +82,7 @@ impl Frame {
             }
             // 数字类型
             b':' => {
-                let _ = get_decimal(src)?;
+                let _ = get_signed(src)?;
                 Ok(())
             }
             // Bulk Strings
@@ -59,6 +97,11 @@ impl Frame {
                         Err(_) => IncompleteSnafu.fail()?,
                     };
 
+                    // 数据还没有收到，就可以拒绝过长的 Bulk String
+                    if len > limits.max_bulk_len {
+                        BulkLengthSnafu.fail()?
+                    }
+
                     // skip that number of bytes + 2 (\r\n).
                     skip(src, len + 2)
                 }
@@ -68,8 +111,16 @@ impl Frame {
                 // 先看这个数组有几个元素
                 let len = get_decimal(src)?;
 
+                if len > limits.max_array_len as u64 {
+                    MultibulkLengthSnafu.fail()?
+                }
+
+                if depth >= limits.max_depth {
+                    NestingSnafu.fail()?
+                }
+
                 for _ in 0..len {
-                    Frame::check(src)?;
+                    Frame::check_depth(src, limits, depth + 1)?;
                 }
 
                 Ok(())
@@ -98,9 +149,9 @@ impl Frame {
                 Ok(Frame::Error(string))
             }
             b':' => {
-                let u = get_decimal(src)?;
+                let n = get_signed(src)?;
 
-                Ok(Frame::Integer(u))
+                Ok(Frame::Integer(n))
             }
             b'$' => {
                 if b'-' == peek_u8(src)? {
@@ -133,12 +184,13 @@ impl Frame {
                 }
             }
             b'*' => {
-                let len = match get_decimal(src)?.try_into() {
+                let len: usize = match get_decimal(src)?.try_into() {
                     Ok(len) => len,
                     Err(_) => IncompleteSnafu.fail()?,
                 };
 
-                let mut out = Vec::with_capacity(len);
+                // 不能完全相信声明的长度：每个元素至少占一个字节，按剩余的数据量限制预分配
+                let mut out = Vec::with_capacity(len.min(src.remaining()));
 
                 for _ in 0..len {
                     out.push(Frame::parse(src)?);
@@ -151,6 +203,49 @@ impl Frame {
     }
 }
 
+impl Frame {
+    /// 把 Frame 编码成 RESP 格式，追加到 dst 的后面。嵌套的数组也可以编码
+    pub fn encode(&self, dst: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(val) => {
+                dst.push(b'+');
+                dst.extend_from_slice(val.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Error(val) => {
+                dst.push(b'-');
+                dst.extend_from_slice(val.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Integer(val) => {
+                dst.push(b':');
+                dst.extend_from_slice(val.to_string().as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Null => dst.extend_from_slice(b"$-1\r\n"),
+            Frame::Bulk(val) => {
+                dst.push(b'$');
+                put_decimal(dst, val.len() as u64);
+                dst.extend_from_slice(val);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Array(val) => {
+                dst.push(b'*');
+                put_decimal(dst, val.len() as u64);
+                for entry in val {
+                    entry.encode(dst);
+                }
+            }
+        }
+    }
+}
+
+// 写入一个十进制数字，以 \r\n 结尾
+fn put_decimal(dst: &mut Vec<u8>, val: u64) {
+    dst.extend_from_slice(val.to_string().as_bytes());
+    dst.extend_from_slice(b"\r\n");
+}
+
 /// 检测到一个完整的行（\r\n 结尾）
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8]> {
     if src.get_ref().is_empty() {
@@ -192,6 +287,15 @@ fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64> {
     atoi::<u64>(line).ok_or_else(|| DecimalSnafu.build())
 }
 
+// 数字类型可以是负数，比如 TTL 返回的 -1 / -2
+fn get_signed(src: &mut Cursor<&[u8]>) -> Result<i64> {
+    use atoi::atoi;
+
+    let line = get_line(src)?;
+
+    atoi::<i64>(line).ok_or_else(|| DecimalSnafu.build())
+}
+
 // 切掉当前 position 之前的内容，然后返回剩余内容的第一个 u8
 fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8> {
     if !src.has_remaining() {
@@ -218,56 +322,113 @@ mod tests {
     #[test]
     fn ts_check() {
         // 普通字符串："ab"
-        let v = vec![b'+', b'a', b'b', b'\r', b'\n'];
+        let v = [b'+', b'a', b'b', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
         assert!(Frame::check(&mut buff).is_ok());
 
         // 错误类型
-        let v = vec![b'-', b'a', b'b', b'\r', b'\n'];
+        let v = [b'-', b'a', b'b', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
         assert!(Frame::check(&mut buff).is_ok());
 
         // 异常的数字类型
-        let v = vec![b':', b'a', b'b', b'\r', b'\n'];
+        let v = [b':', b'a', b'b', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
         assert!(Frame::check(&mut buff).is_err());
 
         // 长度为 0 的数组
-        let v = vec![b'*', b'0', b'\r', b'\n'];
+        let v = [b'*', b'0', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
         assert!(Frame::check(&mut buff).is_ok());
 
         // 特殊 Bulk String：'-1\r\n'
-        let v = vec![b'$', b'-', b'1', b'\r', b'\n'];
+        let v = [b'$', b'-', b'1', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
         assert!(Frame::check(&mut buff).is_ok());
 
         // 异常的 Bulk String
-        let v = vec![b'$', b'3', b'\r', b'\n'];
+        let v = [b'$', b'3', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
         assert!(Frame::check(&mut buff).is_err());
 
         // 长度为 3 的 Bulk String："123"
-        let v = vec![b'$', b'3', b'\r', b'\n', b'1', b'2', b'3', b'\r', b'\n'];
+        let v = [b'$', b'3', b'\r', b'\n', b'1', b'2', b'3', b'\r', b'\n'];
+        let mut buff = Cursor::new(&v[..]);
+        assert!(Frame::check(&mut buff).is_ok());
+    }
+
+    #[test]
+    fn ts_check_limited() {
+        let limits = Limits {
+            max_bulk_len: 3,
+            max_array_len: 2,
+            max_depth: 1,
+        };
+
+        // 声明的长度超过上限，不用等数据到齐就报错
+        let v = b"$4\r\n";
+        let mut buff = Cursor::new(&v[..]);
+        assert!(matches!(
+            Frame::check_limited(&mut buff, &limits),
+            Err(Error::BulkLengthError)
+        ));
+
+        let v = b"*4294967295\r\n";
+        let mut buff = Cursor::new(&v[..]);
+        assert!(matches!(
+            Frame::check_limited(&mut buff, &limits),
+            Err(Error::MultibulkLengthError)
+        ));
+
+        // 嵌套的数组
+        let v = b"*1\r\n*1\r\n:1\r\n";
+        let mut buff = Cursor::new(&v[..]);
+        assert!(matches!(
+            Frame::check_limited(&mut buff, &limits),
+            Err(Error::NestingError)
+        ));
+
+        // 在上限之内
+        let v = b"*2\r\n$3\r\nget\r\n$1\r\nk\r\n";
         let mut buff = Cursor::new(&v[..]);
+        assert!(Frame::check_limited(&mut buff, &limits).is_ok());
+    }
+
+    #[test]
+    fn ts_encode() {
+        let frame = Frame::Array(vec![
+            Frame::Simple("OK".to_string()),
+            Frame::Integer(-12),
+            Frame::Null,
+            Frame::Array(vec![Frame::Bulk(Bytes::from("abc"))]),
+        ]);
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        assert_eq!(
+            buf,
+            b"*4\r\n+OK\r\n:-12\r\n$-1\r\n*1\r\n$3\r\nabc\r\n".to_vec()
+        );
+
+        // 编码出来的数据可以被重新解析
+        let mut buff = Cursor::new(&buf[..]);
         assert!(Frame::check(&mut buff).is_ok());
     }
 
     #[test]
     fn ts_get_decimal() {
-        let v = vec![b'1', b'2', b'\r', b'\n'];
+        let v = [b'1', b'2', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
 
         buff.set_position(0);
         assert_eq!(get_decimal(&mut buff).unwrap(), 12);
 
-        let v = vec![b'1', b'b', b'\r', b'\n'];
+        let v = [b'1', b'b', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
 
         buff.set_position(0);
         assert_eq!(get_decimal(&mut buff).unwrap(), 1);
 
-        let v = vec![b'a', b'b', b'\r', b'\n'];
+        let v = [b'a', b'b', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
 
         buff.set_position(0);
@@ -277,7 +438,7 @@ mod tests {
     #[test]
     fn ts_err_get_line() {
         // should end of \r\n
-        let v = vec![b'1', b'2'];
+        let v = [b'1', b'2'];
         let mut buff = Cursor::new(&v[..]);
 
         buff.set_position(0);
@@ -293,7 +454,7 @@ mod tests {
 
     #[test]
     fn ts_on_get_line() {
-        let v = vec![b'1', b'2', b'\r', b'\n', b'5', b'\r', b'\n'];
+        let v = [b'1', b'2', b'\r', b'\n', b'5', b'\r', b'\n'];
         let mut buff = Cursor::new(&v[..]);
 
         // 把 position 设置到 buff 的最后，get_u8 出错
@@ -323,7 +484,7 @@ mod tests {
 
     #[test]
     fn ts_on_get_u8() {
-        let v = vec![b'1', b'2', b'3', b'4', b'5'];
+        let v = [b'1', b'2', b'3', b'4', b'5'];
         let mut buff = Cursor::new(&v[..]);
 
         // 把 position 设置到 buff 的最后，get_u8 出错
@@ -343,7 +504,7 @@ mod tests {
 
     #[test]
     fn ts_on_peek_u8() {
-        let v = vec![b'1', b'2', b'3', b'4', b'5'];
+        let v = [b'1', b'2', b'3', b'4', b'5'];
         let mut buff = Cursor::new(&v[..]);
 
         // 把 position 设置到 buff 的最后，get_u8 出错
@@ -0,0 +1,100 @@
+/// Redis 风格的 glob 匹配（和 KEYS、ACL 的 key 模式一样）：
+/// `*` 匹配任意多个字符，`?` 匹配一个字符，`[abc]` / `[^abc]` / `[a-z]` 匹配一组字符，
+/// `\` 转义下一个字符
+pub fn matches(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // 最近一个 `*` 的位置，以及它当时匹配到的 s 的位置，用来回溯
+    let mut star: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, i));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => class(&pattern[p..], s[i]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == s[i]).then_some(2),
+            Some(&c) => (c == s[i]).then_some(1),
+            None => None,
+        };
+
+        match (step, star) {
+            (Some(n), _) => {
+                p += n;
+                i += 1;
+            }
+            // 匹配失败，让上一个 `*` 多吃掉一个字符再试
+            (None, Some((sp, si))) => {
+                star = Some((sp, si + 1));
+                p = sp + 1;
+                i = si + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+
+    // s 用完了，剩下的模式只能是 `*`
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// 匹配 `[...]`。匹配成功时返回整个 `[...]` 的长度
+fn class(pattern: &[u8], c: u8) -> Option<usize> {
+    let mut p = 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = (
+                pattern[p].min(pattern[p + 2]),
+                pattern[p].max(pattern[p + 2]),
+            );
+            matched |= (lo..=hi).contains(&c);
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+
+    // 没有闭合的 `[` 和 Redis 一样当作到模式结尾
+    let len = (p + 1).min(pattern.len());
+    (matched != negate).then_some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ts_matches() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"*", b"anything"));
+        assert!(matches(b"h?llo", b"hello"));
+        assert!(!matches(b"h?llo", b"hllo"));
+        assert!(matches(b"h*llo", b"heeeello"));
+        assert!(matches(b"h[ae]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-b]llo", b"hbllo"));
+        assert!(matches(b"a\\*b", b"a*b"));
+        assert!(!matches(b"a\\*b", b"axb"));
+        assert!(matches(
+            b"https://api.example.com/*",
+            b"https://api.example.com/v1/ip"
+        ));
+        assert!(!matches(
+            b"https://api.example.com/*",
+            b"https://evil.com/api.example.com/"
+        ));
+        assert!(matches(b"*a*b", b"xxaxxb"));
+        assert!(!matches(b"*a*b", b"xxaxxbc"));
+    }
+}
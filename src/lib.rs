@@ -1,7 +1,20 @@
+pub mod acl;
+pub mod clients;
 pub mod cmd;
+pub mod config;
+pub mod db;
 pub mod frame;
+pub mod metrics;
+pub mod monitor;
 pub mod server;
 
 mod connection;
+mod glob;
 mod parser;
+pub mod ratelimit;
+mod redact;
+pub mod scripts;
 pub mod shutdown;
+pub mod slowlog;
+pub mod state;
+pub mod telemetry;
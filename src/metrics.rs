@@ -0,0 +1,234 @@
+use std::convert::Infallible;
+use std::io;
+use std::sync::LazyLock;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use tokio::net::TcpListener;
+
+use snafu::{prelude::*, ResultExt};
+use tracing::warn;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("failed for io error {}", source))]
+    IoError { source: io::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// 进程内唯一的一组 Prometheus 指标，通过 admin 端口的 `/metrics` 暴露出去
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub connections_accepted: IntCounter,
+    pub connections_active: IntGauge,
+    /// 阻塞在 BLPOP 等命令上的客户端
+    pub blocked_clients: IntGauge,
+    /// 按命令名和结果（ok / error）统计的命令数
+    pub commands: IntCounterVec,
+    pub command_duration: HistogramVec,
+    /// 客户端发来的不合法请求（connection::Error::Frame）
+    pub frame_errors: IntCounter,
+    /// 按 host 和 http 状态码（请求失败时是 error）统计的上游请求数
+    pub upstream_requests: IntCounterVec,
+    pub upstream_duration: HistogramVec,
+    /// 按限流的对象（user / client / upstream）统计被拒绝的上游请求数
+    pub rate_limited: IntCounterVec,
+    pub net_input_bytes: IntCounter,
+    pub net_output_bytes: IntCounter,
+    /// 读命令在 keyspace 里找到 / 没找到 key 的次数
+    pub keyspace_hits: IntCounter,
+    pub keyspace_misses: IntCounter,
+    /// 因为过期被删除的 key（包括访问时发现的和后台任务删除的）
+    pub expired_keys: IntCounter,
+    /// 因为超过 maxmemory 被淘汰的 key
+    pub evicted_keys: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new();
+
+        // 指标的名字都是写死的，注册失败只可能是代码写错了
+        let connections_accepted = IntCounter::new(
+            "rmr_connections_accepted_total",
+            "Total number of accepted client connections",
+        )
+        .unwrap();
+        let connections_active =
+            IntGauge::new("rmr_connections_active", "Number of connected clients").unwrap();
+        let blocked_clients = IntGauge::new(
+            "rmr_blocked_clients",
+            "Number of clients blocked by BLPOP and similar commands",
+        )
+        .unwrap();
+        let commands = IntCounterVec::new(
+            Opts::new("rmr_commands_total", "Total number of processed commands"),
+            &["command", "outcome"],
+        )
+        .unwrap();
+        let command_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "rmr_command_duration_seconds",
+                "Time spent executing commands",
+            ),
+            &["command"],
+        )
+        .unwrap();
+        let frame_errors = IntCounter::new(
+            "rmr_frame_errors_total",
+            "Total number of malformed requests from clients",
+        )
+        .unwrap();
+        let upstream_requests = IntCounterVec::new(
+            Opts::new(
+                "rmr_upstream_requests_total",
+                "Total number of upstream http requests",
+            ),
+            &["host", "status"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "rmr_upstream_request_duration_seconds",
+                "Latency of upstream http requests",
+            ),
+            &["host"],
+        )
+        .unwrap();
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "rmr_rate_limited_total",
+                "Total number of upstream requests rejected by rate limits",
+            ),
+            &["scope"],
+        )
+        .unwrap();
+        let net_input_bytes =
+            IntCounter::new("rmr_net_input_bytes_total", "Total bytes read from clients").unwrap();
+        let net_output_bytes = IntCounter::new(
+            "rmr_net_output_bytes_total",
+            "Total bytes written to clients",
+        )
+        .unwrap();
+
+        let keyspace_hits = IntCounter::new(
+            "rmr_keyspace_hits_total",
+            "Number of successful key lookups by read commands",
+        )
+        .unwrap();
+        let keyspace_misses = IntCounter::new(
+            "rmr_keyspace_misses_total",
+            "Number of failed key lookups by read commands",
+        )
+        .unwrap();
+        let expired_keys =
+            IntCounter::new("rmr_expired_keys_total", "Total number of expired keys").unwrap();
+        let evicted_keys = IntCounter::new(
+            "rmr_evicted_keys_total",
+            "Total number of keys evicted due to maxmemory",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(connections_accepted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_active.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(blocked_clients.clone()))
+            .unwrap();
+        registry.register(Box::new(commands.clone())).unwrap();
+        registry
+            .register(Box::new(command_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(frame_errors.clone())).unwrap();
+        registry
+            .register(Box::new(upstream_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry
+            .register(Box::new(net_input_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(net_output_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(keyspace_hits.clone())).unwrap();
+        registry
+            .register(Box::new(keyspace_misses.clone()))
+            .unwrap();
+        registry.register(Box::new(expired_keys.clone())).unwrap();
+        registry.register(Box::new(evicted_keys.clone())).unwrap();
+
+        Metrics {
+            registry,
+            connections_accepted,
+            connections_active,
+            blocked_clients,
+            commands,
+            command_duration,
+            frame_errors,
+            upstream_requests,
+            upstream_duration,
+            rate_limited,
+            net_input_bytes,
+            net_output_bytes,
+            keyspace_hits,
+            keyspace_misses,
+            expired_keys,
+            evicted_keys,
+        }
+    }
+
+    /// 按照 Prometheus 的文本格式导出所有指标
+    pub fn render(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // 写到 Vec 里不会出错
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buf);
+        buf
+    }
+}
+
+/// 在 admin 端口上提供 http 服务：`GET /metrics` 返回所有指标
+pub async fn serve(listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await.context(IoSnafu)?;
+
+        tokio::spawn(async move {
+            let service = service_fn(|req| async move { Ok::<_, Infallible>(respond(req)) });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                warn!("failed to serve the metrics request. {}", e);
+            }
+        });
+    }
+}
+
+fn respond(req: Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                TextEncoder::new().format_type().parse().unwrap(),
+            );
+            *response.body_mut() = Body::from(METRICS.render());
+        }
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+
+    response
+}
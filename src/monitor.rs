@@ -0,0 +1,113 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tokio::sync::broadcast;
+
+use crate::clients::ClientInfo;
+
+// 每个 MONITOR 最多积压这么多条还没发出去的记录，再多就丢掉最老的
+const MONITOR_BACKLOG: usize = 1024;
+
+/// 把所有执行的命令广播给 MONITOR 的连接。
+/// 没有 MONITOR 的时候什么都不做，不会格式化命令
+#[derive(Debug)]
+pub struct Monitors {
+    notify: broadcast::Sender<String>,
+}
+
+impl Monitors {
+    pub fn new() -> Monitors {
+        Monitors {
+            notify: broadcast::channel(MONITOR_BACKLOG).0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.notify.receiver_count() > 0
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.notify.subscribe()
+    }
+
+    /// 按照 Redis 的格式广播一条命令：`timestamp [db addr] "cmd" "args"`
+    pub fn feed(&self, client: &ClientInfo, args: &[Bytes]) {
+        if !self.is_active() {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = format!(
+            "{}.{:06} [0 {}]",
+            now.as_secs(),
+            now.subsec_micros(),
+            client.addr
+        );
+        for arg in args {
+            line.push(' ');
+            repr(arg, &mut line);
+        }
+
+        // 所有 MONITOR 都在这期间断开的话会返回错误，不用管
+        let _ = self.notify.send(line);
+    }
+}
+
+impl Default for Monitors {
+    fn default() -> Monitors {
+        Monitors::new()
+    }
+}
+
+// 和 Redis 的 sdscatrepr 一样：加上双引号，转义特殊字符，不可打印的字节写成 \xHH。
+// 这样一条记录里不会有换行，可以作为 simple string 发出去
+pub(crate) fn repr(arg: &[u8], out: &mut String) {
+    out.push('"');
+    for &b in arg {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            b if b.is_ascii_graphic() || b == b' ' => out.push(b as char),
+            b => {
+                let _ = write!(out, "\\x{:02x}", b);
+            }
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::Clients;
+
+    #[test]
+    fn ts_repr() {
+        let mut out = String::new();
+        repr(b"a \"b\"\r\n\x01", &mut out);
+        assert_eq!(out, r#""a \"b\"\r\n\x01""#);
+    }
+
+    #[tokio::test]
+    async fn ts_feed() {
+        let clients = Clients::new();
+        let (client, _) = clients.register("127.0.0.1:5000".to_string(), String::new(), 8);
+
+        let monitors = Monitors::new();
+        assert!(!monitors.is_active());
+        monitors.feed(&client, &[Bytes::from("ping")]);
+
+        let mut rx = monitors.subscribe();
+        monitors.feed(&client, &[Bytes::from("get"), Bytes::from("http://a")]);
+        let line = rx.recv().await.unwrap();
+        assert!(line.ends_with(r#" [0 127.0.0.1:5000] "get" "http://a""#));
+    }
+}
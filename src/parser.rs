@@ -1,3 +1,5 @@
+use bytes::Bytes;
+
 use crate::frame::Frame;
 
 use std::{str, vec};
@@ -36,6 +38,11 @@ impl Parser {
         self.parts.next().ok_or_else(|| ParseSnafu.build())
     }
 
+    /// 是否还有没有读取的参数
+    pub fn has_next(&self) -> bool {
+        !self.parts.as_slice().is_empty()
+    }
+
     pub fn next_string(&mut self) -> Result<String> {
         match self.next()? {
             // Both `Simple` and `Bulk` representation may be strings. Strings
@@ -52,4 +59,13 @@ impl Parser {
             _ => ParseSnafu.fail()?,
         }
     }
+
+    /// 读出一个二进制安全的参数，比如 SET 的 key 和 value
+    pub fn next_bytes(&mut self) -> Result<Bytes> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(Bytes::from(s.into_bytes())),
+            Frame::Bulk(data) => Ok(data),
+            _ => ParseSnafu.fail()?,
+        }
+    }
 }
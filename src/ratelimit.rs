@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 令牌桶超过这么多个时，清理掉已经装满（也就是一段时间没用）的桶，
+// 避免大量不同的客户端 IP 让它无限增长
+const MAX_IDLE_BUCKETS: usize = 10000;
+
+// 清理要遍历所有的桶，最多这么久清理一次，不让每次请求都在锁里遍历一遍
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 限流的对象
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// 登录的 ACL 用户
+    User,
+    /// 客户端的 IP（unix socket 是它的路径）
+    Client,
+    /// 上游的 host
+    Upstream,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::User => "user",
+            Scope::Client => "client",
+            Scope::Upstream => "upstream",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 每秒补充 rate 个令牌，最多攒 burst 个
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// 一条限流规则：`target` 是用户名、IP 或者 host，`*` 表示没有单独配置的所有对象。
+/// 每个对象各自有一个令牌桶，`*` 并不是所有对象共用一个桶
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub scope: Scope,
+    pub target: String,
+    pub limit: Limit,
+}
+
+impl FromStr for Limit {
+    type Err = ();
+
+    /// `<rate> [burst]`，不写 burst 的话等于 rate（至少是 1）
+    fn from_str(s: &str) -> Result<Limit, ()> {
+        let mut args = s.split_whitespace();
+        let rate: f64 = args.next().ok_or(())?.parse().map_err(|_| ())?;
+        let burst: f64 = match args.next() {
+            Some(burst) => burst.parse().map_err(|_| ())?,
+            None => rate.ceil().max(1.0),
+        };
+        // NaN 也是不合法的
+        if args.next().is_some() || rate.is_nan() || rate <= 0.0 || burst.is_nan() || burst < 1.0 {
+            return Err(());
+        }
+        Ok(Limit { rate, burst })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.last = now;
+    }
+
+    // 还要等多久才有一个令牌
+    fn wait(&self, limit: &Limit) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / limit.rate)
+        }
+    }
+}
+
+// 每个对象的令牌桶
+#[derive(Debug)]
+struct Buckets {
+    map: HashMap<(Scope, String), Bucket>,
+    // 上一次清理的时间
+    pruned: Instant,
+}
+
+/// 按用户、客户端 IP 和上游 host 限制访问上游的频率。超过限制的请求直接拒绝，不排队
+#[derive(Debug)]
+pub struct RateLimiter {
+    rules: HashMap<(Scope, String), Limit>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(rules: &[Rule]) -> RateLimiter {
+        RateLimiter {
+            rules: rules
+                .iter()
+                .map(|rule| ((rule.scope, rule.target.clone()), rule.limit))
+                .collect(),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    fn limit(&self, scope: Scope, target: &str) -> Option<Limit> {
+        self.rules
+            .get(&(scope, target.to_string()))
+            .or_else(|| self.rules.get(&(scope, "*".to_string())))
+            .copied()
+    }
+
+    /// 访问一次上游前调用。所有适用的桶都有令牌时各扣掉一个；
+    /// 否则什么都不扣，返回被哪一类限制拦住，以及还要等多久才能再试
+    pub fn acquire(
+        &self,
+        user: Option<&str>,
+        client: &str,
+        host: &str,
+    ) -> Result<(), (Scope, Duration)> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+
+        let targets = [
+            user.map(|user| (Scope::User, user)),
+            Some((Scope::Client, client)),
+            Some((Scope::Upstream, host)),
+        ];
+        let limited: Vec<((Scope, String), Limit)> = targets
+            .into_iter()
+            .flatten()
+            .filter_map(|(scope, target)| {
+                self.limit(scope, target)
+                    .map(|limit| ((scope, target.to_string()), limit))
+            })
+            .collect();
+        if limited.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.map.len() > MAX_IDLE_BUCKETS
+            && now.saturating_duration_since(buckets.pruned) >= PRUNE_INTERVAL
+        {
+            self.prune(&mut buckets.map, now);
+            buckets.pruned = now;
+        }
+
+        let mut rejected: Option<(Scope, Duration)> = None;
+        for (key, limit) in &limited {
+            let bucket = buckets
+                .map
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::new(limit, now));
+            bucket.refill(limit, now);
+            let wait = bucket.wait(limit);
+            if wait > rejected.map_or(Duration::ZERO, |(_, w)| w) {
+                rejected = Some((key.0, wait));
+            }
+        }
+        if let Some(rejected) = rejected {
+            return Err(rejected);
+        }
+
+        for (key, _) in &limited {
+            if let Some(bucket) = buckets.map.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    fn prune(&self, buckets: &mut HashMap<(Scope, String), Bucket>, now: Instant) {
+        buckets.retain(|(scope, target), bucket| match self.limit(*scope, target) {
+            Some(limit) => {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.burst
+            }
+            None => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(scope: Scope, target: &str, limit: &str) -> Rule {
+        Rule {
+            scope,
+            target: target.to_string(),
+            limit: limit.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn ts_parse_limit() {
+        assert_eq!(
+            "10".parse(),
+            Ok(Limit {
+                rate: 10.0,
+                burst: 10.0
+            })
+        );
+        assert_eq!(
+            "0.5 3".parse(),
+            Ok(Limit {
+                rate: 0.5,
+                burst: 3.0
+            })
+        );
+        assert_eq!(
+            "0.5".parse(),
+            Ok(Limit {
+                rate: 0.5,
+                burst: 1.0
+            })
+        );
+        assert!("0".parse::<Limit>().is_err());
+        assert!("1 0".parse::<Limit>().is_err());
+        assert!("fast".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn ts_acquire() {
+        let limiter = RateLimiter::new(&[
+            rule(Scope::User, "batch", "1 2"),
+            rule(Scope::Upstream, "*", "1000"),
+        ]);
+
+        assert!(limiter.acquire(Some("batch"), "10.0.0.1", "a.com").is_ok());
+        assert!(limiter.acquire(Some("batch"), "10.0.0.1", "a.com").is_ok());
+        let (scope, wait) = limiter
+            .acquire(Some("batch"), "10.0.0.1", "a.com")
+            .unwrap_err();
+        assert_eq!(scope, Scope::User);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        // 其他用户不受影响
+        assert!(limiter
+            .acquire(Some("default"), "10.0.0.1", "a.com")
+            .is_ok());
+        // 被拒绝的请求不会扣掉其他桶的令牌：a.com 的桶只用了 3 个
+        let buckets = limiter.buckets.lock().unwrap();
+        let upstream = &buckets.map[&(Scope::Upstream, "a.com".to_string())];
+        assert!(upstream.tokens < 998.0 && upstream.tokens >= 997.0);
+    }
+
+    #[test]
+    fn ts_prune_interval() {
+        let limiter = RateLimiter::new(&[rule(Scope::Client, "*", "10")]);
+        let fill = |limiter: &RateLimiter| {
+            for i in 0..=MAX_IDLE_BUCKETS {
+                limiter
+                    .acquire(None, &format!("10.0.{}.{}", i / 256, i % 256), "a.com")
+                    .unwrap();
+            }
+            // 假装这些桶都已经装满了
+            for bucket in limiter.buckets.lock().unwrap().map.values_mut() {
+                bucket.tokens = 10.0;
+            }
+        };
+
+        // 刚清理过，超过了也先不清理
+        fill(&limiter);
+        limiter.acquire(None, "10.1.0.1", "a.com").unwrap();
+        assert_eq!(
+            limiter.buckets.lock().unwrap().map.len(),
+            MAX_IDLE_BUCKETS + 2
+        );
+
+        // 过了清理的间隔之后，装满的桶都被清理掉，只剩下还在用的两个
+        limiter.buckets.lock().unwrap().pruned -= PRUNE_INTERVAL;
+        limiter.acquire(None, "10.1.0.2", "a.com").unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().map.len(), 2);
+    }
+}
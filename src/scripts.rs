@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+
+/// EVAL / SCRIPT LOAD 加载过的脚本，按脚本内容的 sha1 保存，EVALSHA 用 sha1 执行。
+/// 和 Redis 一样只在内存里，重启或者 SCRIPT FLUSH 之后需要重新加载
+#[derive(Debug, Default)]
+pub struct Scripts {
+    scripts: Mutex<HashMap<String, Bytes>>,
+}
+
+impl Scripts {
+    pub fn new() -> Scripts {
+        Scripts::default()
+    }
+
+    /// 保存脚本，返回它的 sha1
+    pub fn load(&self, body: Bytes) -> String {
+        let sha = sha1_hex(&body);
+        self.scripts.lock().unwrap().insert(sha.clone(), body);
+        sha
+    }
+
+    /// sha1 不区分大小写
+    pub fn get(&self, sha: &str) -> Option<Bytes> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .get(&sha.to_lowercase())
+            .cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.scripts
+            .lock()
+            .unwrap()
+            .contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.scripts.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub fn sha1_hex(body: &[u8]) -> String {
+    Sha1::digest(body)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ts_load_and_flush() {
+        let scripts = Scripts::new();
+        let sha = scripts.load(Bytes::from("return 1"));
+        // 和 Redis 的 SCRIPT LOAD "return 1" 一样
+        assert_eq!(sha, "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
+        assert_eq!(
+            scripts.get(&sha.to_uppercase()),
+            Some(Bytes::from("return 1"))
+        );
+        assert!(scripts.exists(&sha));
+        assert!(!scripts.exists("ffffffffffffffffffffffffffffffffffffffff"));
+
+        scripts.flush();
+        assert!(scripts.is_empty());
+        assert_eq!(scripts.get(&sha), None);
+    }
+}
@@ -1,24 +1,39 @@
+use std::fmt;
+use std::fs;
 use std::future::Future;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::prelude::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
 
+use futures::future;
 use reqwest::header;
+use socket2::{SockRef, TcpKeepalive};
 use std::io;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::error;
-use log::warn;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_native_tls::{native_tls, TlsAcceptor};
 
-use tracing::{info, instrument};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
 
 use snafu::{prelude::*, ResultExt};
 
+use crate::acl;
 use crate::cmd;
+use crate::config::{Address, Config};
 use crate::connection;
 use crate::connection::Connection;
+use crate::db;
+use crate::frame::Frame;
+use crate::metrics::{self, METRICS};
+use crate::redact;
 use crate::shutdown::Shutdown;
+use crate::state::State;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -30,51 +45,348 @@ pub enum Error {
     HttpError { source: reqwest::Error },
     #[snafu(display("failed for io error {}", source))]
     IoError { source: io::Error },
+    #[snafu(display("failed to bind {}. {}", addr, source))]
+    BindError { addr: String, source: io::Error },
+    #[snafu(display("failed to bind {}. the path exists and is not a socket", path))]
+    NotSocketError { path: String },
+    #[snafu(display("failed on tls. {}", source))]
+    TlsError { source: native_tls::Error },
+    #[snafu(display("a tls listener needs both tls-cert-file and tls-key-file"))]
+    TlsConfigError,
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// accept 到的原始 socket，还没有做 TLS 握手
+#[derive(Debug)]
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Socket> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await.map(|(s, _)| Socket::Tcp(s)),
+            Listener::Unix(listener) => listener.accept().await.map(|(s, _)| Socket::Unix(s)),
+        }
+    }
+}
+
+impl Socket {
+    // unix socket 不需要 keepalive
+    fn set_keepalive(&self, keepalive: Duration) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => {
+                // 和 redis 一样：空闲 keepalive 秒后开始探测，探测间隔是它的 1/3。
+                // 间隔至少 1 秒，tcp-keepalive 是 1 或者 2 时为 0 的话 setsockopt 会报 EINVAL
+                let interval = (keepalive / 3).max(Duration::from_secs(1));
+                let params = TcpKeepalive::new()
+                    .with_time(keepalive)
+                    .with_interval(interval);
+                SockRef::from(s).set_tcp_keepalive(&params)
+            }
+            Socket::Unix(_) => Ok(()),
+        }
+    }
+
+    // 对端和本端的地址。unix socket 的对端没有地址，和 Redis 一样写成 `<path>:0`
+    fn addrs(&self) -> (String, String) {
+        match self {
+            Socket::Tcp(s) => {
+                let addr = |a: io::Result<std::net::SocketAddr>| {
+                    a.map(|a| a.to_string()).unwrap_or_default()
+                };
+                (addr(s.peer_addr()), addr(s.local_addr()))
+            }
+            Socket::Unix(s) => {
+                let path = s
+                    .local_addr()
+                    .ok()
+                    .and_then(|a| a.as_pathname().map(|p| p.display().to_string()))
+                    .unwrap_or_default();
+                (format!("{}:0", path), path)
+            }
+        }
+    }
+
+    fn fd(&self) -> i32 {
+        match self {
+            Socket::Tcp(s) => s.as_raw_fd(),
+            Socket::Unix(s) => s.as_raw_fd(),
+        }
+    }
+
+    // 如果端点开启了 TLS，先完成握手，再把 socket 包装成 Connection
+    async fn into_connection(self, tls: Option<TlsAcceptor>) -> Result<Connection> {
+        let connection = match (self, tls) {
+            (Socket::Tcp(s), Some(acceptor)) => {
+                Connection::new(acceptor.accept(s).await.context(TlsSnafu)?)
+            }
+            (Socket::Tcp(s), None) => Connection::new(s),
+            (Socket::Unix(s), _) => Connection::new(s),
+        };
+
+        Ok(connection)
+    }
+
+    // 尽力给客户端回复一个错误，然后关闭连接。放在单独的任务里做，而且有超时，
+    // 不会卡住 accept 循环
+    fn reject(self, tls: Option<TlsAcceptor>, msg: &'static str) {
+        tokio::spawn(time::timeout(REJECT_TIMEOUT, async move {
+            if let Ok(mut connection) = self.into_connection(tls).await {
+                let _ = connection.write_frame(&Frame::Error(msg.to_string())).await;
+            }
+        }));
+    }
+}
+
+/// 一个监听端点：底层的 listener，以及只对这个端点生效的设置
+pub struct Endpoint {
+    listener: Listener,
+    tls: Option<TlsAcceptor>,
+    noauth: bool,
+}
+
+impl Endpoint {
+    pub fn tcp(listener: TcpListener) -> Endpoint {
+        Endpoint {
+            listener: Listener::Tcp(listener),
+            tls: None,
+            noauth: false,
+        }
+    }
+
+    pub fn unix(listener: UnixListener) -> Endpoint {
+        Endpoint {
+            listener: Listener::Unix(listener),
+            tls: None,
+            noauth: false,
+        }
+    }
+
+    /// 从这个端点进来的连接都要先完成 TLS 握手
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Endpoint {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// 从这个端点进来的连接不需要 AUTH，没有登录时以 default 用户执行命令
+    pub fn without_auth(mut self) -> Endpoint {
+        self.noauth = true;
+        self
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls.is_some() { "tls" } else { "tcp" };
+        match &self.listener {
+            Listener::Tcp(l) => match l.local_addr() {
+                Ok(addr) => write!(f, "{}://{}", scheme, addr),
+                Err(_) => write!(f, "{}://?", scheme),
+            },
+            Listener::Unix(l) => match l
+                .local_addr()
+                .ok()
+                .and_then(|a| a.as_pathname().map(|p| p.to_owned()))
+            {
+                Some(path) => write!(f, "unix://{}", path.display()),
+                None => write!(f, "unix://?"),
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("listener", &self.listener)
+            .field("tls", &self.tls.is_some())
+            .field("noauth", &self.noauth)
+            .finish()
+    }
+}
+
+/// 按照配置，绑定所有的监听端点
+pub async fn bind(config: &Config) -> Result<Vec<Endpoint>> {
+    let mut acceptor = None;
+    let mut endpoints = Vec::with_capacity(config.listeners.len());
+
+    for listener in &config.listeners {
+        let endpoint = match &listener.address {
+            Address::Tcp(addr) => {
+                let l = TcpListener::bind(addr).await.context(BindSnafu { addr })?;
+                Endpoint::tcp(l)
+            }
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                let l = UnixListener::bind(path).context(BindSnafu {
+                    addr: path.display().to_string(),
+                })?;
+                Endpoint::unix(l)
+            }
+        };
+
+        let endpoint = if listener.tls {
+            // 所有 TLS 端点共用同一份证书，只加载一次
+            if acceptor.is_none() {
+                acceptor = Some(tls_acceptor(config)?);
+            }
+            endpoint.with_tls(acceptor.clone().unwrap())
+        } else {
+            endpoint
+        };
+        let endpoint = if listener.noauth {
+            endpoint.without_auth()
+        } else {
+            endpoint
+        };
+
+        endpoints.push(endpoint);
+    }
+
+    Ok(endpoints)
+}
+
+// 上次运行残留的 socket 文件会导致 bind 失败，先删掉它。
+// 路径上是普通文件、目录之类的话不能删，直接报错
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let addr = path.display().to_string();
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            fs::remove_file(path).context(BindSnafu { addr })
+        }
+        Ok(_) => NotSocketSnafu { path: addr }.fail(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(BindSnafu { addr }),
+    }
+}
+
+fn tls_acceptor(config: &Config) -> Result<TlsAcceptor> {
+    let (cert, key) = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => TlsConfigSnafu.fail()?,
+    };
+
+    let cert = fs::read(cert).context(IoSnafu)?;
+    let key = fs::read(key).context(IoSnafu)?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).context(TlsSnafu)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).context(TlsSnafu)?;
+
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+fn http_client() -> Result<reqwest::Client> {
+    let mut headers = header::HeaderMap::new();
+    headers.insert("Accept", header::HeaderValue::from_static("text/plain"));
+    headers.insert(
+        "User-Agent",
+        header::HeaderValue::from_static("HTTPie/3.1.0"),
+    );
+
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(3))
+        .pool_max_idle_per_host(20)
+        .build()
+        .context(HttpSnafu)
+}
+
 #[derive(Debug)]
 struct Handler {
-    shutdown: Shutdown,
     connection: Connection,
     fd: i32,
-    cli: reqwest::Client,
+    ctx: cmd::Context,
+    // 等待下一条命令的最长时间，None 表示一直等
+    idle_timeout: Option<Duration>,
+    // 连接来自不需要 AUTH 的端点
+    noauth: bool,
+    _permit: OwnedSemaphorePermit,
     _shutdown_complete: mpsc::Sender<()>,
 }
 
 impl Handler {
     pub fn new(
-        shutdown: Shutdown,
         connection: Connection,
         fd: i32,
-        cli: reqwest::Client,
+        ctx: cmd::Context,
+        idle_timeout: Option<Duration>,
+        noauth: bool,
+        _permit: OwnedSemaphorePermit,
         _shutdown_complete: mpsc::Sender<()>,
     ) -> Handler {
         Handler {
-            shutdown,
             connection,
             fd,
-            cli,
+            ctx,
+            idle_timeout,
+            noauth,
+            _permit,
             _shutdown_complete,
         }
     }
 
     // 针对每个连接，进行无限循环，直到：出错（返回 Err）或者客户端关闭连接（返回一个 Ok）
-    #[instrument(skip(self), fields(fd = self.fd))]
     pub async fn process(&mut self) -> Result<()> {
-        info!("the server accepted a new client. fd is: {}", self.fd);
+        info!("the server accepted a new client");
 
-        while !self.shutdown.is_shutdown() {
+        loop {
             // read_frame 返回 Err 的话，返回 Err 给 process 的调用者
-            let maybe_frame = tokio::select! {
-                res = self.connection.read_frame() => res,
-                _ = self.shutdown.recv() => {
-                    info!("the client shutdown grace. fd is: {}", self.fd);
-                    return Ok(());
+            // 空闲超时只在这里（等待下一条命令时）计算：执行中的命令，以及以后自己接管
+            // 连接的命令（订阅、MONITOR 之类）都不受它影响
+            let maybe_frame = if self.ctx.shutdown.is_shutdown() {
+                // 已经开始 shutdown：不再等待新的数据，只把 buffer 里已经完整收到的命令处理完
+                match self.connection.buffered_frame() {
+                    Ok(None) => {
+                        // 收尾结束，告诉客户端 server 要关闭了
+                        info!("the client shutdown grace");
+                        let reply = Frame::Error("ERR server is shutting down".to_string());
+                        let _ = self.connection.write_frame(&reply).await;
+                        return Ok(());
+                    }
+                    res => res,
                 }
-            }
-            .context(ConnectSnafu)?;
+            } else {
+                tokio::select! {
+                    res = self.connection.read_frame() => res,
+                    _ = idle(self.idle_timeout) => {
+                        info!("the client is idle for too long, close it");
+                        return Ok(());
+                    }
+                    // read_frame 被取消时，已经读到的数据还留在 buffer 里，回到循环开头去处理
+                    _ = self.ctx.shutdown.recv() => continue,
+                }
+            };
+
+            // 客户端发来了不合法（或者超过上限）的请求：回复一个协议错误，然后断开连接
+            let maybe_frame = match maybe_frame {
+                Ok(maybe_frame) => maybe_frame,
+                Err(e) => {
+                    let msg = match &e {
+                        connection::Error::Frame { source } => {
+                            METRICS.frame_errors.inc();
+                            Some(source.to_string())
+                        }
+                        connection::Error::QueryBuffer { .. } => {
+                            Some("query buffer too big".to_string())
+                        }
+                        _ => None,
+                    };
+                    if let Some(msg) = msg {
+                        let reply = Frame::Error(format!("ERR Protocol error: {}", msg));
+                        let _ = self.connection.write_frame(&reply).await;
+                    }
+                    return Err(e).context(ConnectSnafu);
+                }
+            };
 
             // 成功读到一个 Fame 的话，又有 2 种可能，match：
             let frame = match maybe_frame {
@@ -86,54 +398,185 @@ impl Handler {
                 }
             };
 
-            info!("get a new frame: {:?}", frame);
+            self.execute(frame).await?;
+        }
+    }
+
+    // 执行一条命令，并记录它的指标和 slowlog
+    async fn execute(&mut self, frame: Frame) -> Result<()> {
+        // 日志、slowlog 和 MONITOR 里用的参数，密码之类的敏感信息已经被替换掉
+        let state = self.ctx.state.clone();
+        let mut args = cmd::args(&frame);
+        redact::args(&mut args);
+        debug!(args = %redact::Args(&args), "get a new command");
+
+        // 把 Frame 转换为 Command
+        let cmd = cmd::Command::from_frame(frame).context(CommandSnafu)?;
+
+        let name = cmd.name();
+        self.ctx.client.touch(name, self.connection.buffered_len());
+        if !matches!(cmd, cmd::Command::Monitor(_)) {
+            state.monitor.feed(&self.ctx.client, &args);
+        }
+        self.ctx.upstream = None;
+
+        // 不需要 AUTH 的端点上，还没有登录的连接以 default 用户执行命令。
+        // default 用户被禁用（off）的话照样回复 NOAUTH
+        if self.noauth && self.ctx.client.user().is_none() {
+            self.ctx
+                .client
+                .set_user(Some(acl::DEFAULT_USER.to_string()));
+        }
 
-            // 把 Frame 转换为 Command
-            let cmd = cmd::Command::from_frame(frame).context(CommandSnafu)?;
-            info!("get first cmd: {:?}", cmd);
+        // 执行 Command。遇到异常的话，退出循环
+        // 执行过程不会被 shutdown 打断：正在进行的 http 请求会完成，回复也会发出去
+        // 每条命令是一个单独的 trace，不挂在整个连接的 span 下面，
+        // 否则一个长连接上的所有命令会变成一个巨大的 trace
+        let span = info_span!(
+            parent: None,
+            "command",
+            otel.name = name,
+            otel.kind = "server",
+            db.system = "redis",
+            db.operation = name,
+            client.id = self.ctx.client.id,
+            net.peer.name = %self.ctx.client.addr,
+            fd = self.fd,
+            otel.status_code = field::Empty,
+        );
 
-            // 执行 Command。遇到异常的话，退出循环
-            cmd.apply(&mut self.cli, &mut self.connection)
-                .await
-                .context(CommandSnafu)?;
+        let start = Instant::now();
+        let res = cmd
+            .apply(&mut self.ctx, &mut self.connection)
+            .instrument(span.clone())
+            .await;
+        let elapsed = start.elapsed();
+        if res.is_err() {
+            span.record("otel.status_code", "ERROR");
         }
 
-        Ok(())
+        METRICS
+            .command_duration
+            .with_label_values(&[name])
+            .observe(elapsed.as_secs_f64());
+        let outcome = if res.is_ok() { "ok" } else { "error" };
+        METRICS.commands.with_label_values(&[name, outcome]).inc();
+
+        let upstream = self.ctx.upstream.take();
+        state
+            .slowlog
+            .record(elapsed, args, &self.ctx.client, upstream);
+
+        res.context(CommandSnafu)
     }
 }
 
-pub async fn loop_on_listener(
-    listener: TcpListener,
+// 空闲超时的计时器；没有设置超时的话永远不会结束
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => time::sleep(timeout).await,
+        None => future::pending().await,
+    }
+}
+
+/// 所有监听端点共享的状态
+#[derive(Debug)]
+struct Shared {
+    cli: reqwest::Client,
+    state: Arc<State>,
+    // 用 semaphore 限制同时在线的客户端数量，每个连接持有一个 permit
+    limit_connections: Arc<Semaphore>,
+    // 优雅关闭超时后，通过它强制关闭还没有结束的连接
+    notify_force: broadcast::Sender<()>,
+}
+
+// accept 出错后第一次重试前等待的时间，之后每次翻倍，直到 MAX_ACCEPT_BACKOFF
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// 给被拒绝的连接回复错误时，最多等这么久
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// 这些错误是暂时性的（比如 fd 用完了），等一会儿就可能恢复，不应该因此关闭 listener
+fn is_transient_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    ) || matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM)
+    )
+}
+
+async fn loop_on_listener(
+    endpoint: Endpoint,
+    shared: &Shared,
     notify_shutdown: &broadcast::Sender<()>,
     shutdown_complete_tx: &mpsc::Sender<()>,
 ) -> Result<()> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert("Accept", header::HeaderValue::from_static("text/plain"));
-    headers.insert(
-        "User-Agent",
-        header::HeaderValue::from_static("HTTPie/3.1.0"),
-    );
-
-    let client = reqwest::Client::builder()
-        .default_headers(headers)
-        .timeout(Duration::from_secs(3))
-        .connection_verbose(true)
-        .pool_max_idle_per_host(20)
-        .build()
-        .context(HttpSnafu)?;
+    let mut backoff = MIN_ACCEPT_BACKOFF;
 
     // 进入主循环
     loop {
         // 进行 accept 操作
         // 如果 accept 到新的 socket，返回这个 socket；
-        // TODO: 如果遇到 Err，server 进入 shutdown 流程
-        let (socket, _) = listener.accept().await.context(IoSnafu)?;
+        // 如果遇到暂时性的错误，等待一段时间后重试（每次等待的时间翻倍）；
+        // 其他的错误则关闭这个 listener
+        let socket = match endpoint.listener.accept().await {
+            Ok(socket) => {
+                backoff = MIN_ACCEPT_BACKOFF;
+                socket
+            }
+            Err(e) if is_transient_accept_error(&e) => {
+                warn!(
+                    "failed to accept on {}, retry in {:?}. {}",
+                    endpoint, backoff, e
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+            Err(e) => return Err(e).context(IoSnafu),
+        };
+
+        // 超过 maxclients 的连接，回复一个错误后直接关闭
+        let permit = match shared.limit_connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("max number of clients reached, reject fd: {}", socket.fd());
+                socket.reject(endpoint.tls.clone(), "ERR max number of clients reached");
+                continue;
+            }
+        };
+
+        if shared.state.config.tcp_keepalive > 0 {
+            let keepalive = Duration::from_secs(shared.state.config.tcp_keepalive);
+            if let Err(e) = socket.set_keepalive(keepalive) {
+                warn!("failed to set tcp keepalive. fd is: {}. {}", socket.fd(), e);
+            }
+        }
+
+        METRICS.connections_accepted.inc();
 
-        let cli = client.clone();
+        let cli = shared.cli.clone();
+        let state = shared.state.clone();
+        let tls = endpoint.tls.clone();
+        let noauth = endpoint.noauth;
+        let limits = shared.state.config.frame_limits();
+        let max_buffer = shared.state.config.client_query_buffer_limit;
+        let idle_timeout = match shared.state.config.timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
 
         // 给每个连接一个 shutdown 实例，用来通知该连接优雅结束
         let shutdown = Shutdown::new(notify_shutdown.subscribe());
 
+        // 优雅关闭超时后，用它强制结束这个连接
+        let mut force = Shutdown::new(shared.notify_force.subscribe());
+
         // server shutdown 时要等所有的异步任务结束才能退出
         // 当异步任务的收尾结束时，利用这个发送者通知 server 该异步任务结束
         let shutdown_complete_tx = shutdown_complete_tx.clone();
@@ -141,23 +584,88 @@ pub async fn loop_on_listener(
         // 为每一条连接都生成一个新的任务，
         // `socket` 的所有权将被移动到新的任务中，并在那里进行处理
         tokio::spawn(async move {
-            let fd = socket.as_raw_fd();
-            let connection = Connection::new(socket);
+            let fd = socket.fd();
+            let (addr, laddr) = socket.addrs();
+            // TLS 握手放在新任务里做，慢的客户端不会卡住 accept 循环
+            let connection = match socket.into_connection(tls).await {
+                Ok(connection) => connection.with_limits(limits, max_buffer),
+                Err(err) => {
+                    warn!(
+                        "failed to set up the client connection. fd is: {}. {}",
+                        fd, err
+                    );
+                    return;
+                }
+            };
+
+            // 登记到客户端列表里，CLIENT KILL 通过 killed 结束这个连接
+            let (client, mut killed) = state.clients.register(addr.clone(), laddr, fd);
+            let id = client.id;
+            // default 用户不需要密码的话，连接上来就以它登录
+            client.set_user(state.acl.auto_login());
+            let ctx = cmd::Context {
+                cli,
+                state: state.clone(),
+                client,
+                shutdown,
+                upstream: None,
+                transaction: None,
+                watched: None,
+            };
 
             // shutdown_complete_tx 的 ownership 是 handler，当异步任务完成时，
             // handler 被释放，shutdown_complete_tx 也被释放
             // shutdown_complete_tx 是一个 sender，当释放一个 sender 时，会
             // 通知它的「接收者」
-            let mut handler = Handler::new(shutdown, connection, fd, cli, shutdown_complete_tx);
+            // permit 也一样：handler 被释放时，permit 归还给 semaphore
+            let mut handler = Handler::new(
+                connection,
+                fd,
+                ctx,
+                idle_timeout,
+                noauth,
+                permit,
+                shutdown_complete_tx,
+            );
 
-            if let Err(err) = handler.process().await {
-                error!("this client has an error, disconnect it {}!", err);
+            // 连接上的日志都带上 fd、客户端 id 和地址
+            let span = info_span!("client", fd, client.id = id, net.peer.name = %addr);
+
+            METRICS.connections_active.inc();
+            async {
+                tokio::select! {
+                    res = handler.process() => {
+                        if let Err(err) = res {
+                            error!("this client has an error, disconnect it {}!", err);
+                        }
+                    }
+                    _ = force.recv() => {
+                        warn!("the client is force closed");
+                    }
+                    _ = killed.recv() => {
+                        warn!("the client is killed");
+                    }
+                }
             }
+            .instrument(span)
+            .await;
+            METRICS.connections_active.dec();
+            state.clients.unregister(id);
         });
     }
 }
 
 pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
+    run_endpoints(vec![Endpoint::tcp(listener)], Config::default(), shutdown).await
+}
+
+/// 同时在多个端点上提供服务。所有端点共用同一个 http client、shutdown 广播和
+/// 连接数限制；某一个端点出错关闭时，其他端点不受影响
+pub async fn run_endpoints(
+    endpoints: Vec<Endpoint>,
+    config: Config,
+    shutdown: impl Future,
+) -> Result<()> {
     // 创建一个大小为 1 的 广播型 channel：当要 shutdown 整个 server 时，
     // 对所有的异步 tasks 进行广播现在要 Shutdown
     // 所有的异步任务接收到 shutdown 通知后，从异步任务循环中退出
@@ -165,24 +673,103 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
 
     let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
-    tokio::select! {
-        resp = loop_on_listener(listener, &notify_shutdown, &shutdown_complete_tx) => {
-            if let Err(e) = resp {
-                error!("the server on error: {}", e);
+    // admin 端口（/metrics）和客户端的端点分开，出错了也不影响客户端
+    let admin = match &config.metrics_bind {
+        Some(addr) => Some(TcpListener::bind(addr).await.context(BindSnafu { addr })?),
+        None => None,
+    };
+    let serve_metrics = async {
+        if let Some(listener) = admin {
+            if let Err(e) = metrics::serve(listener).await {
+                error!("the metrics listener on error: {}", e);
+            }
+        }
+        future::pending::<()>().await
+    };
+
+    let shared = Shared {
+        cli: http_client()?,
+        limit_connections: Arc::new(Semaphore::new(config.maxclients)),
+        notify_force: broadcast::channel(1).0,
+        state: Arc::new(State::new(config)),
+    };
+
+    // 后台删除过期的 key，和连接一样在 shutdown 时结束
+    let purge = db::purge_expired_keys(
+        shared.state.db.clone(),
+        Shutdown::new(notify_shutdown.subscribe()),
+    );
+    let purge_complete = shutdown_complete_tx.clone();
+    tokio::spawn(async move {
+        purge.await;
+        drop(purge_complete);
+    });
+
+    let listeners = endpoints.into_iter().map(|endpoint| {
+        let name = endpoint.to_string();
+        let listening =
+            loop_on_listener(endpoint, &shared, &notify_shutdown, &shutdown_complete_tx);
+        async move {
+            if let Err(e) = listening.await {
+                error!("the listener {} on error: {}", name, e);
             }
         }
+    });
+
+    tokio::select! {
+        _ = future::join_all(listeners) => {
+            error!("all the listeners are closed");
+        }
+        _ = serve_metrics => {}
         _ = shutdown => {
             warn!("the server shutdown");
         }
     }
 
+    shared.state.set_shutting_down();
+
+    // 走到这里时 listener 都已经被 drop 了，不会再 accept 新的连接
+
     // 当要 shutdown 时，drop 这个 channel 的发送者，这样所有的接收者会接收到一个 Err 消息作为
     // shutdown 的通知
     drop(notify_shutdown);
     drop(shutdown_complete_tx);
 
-    // 等待所有的异步任务完成收尾工作
-    shutdown_complete_rx.recv().await;
+    // 等待所有的异步任务完成收尾工作：执行完手上的命令，把回复发出去。
+    // 超过 shutdown-timeout 还没有结束的连接，强制关闭
+    let deadline = Duration::from_secs(shared.state.config.shutdown_timeout);
+    if time::timeout(deadline, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        warn!(
+            "some clients are still busy after {:?}, force close them",
+            deadline
+        );
+        let _ = shared.notify_force.send(());
+        shutdown_complete_rx.recv().await;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ts_small_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let socket = listener
+            .accept()
+            .await
+            .map(|(s, _)| Socket::Tcp(s))
+            .unwrap();
+
+        for secs in [1, 2, 3, 300] {
+            socket.set_keepalive(Duration::from_secs(secs)).unwrap();
+        }
+    }
+}
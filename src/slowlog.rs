@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::clients::ClientInfo;
+use crate::cmd::Upstream;
+
+// 和 Redis 一样：最多记录 32 个参数，每个参数最多 128 个字节
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+/// 执行得很慢的命令的记录，保存在一个有上限的环形 buffer 里，
+/// 通过 SLOWLOG GET / LEN / RESET 查看和清空
+#[derive(Debug)]
+pub struct Slowlog {
+    // 微秒。负数表示不记录
+    slower_than: i64,
+    max_len: usize,
+    next_id: AtomicU64,
+    // 最新的记录在最前面
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub id: u64,
+    /// 命令开始执行的 unix 时间（秒）
+    pub timestamp: u64,
+    pub duration: Duration,
+    /// 命令名和参数，太多、太长的部分被截断
+    pub args: Vec<Bytes>,
+    pub addr: String,
+    pub name: String,
+    /// 命令最后一次访问的上游，没有访问上游的命令是 None
+    pub upstream: Option<Upstream>,
+}
+
+impl Slowlog {
+    pub fn new(slower_than: i64, max_len: usize) -> Slowlog {
+        Slowlog {
+            slower_than,
+            max_len,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// slowlog-log-slower-than 是负数或者 slowlog-max-len 是 0 时不记录
+    pub fn enabled(&self) -> bool {
+        self.slower_than >= 0 && self.max_len > 0
+    }
+
+    /// 如果命令执行的时间超过了阈值，记录下来
+    pub fn record(
+        &self,
+        duration: Duration,
+        args: Vec<Bytes>,
+        client: &ClientInfo,
+        upstream: Option<Upstream>,
+    ) {
+        if !self.enabled() || duration.as_micros() < self.slower_than as u128 {
+            return;
+        }
+
+        let started = SystemTime::now() - duration;
+        let entry = Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: started
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            duration,
+            args: truncate(args),
+            addr: client.addr.clone(),
+            name: client.name().unwrap_or_default(),
+            upstream,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front(entry);
+        entries.truncate(self.max_len);
+    }
+
+    /// 最新的 n 条记录
+    pub fn get(&self, n: usize) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().take(n).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+fn truncate(mut args: Vec<Bytes>) -> Vec<Bytes> {
+    if args.len() > MAX_ARGS {
+        let more = args.len() - MAX_ARGS + 1;
+        args.truncate(MAX_ARGS - 1);
+        args.push(Bytes::from(format!("... ({} more arguments)", more)));
+    }
+
+    args.into_iter()
+        .map(|arg| {
+            if arg.len() <= MAX_ARG_LEN {
+                return arg;
+            }
+            let mut buf = BytesMut::with_capacity(MAX_ARG_LEN + 32);
+            buf.put_slice(&arg[..MAX_ARG_LEN]);
+            buf.put_slice(format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).as_bytes());
+            buf.freeze()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::Clients;
+
+    #[test]
+    fn ts_record() {
+        let clients = Clients::new();
+        let (client, _) = clients.register("127.0.0.1:5000".to_string(), String::new(), 8);
+
+        let slowlog = Slowlog::new(1000, 2);
+        let args = vec![Bytes::from("get"), Bytes::from("http://a")];
+
+        slowlog.record(Duration::from_micros(999), args.clone(), &client, None);
+        assert!(slowlog.is_empty());
+
+        for _ in 0..3 {
+            slowlog.record(Duration::from_millis(5), args.clone(), &client, None);
+        }
+        assert_eq!(slowlog.len(), 2);
+
+        let entries = slowlog.get(10);
+        assert_eq!(entries[0].id, 2);
+        assert_eq!(entries[1].id, 1);
+        assert_eq!(entries[0].addr, "127.0.0.1:5000");
+        assert_eq!(entries[0].args, args);
+
+        slowlog.reset();
+        assert!(slowlog.is_empty());
+    }
+
+    #[test]
+    fn ts_truncate() {
+        let args: Vec<Bytes> = (0..40).map(|i| Bytes::from(i.to_string())).collect();
+        let args = truncate(args);
+        assert_eq!(args.len(), MAX_ARGS);
+        assert_eq!(args[31], Bytes::from("... (9 more arguments)"));
+
+        let args = truncate(vec![Bytes::from(vec![b'a'; 130])]);
+        assert_eq!(args[0].len(), MAX_ARG_LEN + "... (2 more bytes)".len());
+    }
+}
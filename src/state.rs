@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::acl::Acl;
+use crate::clients::Clients;
+use crate::config::Config;
+use crate::db::Db;
+use crate::monitor::Monitors;
+use crate::ratelimit::RateLimiter;
+use crate::scripts::Scripts;
+use crate::slowlog::Slowlog;
+
+/// 所有连接共享的 server 状态。INFO 之类的管理命令从这里读取 server 的情况
+#[derive(Debug)]
+pub struct State {
+    pub config: Config,
+    pub acl: Acl,
+    pub db: Db,
+    pub clients: Clients,
+    pub slowlog: Slowlog,
+    pub monitor: Monitors,
+    pub ratelimit: RateLimiter,
+    pub scripts: Scripts,
+    start: Instant,
+    shutting_down: AtomicBool,
+}
+
+impl State {
+    pub fn new(config: Config) -> State {
+        State {
+            acl: Acl::new(&config),
+            db: Db::with_shards(config.keyspace_shards),
+            ratelimit: RateLimiter::new(&config.ratelimits),
+            slowlog: Slowlog::new(config.slowlog_log_slower_than, config.slowlog_max_len),
+            config,
+            clients: Clients::new(),
+            monitor: Monitors::new(),
+            scripts: Scripts::new(),
+            start: Instant::now(),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+}
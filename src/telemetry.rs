@@ -0,0 +1,184 @@
+use opentelemetry::global;
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::{Level, Metadata, Span};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, RollingFileAppender, Rotation};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::{self, EnvFilter, ParseError};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::util::TryInitError;
+
+use snafu::{prelude::*, ResultExt};
+
+use crate::config::{Config, LogFormat, LogRotation};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("failed to set up the otlp exporter. {}", source))]
+    OtlpError { source: TraceError },
+    #[snafu(display("failed to install the tracing subscriber. {}", source))]
+    SubscriberError { source: TryInitError },
+    #[snafu(display("bad log level '{}'. {}", level, source))]
+    LevelError { level: String, source: ParseError },
+    #[snafu(display("failed to open the log file. {}", source))]
+    LogFileError { source: rolling::InitError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// 日志和 trace 的全局设置。被 drop 时把还没有发出去的 span 发给 collector，
+/// 把还没有写完的日志写到文件里
+#[derive(Debug)]
+pub struct Telemetry {
+    otlp: bool,
+    _log_guard: Option<WorkerGuard>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if self.otlp {
+            global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// 安装全局的 tracing subscriber：
+/// 日志按照 log-level（或者 RUST_LOG）过滤，以文本或者 JSON 的格式写到标准输出或者日志文件；
+/// 配置了 otlp-endpoint 的话，每条命令和它访问上游的 span 通过 OTLP/HTTP 导出
+pub fn init(config: &Config) -> Result<Telemetry> {
+    // 访问上游时用 W3C traceparent 头传递 trace，上游的 span 能接到我们的 trace 上
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let level = match std::env::var("RUST_LOG") {
+        Ok(level) if !level.is_empty() => level,
+        _ => config.log_level.clone(),
+    };
+    let filter = EnvFilter::try_new(&level).context(LevelSnafu { level })?;
+
+    let (writer, log_guard) = match &config.logfile {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(log_file(path, config)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    // JSON 格式的每条日志都带上当前 span 的字段：
+    // 连接上的事件有 client span（fd、客户端 id、地址），命令里的事件有 command span
+    let logs = match config.log_format {
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_ansi(config.logfile.is_none())
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+    };
+
+    let otlp = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = tracer(endpoint, &config.otlp_service_name)?;
+            let layer = tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(filter::filter_fn(exported));
+            Some(layer)
+        }
+        None => None,
+    };
+    let enabled = otlp.is_some();
+
+    tracing_subscriber::registry()
+        .with(logs)
+        .with(otlp)
+        .try_init()
+        .context(SubscriberSnafu)?;
+
+    Ok(Telemetry {
+        otlp: enabled,
+        _log_guard: log_guard,
+    })
+}
+
+// 只导出命令和上游请求的 span（以及其中 info 以上的事件）。
+// 整个连接的 client span 只用来给日志加字段，它可能持续几个小时，不适合作为 trace
+fn exported(meta: &Metadata<'_>) -> bool {
+    if meta.is_span() {
+        matches!(meta.name(), "command" | "upstream")
+    } else {
+        *meta.level() <= Level::INFO
+    }
+}
+
+fn log_file(path: &std::path::Path, config: &Config) -> Result<RollingFileAppender> {
+    let rotation = match config.logfile_rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let prefix = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "rmr.log".to_string());
+
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(prefix)
+        .build(dir)
+        .context(LogFileSnafu)
+}
+
+fn tracer(endpoint: &str, service_name: &str) -> Result<trace::Tracer> {
+    let resource = Resource::new(vec![KeyValue::new(
+        "service.name",
+        service_name.to_string(),
+    )]);
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)
+        .context(OtlpSnafu)
+}
+
+/// 把 span 的 trace 上下文写到要发给上游的 http 头里。没有开启 trace 时什么都不写
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -13,7 +13,7 @@ async fn test_on_mock_http() {
 
     let mut client = client::connect(addr).await.unwrap();
     let value = client.get(&url).await.unwrap().unwrap();
-    assert!(value.len() > 0);
+    assert!(!value.is_empty());
     assert_eq!(b"1.1.1.1", &value[..]);
 }
 
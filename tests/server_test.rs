@@ -0,0 +1,1405 @@
+use httpmock::prelude::*;
+use rmr::config::Config;
+use rmr::server::{self, Endpoint};
+use serde_json::json;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+// 同一个 server 同时监听 TCP 和 unix socket，两边都可以正常处理命令
+#[tokio::test]
+async fn test_on_multi_listeners() {
+    let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = tcp.local_addr().unwrap();
+
+    let path = std::env::temp_dir().join(format!("rmr-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let unix = UnixListener::bind(&path).unwrap();
+
+    let (_stop_tx, stop_rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let endpoints = vec![Endpoint::tcp(tcp), Endpoint::unix(unix)];
+        server::run_endpoints(endpoints, Config::default(), stop_rx)
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, b"*1\r\n$4\r\nping\r\n").await,
+        b"+OK\r\n"
+    );
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, b"*1\r\n$4\r\nping\r\n").await,
+        b"+OK\r\n"
+    );
+
+    let _ = std::fs::remove_file(&path);
+}
+
+// unixsocket 的路径上残留的 socket 文件被替换掉；是普通文件的话不删除它，bind 失败
+#[tokio::test]
+async fn test_on_unixsocket_path() {
+    let path = std::env::temp_dir().join(format!("rmr-test-{}-stale.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    drop(UnixListener::bind(&path).unwrap());
+    let config: Config = format!("unixsocket {}", path.display()).parse().unwrap();
+    assert_eq!(server::bind(&config).await.unwrap().len(), 1);
+    let _ = std::fs::remove_file(&path);
+
+    let path = std::env::temp_dir().join(format!("rmr-test-{}.conf", std::process::id()));
+    std::fs::write(&path, "keep me").unwrap();
+    let config: Config = format!("unixsocket {}", path.display()).parse().unwrap();
+    let err = server::bind(&config).await.unwrap_err();
+    assert!(err.to_string().contains("is not a socket"), "{}", err);
+    assert_eq!(std::fs::read(&path).unwrap(), b"keep me");
+    let _ = std::fs::remove_file(&path);
+}
+
+// 超过 maxclients 的连接收到一个错误回复后被关闭，已有的连接不受影响
+#[tokio::test]
+async fn test_on_maxclients() {
+    let (addr, _stop_tx, _) = start_server(Config {
+        maxclients: 1,
+        ..Config::default()
+    })
+    .await;
+
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut first, b"*1\r\n$4\r\nping\r\n").await,
+        b"+OK\r\n"
+    );
+
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    let mut buf = Vec::new();
+    second.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"-ERR max number of clients reached\r\n");
+
+    assert_eq!(
+        roundtrip(&mut first, b"*1\r\n$4\r\nping\r\n").await,
+        b"+OK\r\n"
+    );
+}
+
+// 空闲超过 timeout 的连接被 server 关闭
+#[tokio::test]
+async fn test_on_idle_timeout() {
+    let (addr, _stop_tx, _) = start_server(Config {
+        timeout: 1,
+        ..Config::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, b"*1\r\n$4\r\nping\r\n").await,
+        b"+OK\r\n"
+    );
+
+    // 之后什么都不发，server 应该在 1 秒左右关闭连接
+    let mut buf = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+}
+
+// 声明的长度超过上限的请求：回复协议错误并断开连接
+#[tokio::test]
+async fn test_on_oversized_frame() {
+    let (addr, _stop_tx, _) = start_server(Config {
+        proto_max_bulk_len: 16,
+        ..Config::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"*4294967295\r\n").await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"-ERR Protocol error: invalid multibulk length\r\n");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"*2\r\n$3\r\nget\r\n$17\r\n")
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"-ERR Protocol error: invalid bulk length\r\n");
+}
+
+// shutdown 时，正在执行的命令（这里是一个慢的 http 请求）会完成并回复，之后客户端收到通知
+#[tokio::test]
+async fn test_on_graceful_shutdown() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/slow");
+        then.status(200)
+            .delay(Duration::from_millis(500))
+            .json_body(json!({ "origin": "1.1.1.1" }));
+    })
+    .await;
+    let url = mock.url("/slow");
+
+    let (addr, stop_tx, server) = start_server(Config::default()).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&command(&["get", &url])).await.unwrap();
+
+    // 等命令开始执行后再 shutdown
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    stop_tx.send(()).unwrap();
+
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(
+        buf,
+        b"$7\r\n1.1.1.1\r\n-ERR server is shutting down\r\n".to_vec()
+    );
+    server.await.unwrap();
+}
+
+// 超过 shutdown-timeout 还没有完成的连接会被强制关闭
+#[tokio::test]
+async fn test_on_shutdown_deadline() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/slow");
+        then.status(200)
+            .delay(Duration::from_secs(2))
+            .json_body(json!({ "origin": "1.1.1.1" }));
+    })
+    .await;
+    let url = mock.url("/slow");
+
+    let (addr, stop_tx, server) = start_server(Config {
+        shutdown_timeout: 0,
+        ..Config::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&command(&["get", &url])).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    stop_tx.send(()).unwrap();
+
+    // 没有等 http 请求完成，连接就被关闭了
+    let mut buf = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut buf)).await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+    server.await.unwrap();
+}
+
+// admin 端口上的 /metrics 能看到连接数、命令数和上游请求的指标
+#[tokio::test]
+async fn test_on_metrics() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/ip");
+        then.status(200).json_body(json!({ "origin": "1.1.1.1" }));
+    })
+    .await;
+    let url = mock.url("/ip");
+
+    let admin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let admin_addr = admin.local_addr().unwrap();
+    drop(admin);
+
+    let (addr, _stop_tx, _) = start_server(Config {
+        metrics_bind: Some(admin_addr.to_string()),
+        ..Config::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", &url])).await,
+        b"$7\r\n1.1.1.1\r\n"
+    );
+
+    let body = reqwest::get(format!("http://{}/metrics", admin_addr))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("rmr_connections_active"));
+    assert!(body.contains(r#"rmr_commands_total{command="get",outcome="ok"}"#));
+    assert!(body.contains(r#"rmr_upstream_requests_total{host="127.0.0.1",status="200"}"#));
+    assert!(body.contains("rmr_net_input_bytes_total"));
+
+    let status = reqwest::get(format!("http://{}/other", admin_addr))
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, 404);
+}
+
+// INFO 按 section 返回 Redis 格式的统计信息
+#[tokio::test]
+async fn test_on_info() {
+    let (addr, _stop_tx, _) = start_server(Config::default()).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    roundtrip(&mut stream, &command(&["ping"])).await;
+
+    let reply = roundtrip(&mut stream, &command(&["info", "server"])).await;
+    let reply = String::from_utf8(reply).unwrap();
+    assert!(reply.starts_with('$'));
+    assert!(reply.contains("# Server\r\n"));
+    assert!(reply.contains("uptime_in_seconds:"));
+    assert!(reply.contains("shutdown_in_progress:0\r\n"));
+    assert!(!reply.contains("# Clients"));
+
+    // 同一个进程里其他测试执行过的命令也会统计进来，回复可能一次读不完
+    stream
+        .write_all(&command(&["INFO", "Commandstats"]))
+        .await
+        .unwrap();
+    let reply = String::from_utf8(read_bulk(&mut stream).await).unwrap();
+    assert!(reply.contains("cmdstat_info:calls="));
+
+    // 未知的 section 返回空字符串
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["info", "nothing"])).await,
+        b"$0\r\n\r\n"
+    );
+}
+
+// CLIENT SETNAME / LIST / ID / KILL：可以找到某个客户端并把它断开
+#[tokio::test]
+async fn test_on_client_admin() {
+    let (addr, _stop_tx, _) = start_server(Config::default()).await;
+
+    let mut worker = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut worker, &command(&["client", "setname", "worker-1"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut worker, &command(&["client", "getname"])).await,
+        b"$8\r\nworker-1\r\n"
+    );
+    let id = roundtrip(&mut worker, &command(&["client", "id"])).await;
+    let id = String::from_utf8(id).unwrap();
+    let id = id.trim_start_matches(':').trim_end();
+
+    let mut admin = TcpStream::connect(addr).await.unwrap();
+    let list = roundtrip(&mut admin, &command(&["client", "list"])).await;
+    let list = String::from_utf8(list).unwrap();
+    let line = list.lines().find(|l| l.contains("name=worker-1")).unwrap();
+    assert!(line.contains(&format!("id={} ", id)));
+    assert!(line.contains("cmd=client"));
+
+    assert_eq!(
+        roundtrip(&mut admin, &command(&["client", "setname", "bad name"])).await,
+        b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n"
+    );
+
+    assert_eq!(
+        roundtrip(&mut admin, &command(&["client", "kill", "id", id])).await,
+        b":1\r\n"
+    );
+    let mut buf = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(1), worker.read_to_end(&mut buf)).await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+
+    assert_eq!(
+        roundtrip(&mut admin, &command(&["client", "kill", "127.0.0.1:1"])).await,
+        b"-ERR No such client\r\n"
+    );
+}
+
+// 慢的命令（包括访问上游的时间）会记到 slowlog 里，带上上游的 url 和状态
+#[tokio::test]
+async fn test_on_slowlog() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/slow");
+        then.status(200)
+            .delay(Duration::from_millis(200))
+            .json_body(json!({ "origin": "1.1.1.1" }));
+    })
+    .await;
+    let url = mock.url("/slow");
+
+    let (addr, _stop_tx, _) = start_server(Config {
+        slowlog_log_slower_than: 100_000,
+        ..Config::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    roundtrip(&mut stream, &command(&["get", &url])).await;
+    roundtrip(&mut stream, &command(&["ping"])).await;
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["slowlog", "len"])).await,
+        b":1\r\n"
+    );
+
+    let reply = roundtrip(&mut stream, &command(&["slowlog", "get"])).await;
+    let reply = String::from_utf8(reply).unwrap();
+    assert!(reply.starts_with("*1\r\n*8\r\n:0\r\n"));
+    assert!(reply.contains(&format!("*2\r\n$3\r\nget\r\n${}\r\n{}\r\n", url.len(), url)));
+    assert!(reply.ends_with("$3\r\n200\r\n"));
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["slowlog", "reset"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["slowlog", "get", "-1"])).await,
+        b"*0\r\n"
+    );
+}
+
+// MONITOR 实时收到其他客户端执行的命令，格式和 Redis 一样
+#[tokio::test]
+async fn test_on_monitor() {
+    let (addr, stop_tx, server) = start_server(Config::default()).await;
+
+    let mut monitor = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut monitor, &command(&["monitor"])).await,
+        b"+OK\r\n"
+    );
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let local = stream.local_addr().unwrap();
+    roundtrip(&mut stream, &command(&["client", "setname", "a b"])).await;
+
+    let mut buf = vec![0; 1024];
+    let n = monitor.read(&mut buf).await.unwrap();
+    let line = String::from_utf8(buf[..n].to_vec()).unwrap();
+    assert!(line.starts_with('+'));
+    assert!(line.ends_with(&format!(
+        " [0 {}] \"client\" \"setname\" \"a b\"\r\n",
+        local
+    )));
+
+    // shutdown 时监视器也会结束
+    stop_tx.send(()).unwrap();
+    let mut buf = Vec::new();
+    monitor.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"-ERR server is shutting down\r\n");
+    server.await.unwrap();
+}
+
+// 启动 server，返回它监听的地址、用来 shutdown 的 sender，以及 server 任务的 handle
+async fn start_server(config: Config) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let handle = tokio::spawn(async move {
+        server::run_endpoints(vec![Endpoint::tcp(listener)], config, stop_rx)
+            .await
+            .unwrap();
+    });
+
+    (addr, stop_tx, handle)
+}
+
+// 设置了 requirepass 后必须先 AUTH；ACL 用户只能执行允许的命令、访问允许的 url
+#[tokio::test]
+async fn test_on_acl() {
+    let config: Config = "
+        requirepass foobared
+        user reader on >secret +get url~http://127.0.0.1:1/*
+    "
+    .parse()
+    .unwrap();
+    let (addr, _stop_tx, _) = start_server(config).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["client", "id"])).await,
+        b"-NOAUTH Authentication required.\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["auth", "wrong"])).await,
+        b"-WRONGPASS invalid username-password pair or user is disabled.\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["auth", "reader", "secret"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["acl", "whoami"])).await,
+        b"-NOPERM User reader has no permissions to run the 'acl|whoami' command\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", "http://example.com/ip"])).await,
+        b"-NOPERM No permissions to access a url\r\n"
+    );
+
+    let mut admin = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut admin, &command(&["auth", "foobared"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut admin, &command(&["acl", "whoami"])).await,
+        b"$7\r\ndefault\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut admin, &command(&["acl", "deluser", "reader"])).await,
+        b":1\r\n"
+    );
+    // 删除用户后，以它登录的连接被断开
+    let mut buf = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut buf)).await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+}
+
+// 设置了 requirepass 时，noauth 端点上的连接不用 AUTH 就以 default 用户执行命令，另一个端点照样要先 AUTH
+#[tokio::test]
+async fn test_on_listener_noauth() {
+    let config: Config = "requirepass foobared".parse().unwrap();
+    let secured = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let secured_addr = secured.local_addr().unwrap();
+    let trusted = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let trusted_addr = trusted.local_addr().unwrap();
+
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let endpoints = vec![
+            Endpoint::tcp(secured),
+            Endpoint::tcp(trusted).without_auth(),
+        ];
+        server::run_endpoints(endpoints, config, stop_rx)
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(secured_addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["acl", "whoami"])).await,
+        b"-NOAUTH Authentication required.\r\n"
+    );
+    let mut stream = TcpStream::connect(trusted_addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["acl", "whoami"])).await,
+        b"$7\r\ndefault\r\n"
+    );
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// 超过限流的上游请求直接被拒绝，回复多久之后可以重试
+#[tokio::test]
+async fn test_on_rate_limit() {
+    let mock = MockServer::start_async().await;
+    let api = mock.mock_async(|when, then| {
+        when.method(GET).path("/ip");
+        then.status(200).json_body(json!({ "origin": "1.1.1.1" }));
+    });
+    let api = api.await;
+
+    let config: Config = "ratelimit-upstream 127.0.0.1 0.5 2".parse().unwrap();
+    let (addr, _stop_tx, _) = start_server(config).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let get = command(&["get", &mock.url("/ip")]);
+    assert_eq!(roundtrip(&mut stream, &get).await, b"$7\r\n1.1.1.1\r\n");
+    assert_eq!(roundtrip(&mut stream, &get).await, b"$7\r\n1.1.1.1\r\n");
+
+    let reply = String::from_utf8(roundtrip(&mut stream, &get).await).unwrap();
+    assert!(reply.starts_with("-RATELIMITED retry after "), "{}", reply);
+    let wait: u64 = reply
+        .trim_start_matches("-RATELIMITED retry after ")
+        .trim_end_matches("ms\r\n")
+        .parse()
+        .unwrap();
+    assert!(wait > 1000 && wait <= 2000);
+    // 被拒绝的请求没有发到上游
+    assert_eq!(api.hits_async().await, 2);
+}
+
+// SET 写入 keyspace，带过期时间的 key 到期后被删除；http(s) 的 key 有缓存时不访问上游
+#[tokio::test]
+async fn test_on_keyspace_expire() {
+    let mock = MockServer::start_async().await;
+    let api = mock
+        .mock_async(|when, then| {
+            when.method(GET).path("/ip");
+            then.status(200).json_body(json!({ "origin": "1.1.1.1" }));
+        })
+        .await;
+    let (addr, _stop_tx, _) = start_server(Config::default()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&["set", "job", "running", "px", "100"])
+        )
+        .await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", "job"])).await,
+        b"$7\r\nrunning\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["ttl", "job"])).await,
+        b":0\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["set", "job", "x", "ex", "0"])).await,
+        b"-ERR invalid expire time in 'set' command\r\n"
+    );
+
+    // worker 缓存的上游结果，过期之前 GET 直接返回缓存
+    let url = mock.url("/ip");
+    roundtrip(&mut stream, &command(&["set", &url, "cached", "px", "100"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", &url])).await,
+        b"$6\r\ncached\r\n"
+    );
+    assert_eq!(api.hits_async().await, 0);
+
+    // 后台任务删除过期的 key，之后 GET 重新访问上游
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let info = roundtrip(&mut stream, &command(&["info", "keyspace"])).await;
+    assert_eq!(info, b"$12\r\n# Keyspace\r\n\r\n");
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", "job"])).await,
+        b"$-1\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", &url])).await,
+        b"$7\r\n1.1.1.1\r\n"
+    );
+    assert_eq!(api.hits_async().await, 1);
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["ttl", "job"])).await,
+        b":-2\r\n"
+    );
+}
+
+// string 命令读写 keyspace 里的值；MGET 里缓存没有的 url 同时访问上游
+#[tokio::test]
+async fn test_on_string_commands() {
+    let mock = MockServer::start_async().await;
+    let a = mock
+        .mock_async(|when, then| {
+            when.method(GET).path("/a");
+            then.status(200)
+                .json_body(json!({ "origin": "1.1.1.1" }))
+                .delay(Duration::from_millis(200));
+        })
+        .await;
+    let b = mock
+        .mock_async(|when, then| {
+            when.method(GET).path("/b");
+            then.status(200)
+                .json_body(json!({ "origin": "2.2.2.2" }))
+                .delay(Duration::from_millis(200));
+        })
+        .await;
+    let (addr, _stop_tx, _) = start_server(Config::default()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["mset", "n", "10", "s", "ab"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["incrby", "n", "-3"])).await,
+        b":7\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["append", "s", "cd"])).await,
+        b":4\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["incr", "s"])).await,
+        b"-ERR value is not an integer or out of range\r\n"
+    );
+
+    // 缓存里没有的两个 url 同时访问上游，总耗时接近一次请求
+    let (url_a, url_b) = (mock.url("/a"), mock.url("/b"));
+    let start = Instant::now();
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&["mget", "n", &url_a, "missing", &url_b])
+        )
+        .await,
+        b"*4\r\n$1\r\n7\r\n$7\r\n1.1.1.1\r\n$-1\r\n$7\r\n2.2.2.2\r\n"
+    );
+    assert!(start.elapsed() < Duration::from_millis(390));
+    assert_eq!((a.hits_async().await, b.hits_async().await), (1, 1));
+}
+
+// HFETCH 用上游返回的 JSON 对象替换整个 hash，上游出错时 hash 保持不变
+#[tokio::test]
+async fn test_on_hfetch() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/users/1");
+        then.status(200)
+            .json_body(json!({ "name": "alice", "age": 30, "tags": ["a"] }));
+    })
+    .await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/missing");
+        then.status(404).json_body(json!({ "error": "not found" }));
+    })
+    .await;
+    let (addr, _stop_tx, _) = start_server(Config::default()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // 上游的 JSON 对象整个替换掉原来的 hash
+    roundtrip(&mut stream, &command(&["hset", "user:1", "stale", "x"])).await;
+    let url = mock.url("/users/1");
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["hfetch", "user:1", &url])).await,
+        b":3\r\n"
+    );
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&["hmget", "user:1", "name", "age", "tags", "stale"])
+        )
+        .await,
+        b"*4\r\n$5\r\nalice\r\n$2\r\n30\r\n$5\r\n[\"a\"]\r\n$-1\r\n"
+    );
+
+    // 上游出错时不修改 hash
+    let reply = roundtrip(
+        &mut stream,
+        &command(&["hfetch", "user:1", &mock.url("/missing")]),
+    )
+    .await;
+    assert!(reply.starts_with(b"-ERR failed for http error"));
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["hlen", "user:1"])).await,
+        b":3\r\n"
+    );
+}
+
+// 阻塞的 BLPOP 按先来后到拿到元素；超时回复 nil；shutdown 时不再等待
+#[tokio::test]
+async fn test_on_blocking_pop() {
+    let (addr, stop_tx, server) = start_server(Config::default()).await;
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    let mut producer = TcpStream::connect(addr).await.unwrap();
+
+    first
+        .write_all(&command(&["blpop", "jobs", "0"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    second
+        .write_all(&command(&["blpop", "other", "jobs", "0"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        roundtrip(&mut producer, &command(&["rpush", "jobs", "a", "b", "c"])).await,
+        b":3\r\n"
+    );
+    let mut buf = vec![0; 1024];
+    let n = first.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"*2\r\n$4\r\njobs\r\n$1\r\na\r\n");
+    let n = second.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"*2\r\n$4\r\njobs\r\n$1\r\nb\r\n");
+    assert_eq!(
+        roundtrip(&mut producer, &command(&["lrange", "jobs", "0", "-1"])).await,
+        b"*1\r\n$1\r\nc\r\n"
+    );
+
+    let start = Instant::now();
+    assert_eq!(
+        roundtrip(&mut producer, &command(&["brpop", "empty", "0.1"])).await,
+        b"$-1\r\n"
+    );
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    first
+        .write_all(&command(&["blmove", "empty", "done", "left", "right", "0"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stop_tx.send(()).unwrap();
+    let mut buf = Vec::new();
+    first.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"$-1\r\n-ERR server is shutting down\r\n".to_vec());
+    drop((second, producer));
+    server.await.unwrap();
+}
+
+// set 和 sorted set 的命令，以及类型不对时的 WRONGTYPE
+#[tokio::test]
+async fn test_on_sorted_sets() {
+    let (addr, stop_tx, server) = start_server(Config::default()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["sadd", "tags", "a", "b", "a"])).await,
+        b":2\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["sinter", "tags", "missing"])).await,
+        b"*0\r\n"
+    );
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&["zadd", "board", "10", "ann", "7.5", "bob", "12", "cat"])
+        )
+        .await,
+        b":3\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["zincrby", "board", "5", "bob"])).await,
+        b"$4\r\n12.5\r\n"
+    );
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&[
+                "zrange",
+                "board",
+                "+inf",
+                "11",
+                "byscore",
+                "rev",
+                "withscores"
+            ])
+        )
+        .await,
+        b"*4\r\n$3\r\nbob\r\n$4\r\n12.5\r\n$3\r\ncat\r\n$2\r\n12\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["zpopmin", "board"])).await,
+        b"*2\r\n$3\r\nann\r\n$2\r\n10\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["zrank", "board", "bob"])).await,
+        b":1\r\n"
+    );
+    assert!(
+        roundtrip(&mut stream, &command(&["zadd", "tags", "1", "x"]))
+            .await
+            .starts_with(b"-WRONGTYPE")
+    );
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// 上游的回复记到 upstream-stream 里，阻塞在 XREADGROUP 上的 worker 马上拿到，确认之后不再 pending
+#[tokio::test]
+async fn test_on_upstream_stream() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/ip");
+        then.status(200).json_body(json!({ "origin": "1.1.1.1" }));
+    })
+    .await;
+    let config = Config {
+        upstream_stream: Some("responses".to_string()),
+        ..Config::default()
+    };
+    let (addr, stop_tx, server) = start_server(config).await;
+    let mut worker = TcpStream::connect(addr).await.unwrap();
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    assert_eq!(
+        roundtrip(
+            &mut worker,
+            &command(&["xgroup", "create", "responses", "workers", "$", "mkstream"])
+        )
+        .await,
+        b"+OK\r\n"
+    );
+    worker
+        .write_all(&command(&[
+            "xreadgroup",
+            "group",
+            "workers",
+            "w1",
+            "block",
+            "0",
+            "streams",
+            "responses",
+            ">",
+        ]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let url = mock.url("/ip");
+    assert_eq!(
+        roundtrip(&mut client, &command(&["get", &url])).await,
+        b"$7\r\n1.1.1.1\r\n"
+    );
+    let mut buf = vec![0; 1024];
+    let n = worker.read(&mut buf).await.unwrap();
+    let reply = String::from_utf8_lossy(&buf[..n]).to_string();
+    assert!(reply.starts_with("*1\r\n*2\r\n$9\r\nresponses\r\n*1\r\n"));
+    assert!(reply.contains(&format!("$3\r\nurl\r\n${}\r\n{}\r\n", url.len(), url)));
+    assert!(reply.contains("$6\r\nstatus\r\n$3\r\n200\r\n"));
+    let id = reply.split("\r\n").nth(7).unwrap().to_string();
+
+    assert_eq!(
+        roundtrip(
+            &mut worker,
+            &command(&["xack", "responses", "workers", &id])
+        )
+        .await,
+        b":1\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut worker, &command(&["xpending", "responses", "workers"])).await,
+        b"*4\r\n:0\r\n$-1\r\n$-1\r\n$-1\r\n"
+    );
+
+    // XREAD 的 BLOCK 超时回复 nil
+    let start = Instant::now();
+    assert_eq!(
+        roundtrip(
+            &mut client,
+            &command(&["xread", "block", "100", "streams", "responses", "$"])
+        )
+        .await,
+        b"$-1\r\n"
+    );
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// DEL / EXISTS / SCAN 等管理命令；RENAME 过来的 list 交给阻塞在目标 key 上的客户端
+#[tokio::test]
+async fn test_on_keyspace_commands() {
+    let (addr, stop_tx, server) = start_server(Config::default()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut waiter = TcpStream::connect(addr).await.unwrap();
+
+    roundtrip(&mut stream, &command(&["mset", "a", "1", "b", "2"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exists", "a", "b", "c"])).await,
+        b":2\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["scan", "0", "match", "a*"])).await,
+        b"*2\r\n$1\r\n0\r\n*1\r\n$1\r\na\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["del", "a", "c"])).await,
+        b":1\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["dbsize"])).await,
+        b":1\r\n"
+    );
+
+    waiter
+        .write_all(&command(&["blpop", "jobs", "0"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    roundtrip(&mut stream, &command(&["rpush", "staging", "x"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["rename", "staging", "jobs"])).await,
+        b"+OK\r\n"
+    );
+    let mut buf = vec![0; 1024];
+    let n = waiter.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"*2\r\n$4\r\njobs\r\n$1\r\nx\r\n");
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["flushdb", "async"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["keys", "*"])).await,
+        b"*0\r\n"
+    );
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// 多个客户端在多个线程上同时修改分在不同 shard 里的 key，结果和一个一个执行一样
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_on_concurrent_clients() {
+    let (addr, stop_tx, server) = start_server(Config::default()).await;
+
+    let clients: Vec<_> = (0..8)
+        .map(|_| {
+            tokio::spawn(async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                for i in 0..100 {
+                    let key = format!("counter:{}", i % 10);
+                    roundtrip(&mut stream, &command(&["incr", &key])).await;
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    for i in 0..10 {
+        let key = format!("counter:{}", i);
+        assert_eq!(
+            roundtrip(&mut stream, &command(&["get", &key])).await,
+            b"$2\r\n80\r\n"
+        );
+    }
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["dbsize"])).await,
+        b":10\r\n"
+    );
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// 超过 maxmemory 时：noeviction 拒绝写入但是可以 DEL；allkeys-lru 淘汰 key 腾出空间
+#[tokio::test]
+async fn test_on_maxmemory() {
+    let mut config = Config {
+        maxmemory: 1000,
+        ..Config::default()
+    };
+    let (addr, stop_tx, server) = start_server(config.clone()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    for i in 0..20 {
+        roundtrip(&mut stream, &command(&["set", &format!("k{}", i), "v"])).await;
+    }
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["set", "one-more", "v"])).await,
+        b"-OOM command not allowed when used memory > 'maxmemory'.\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["decr", "counter"])).await,
+        b"-OOM command not allowed when used memory > 'maxmemory'.\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["eval", "return 1", "0"])).await,
+        b"-OOM command not allowed when used memory > 'maxmemory'.\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", "k0"])).await,
+        b"$1\r\nv\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["del", "k0", "k1"])).await,
+        b":2\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["set", "one-more", "v"])).await,
+        b"+OK\r\n"
+    );
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+
+    config.maxmemory_policy = "allkeys-lru".parse().unwrap();
+    let (addr, stop_tx, server) = start_server(config).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    for i in 0..100 {
+        assert_eq!(
+            roundtrip(&mut stream, &command(&["set", &format!("k{}", i), "v"])).await,
+            b"+OK\r\n"
+        );
+    }
+    let dbsize = roundtrip(&mut stream, &command(&["dbsize"])).await;
+    let dbsize: usize = std::str::from_utf8(&dbsize[1..dbsize.len() - 2])
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(dbsize < 20, "dbsize {}", dbsize);
+    // 最后写入的 key 还在
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", "k99"])).await,
+        b"$1\r\nv\r\n"
+    );
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// MULTI 之后命令排队，EXEC 时一起执行；WATCH 的 key 被别的连接修改过的话 EXEC 回复 nil
+#[tokio::test]
+async fn test_on_transactions() {
+    let (addr, stop_tx, server) = start_server(Config::default()).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut other = TcpStream::connect(addr).await.unwrap();
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["multi"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["set", "k", "1"])).await,
+        b"+QUEUED\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["incr", "k"])).await,
+        b"+QUEUED\r\n"
+    );
+    // list 是空的，事务里的 BLPOP 不阻塞
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["blpop", "list", "0"])).await,
+        b"+QUEUED\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exec"])).await,
+        b"*3\r\n+OK\r\n:2\r\n$-1\r\n"
+    );
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["multi"])).await,
+        b"+OK\r\n"
+    );
+    roundtrip(&mut stream, &command(&["incr", "k"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["discard"])).await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", "k"])).await,
+        b"$1\r\n2\r\n"
+    );
+
+    // 排队时出错的话放弃整个事务
+    roundtrip(&mut stream, &command(&["multi"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["incr"])).await,
+        b"-ERR wrong number of arguments for 'incr' command\r\n"
+    );
+    roundtrip(&mut stream, &command(&["incr", "k"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exec"])).await,
+        b"-EXECABORT Transaction discarded because of previous errors.\r\n"
+    );
+    // 不认识的命令也一样
+    roundtrip(&mut stream, &command(&["multi"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["nosuchcmd"])).await,
+        b"-ERR unknown command 'nosuchcmd'\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exec"])).await,
+        b"-EXECABORT Transaction discarded because of previous errors.\r\n"
+    );
+
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["watch", "k"])).await,
+        b"+OK\r\n"
+    );
+    roundtrip(&mut other, &command(&["incr", "k"])).await;
+    roundtrip(&mut stream, &command(&["multi"])).await;
+    roundtrip(&mut stream, &command(&["incr", "k"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exec"])).await,
+        b"$-1\r\n"
+    );
+
+    // EXEC 之后不再 WATCH
+    roundtrip(&mut stream, &command(&["watch", "k"])).await;
+    roundtrip(&mut stream, &command(&["multi"])).await;
+    roundtrip(&mut stream, &command(&["incr", "k"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exec"])).await,
+        b"*1\r\n:4\r\n"
+    );
+    roundtrip(&mut other, &command(&["incr", "k"])).await;
+    roundtrip(&mut stream, &command(&["multi"])).await;
+    roundtrip(&mut stream, &command(&["incr", "k"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exec"])).await,
+        b"*1\r\n:6\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["exec"])).await,
+        b"-ERR EXEC without MULTI\r\n"
+    );
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// EVAL / EVALSHA 执行脚本：redis.call 访问 keyspace，http.get 访问上游，沙箱里没有 os 等库，超时后中止
+#[tokio::test]
+async fn test_on_scripting() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/ip");
+        then.status(200).json_body(json!({ "origin": "1.1.1.1" }));
+    })
+    .await;
+    let config = Config {
+        lua_time_limit: 100,
+        ..Config::default()
+    };
+    let (addr, stop_tx, server) = start_server(config).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&[
+                "eval",
+                "redis.call('rpush', KEYS[1], ARGV[1], ARGV[2]) return redis.call('lrange', KEYS[1], 0, -1)",
+                "1",
+                "list",
+                "a",
+                "b"
+            ])
+        )
+        .await,
+        b"*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+    );
+    // redis.call 的错误回复原样回复，redis.pcall 把它作为返回值
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&["eval", "return redis.call('incr', KEYS[1])", "1", "list"])
+        )
+        .await,
+        b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+    );
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&[
+                "eval",
+                "local res = redis.pcall('incr', KEYS[1]) return res.err ~= nil",
+                "1",
+                "list"
+            ])
+        )
+        .await,
+        b":1\r\n"
+    );
+
+    let sha = "e0e1f9fabfc9d4800c877a703b823ac0578ff8db";
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["script", "load", "return 1"])).await,
+        format!("$40\r\n{}\r\n", sha).as_bytes()
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["evalsha", sha, "0"])).await,
+        b":1\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["script", "exists", sha, "ffff"])).await,
+        b"*2\r\n:1\r\n:0\r\n"
+    );
+    roundtrip(&mut stream, &command(&["script", "flush"])).await;
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["evalsha", sha, "0"])).await,
+        b"-NOSCRIPT No matching script. Please use EVAL.\r\n"
+    );
+
+    // 沙箱
+    for script in [
+        "return os.time()",
+        "x = 1",
+        "return loadstring('return 1')()",
+    ] {
+        let reply = roundtrip(&mut stream, &command(&["eval", script, "0"])).await;
+        assert!(
+            reply.starts_with(b"-ERR Error running script: "),
+            "{}",
+            String::from_utf8_lossy(&reply)
+        );
+    }
+    // 声明了 KEYS 的脚本只锁住了这些 key 所在的 shard，不能执行访问所有 key 的命令
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&["eval", "return redis.call('dbsize')", "1", "list"])
+        )
+        .await,
+        b"-ERR Script attempted to access a key that was not declared in KEYS\r\n"
+    );
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&["eval", "return redis.call('dbsize')", "0"])
+        )
+        .await,
+        b":1\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["eval", "while true do end", "0"])).await,
+        b"-ERR Script killed after running for more than lua-time-limit (100ms)\r\n"
+    );
+
+    // 上游的结果和写入 keyspace 在同一个脚本里完成
+    let url = mock.url("/ip");
+    assert_eq!(
+        roundtrip(
+            &mut stream,
+            &command(&[
+                "eval",
+                "local ip = http.get(ARGV[1]) redis.call('set', KEYS[1], ip) return ip",
+                "1",
+                "ip",
+                &url
+            ])
+        )
+        .await,
+        b"$7\r\n1.1.1.1\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &command(&["get", "ip"])).await,
+        b"$7\r\n1.1.1.1\r\n"
+    );
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// 脚本等 http.get 的回复时一直锁着 KEYS：别的客户端写同一个 key 要等脚本执行完，
+// 脚本里的写入顺序不会被打断；等锁的客户端不会卡住 runtime，别的连接照样有回复；
+// 脚本的连接被关闭后脚本停下来
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_on_script_concurrency() {
+    let mock = MockServer::start_async().await;
+    mock.mock_async(|when, then| {
+        when.method(GET).path("/slow");
+        then.status(200)
+            .json_body(json!({ "origin": "1.1.1.1" }))
+            .delay(Duration::from_millis(200));
+    })
+    .await;
+    // 脚本不会超时
+    let config = Config {
+        lua_time_limit: 0,
+        ..Config::default()
+    };
+    let (addr, stop_tx, server) = start_server(config).await;
+
+    let mut script = TcpStream::connect(addr).await.unwrap();
+    let url = mock.url("/slow");
+    let body = "redis.call('set', KEYS[1], 'ip:') \
+                redis.call('append', KEYS[1], http.get(ARGV[1])) \
+                return redis.call('get', KEYS[1])";
+    script
+        .write_all(&command(&["eval", body, "1", "job", &url]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut other = TcpStream::connect(addr).await.unwrap();
+    other
+        .write_all(&command(&["append", "job", ";done"]))
+        .await
+        .unwrap();
+    // 不用 tokio 的 sleep：worker 被卡住的话 tokio 的定时器也会被耽误
+    std::thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    let mut third = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut third, b"*1\r\n$4\r\nping\r\n").await,
+        b"+OK\r\n"
+    );
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    // APPEND 在脚本之后执行
+    assert_eq!(read_bulk(&mut script).await, b"$10\r\nip:1.1.1.1\r\n");
+    let mut buf = vec![0; 1024];
+    let n = other.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b":15\r\n");
+    assert_eq!(
+        roundtrip(&mut third, &command(&["get", "job"])).await,
+        b"$15\r\nip:1.1.1.1;done\r\n"
+    );
+
+    let id = roundtrip(&mut script, &command(&["client", "id"])).await;
+    let id = std::str::from_utf8(&id[1..id.len() - 2])
+        .unwrap()
+        .to_string();
+    script
+        .write_all(&command(&["eval", "while true do end", "0"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    other
+        .write_all(&command(&["set", "k", "v2"]))
+        .await
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    assert_eq!(
+        roundtrip(&mut third, b"*1\r\n$4\r\nping\r\n").await,
+        b"+OK\r\n"
+    );
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    assert_eq!(
+        roundtrip(&mut third, &command(&["client", "kill", "id", &id])).await,
+        b":1\r\n"
+    );
+    let mut buf = vec![0; 1024];
+    let n = tokio::time::timeout(Duration::from_secs(1), other.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..n], b"+OK\r\n");
+
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+}
+
+// 把命令编码成 RESP 数组
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    buf
+}
+
+// 读取一个完整的 bulk string 回复
+async fn read_bulk(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = Vec::new();
+    loop {
+        let mut chunk = vec![0; 1024];
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "the connection is closed");
+        buf.extend_from_slice(&chunk[..n]);
+
+        let header = buf.iter().position(|b| *b == b'\n');
+        if let Some(header) = header {
+            let len: usize = std::str::from_utf8(&buf[1..header - 1])
+                .unwrap()
+                .parse()
+                .unwrap();
+            if buf.len() >= header + 1 + len + 2 {
+                return buf;
+            }
+        }
+    }
+}
+
+// 发送一个原始的 RESP 请求，读取一次回复
+async fn roundtrip<S: AsyncReadExt + AsyncWriteExt + Unpin>(stream: &mut S, req: &[u8]) -> Vec<u8> {
+    stream.write_all(req).await.unwrap();
+
+    let mut buf = vec![0; 1024];
+    let n = stream.read(&mut buf).await.unwrap();
+    buf.truncate(n);
+    buf
+}
@@ -0,0 +1,59 @@
+use httpmock::prelude::*;
+use rmr::config::Config;
+use rmr::server::{self, Endpoint};
+use rmr::telemetry;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+// 命令的 span 通过 OTLP 导出到 collector，访问上游时带上 traceparent 头。
+// 全局的 subscriber 只能装一次，所以单独放在一个测试文件里
+#[tokio::test(flavor = "multi_thread")]
+async fn test_on_otlp_export() {
+    let collector = MockServer::start_async().await;
+    let traces = collector
+        .mock_async(|when, then| {
+            when.method(POST).path("/v1/traces");
+            then.status(200);
+        })
+        .await;
+
+    let upstream = MockServer::start_async().await;
+    let api = upstream
+        .mock_async(|when, then| {
+            when.method(GET).path("/ip").header_exists("traceparent");
+            then.status(200).json_body(json!({ "origin": "1.1.1.1" }));
+        })
+        .await;
+    let url = upstream.url("/ip");
+
+    let config = Config {
+        otlp_endpoint: Some(collector.base_url()),
+        ..Config::default()
+    };
+    let telemetry = telemetry::init(&config).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_stop_tx, stop_rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        server::run_endpoints(vec![Endpoint::tcp(listener)], config, stop_rx)
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let req = format!("*2\r\n$3\r\nget\r\n${}\r\n{}\r\n", url.len(), url);
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut buf = vec![0; 1024];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"$7\r\n1.1.1.1\r\n");
+    api.assert_async().await;
+
+    // 关闭 tracer provider 时把缓存的 span 都发出去。它会阻塞当前线程
+    tokio::task::spawn_blocking(move || drop(telemetry))
+        .await
+        .unwrap();
+    assert!(traces.hits_async().await >= 1);
+}